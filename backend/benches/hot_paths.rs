@@ -0,0 +1,128 @@
+//! Benchmarks for the per-request hot paths: access-token signing and
+//! verification, Argon2 hashing, and the cache service.
+//!
+//! Run with `cargo bench`, or a single group with e.g.
+//! `cargo bench -- tokens`. Criterion keeps the previous run under
+//! `target/criterion` and reports each result against it, so run once on
+//! the base branch and again on yours; a "Performance has regressed" line
+//! with a change well outside the noise threshold is worth a look.
+//! `target/criterion/report/index.html` has the plots.
+//!
+//! The cache benchmarks use the in-memory backend, so they measure key
+//! building, serialization and the service layer, not Redis round trips.
+//!
+//! For CI, `BENCH_QUICK=1 cargo bench` takes few, short samples, enough
+//! to catch gross regressions in well under a minute. `cargo test
+//! --benches` just runs each benchmark once as a smoke test.
+
+use std::{env, sync::Arc, time::Duration};
+
+use backend::{
+    auth::{ids::UserId, password, role::Role, token_service::TokenService},
+    cache::{
+        cache_service::{CacheService, Persistence},
+        memory::MemoryBackend,
+    },
+};
+use criterion::{criterion_group, criterion_main, Criterion};
+use serde::{Deserialize, Serialize};
+use tokio::runtime::Runtime;
+
+const SECRET: &str = "benchmark-secret-that-is-at-least-32-bytes";
+
+fn tokens(c: &mut Criterion) {
+    let service = TokenService::new(SECRET, Duration::from_secs(900));
+    let user_id = UserId::new();
+    let token = service.issue_access_token(user_id, Role::Employee).unwrap();
+
+    let mut group = c.benchmark_group("tokens");
+    group.bench_function("issue_access_token", |b| {
+        b.iter(|| service.issue_access_token(user_id, Role::Employee).unwrap())
+    });
+    group.bench_function("verify_access_token", |b| {
+        b.iter(|| service.verify_access_token(&token).unwrap())
+    });
+    group.finish();
+}
+
+fn argon2(c: &mut Criterion) {
+    let hash = password::hash_password("correct horse battery staple").unwrap();
+
+    let mut group = c.benchmark_group("argon2");
+    // Each iteration takes tens of milliseconds by design.
+    group.sample_size(10);
+    group.bench_function("hash_password", |b| {
+        b.iter(|| password::hash_password("correct horse battery staple").unwrap())
+    });
+    group.bench_function("verify_password", |b| {
+        b.iter(|| password::verify_password("correct horse battery staple", &hash))
+    });
+    group.finish();
+}
+
+/// Roughly the shape of what handlers cache.
+#[derive(Serialize, Deserialize)]
+struct Profile {
+    id: UserId,
+    name: String,
+    email: String,
+    department: String,
+    roles: Vec<Role>,
+}
+
+fn cache(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let cache = CacheService::from_backend(Arc::new(MemoryBackend::new()), "bench").values();
+    let profile = Profile {
+        id: UserId::new(),
+        name: "Ada Lovelace".to_string(),
+        email: "ada@example.com".to_string(),
+        department: "engineering".to_string(),
+        roles: vec![Role::Employee, Role::Manager],
+    };
+    runtime
+        .block_on(cache.set("profile", &profile, Persistence::Persist))
+        .unwrap();
+
+    let mut group = c.benchmark_group("cache");
+    group.bench_function("set", |b| {
+        b.to_async(&runtime).iter(|| async {
+            cache
+                .set("profile", &profile, Persistence::Persist)
+                .await
+                .unwrap()
+        })
+    });
+    group.bench_function("get", |b| {
+        b.to_async(&runtime)
+            .iter(|| async { cache.get::<Profile>("profile").await.unwrap().unwrap() })
+    });
+    group.bench_function("increment", |b| {
+        b.to_async(&runtime).iter(|| async {
+            cache
+                .increment("counter", 1, Persistence::Persist)
+                .await
+                .unwrap()
+        })
+    });
+    group.finish();
+}
+
+fn config() -> Criterion {
+    let criterion = Criterion::default();
+    if env::var_os("BENCH_QUICK").is_some() {
+        criterion
+            .sample_size(10)
+            .warm_up_time(Duration::from_millis(200))
+            .measurement_time(Duration::from_secs(1))
+    } else {
+        criterion
+    }
+}
+
+criterion_group! {
+    name = benches;
+    config = config();
+    targets = tokens, argon2, cache
+}
+criterion_main!(benches);
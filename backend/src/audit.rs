@@ -0,0 +1,322 @@
+//! Security audit trail.
+//!
+//! Every event is emitted on the `audit` tracing target and appended to a
+//! capped Redis stream so recent history can be queried. Recording is
+//! best-effort: a failed append is logged but never fails the operation
+//! being audited.
+//!
+//! The stream is append-only: entries are never edited, only trimmed from
+//! the old end once it grows past its cap.
+
+use chrono::{DateTime, Utc};
+use redis::{streams::StreamRangeReply, AsyncCommands};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    auth::token_service::AccessTokenClaims, cache::redis_client::RedisClient,
+    middleware::request_id::current_request_id,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditOutcome {
+    Success,
+    Failure,
+    Denied,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditEvent {
+    pub actor: String,
+    /// The admin who acted as `actor` through an impersonation token.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub impersonator: Option<String>,
+    pub action: String,
+    pub outcome: AuditOutcome,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ip: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detail: Option<Value>,
+    pub correlation_id: String,
+    #[serde(with = "crate::timestamp::millis")]
+    pub at: DateTime<Utc>,
+}
+
+impl AuditEvent {
+    pub fn new(actor: impl Into<String>, action: impl Into<String>, outcome: AuditOutcome) -> Self {
+        Self {
+            actor: actor.into(),
+            impersonator: None,
+            action: action.into(),
+            outcome,
+            target: None,
+            ip: None,
+            detail: None,
+            correlation_id: current_request_id(),
+            at: Utc::now(),
+        }
+    }
+
+    /// An event acted by the owner of `claims`, naming the impersonator
+    /// as well when there is one.
+    pub fn by(
+        claims: &AccessTokenClaims,
+        action: impl Into<String>,
+        outcome: AuditOutcome,
+    ) -> Self {
+        Self {
+            impersonator: claims.impersonator().map(|admin| admin.to_string()),
+            ..Self::new(claims.sub.to_string(), action, outcome)
+        }
+    }
+
+    pub fn target(mut self, target: impl Into<String>) -> Self {
+        self.target = Some(target.into());
+        self
+    }
+
+    pub fn ip(mut self, ip: impl Into<String>) -> Self {
+        self.ip = Some(ip.into());
+        self
+    }
+
+    pub fn detail(mut self, detail: Value) -> Self {
+        self.detail = Some(detail);
+        self
+    }
+}
+
+/// Largest page [`AuditLog::query`] will return.
+pub const MAX_PAGE_SIZE: usize = 200;
+const DEFAULT_PAGE_SIZE: usize = 50;
+
+/// Upper bound on entries examined per query, so a filter that matches
+/// almost nothing can't walk the entire stream in one request.
+const MAX_SCAN: usize = 5_000;
+
+/// Filters for [`AuditLog::query`]. Every field is optional, and the
+/// filters that are set must all match.
+#[derive(Debug, Default, Clone, Deserialize)]
+pub struct AuditQuery {
+    pub actor: Option<String>,
+    pub action: Option<String>,
+    pub outcome: Option<AuditOutcome>,
+    pub ip: Option<String>,
+    /// Inclusive lower bound, unix seconds.
+    pub since: Option<usize>,
+    /// Inclusive upper bound, unix seconds.
+    pub until: Option<usize>,
+    /// `next_cursor` from a previous page.
+    pub cursor: Option<String>,
+    pub limit: Option<usize>,
+}
+
+impl AuditQuery {
+    fn matches(&self, event: &AuditEvent) -> bool {
+        self.actor.as_ref().is_none_or(|a| *a == event.actor)
+            && self.action.as_ref().is_none_or(|a| *a == event.action)
+            && self.outcome.is_none_or(|o| o == event.outcome)
+            && self
+                .ip
+                .as_ref()
+                .is_none_or(|ip| event.ip.as_ref() == Some(ip))
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct AuditRecord {
+    pub id: String,
+    #[serde(flatten)]
+    pub event: AuditEvent,
+}
+
+/// One page of events, newest first. `next_cursor` is set when older
+/// entries may still match.
+#[derive(Debug, Serialize)]
+pub struct AuditPage {
+    pub events: Vec<AuditRecord>,
+    pub next_cursor: Option<String>,
+}
+
+/// Whether `cursor` has the `<ms>-<seq>` shape of a stream entry id.
+pub fn is_valid_cursor(cursor: &str) -> bool {
+    cursor
+        .split_once('-')
+        .is_some_and(|(ms, seq)| ms.parse::<u64>().is_ok() && seq.parse::<u64>().is_ok())
+}
+
+#[derive(Clone)]
+pub struct AuditLog {
+    redis: RedisClient,
+    stream: String,
+    max_len: usize,
+}
+
+impl AuditLog {
+    pub fn new(redis: RedisClient, prefix: &str, max_len: usize) -> Self {
+        Self {
+            redis,
+            stream: format!("{prefix}:audit"),
+            max_len,
+        }
+    }
+
+    pub async fn record(&self, event: AuditEvent) {
+        tracing::info!(
+            target: "audit",
+            actor = %event.actor,
+            action = %event.action,
+            outcome = ?event.outcome,
+            target_id = event.target.as_deref().unwrap_or("-"),
+            correlation_id = %event.correlation_id,
+            "audit event"
+        );
+
+        if let Err(err) = self.append(&event).await {
+            tracing::error!(error = ?err, action = %event.action, "failed to persist audit event");
+        }
+    }
+
+    async fn append(&self, event: &AuditEvent) -> anyhow::Result<()> {
+        let mut conn = self.redis.connection();
+        let payload = serde_json::to_string(event)?;
+
+        redis::cmd("XADD")
+            .arg(&self.stream)
+            .arg("MAXLEN")
+            .arg("~")
+            .arg(self.max_len)
+            .arg("*")
+            .arg("event")
+            .arg(payload)
+            .query_async::<_, ()>(&mut conn)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Walks the stream from newest to oldest. Entry ids are millisecond
+    /// timestamps, so the time range and the cursor become range bounds
+    /// and only the remaining filters are applied here.
+    pub async fn query(&self, query: &AuditQuery) -> anyhow::Result<AuditPage> {
+        let mut conn = self.redis.connection();
+        let limit = query
+            .limit
+            .unwrap_or(DEFAULT_PAGE_SIZE)
+            .clamp(1, MAX_PAGE_SIZE);
+        let batch = (limit * 4).min(MAX_SCAN);
+
+        let mut end = match (&query.cursor, query.until) {
+            (Some(cursor), _) => format!("({cursor}"),
+            (None, Some(until)) => format!("{}", until as u64 * 1000 + 999),
+            (None, None) => "+".to_string(),
+        };
+        let start = query
+            .since
+            .map_or("-".to_string(), |since| (since as u64 * 1000).to_string());
+
+        let mut events = Vec::new();
+        let mut scanned = 0;
+
+        loop {
+            let reply: StreamRangeReply = conn
+                .xrevrange_count(&self.stream, &end, &start, batch)
+                .await?;
+            let exhausted = reply.ids.len() < batch;
+
+            for entry in reply.ids {
+                scanned += 1;
+                end = format!("({}", entry.id);
+
+                let Some(payload) = entry.get::<String>("event") else {
+                    continue;
+                };
+                let Ok(event) = serde_json::from_str::<AuditEvent>(&payload) else {
+                    continue;
+                };
+
+                if query.matches(&event) {
+                    events.push(AuditRecord {
+                        id: entry.id.clone(),
+                        event,
+                    });
+                }
+
+                if events.len() == limit || scanned >= MAX_SCAN {
+                    return Ok(AuditPage {
+                        events,
+                        next_cursor: Some(entry.id),
+                    });
+                }
+            }
+
+            if exhausted {
+                return Ok(AuditPage {
+                    events,
+                    next_cursor: None,
+                });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::fake_redis;
+
+    async fn log() -> AuditLog {
+        AuditLog::new(fake_redis::client().await, "test", 100)
+    }
+
+    #[tokio::test]
+    async fn recorded_events_come_back_newest_first() {
+        let audit = log().await;
+        audit
+            .record(AuditEvent::new("alice", "login", AuditOutcome::Success))
+            .await;
+        audit
+            .record(AuditEvent::new("bob", "login", AuditOutcome::Failure))
+            .await;
+
+        let page = audit.query(&AuditQuery::default()).await.unwrap();
+
+        let actors: Vec<&str> = page.events.iter().map(|r| r.event.actor.as_str()).collect();
+        assert_eq!(actors, ["bob", "alice"]);
+        assert_eq!(page.next_cursor, None);
+    }
+
+    #[tokio::test]
+    async fn query_filters_and_pages_by_cursor() {
+        let audit = log().await;
+        for actor in ["alice", "bob", "alice", "alice"] {
+            audit
+                .record(AuditEvent::new(actor, "login", AuditOutcome::Success))
+                .await;
+        }
+        let query = AuditQuery {
+            actor: Some("alice".to_string()),
+            limit: Some(2),
+            ..AuditQuery::default()
+        };
+
+        let first = audit.query(&query).await.unwrap();
+        assert_eq!(first.events.len(), 2);
+        let cursor = first.next_cursor.clone().unwrap();
+        assert!(is_valid_cursor(&cursor));
+
+        let second = audit
+            .query(&AuditQuery {
+                cursor: Some(cursor),
+                ..query
+            })
+            .await
+            .unwrap();
+        assert_eq!(second.events.len(), 1);
+        assert!(second.events[0].id < first.events[1].id);
+        assert_eq!(second.next_cursor, None);
+    }
+}
@@ -0,0 +1,1522 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashSet, fmt, time::Duration};
+
+use crate::{
+    audit::{AuditEvent, AuditLog, AuditOutcome},
+    auth::{
+        deactivation::Deactivations,
+        elevation::Elevations,
+        ids::{SessionId, UserId},
+        role::Role,
+        token_service::{AccessTokenClaims, ActionTokenClaims, TokenError, TokenService},
+    },
+    cache::{
+        cache_service::{CacheService, Persistence},
+        keys::{self, CacheKey},
+    },
+    error::AppError,
+    metrics::{self, AuthFailure, AuthStep, AuthSuccess},
+    notifications::{dispatch::Dispatcher, Notification},
+    rate_limit::RateLimiter,
+    shutdown::ShutdownSignal,
+    timestamp::from_unix_seconds,
+};
+
+/// Server-side record of a refresh session. Only the Argon2 hash of the
+/// refresh secret is stored.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Session {
+    pub user_id: UserId,
+    pub role: Role,
+    pub hash: String,
+    #[serde(with = "crate::timestamp::millis")]
+    pub created_at: DateTime<Utc>,
+    /// Last successful refresh. Absent until the first one, and on records
+    /// written before this was tracked.
+    #[serde(default, with = "crate::timestamp::millis_option")]
+    pub last_used_at: Option<DateTime<Utc>>,
+    /// Client description supplied at login, e.g. the user agent.
+    #[serde(default)]
+    pub device: Option<String>,
+    /// Hash of the secret this one replaced. Presenting it again means the
+    /// refresh token leaked, unless it happens within the grace window.
+    #[serde(default)]
+    pub previous_hash: Option<String>,
+}
+
+pub struct IssuedTokens {
+    pub access_token: String,
+    pub access_expires_at: DateTime<Utc>,
+    pub refresh_token: String,
+    pub refresh_expires_at: DateTime<Utc>,
+    /// Sessions closed to make room for this one under
+    /// [`SessionLimitPolicy::EvictOldest`].
+    pub evicted_sessions: Vec<EvictedSession>,
+}
+
+/// Tokens are bearer credentials, so only their expiry is shown.
+impl fmt::Debug for IssuedTokens {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("IssuedTokens")
+            .field("access_token", &"<redacted>")
+            .field("access_expires_at", &self.access_expires_at)
+            .field("refresh_token", &"<redacted>")
+            .field("refresh_expires_at", &self.refresh_expires_at)
+            .field("evicted_sessions", &self.evicted_sessions)
+            .finish()
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct EvictedSession {
+    pub session_id: SessionId,
+    #[serde(with = "crate::timestamp::millis")]
+    pub created_at: DateTime<Utc>,
+}
+
+/// What [`AuthService::start_session`] does when a user already has the
+/// maximum number of live sessions.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SessionLimitPolicy {
+    Reject,
+    #[default]
+    EvictOldest,
+}
+
+impl SessionLimitPolicy {
+    pub fn parse(raw: &str) -> Option<Self> {
+        match raw.to_ascii_lowercase().as_str() {
+            "reject" => Some(Self::Reject),
+            "evict_oldest" => Some(Self::EvictOldest),
+            _ => None,
+        }
+    }
+}
+
+/// What [`AuthService::refresh`] does when a spent refresh token is
+/// presented again, which usually means it was stolen.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ReuseResponse {
+    /// End the session the token belongs to.
+    #[default]
+    KillSession,
+    /// End every session of the user and reject all their access tokens,
+    /// forcing a new login on every device.
+    KillAll,
+}
+
+impl ReuseResponse {
+    pub fn parse(raw: &str) -> Option<Self> {
+        match raw.to_ascii_lowercase().as_str() {
+            "kill_session" => Some(Self::KillSession),
+            "kill_all" => Some(Self::KillAll),
+            _ => None,
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::KillSession => "kill_session",
+            Self::KillAll => "kill_all",
+        }
+    }
+}
+
+/// Caps how many access tokens one user can be issued per window, so a
+/// stolen credential can't mint tokens without limit.
+#[derive(Clone)]
+pub struct IssuanceLimit {
+    limiter: RateLimiter,
+    audit: AuditLog,
+    /// Service accounts that legitimately refresh far more often.
+    exempt: HashSet<UserId>,
+}
+
+impl IssuanceLimit {
+    pub fn new(limiter: RateLimiter, audit: AuditLog, exempt: HashSet<UserId>) -> Self {
+        Self {
+            limiter,
+            audit,
+            exempt,
+        }
+    }
+
+    async fn check(&self, user_id: UserId) -> Result<(), AppError> {
+        if self.exempt.contains(&user_id) {
+            return Ok(());
+        }
+
+        let result = self.limiter.check(&format!("issuance:{user_id}")).await?;
+        if result.allowed {
+            return Ok(());
+        }
+
+        self.audit
+            .record(
+                AuditEvent::new(
+                    user_id.to_string(),
+                    "auth.token_issuance_limited",
+                    AuditOutcome::Denied,
+                )
+                .detail(serde_json::json!({ "limit": result.limit })),
+            )
+            .await;
+        Err(AppError::TooManyRequests(result))
+    }
+}
+
+pub struct RefreshedAccess {
+    pub session_id: SessionId,
+    pub access_token: String,
+    pub access_expires_at: DateTime<Utc>,
+    /// Replaces the presented refresh token, which is now spent.
+    pub refresh_token: String,
+    pub refresh_expires_at: DateTime<Utc>,
+}
+
+impl fmt::Debug for RefreshedAccess {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RefreshedAccess")
+            .field("session_id", &self.session_id)
+            .field("access_token", &"<redacted>")
+            .field("access_expires_at", &self.access_expires_at)
+            .field("refresh_token", &"<redacted>")
+            .field("refresh_expires_at", &self.refresh_expires_at)
+            .finish()
+    }
+}
+
+/// What [`AuthService::verify_and_refresh`] made of a token pair.
+#[derive(Debug)]
+pub enum AuthOutcome {
+    /// The access token is still good.
+    Valid(AccessTokenClaims),
+    /// The access token had expired, so the session was refreshed; the
+    /// client should replace both of its tokens.
+    Refreshed {
+        access: RefreshedAccess,
+        claims: AccessTokenClaims,
+    },
+}
+
+/// Token lifecycle on top of [`TokenService`]: verification that honours
+/// revocation, refresh sessions, and the revocation primitives.
+#[derive(Clone)]
+pub struct AuthService {
+    tokens: TokenService,
+    cache: CacheService,
+    audit: AuditLog,
+    max_sessions: Option<usize>,
+    session_limit_policy: SessionLimitPolicy,
+    refresh_reuse_grace: Duration,
+    reuse_response: ReuseResponse,
+    security_notifications: Option<Dispatcher>,
+    issuance_limit: Option<IssuanceLimit>,
+    idle_timeout: Option<Duration>,
+    elevations: Option<Elevations>,
+    deactivations: Option<Deactivations>,
+}
+
+/// How often an authenticated request may record activity on its
+/// session; the idle timeout is only as precise as this.
+pub const ACTIVITY_TOUCH_INTERVAL: Duration = Duration::from_secs(60);
+
+impl AuthService {
+    pub fn new(tokens: TokenService, cache: CacheService, audit: AuditLog) -> Self {
+        Self {
+            tokens,
+            cache,
+            audit,
+            max_sessions: None,
+            session_limit_policy: SessionLimitPolicy::EvictOldest,
+            refresh_reuse_grace: Duration::ZERO,
+            reuse_response: ReuseResponse::KillSession,
+            security_notifications: None,
+            issuance_limit: None,
+            idle_timeout: None,
+            elevations: None,
+            deactivations: None,
+        }
+    }
+
+    /// Raises each authenticated user's role to any active elevation
+    /// granted to them. Costs one more Redis read per request.
+    pub fn with_elevations(mut self, elevations: Elevations) -> Self {
+        self.elevations = Some(elevations);
+        self
+    }
+
+    pub fn elevations(&self) -> Option<&Elevations> {
+        self.elevations.as_ref()
+    }
+
+    /// Refuses new and refreshed sessions for deactivated accounts. Run
+    /// [`run_deactivation_sweeper`](Self::run_deactivation_sweeper) to
+    /// carry out scheduled deactivations.
+    pub fn with_deactivations(mut self, deactivations: Deactivations) -> Self {
+        self.deactivations = Some(deactivations);
+        self
+    }
+
+    pub fn deactivations(&self) -> Option<&Deactivations> {
+        self.deactivations.as_ref()
+    }
+
+    /// Caps live refresh sessions per user. Concurrent logins can briefly
+    /// overshoot the cap; the next login brings it back in line.
+    pub fn with_session_limit(mut self, max: usize, policy: SessionLimitPolicy) -> Self {
+        self.max_sessions = Some(max.max(1));
+        self.session_limit_policy = policy;
+        self
+    }
+
+    /// Lets a client that retries a refresh within `grace` of the rotation
+    /// (a lost response, say) get the rotated token again rather than have
+    /// the retry treated as reuse and the session killed.
+    pub fn with_refresh_reuse_grace(mut self, grace: Duration) -> Self {
+        self.refresh_reuse_grace = grace;
+        self
+    }
+
+    /// Sets how far a detected refresh token reuse reaches; see
+    /// [`ReuseResponse`]. Reuse is audited either way.
+    pub fn with_reuse_response(mut self, response: ReuseResponse) -> Self {
+        self.reuse_response = response;
+        self
+    }
+
+    /// Tells users, on their enabled channels, when a reused refresh token
+    /// ended their sessions.
+    pub fn with_security_notifications(mut self, notifications: Dispatcher) -> Self {
+        self.security_notifications = Some(notifications);
+        self
+    }
+
+    /// Refuses logins and refreshes with a 429 once a user has been
+    /// issued too many access tokens; see [`IssuanceLimit`].
+    pub fn with_issuance_limit(mut self, limit: IssuanceLimit) -> Self {
+        self.issuance_limit = Some(limit);
+        self
+    }
+
+    /// Ends sessions that see no activity for `idle_timeout`, even while
+    /// their refresh token is still valid. Activity is any request made
+    /// with an access token from the session, or a refresh.
+    pub fn with_idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.idle_timeout = Some(idle_timeout);
+        self
+    }
+
+    async fn check_issuance(&self, user_id: UserId, step: AuthStep) -> Result<(), AppError> {
+        let Some(limit) = &self.issuance_limit else {
+            return Ok(());
+        };
+        let checked = limit.check(user_id).await;
+        if let Err(AppError::TooManyRequests(_)) = &checked {
+            metrics::auth_failure(step, AuthFailure::IssuanceLimited);
+        }
+        checked
+    }
+
+    pub fn tokens(&self) -> &TokenService {
+        &self.tokens
+    }
+
+    /// Verifies signature and expiry, then checks the token against the
+    /// jti blacklist and the user's "invalidated before" watermark. The
+    /// returned claims carry the effective role, which an active
+    /// elevation may have raised above the token's.
+    ///
+    /// Refusals are 401s, except for a token that isn't a JWT at all,
+    /// which is malformed input and a 400.
+    pub async fn authenticate(&self, token: &str) -> Result<AccessTokenClaims, AppError> {
+        let mut claims = self.verify_unrevoked(token).await?.map_err(|err| {
+            let (reason, error) = match err {
+                TokenError::Expired => (AuthFailure::Expired, AppError::TokenExpired),
+                TokenError::Blacklisted => {
+                    (AuthFailure::Revoked, AppError::Unauthorized("token revoked"))
+                }
+                TokenError::TooOld { .. } => {
+                    (AuthFailure::TooOld, AppError::Unauthorized("token too old"))
+                }
+                TokenError::WrongUse(_) | TokenError::WrongAction(_) => {
+                    (AuthFailure::WrongUse, AppError::Unauthorized("invalid token"))
+                }
+                TokenError::Malformed => {
+                    (AuthFailure::Malformed, AppError::BadRequest("malformed token"))
+                }
+                TokenError::InvalidSignature
+                | TokenError::Invalid(_)
+                | TokenError::UnknownKey(_)
+                | TokenError::UnknownIssuer
+                | TokenError::KeyMismatch => {
+                    (AuthFailure::InvalidToken, AppError::Unauthorized("invalid token"))
+                }
+            };
+            metrics::auth_failure(AuthStep::Token, reason);
+            error
+        })?;
+        metrics::auth_success(AuthStep::Token, AuthSuccess::Verified);
+
+        // An impersonator gets the role they asked for, never the
+        // target's elevation on top.
+        if let Some(elevations) = self.elevations.as_ref().filter(|_| claims.act.is_none()) {
+            if let Some(elevation) = elevations.active(claims.sub).await? {
+                claims.role = claims.role.max(elevation.role);
+            }
+        }
+
+        if let Some(session_id) = claims.sid {
+            self.touch_session(session_id).await;
+        }
+
+        Ok(claims)
+    }
+
+    /// [`TokenService::verify_access_token`], then [`TokenError::Blacklisted`]
+    /// if the token's `jti` was revoked or it predates the user's
+    /// invalidation watermark. The outer error is a failed cache read.
+    async fn verify_unrevoked(
+        &self,
+        token: &str,
+    ) -> anyhow::Result<Result<AccessTokenClaims, TokenError>> {
+        let claims = match self.tokens.verify_access_token(token) {
+            Ok(claims) => claims,
+            Err(err) => return Ok(Err(err)),
+        };
+
+        if self.cache.is_token_blacklisted(&claims.jti).await? {
+            return Ok(Err(TokenError::Blacklisted));
+        }
+        let watermark: Option<Watermark> = self
+            .cache
+            .get(&keys::tokens_invalidated(claims.sub))
+            .await?;
+        if watermark.is_some_and(|watermark| watermark.rejects(&claims)) {
+            return Ok(Err(TokenError::Blacklisted));
+        }
+
+        Ok(Ok(claims))
+    }
+
+    /// [`authenticate`](Self::authenticate)s `access_token`, and if it has
+    /// only expired, [`refresh`](Self::refresh)es with `refresh_token`
+    /// instead, rotating it as usual.
+    ///
+    /// A token that was revoked or is otherwise invalid is never
+    /// refreshed past; like a refused refresh it is a 401. The new
+    /// session's tokens are whatever the refresh token is for, whoever
+    /// the expired access token named.
+    pub async fn verify_and_refresh(
+        &self,
+        access_token: &str,
+        refresh_token: &str,
+    ) -> Result<AuthOutcome, AppError> {
+        match self.authenticate(access_token).await {
+            Ok(claims) => return Ok(AuthOutcome::Valid(claims)),
+            Err(AppError::TokenExpired) => {}
+            Err(err) => return Err(err),
+        }
+
+        let access = self.refresh(refresh_token).await?;
+        let claims = self.authenticate(&access.access_token).await?;
+        Ok(AuthOutcome::Refreshed { access, claims })
+    }
+
+    /// Records activity on a session, at most once per
+    /// [`ACTIVITY_TOUCH_INTERVAL`]. Best-effort: a failed write only costs
+    /// idle-timeout precision, so it never fails the request.
+    async fn touch_session(&self, session_id: SessionId) {
+        let Some(idle_timeout) = self.idle_timeout else {
+            return;
+        };
+
+        let touched = async {
+            let due = self
+                .cache
+                .set_if_not_exists(
+                    &keys::session_touch_throttle(session_id),
+                    "1",
+                    ACTIVITY_TOUCH_INTERVAL,
+                )
+                .await?;
+            if due {
+                self.cache
+                    .set(
+                        &keys::session_last_seen(session_id),
+                        &Utc::now().timestamp(),
+                        Persistence::Ttl(idle_timeout),
+                    )
+                    .await?;
+            }
+            anyhow::Ok(())
+        };
+
+        if let Err(err) = touched.await {
+            tracing::warn!(%session_id, error = ?err, "failed to record session activity");
+        }
+    }
+
+    /// Whether the session has gone longer than the idle timeout without
+    /// activity. The last-seen key expires after the timeout, so once it
+    /// is gone only the last refresh (or creation) is left to go by.
+    async fn is_idle(&self, session_id: SessionId, session: &Session) -> Result<bool> {
+        let Some(idle_timeout) = self.idle_timeout else {
+            return Ok(false);
+        };
+
+        let last_seen: Option<i64> = self.cache.get(&keys::session_last_seen(session_id)).await?;
+        let last_active = last_seen
+            .map(from_unix_seconds)
+            .into_iter()
+            .chain(session.last_used_at)
+            .fold(session.created_at, DateTime::max);
+
+        Ok((Utc::now() - last_active).to_std().unwrap_or_default() > idle_timeout)
+    }
+
+    /// Opens a refresh session for an already-authenticated user and
+    /// returns the first access/refresh token pair for it. With a session
+    /// limit set, a user at the cap is either refused or loses their
+    /// oldest sessions, depending on the policy.
+    pub async fn start_session(
+        &self,
+        user_id: UserId,
+        role: Role,
+        device: Option<&str>,
+    ) -> Result<IssuedTokens, AppError> {
+        self.check_deactivated(user_id, AuthStep::Login).await?;
+        self.check_issuance(user_id, AuthStep::Login).await?;
+        let evicted_sessions = self.enforce_session_limit(user_id).await?;
+
+        let (refresh, hash) = self.tokens.create_refresh_token().await?;
+        let now = Utc::now();
+        let session = Session {
+            user_id,
+            role,
+            hash: hash.hash,
+            created_at: now,
+            last_used_at: None,
+            device: device.map(str::to_string),
+            previous_hash: None,
+        };
+
+        let refresh_ttl = self.tokens.refresh_ttl_for(session.created_at);
+        self.cache
+            .set(
+                &keys::session(refresh.session_id),
+                &session,
+                Persistence::Ttl(refresh_ttl),
+            )
+            .await?;
+        self.cache
+            .sorted_add(
+                &keys::user_sessions(user_id),
+                &refresh.session_id.to_string(),
+                session.created_at.timestamp(),
+                Persistence::Ttl(self.tokens.refresh_token_absolute_ttl()),
+            )
+            .await?;
+        self.cache
+            .sorted_add(
+                &keys::session_users(),
+                &user_id.to_string(),
+                session.created_at.timestamp(),
+                Persistence::Persist,
+            )
+            .await?;
+
+        metrics::auth_success(AuthStep::Login, AuthSuccess::Issued);
+        Ok(IssuedTokens {
+            access_token: self.tokens.issue_session_access_token(
+                refresh.session_id,
+                user_id,
+                role,
+            )?,
+            access_expires_at: now + self.tokens.access_token_ttl(),
+            refresh_token: self
+                .tokens
+                .format_refresh_token(refresh.session_id, &refresh.secret),
+            refresh_expires_at: now + refresh_ttl,
+            evicted_sessions,
+        })
+    }
+
+    /// Drops index entries whose session already expired, then makes room
+    /// for one more session if the user is at the cap.
+    async fn enforce_session_limit(
+        &self,
+        user_id: UserId,
+    ) -> Result<Vec<EvictedSession>, AppError> {
+        let Some(max) = self.max_sessions else {
+            return Ok(Vec::new());
+        };
+
+        let index = keys::user_sessions(user_id);
+        let indexed = self.cache.sorted_members_with_scores(&index).await?;
+
+        let session_keys: Vec<CacheKey> = indexed.iter().map(|(id, _)| keys::session(id)).collect();
+        let session_keys: Vec<&str> = session_keys.iter().map(CacheKey::as_str).collect();
+        let live_flags = self.cache.exists_many(&session_keys).await?;
+
+        let (live, expired): (Vec<_>, Vec<_>) = indexed
+            .into_iter()
+            .zip(live_flags)
+            .partition(|(_, live)| *live);
+        let expired: Vec<&str> = expired.iter().map(|((id, _), _)| id.as_str()).collect();
+        self.cache.sorted_remove(&index, &expired).await?;
+
+        if live.len() < max {
+            return Ok(Vec::new());
+        }
+
+        if self.session_limit_policy == SessionLimitPolicy::Reject {
+            metrics::auth_failure(AuthStep::Login, AuthFailure::SessionLimit);
+            return Err(AppError::Conflict("maximum active sessions reached"));
+        }
+
+        // Oldest first, since the index is scored by creation time.
+        let excess = live.len() + 1 - max;
+        let mut evicted = Vec::with_capacity(excess);
+        for ((session_id, created_at), _) in live.into_iter().take(excess) {
+            self.cache.sorted_remove(&index, &[&session_id]).await?;
+            // Members are only ever written from a SessionId, so this
+            // can't fail short of someone editing Redis by hand.
+            if let Ok(session_id) = session_id.parse() {
+                self.delete_session_keys(session_id).await?;
+                evicted.push(EvictedSession {
+                    session_id,
+                    created_at: from_unix_seconds(created_at),
+                });
+            }
+        }
+
+        metrics::sessions_revoked(evicted.len() as u64);
+        Ok(evicted)
+    }
+
+    /// Exchanges a refresh token for a new access and refresh token pair,
+    /// spending the presented one. Malformed tokens are a 400; well-formed
+    /// ones that don't match a live session are a 401, as is a session
+    /// past its idle timeout, which is ended once the secret has checked
+    /// out. Presenting the
+    /// previous secret again is treated as theft and answered per the
+    /// [`ReuseResponse`], except within the reuse grace window, where the
+    /// already-rotated token is handed back instead.
+    ///
+    /// Rotation is a compare-and-set on the session record, so of two
+    /// concurrent refreshes with the same secret exactly one rotates; the
+    /// other is then a reuse of it like any later replay.
+    pub async fn refresh(&self, refresh_token: &str) -> Result<RefreshedAccess, AppError> {
+        let Some(presented) = TokenService::parse_refresh_token(refresh_token) else {
+            metrics::auth_failure(AuthStep::Refresh, AuthFailure::Malformed);
+            return Err(AppError::BadRequest("malformed refresh token"));
+        };
+        let session_id = presented.session_id;
+        let session_key = keys::session(session_id);
+
+        // Kept as stored, to compare against when rotating.
+        let Some(stored) = self.cache.get_raw(&session_key).await? else {
+            metrics::auth_failure(AuthStep::Refresh, AuthFailure::UnknownSession);
+            return Err(AppError::Unauthorized("invalid refresh token"));
+        };
+        let session: Session =
+            serde_json::from_str(&stored).context("undecodable session record")?;
+
+        let now = Utc::now();
+        let refresh_ttl = self.tokens.refresh_ttl_for(session.created_at);
+
+        let current = self
+            .tokens
+            .verify_refresh_secret(&presented.secret, &session.hash)
+            .await;
+        let reused = !current
+            && match &session.previous_hash {
+                Some(previous) => {
+                    self.tokens
+                        .verify_refresh_secret(&presented.secret, previous)
+                        .await
+                }
+                None => false,
+            };
+        if !current && !reused {
+            metrics::auth_failure(AuthStep::Refresh, AuthFailure::BadSecret);
+            return Err(AppError::Unauthorized("invalid refresh token"));
+        }
+
+        // Only past the secret check: ending the session is for whoever
+        // holds it, not anyone who learns its id.
+        if self.is_idle(session_id, &session).await? {
+            metrics::auth_failure(AuthStep::Refresh, AuthFailure::Idle);
+            self.revoke_session(session_id).await?;
+            return Err(AppError::Unauthorized("session expired due to inactivity"));
+        }
+        // Normally the sweeper has ended the session already; this covers
+        // one opened while it ran.
+        if let Err(err) = self
+            .check_deactivated(session.user_id, AuthStep::Refresh)
+            .await
+        {
+            self.revoke_session(session_id).await?;
+            return Err(err);
+        }
+
+        if reused {
+            return self.refresh_reused(session_id, &session, refresh_ttl).await;
+        }
+
+        self.check_issuance(session.user_id, AuthStep::Refresh)
+            .await?;
+        let (next, hash) = self.tokens.reissue_refresh_token(session_id).await?;
+        let refresh_token = self.tokens.format_refresh_token(session_id, &next.secret);
+
+        let rotated = Session {
+            hash: hash.hash,
+            previous_hash: Some(session.hash.clone()),
+            last_used_at: Some(now),
+            role: self.current_role(&session).await?,
+            ..session.clone()
+        };
+        let won = self
+            .cache
+            .compare_raw_and_set(
+                &session_key,
+                &stored,
+                &rotated,
+                Persistence::Ttl(refresh_ttl),
+            )
+            .await?;
+        if !won {
+            // Another refresh with this secret rotated first, or the
+            // session ended meanwhile; either way the secret is spent.
+            return self.refresh_reused(session_id, &session, refresh_ttl).await;
+        }
+
+        // The one place a refresh secret is kept in the clear, and only
+        // for as long as a retry may need it.
+        if !self.refresh_reuse_grace.is_zero() {
+            self.cache
+                .set(
+                    &keys::session_grace(session_id),
+                    &refresh_token,
+                    Persistence::Ttl(self.refresh_reuse_grace),
+                )
+                .await?;
+        }
+
+        metrics::auth_success(AuthStep::Refresh, AuthSuccess::Rotated);
+        Ok(RefreshedAccess {
+            session_id,
+            access_token: self.tokens.issue_session_access_token(
+                session_id,
+                rotated.user_id,
+                rotated.role,
+            )?,
+            access_expires_at: now + self.tokens.access_token_ttl(),
+            refresh_token,
+            refresh_expires_at: now + refresh_ttl,
+        })
+    }
+
+    /// A refresh presenting a secret that was already rotated: within the
+    /// grace window it gets the token the rotation issued, otherwise it is
+    /// answered per the [`ReuseResponse`].
+    async fn refresh_reused(
+        &self,
+        session_id: SessionId,
+        session: &Session,
+        refresh_ttl: Duration,
+    ) -> Result<RefreshedAccess, AppError> {
+        let rotated: Option<String> = self.cache.get(&keys::session_grace(session_id)).await?;
+        let Some(refresh_token) = rotated else {
+            tracing::warn!(%session_id, user_id = %session.user_id, "refresh token reused");
+            metrics::auth_failure(AuthStep::Refresh, AuthFailure::ReuseDetected);
+            self.respond_to_reuse(session_id, session.user_id).await?;
+            return Err(AppError::Unauthorized("refresh token reused"));
+        };
+        self.check_issuance(session.user_id, AuthStep::Refresh)
+            .await?;
+        let role = self.current_role(session).await?;
+        metrics::auth_success(AuthStep::Refresh, AuthSuccess::GraceRetry);
+
+        let now = Utc::now();
+        Ok(RefreshedAccess {
+            session_id,
+            access_token: self.tokens.issue_session_access_token(
+                session_id,
+                session.user_id,
+                role,
+            )?,
+            access_expires_at: now + self.tokens.access_token_ttl(),
+            refresh_token,
+            refresh_expires_at: now + refresh_ttl,
+        })
+    }
+
+    /// The role a session's next access token carries: the one last set
+    /// with [`change_role`](Self::change_role) if that happened after the
+    /// session opened, else the session's own. Sessions opened later got
+    /// their role from the login that opened them.
+    async fn current_role(&self, session: &Session) -> Result<Role> {
+        let change: Option<RoleChange> = self.cache.get(&keys::user_role(session.user_id)).await?;
+        Ok(change
+            .filter(|change| change.changed_at >= session.created_at)
+            .map_or(session.role, |change| change.role))
+    }
+
+    /// Records `role` as `user_id`'s role from now on. Each of their
+    /// sessions picks it up on its next refresh, so access tokens already
+    /// issued carry the old role until they expire, at most the access
+    /// token TTL. With `revoke_access_tokens` those are rejected at once
+    /// instead, and clients holding them must refresh early.
+    pub async fn change_role(
+        &self,
+        user_id: UserId,
+        role: Role,
+        revoke_access_tokens: bool,
+    ) -> Result<()> {
+        // No session opened before the change outlives this.
+        self.cache
+            .set(
+                &keys::user_role(user_id),
+                &RoleChange {
+                    role,
+                    changed_at: Utc::now(),
+                },
+                Persistence::Ttl(self.tokens.refresh_token_absolute_ttl()),
+            )
+            .await?;
+
+        if revoke_access_tokens {
+            self.invalidate_user_tokens(user_id).await?;
+        }
+        Ok(())
+    }
+
+    /// Ends the reused session, or all of its user's sessions and access
+    /// tokens, then audits it and lets the user know.
+    async fn respond_to_reuse(&self, session_id: SessionId, user_id: UserId) -> Result<()> {
+        let response = self.reuse_response;
+        let revoked = match response {
+            ReuseResponse::KillSession => {
+                self.revoke_session(session_id).await?;
+                1
+            }
+            ReuseResponse::KillAll => {
+                self.invalidate_user_tokens(user_id).await?;
+                self.revoke_all_sessions(user_id).await?
+            }
+        };
+
+        self.audit
+            .record(
+                AuditEvent::new(
+                    user_id.to_string(),
+                    "auth.refresh_token_reused",
+                    AuditOutcome::Denied,
+                )
+                .target(session_id.to_string())
+                .detail(serde_json::json!({
+                    "response": response.as_str(),
+                    "sessions_revoked": revoked,
+                })),
+            )
+            .await;
+
+        if let Some(notifications) = &self.security_notifications {
+            let notification = Notification::new(
+                "security.refresh_token_reused",
+                serde_json::json!({
+                    "session_id": session_id,
+                    "all_sessions_ended": response == ReuseResponse::KillAll,
+                }),
+            );
+            // The sessions are already gone; a failed warning shouldn't
+            // turn the 401 into a 500.
+            if let Err(err) = notifications.dispatch(user_id, notification).await {
+                tracing::warn!(%user_id, error = ?err, "failed to send security notification");
+            }
+        }
+
+        Ok(())
+    }
+
+    pub async fn revoke_session(&self, session_id: SessionId) -> Result<()> {
+        let session: Option<Session> = self.cache.get(&keys::session(session_id)).await?;
+
+        self.delete_session_keys(session_id).await?;
+        if let Some(session) = session {
+            self.cache
+                .sorted_remove(
+                    &keys::user_sessions(session.user_id),
+                    &[&session_id.to_string()],
+                )
+                .await?;
+            metrics::sessions_revoked(1);
+        }
+
+        Ok(())
+    }
+
+    /// Deletes the session record and everything kept alongside it, so a
+    /// revoked session leaves nothing behind to expire later.
+    async fn delete_session_keys(&self, session_id: SessionId) -> Result<()> {
+        self.cache.delete(&keys::session(session_id)).await?;
+        self.cache.delete(&keys::session_grace(session_id)).await?;
+        self.cache
+            .delete(&keys::session_last_seen(session_id))
+            .await?;
+        self.cache
+            .delete(&keys::session_touch_throttle(session_id))
+            .await
+    }
+
+    /// Ids of `user_id`'s live refresh sessions, i.e. what
+    /// [`revoke_all_sessions`](Self::revoke_all_sessions) would end.
+    pub async fn live_sessions(&self, user_id: UserId) -> Result<Vec<SessionId>> {
+        let session_ids = self
+            .cache
+            .sorted_members(&keys::user_sessions(user_id))
+            .await?;
+
+        let session_keys: Vec<CacheKey> = session_ids.iter().map(keys::session).collect();
+        let session_keys: Vec<&str> = session_keys.iter().map(CacheKey::as_str).collect();
+        let live = self.cache.exists_many(&session_keys).await?;
+
+        Ok(session_ids
+            .iter()
+            .zip(live)
+            .filter(|(_, live)| *live)
+            .filter_map(|(id, _)| id.parse().ok())
+            .collect())
+    }
+
+    /// Ends every refresh session of `user_id` and returns how many were
+    /// still live.
+    pub async fn revoke_all_sessions(&self, user_id: UserId) -> Result<u64> {
+        let index = keys::user_sessions(user_id);
+        let session_ids = self.cache.sorted_members(&index).await?;
+
+        let session_keys: Vec<CacheKey> = session_ids.iter().map(keys::session).collect();
+        let session_keys: Vec<&str> = session_keys.iter().map(CacheKey::as_str).collect();
+        let live = self.cache.exists_many(&session_keys).await?;
+
+        for session_id in session_ids.iter().filter_map(|id| id.parse().ok()) {
+            self.delete_session_keys(session_id).await?;
+        }
+        self.cache.delete(&index).await?;
+        self.cache
+            .sorted_remove(&keys::session_users(), &[&user_id.to_string()])
+            .await?;
+
+        let live = live.into_iter().filter(|live| *live).count() as u64;
+        metrics::sessions_revoked(live);
+        Ok(live)
+    }
+
+    /// Drops index entries of sessions that have expired, and indexes
+    /// left with none, so session bookkeeping doesn't grow without bound.
+    /// Updates the active session gauge as it goes. The lock outlives the
+    /// pass on purpose: held for half the `interval`, it stops other
+    /// instances ticking shortly after this one from pruning again, yet
+    /// has expired by this instance's next tick, which a lock held for
+    /// the whole `interval` might still be blocking.
+    async fn prune_sessions(&self, interval: Duration) -> Result<()> {
+        if self
+            .cache
+            .acquire_lock(&keys::session_prune_lock(), interval / 2)
+            .await?
+            .is_none()
+        {
+            return Ok(());
+        }
+
+        // Nothing opened before this can still be live.
+        let horizon = (Utc::now() - self.tokens.refresh_token_absolute_ttl()).timestamp();
+        let users = self
+            .cache
+            .sorted_members_with_scores(&keys::session_users())
+            .await?;
+        let (mut active, mut pruned) = (0, 0);
+
+        for (user_id, last_opened) in users {
+            let index = keys::user_sessions(&user_id);
+            let indexed = if last_opened < horizon {
+                Vec::new()
+            } else {
+                self.cache.sorted_members(&index).await?
+            };
+
+            let session_keys: Vec<CacheKey> = indexed.iter().map(keys::session).collect();
+            let session_keys: Vec<&str> = session_keys.iter().map(CacheKey::as_str).collect();
+            let live_flags = self.cache.exists_many(&session_keys).await?;
+            let expired: Vec<&str> = indexed
+                .iter()
+                .zip(&live_flags)
+                .filter(|(_, live)| !**live)
+                .map(|(id, _)| id.as_str())
+                .collect();
+            let live = live_flags.len() - expired.len();
+
+            if live == 0 {
+                self.cache.delete(&index).await?;
+                self.cache
+                    .sorted_remove(&keys::session_users(), &[&user_id])
+                    .await?;
+            } else if !expired.is_empty() {
+                self.cache.sorted_remove(&index, &expired).await?;
+            }
+            active += live as u64;
+            pruned += expired.len() as u64;
+        }
+
+        metrics::sessions_active(active);
+        metrics::sessions_pruned(pruned);
+        Ok(())
+    }
+
+    /// Refuses `user_id` if their account has been deactivated.
+    async fn check_deactivated(&self, user_id: UserId, step: AuthStep) -> Result<(), AppError> {
+        let Some(deactivations) = &self.deactivations else {
+            return Ok(());
+        };
+        if deactivations.is_deactivated(user_id).await? {
+            metrics::auth_failure(step, AuthFailure::Deactivated);
+            return Err(AppError::Forbidden("account is deactivated"));
+        }
+        Ok(())
+    }
+
+    /// Carries out every scheduled deactivation that is due: the account
+    /// is marked first, so it can't sign in again while its sessions and
+    /// access tokens are being revoked.
+    async fn deactivate_due(&self, interval: Duration) -> Result<()> {
+        let Some(deactivations) = &self.deactivations else {
+            return Ok(());
+        };
+
+        for user_id in deactivations.due(interval).await? {
+            let Some(schedule) = deactivations.mark_deactivated(user_id).await? else {
+                continue;
+            };
+            self.invalidate_user_tokens(user_id).await?;
+            let sessions_revoked = self.revoke_all_sessions(user_id).await?;
+            deactivations
+                .record_deactivated(user_id, &schedule, sessions_revoked)
+                .await;
+        }
+        Ok(())
+    }
+
+    /// Carries out due deactivations every `interval` until shutdown.
+    pub async fn run_deactivation_sweeper(
+        self,
+        interval: Duration,
+        mut shutdown: ShutdownSignal,
+    ) -> Result<()> {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            tokio::select! {
+                _ = ticker.tick() => {}
+                () = shutdown.cancelled() => return Ok(()),
+            }
+            if let Err(err) = self.deactivate_due(interval).await {
+                tracing::warn!(error = ?err, "deactivation sweep failed");
+            }
+        }
+    }
+
+    /// Prunes expired session bookkeeping every `interval` until shutdown.
+    pub async fn run_session_pruner(
+        self,
+        interval: Duration,
+        mut shutdown: ShutdownSignal,
+    ) -> Result<()> {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            tokio::select! {
+                _ = ticker.tick() => {}
+                () = shutdown.cancelled() => return Ok(()),
+            }
+            if let Err(err) = self.prune_sessions(interval).await {
+                tracing::warn!(error = ?err, "session pruning failed");
+            }
+        }
+    }
+
+    /// Rejects every access token issued to `user_id` up to now. The
+    /// watermark only needs to outlive the longest-lived access token,
+    /// leeway included.
+    ///
+    /// Written as a `Watermark::Since` in unix milliseconds and compared
+    /// with each token's [`issued_at`](AccessTokenClaims::issued_at), which
+    /// is millisecond-precise through the `iat_ms` claim. So a token minted
+    /// later in the same second, e.g. right after a password change, still
+    /// verifies. Tokens without `iat_ms` fall back to whole-second `iat`
+    /// and are rejected for the entire second.
+    pub async fn invalidate_user_tokens(&self, user_id: UserId) -> Result<()> {
+        self.set_watermark(
+            user_id,
+            Watermark::Since {
+                invalidated_at: Utc::now(),
+                session_id: None,
+            },
+        )
+        .await
+    }
+
+    /// Ends every session of `current`'s owner except the one `current`
+    /// was minted from, and rejects their other access tokens issued so
+    /// far. Returns how many other sessions were still live. A token not
+    /// minted from a session spares nothing, itself included.
+    ///
+    /// What a change-password handler should call once the new hash is
+    /// stored. This service has no password store yet, so nothing does.
+    pub async fn revoke_other_sessions(&self, current: &AccessTokenClaims) -> Result<u64> {
+        let user_id = current.sub;
+        let Some(keep) = current.sid else {
+            self.invalidate_user_tokens(user_id).await?;
+            return self.revoke_all_sessions(user_id).await;
+        };
+
+        let index = keys::user_sessions(user_id);
+        let others: Vec<SessionId> = self
+            .cache
+            .sorted_members(&index)
+            .await?
+            .iter()
+            .filter_map(|id| id.parse().ok())
+            .filter(|id| *id != keep)
+            .collect();
+
+        let session_keys: Vec<CacheKey> = others.iter().map(keys::session).collect();
+        let session_keys: Vec<&str> = session_keys.iter().map(CacheKey::as_str).collect();
+        let live = self.cache.exists_many(&session_keys).await?;
+
+        for session_id in &others {
+            self.revoke_session(*session_id).await?;
+        }
+        // Sessions already gone never made it past `revoke_session`'s
+        // index cleanup, so drop their entries here.
+        let members: Vec<String> = others.iter().map(SessionId::to_string).collect();
+        let members: Vec<&str> = members.iter().map(String::as_str).collect();
+        self.cache.sorted_remove(&index, &members).await?;
+
+        self.set_watermark(
+            user_id,
+            Watermark::Since {
+                invalidated_at: Utc::now(),
+                session_id: Some(keep),
+            },
+        )
+        .await?;
+
+        Ok(live.into_iter().filter(|live| *live).count() as u64)
+    }
+
+    async fn set_watermark(&self, user_id: UserId, watermark: Watermark) -> Result<()> {
+        self.cache
+            .set(
+                &keys::tokens_invalidated(user_id),
+                &watermark,
+                Persistence::Ttl(self.tokens.longest_access_token_lifetime()),
+            )
+            .await
+    }
+
+    /// Verifies an action token for `action`, including that it hasn't
+    /// been revoked.
+    pub async fn verify_action_token(
+        &self,
+        token: &str,
+        action: &str,
+    ) -> Result<ActionTokenClaims, AppError> {
+        let claims = self
+            .tokens
+            .verify_action_token(token, action)
+            .map_err(|err| {
+                tracing::debug!(error = %err, action, "action token rejected");
+                AppError::Unauthorized("invalid action token")
+            })?;
+
+        if self.cache.is_token_blacklisted(&claims.jti).await? {
+            return Err(AppError::Unauthorized("action token revoked"));
+        }
+        Ok(claims)
+    }
+
+    /// Blacklists an action token for the rest of its lifetime. Call it
+    /// once the action is done to make the token single-use.
+    pub async fn revoke_action_token(&self, claims: &ActionTokenClaims) -> Result<()> {
+        let remaining = (claims.expires_at() - Utc::now())
+            .to_std()
+            .unwrap_or_default()
+            .max(Duration::from_secs(1));
+        self.cache.blacklist_token(&claims.jti, remaining).await
+    }
+
+    /// Blacklists a single token for the rest of its lifetime.
+    pub async fn revoke_access_token(&self, claims: &AccessTokenClaims) -> Result<()> {
+        let remaining = (claims.expires_at() - Utc::now())
+            .to_std()
+            .unwrap_or_default()
+            .max(Duration::from_secs(1));
+        self.cache.blacklist_token(&claims.jti, remaining).await
+    }
+}
+
+/// A role set by [`AuthService::change_role`].
+#[derive(Debug, Serialize, Deserialize)]
+struct RoleChange {
+    role: Role,
+    #[serde(with = "crate::timestamp::millis")]
+    changed_at: DateTime<Utc>,
+}
+
+/// Access tokens issued to a user up to `invalidated_at` are rejected,
+/// except those minted from `session_id`. Compared to the millisecond, so
+/// a token issued right after, in the same second, still works.
+///
+/// `All` and `AllExcept` are the forms written before then, in unix
+/// seconds: a bare number from before carve-outs existed, and the same
+/// with one. They reject the whole second they name.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+enum Watermark {
+    All(i64),
+    AllExcept {
+        at: i64,
+        session_id: SessionId,
+    },
+    Since {
+        #[serde(with = "crate::timestamp::millis")]
+        invalidated_at: DateTime<Utc>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        session_id: Option<SessionId>,
+    },
+}
+
+impl Watermark {
+    fn rejects(&self, claims: &AccessTokenClaims) -> bool {
+        let (rejected, spared) = match self {
+            Watermark::All(at) => (claims.iat as i64 <= *at, None),
+            Watermark::AllExcept { at, session_id } => {
+                (claims.iat as i64 <= *at, Some(*session_id))
+            }
+            Watermark::Since {
+                invalidated_at,
+                session_id,
+            } => (claims.issued_at() <= *invalidated_at, *session_id),
+        };
+
+        rejected && spared.is_none_or(|spared| claims.sid != Some(spared))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use axum::{extract::State, http::HeaderMap, routing::get, Router};
+    use reqwest::StatusCode;
+
+    use super::*;
+    use crate::{
+        auth::impersonation::MAX_IMPERSONATION,
+        cache::{cache_service::KeyTtl, fake_redis, memory::MemoryBackend},
+    };
+
+    async fn service() -> AuthService {
+        // The cheapest Argon2 parameters allowed, to keep tests fast.
+        let hash_params = argon2::Params::new(8, 1, 1, None).unwrap();
+        AuthService::new(
+            TokenService::new("test-secret-that-is-long-enough", Duration::from_secs(900))
+                .with_refresh_hash_params(hash_params),
+            CacheService::from_backend(Arc::new(MemoryBackend::new()), "test"),
+            AuditLog::new(fake_redis::client().await, "test", 100),
+        )
+    }
+
+    /// Serves `GET /` to whoever `authenticate` admits, as the
+    /// extractors do, and returns its URL.
+    async fn serve(auth: AuthService) -> String {
+        async fn whoami(
+            State(auth): State<AuthService>,
+            headers: HeaderMap,
+        ) -> Result<String, AppError> {
+            let token = crate::auth::extractor::bearer_token(&headers)
+                .ok_or(AppError::MissingCredentials("missing bearer token"))?;
+            Ok(auth.authenticate(token).await?.sub.to_string())
+        }
+
+        let app = Router::new().route("/", get(whoami)).with_state(auth);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        url
+    }
+
+    async fn call(url: &str, token: &str) -> reqwest::Response {
+        reqwest::Client::new()
+            .get(url)
+            .bearer_auth(token)
+            .send()
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn malformed_token_is_a_bad_request() {
+        let url = serve(service().await).await;
+
+        let response = call(&url, "not-a-jwt").await;
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(body["code"], "BAD_REQUEST");
+    }
+
+    #[tokio::test]
+    async fn expired_token_is_a_401_asking_for_a_refresh() {
+        let auth = service().await;
+        let auth = AuthService {
+            tokens: TokenService::new("test-secret-that-is-long-enough", Duration::ZERO),
+            ..auth
+        };
+        let token = auth
+            .tokens
+            .issue_access_token(UserId::new(), Role::Employee)
+            .unwrap();
+        let url = serve(auth).await;
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+
+        let response = call(&url, &token).await;
+
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let challenge = response.headers()[reqwest::header::WWW_AUTHENTICATE]
+            .to_str()
+            .unwrap()
+            .to_string();
+        assert!(challenge.contains("invalid_token"), "{challenge}");
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(body["code"], "AUTH_TOKEN_EXPIRED");
+    }
+
+    #[tokio::test]
+    async fn revoked_token_is_blacklisted() {
+        let auth = service().await;
+        let token = auth
+            .tokens
+            .issue_access_token(UserId::new(), Role::Employee)
+            .unwrap();
+        let claims = auth.authenticate(&token).await.unwrap();
+
+        auth.revoke_access_token(&claims).await.unwrap();
+
+        assert!(matches!(
+            auth.verify_unrevoked(&token).await.unwrap(),
+            Err(TokenError::Blacklisted)
+        ));
+        assert!(matches!(
+            auth.authenticate(&token).await,
+            Err(AppError::Unauthorized("token revoked"))
+        ));
+    }
+
+    #[tokio::test]
+    async fn revoking_one_token_spares_the_others() {
+        let auth = service().await;
+        let user_id = UserId::new();
+        let revoked = auth.tokens.issue_access_token(user_id, Role::Employee).unwrap();
+        let kept = auth.tokens.issue_access_token(user_id, Role::Employee).unwrap();
+
+        let claims = auth.authenticate(&revoked).await.unwrap();
+        auth.revoke_access_token(&claims).await.unwrap();
+
+        assert!(auth.authenticate(&kept).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn blacklist_entry_expires_with_the_token() {
+        let auth = service().await;
+        let token = auth
+            .tokens
+            .issue_access_token(UserId::new(), Role::Employee)
+            .unwrap();
+        let claims = auth.authenticate(&token).await.unwrap();
+
+        auth.revoke_access_token(&claims).await.unwrap();
+
+        let KeyTtl::Expires(ttl) = auth
+            .cache
+            .ttl(&keys::token_blacklist(&claims.jti))
+            .await
+            .unwrap()
+        else {
+            panic!("blacklist entry has no expiry");
+        };
+        let remaining = (claims.expires_at() - Utc::now()).to_std().unwrap();
+        let drift = ttl.abs_diff(remaining);
+        assert!(drift <= Duration::from_secs(2), "{ttl:?} vs {remaining:?}");
+    }
+
+    #[tokio::test]
+    async fn watermark_outlives_impersonation_tokens_and_leeway() {
+        let auth = service().await;
+        let auth = AuthService {
+            tokens: auth.tokens.with_leeway(Duration::from_secs(30)),
+            ..auth
+        };
+        let user_id = UserId::new();
+        let token = auth.tokens.issue_access_token(user_id, Role::Employee).unwrap();
+
+        auth.invalidate_user_tokens(user_id).await.unwrap();
+
+        let ttl = auth
+            .cache
+            .ttl(&keys::tokens_invalidated(user_id))
+            .await
+            .unwrap();
+        assert!(
+            matches!(ttl, KeyTtl::Expires(ttl) if ttl > MAX_IMPERSONATION),
+            "watermark ttl {ttl:?} is shorter than an impersonation token"
+        );
+        assert!(matches!(
+            auth.authenticate(&token).await,
+            Err(AppError::Unauthorized("token revoked"))
+        ));
+    }
+
+    #[tokio::test]
+    async fn concurrent_refreshes_rotate_once_and_share_the_result() {
+        let auth = service().await.with_refresh_reuse_grace(Duration::from_secs(10));
+        let issued = auth
+            .start_session(UserId::new(), Role::Employee, None)
+            .await
+            .unwrap();
+
+        let (first, second) = tokio::join!(
+            auth.refresh(&issued.refresh_token),
+            auth.refresh(&issued.refresh_token),
+        );
+        let (first, second) = (first.unwrap(), second.unwrap());
+
+        assert_eq!(first.refresh_token, second.refresh_token);
+        assert!(auth.refresh(&first.refresh_token).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn concurrent_refreshes_without_grace_let_exactly_one_through() {
+        let auth = service().await;
+        let issued = auth
+            .start_session(UserId::new(), Role::Employee, None)
+            .await
+            .unwrap();
+
+        let (first, second) = tokio::join!(
+            auth.refresh(&issued.refresh_token),
+            auth.refresh(&issued.refresh_token),
+        );
+
+        assert!(
+            first.is_ok() != second.is_ok(),
+            "both refreshes {}",
+            if first.is_ok() { "rotated" } else { "failed" }
+        );
+        let refused = if first.is_ok() { second } else { first };
+        assert!(matches!(
+            refused,
+            Err(AppError::Unauthorized("refresh token reused"))
+        ));
+    }
+
+    #[tokio::test]
+    async fn token_issued_right_after_invalidation_is_accepted() {
+        let auth = service().await;
+        let user_id = UserId::new();
+        let before = auth.tokens.issue_access_token(user_id, Role::Employee).unwrap();
+
+        auth.invalidate_user_tokens(user_id).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(2)).await;
+        let after = auth.tokens.issue_access_token(user_id, Role::Employee).unwrap();
+
+        assert!(auth.authenticate(&before).await.is_err());
+        assert!(auth.authenticate(&after).await.is_ok());
+    }
+
+    #[test]
+    fn watermarks_in_unix_seconds_still_load() {
+        let session_id = SessionId::new();
+
+        let all: Watermark = serde_json::from_str("1700000000").unwrap();
+        assert!(matches!(all, Watermark::All(1_700_000_000)));
+
+        let except: Watermark = serde_json::from_str(&format!(
+            r#"{{"at":1700000000,"session_id":"{session_id}"}}"#
+        ))
+        .unwrap();
+        assert!(matches!(
+            except,
+            Watermark::AllExcept { at: 1_700_000_000, session_id: spared } if spared == session_id
+        ));
+
+        let since = Watermark::Since {
+            invalidated_at: Utc::now(),
+            session_id: Some(session_id),
+        };
+        let since = serde_json::to_string(&since).unwrap();
+        assert!(matches!(
+            serde_json::from_str(&since).unwrap(),
+            Watermark::Since { session_id: Some(spared), .. } if spared == session_id
+        ));
+    }
+
+    #[tokio::test]
+    async fn revoking_other_sessions_spares_the_current_one() {
+        let auth = service().await;
+        let user_id = UserId::new();
+        let current = auth.start_session(user_id, Role::Employee, None).await.unwrap();
+        let other = auth.start_session(user_id, Role::Employee, None).await.unwrap();
+        let claims = auth.authenticate(&current.access_token).await.unwrap();
+
+        assert_eq!(auth.revoke_other_sessions(&claims).await.unwrap(), 1);
+
+        assert!(auth.authenticate(&current.access_token).await.is_ok());
+        assert!(auth.refresh(&current.refresh_token).await.is_ok());
+        assert!(auth.authenticate(&other.access_token).await.is_err());
+        assert!(auth.refresh(&other.refresh_token).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn only_the_secret_holder_can_end_an_idle_session() {
+        let auth = service()
+            .await
+            .with_idle_timeout(Duration::from_millis(10));
+        let issued = auth
+            .start_session(UserId::new(), Role::Employee, None)
+            .await
+            .unwrap();
+        let session_id = TokenService::parse_refresh_token(&issued.refresh_token)
+            .unwrap()
+            .session_id;
+        let forged = auth.tokens.format_refresh_token(
+            session_id,
+            &crate::auth::token_service::generate_secret(32),
+        );
+        tokio::time::sleep(Duration::from_millis(20)).await;
+
+        assert!(matches!(
+            auth.refresh(&forged).await,
+            Err(AppError::Unauthorized("invalid refresh token"))
+        ));
+        assert!(auth.cache.exists(&keys::session(session_id)).await.unwrap());
+
+        assert!(matches!(
+            auth.refresh(&issued.refresh_token).await,
+            Err(AppError::Unauthorized("session expired due to inactivity"))
+        ));
+        assert!(!auth.cache.exists(&keys::session(session_id)).await.unwrap());
+    }
+}
@@ -0,0 +1,23 @@
+//! Identity of a caller that authenticated with a TLS client certificate.
+//!
+//! Only the `mtls` server attaches one, after the certificate chain has
+//! been verified against the configured CA. Plain HTTP requests never
+//! carry one, so [`ServiceClient`] rejects them.
+//!
+//! [`ServiceClient`]: crate::auth::extractor::ServiceClient
+
+/// Names from the verified leaf certificate.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientIdentity {
+    pub common_name: Option<String>,
+    /// DNS and URI subject alternative names, e.g. a SPIFFE id.
+    pub subject_alt_names: Vec<String>,
+}
+
+impl ClientIdentity {
+    /// Whether `name` is the certificate's CN or one of its SANs.
+    pub fn is(&self, name: &str) -> bool {
+        self.common_name.as_deref() == Some(name)
+            || self.subject_alt_names.iter().any(|san| san == name)
+    }
+}
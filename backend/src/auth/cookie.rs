@@ -0,0 +1,126 @@
+//! HttpOnly cookie delivery of access tokens, for server-rendered clients
+//! that can't attach an `Authorization` header themselves.
+//!
+//! Browsers send the cookie on cross-site requests too (subject to
+//! `SameSite`), so state-changing requests authenticated by it must also
+//! carry a token from [`crate::auth::csrf`]; the
+//! [`csrf`](crate::middleware::csrf::csrf) middleware enforces that.
+//!
+//! Clients on different origins may need different `SameSite` modes (an
+//! SPA on another subdomain needs `None`, an SSR admin wants `Strict`),
+//! so the mode can be chosen per request `Origin`.
+
+use axum::http::{header::ORIGIN, HeaderMap};
+use std::{collections::HashMap, fmt, time::Duration};
+
+use crate::auth::csrf::{cookie_value, CsrfProtection, CSRF_COOKIE};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SameSite {
+    Strict,
+    #[default]
+    Lax,
+    None,
+}
+
+impl SameSite {
+    pub fn parse(raw: &str) -> Option<Self> {
+        match raw.to_ascii_lowercase().as_str() {
+            "strict" => Some(Self::Strict),
+            "lax" => Some(Self::Lax),
+            "none" => Some(Self::None),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for SameSite {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Strict => "Strict",
+            Self::Lax => "Lax",
+            Self::None => "None",
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TokenCookie {
+    name: String,
+    domain: Option<String>,
+    path: String,
+    same_site: SameSite,
+    same_site_by_origin: HashMap<String, SameSite>,
+}
+
+impl TokenCookie {
+    pub fn new(
+        name: impl Into<String>,
+        domain: Option<String>,
+        path: impl Into<String>,
+        same_site: SameSite,
+    ) -> Self {
+        Self {
+            name: name.into(),
+            domain,
+            path: path.into(),
+            same_site,
+            same_site_by_origin: HashMap::new(),
+        }
+    }
+
+    /// Overrides the `SameSite` mode for requests from these origins
+    /// (e.g. `https://app.example.com`).
+    pub fn with_same_site_by_origin(mut self, modes: HashMap<String, SameSite>) -> Self {
+        self.same_site_by_origin = modes;
+        self
+    }
+
+    /// The `SameSite` mode for the client that sent `headers`.
+    pub fn same_site_for(&self, headers: &HeaderMap) -> SameSite {
+        headers
+            .get(ORIGIN)
+            .and_then(|origin| origin.to_str().ok())
+            .and_then(|origin| self.same_site_by_origin.get(origin))
+            .copied()
+            .unwrap_or(self.same_site)
+    }
+
+    /// `Set-Cookie` value carrying `token` for `max_age`. Always `Secure`
+    /// and `HttpOnly`, so scripts on the page never see the token.
+    pub fn set_cookie(&self, token: &str, max_age: Duration, same_site: SameSite) -> String {
+        let mut cookie = format!(
+            "{}={token}; Path={}; Max-Age={}; Secure; HttpOnly; SameSite={same_site}",
+            self.name,
+            self.path,
+            max_age.as_secs(),
+        );
+        self.push_domain(&mut cookie);
+        cookie
+    }
+
+    /// `Set-Cookie` value for the CSRF token that goes with the access
+    /// cookie: same domain and `SameSite` mode, so it reaches every request
+    /// the access cookie does, but readable by scripts, which have to echo
+    /// it in the header.
+    pub fn csrf_cookie(&self, csrf: &CsrfProtection, token: &str, same_site: SameSite) -> String {
+        let mut cookie = format!(
+            "{CSRF_COOKIE}={token}; Path={}; Max-Age={}; Secure; SameSite={same_site}",
+            self.path,
+            csrf.ttl().as_secs(),
+        );
+        self.push_domain(&mut cookie);
+        cookie
+    }
+
+    fn push_domain(&self, cookie: &mut String) {
+        if let Some(domain) = &self.domain {
+            cookie.push_str("; Domain=");
+            cookie.push_str(domain);
+        }
+    }
+
+    pub fn read<'a>(&self, headers: &'a HeaderMap) -> Option<&'a str> {
+        cookie_value(headers, &self.name).filter(|token| !token.is_empty())
+    }
+}
@@ -0,0 +1,243 @@
+//! Scheduled account deactivation.
+//!
+//! HR usually knows an employee's last day ahead of time. An admin
+//! schedules the deactivation for that moment, and can move or cancel it
+//! until it passes. When it does, a sweeper marks the account deactivated
+//! and [`AuthService`] ends its sessions and rejects its access tokens;
+//! from then on the account can't open or refresh a session until it is
+//! reactivated. Access ends within one sweep interval of the scheduled
+//! time.
+//!
+//! Scheduling, changes, cancellations, the deactivation itself and
+//! reactivations are all audited.
+//!
+//! [`AuthService`]: crate::auth::auth_service::AuthService
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+use crate::{
+    audit::{AuditEvent, AuditLog, AuditOutcome},
+    auth::ids::UserId,
+    cache::{
+        cache_service::{CacheService, Persistence},
+        keys,
+    },
+    timestamp,
+};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScheduledDeactivation {
+    #[serde(with = "crate::timestamp::millis")]
+    pub at: DateTime<Utc>,
+    pub scheduled_by: UserId,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    #[serde(with = "crate::timestamp::millis")]
+    pub scheduled_at: DateTime<Utc>,
+}
+
+/// Marks a deactivated account; kept until it is reactivated.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Deactivated {
+    #[serde(with = "crate::timestamp::millis")]
+    at: DateTime<Utc>,
+}
+
+#[derive(Clone)]
+pub struct Deactivations {
+    cache: CacheService,
+    audit: AuditLog,
+}
+
+impl Deactivations {
+    pub fn new(cache: CacheService, audit: AuditLog) -> Self {
+        Self { cache, audit }
+    }
+
+    /// Schedules `user_id` to be deactivated at `at`, replacing any
+    /// earlier schedule. Callers check that `at` is in the future.
+    pub async fn schedule(
+        &self,
+        user_id: UserId,
+        at: DateTime<Utc>,
+        scheduled_by: UserId,
+        reason: Option<String>,
+    ) -> Result<ScheduledDeactivation> {
+        let previous = self.scheduled(user_id).await?;
+        let schedule = ScheduledDeactivation {
+            at,
+            scheduled_by,
+            reason,
+            scheduled_at: Utc::now(),
+        };
+
+        self.cache
+            .set(
+                &keys::deactivation(user_id),
+                &schedule,
+                Persistence::Persist,
+            )
+            .await?;
+        self.cache
+            .sorted_add(
+                &keys::deactivations_due(),
+                &user_id.to_string(),
+                at.timestamp(),
+                Persistence::Persist,
+            )
+            .await?;
+
+        let action = match previous {
+            Some(_) => "account.deactivation_rescheduled",
+            None => "account.deactivation_scheduled",
+        };
+        self.audit
+            .record(
+                AuditEvent::new(scheduled_by.to_string(), action, AuditOutcome::Success)
+                    .target(user_id.to_string())
+                    .detail(serde_json::json!({
+                        "at": timestamp::format(at),
+                        "previous_at": previous.map(|previous| timestamp::format(previous.at)),
+                        "reason": schedule.reason,
+                    })),
+            )
+            .await;
+        Ok(schedule)
+    }
+
+    pub async fn scheduled(&self, user_id: UserId) -> Result<Option<ScheduledDeactivation>> {
+        self.cache.get(&keys::deactivation(user_id)).await
+    }
+
+    /// Drops `user_id`'s schedule. Returns `false` if there was none,
+    /// including when it has already been carried out.
+    pub async fn cancel(&self, user_id: UserId, cancelled_by: UserId) -> Result<bool> {
+        let Some(schedule) = self
+            .cache
+            .take::<ScheduledDeactivation>(&keys::deactivation(user_id))
+            .await?
+        else {
+            return Ok(false);
+        };
+        self.cache
+            .sorted_remove(&keys::deactivations_due(), &[&user_id.to_string()])
+            .await?;
+
+        self.audit
+            .record(
+                AuditEvent::new(
+                    cancelled_by.to_string(),
+                    "account.deactivation_cancelled",
+                    AuditOutcome::Success,
+                )
+                .target(user_id.to_string())
+                .detail(serde_json::json!({ "at": timestamp::format(schedule.at) })),
+            )
+            .await;
+        Ok(true)
+    }
+
+    pub async fn is_deactivated(&self, user_id: UserId) -> Result<bool> {
+        self.cache.exists(&keys::account_deactivated(user_id)).await
+    }
+
+    /// Lets a deactivated account sign in again. Returns `false` if it
+    /// wasn't deactivated.
+    pub async fn reactivate(&self, user_id: UserId, reactivated_by: UserId) -> Result<bool> {
+        let Some(deactivated) = self
+            .cache
+            .take::<Deactivated>(&keys::account_deactivated(user_id))
+            .await?
+        else {
+            return Ok(false);
+        };
+
+        self.audit
+            .record(
+                AuditEvent::new(
+                    reactivated_by.to_string(),
+                    "account.reactivated",
+                    AuditOutcome::Success,
+                )
+                .target(user_id.to_string())
+                .detail(serde_json::json!({
+                    "deactivated_at": timestamp::format(deactivated.at),
+                })),
+            )
+            .await;
+        Ok(true)
+    }
+
+    /// Users whose deactivation is due. Empty when another instance holds
+    /// the sweep lock; like the elevation sweeper's, it outlives the sweep
+    /// so no other instance sweeps again within the same `interval`.
+    pub(crate) async fn due(&self, interval: Duration) -> Result<Vec<UserId>> {
+        if self
+            .cache
+            .acquire_lock(&keys::deactivation_sweep_lock(), interval / 2)
+            .await?
+            .is_none()
+        {
+            return Ok(Vec::new());
+        }
+
+        let now = Utc::now().timestamp();
+        let scheduled = self
+            .cache
+            .sorted_members_with_scores(&keys::deactivations_due())
+            .await?;
+        Ok(scheduled
+            .into_iter()
+            .take_while(|(_, at)| *at <= now)
+            .filter_map(|(user_id, _)| user_id.parse().ok())
+            .collect())
+    }
+
+    /// Marks `user_id` deactivated and drops their schedule, if it is
+    /// still due; it may have been moved or cancelled since
+    /// [`due`](Self::due). Returns the schedule carried out.
+    pub(crate) async fn mark_deactivated(
+        &self,
+        user_id: UserId,
+    ) -> Result<Option<ScheduledDeactivation>> {
+        let schedule = self.scheduled(user_id).await?;
+        let Some(schedule) = schedule.filter(|schedule| schedule.at <= Utc::now()) else {
+            return Ok(None);
+        };
+
+        self.cache
+            .set(
+                &keys::account_deactivated(user_id),
+                &Deactivated { at: schedule.at },
+                Persistence::Persist,
+            )
+            .await?;
+        self.cache.delete(&keys::deactivation(user_id)).await?;
+        self.cache
+            .sorted_remove(&keys::deactivations_due(), &[&user_id.to_string()])
+            .await?;
+        Ok(Some(schedule))
+    }
+
+    pub(crate) async fn record_deactivated(
+        &self,
+        user_id: UserId,
+        schedule: &ScheduledDeactivation,
+        sessions_revoked: u64,
+    ) {
+        self.audit
+            .record(
+                AuditEvent::new("system", "account.deactivated", AuditOutcome::Success)
+                    .target(user_id.to_string())
+                    .detail(serde_json::json!({
+                        "scheduled_at": timestamp::format(schedule.at),
+                        "scheduled_by": schedule.scheduled_by,
+                        "sessions_revoked": sessions_revoked,
+                    })),
+            )
+            .await;
+    }
+}
@@ -0,0 +1,190 @@
+//! Temporary role elevation.
+//!
+//! An approver can grant a user a higher role for a fixed window, e.g. a
+//! manager who needs HR-admin access for one task. The grant is stored
+//! with a TTL, so it lapses on its own. While it is active,
+//! [`AuthService::authenticate`] raises the token's role to the elevated
+//! one. Tokens never carry the elevation, so revoking it early takes
+//! effect on the next request.
+//!
+//! Grants and revocations are audited when they happen. Expiry has no
+//! moment of its own in Redis, so a sweeper records it shortly afterwards.
+//!
+//! [`AuthService::authenticate`]: crate::auth::auth_service::AuthService::authenticate
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+use crate::{
+    audit::{AuditEvent, AuditLog, AuditOutcome},
+    auth::{ids::UserId, role::Role},
+    cache::{
+        cache_service::{CacheService, Persistence},
+        keys,
+    },
+    shutdown::ShutdownSignal,
+    timestamp::{self, from_unix_seconds},
+};
+
+/// Longest window one grant may cover.
+pub const MAX_ELEVATION: Duration = Duration::from_secs(8 * 3600);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Elevation {
+    pub role: Role,
+    pub granted_by: UserId,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    #[serde(with = "crate::timestamp::millis")]
+    pub granted_at: DateTime<Utc>,
+    #[serde(with = "crate::timestamp::millis")]
+    pub expires_at: DateTime<Utc>,
+}
+
+#[derive(Clone)]
+pub struct Elevations {
+    cache: CacheService,
+    audit: AuditLog,
+}
+
+impl Elevations {
+    pub fn new(cache: CacheService, audit: AuditLog) -> Self {
+        Self { cache, audit }
+    }
+
+    /// Grants `role` to `user_id` for `ttl`, replacing any earlier grant.
+    /// Callers check that `granted_by` may grant it and that `ttl` is
+    /// within [`MAX_ELEVATION`].
+    pub async fn grant(
+        &self,
+        user_id: UserId,
+        role: Role,
+        granted_by: UserId,
+        ttl: Duration,
+        reason: Option<String>,
+    ) -> Result<Elevation> {
+        let granted_at = Utc::now();
+        let elevation = Elevation {
+            role,
+            granted_by,
+            reason,
+            granted_at,
+            expires_at: granted_at + ttl,
+        };
+
+        self.cache
+            .set(&keys::elevation(user_id), &elevation, Persistence::Ttl(ttl))
+            .await?;
+        self.cache
+            .sorted_add(
+                &keys::elevations_expiring(),
+                &user_id.to_string(),
+                elevation.expires_at.timestamp(),
+                Persistence::Persist,
+            )
+            .await?;
+
+        self.audit
+            .record(
+                AuditEvent::new(
+                    granted_by.to_string(),
+                    "auth.elevation_granted",
+                    AuditOutcome::Success,
+                )
+                .target(user_id.to_string())
+                .detail(serde_json::json!({
+                    "role": role,
+                    "expires_at": timestamp::format(elevation.expires_at),
+                    "reason": elevation.reason,
+                })),
+            )
+            .await;
+        Ok(elevation)
+    }
+
+    pub async fn active(&self, user_id: UserId) -> Result<Option<Elevation>> {
+        self.cache.get(&keys::elevation(user_id)).await
+    }
+
+    /// Ends `user_id`'s grant early. Returns `false` if there was none.
+    pub async fn revoke(&self, user_id: UserId, revoked_by: UserId) -> Result<bool> {
+        let Some(elevation) = self
+            .cache
+            .take::<Elevation>(&keys::elevation(user_id))
+            .await?
+        else {
+            return Ok(false);
+        };
+        self.cache
+            .sorted_remove(&keys::elevations_expiring(), &[&user_id.to_string()])
+            .await?;
+
+        self.audit
+            .record(
+                AuditEvent::new(
+                    revoked_by.to_string(),
+                    "auth.elevation_revoked",
+                    AuditOutcome::Success,
+                )
+                .target(user_id.to_string())
+                .detail(serde_json::json!({ "role": elevation.role })),
+            )
+            .await;
+        Ok(true)
+    }
+
+    /// Records an expiry event for every grant that lapsed since the last
+    /// sweep. The lock outlives the sweep on purpose: it stops other
+    /// instances from sweeping again within the same `interval`.
+    async fn sweep(&self, interval: Duration) -> Result<()> {
+        if self
+            .cache
+            .acquire_lock(&keys::elevation_sweep_lock(), interval / 2)
+            .await?
+            .is_none()
+        {
+            return Ok(());
+        }
+
+        let now = Utc::now().timestamp();
+        let expiring = self
+            .cache
+            .sorted_members_with_scores(&keys::elevations_expiring())
+            .await?;
+        for (user_id, expires_at) in expiring.into_iter().take_while(|(_, at)| *at <= now) {
+            self.cache
+                .sorted_remove(&keys::elevations_expiring(), &[&user_id])
+                .await?;
+            self.audit
+                .record(
+                    AuditEvent::new("system", "auth.elevation_expired", AuditOutcome::Success)
+                        .target(user_id)
+                        .detail(serde_json::json!({
+                            "expired_at": timestamp::format(from_unix_seconds(expires_at)),
+                        })),
+                )
+                .await;
+        }
+        Ok(())
+    }
+
+    /// Sweeps for lapsed grants every `interval` until shutdown.
+    pub async fn run_expiry_sweeper(
+        self,
+        interval: Duration,
+        mut shutdown: ShutdownSignal,
+    ) -> Result<()> {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            tokio::select! {
+                _ = ticker.tick() => {}
+                () = shutdown.cancelled() => return Ok(()),
+            }
+            if let Err(err) = self.sweep(interval).await {
+                tracing::warn!(error = ?err, "elevation expiry sweep failed");
+            }
+        }
+    }
+}
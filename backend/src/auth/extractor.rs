@@ -0,0 +1,184 @@
+//! Extractors that state what a handler requires of its caller.
+//!
+//! Pick one per handler by the most it needs:
+//!
+//! - none: the route is public; say so in a comment on the handler.
+//! - [`OptionalAuthUser`]: the route is public but shows more to a signed-in
+//!   caller, e.g. a profile that adds contact details for colleagues.
+//! - [`AuthUser`]: any signed-in caller.
+//! - [`AdminUser`]: HR admins only.
+//! - [`SteppedUpUser`]: any signed-in caller who recently verified a
+//!   second factor, for the most sensitive operations; see
+//!   [`step_up`](crate::auth::step_up).
+//!
+//! Neither of the last two admits an impersonation token; see
+//! [`impersonation`](crate::auth::impersonation).
+//! - [`ServiceClient`]: other services, by client certificate.
+//!
+//! Anything a handler does with a caller's identity must come from one of
+//! these, so a route can't end up unprotected by forgetting a check inside
+//! it.
+
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, MatchedPath},
+    http::{header::AUTHORIZATION, request::Parts, HeaderMap},
+};
+
+use crate::{
+    auth::{
+        client_cert::ClientIdentity, impersonation::IMPERSONATION_BLOCKED, policy::is_admin,
+        token_service::AccessTokenClaims,
+    },
+    error::AppError,
+    state::AppState,
+};
+
+/// Claims of a request carrying a valid, unrevoked bearer token.
+pub struct AuthUser(pub AccessTokenClaims);
+
+#[async_trait]
+impl FromRequestParts<AppState> for AuthUser {
+    type Rejection = AppError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let token = request_token(&parts.headers, state)
+            .ok_or(AppError::MissingCredentials("missing bearer token"))?;
+        let claims = state.auth.authenticate(token).await?;
+        Ok(AuthUser(claims))
+    }
+}
+
+/// Claims of the caller if they presented a valid token, `None` if they
+/// presented none. A token that fails verification (expired, revoked,
+/// malformed) also yields `None`, so a stale cookie can't break a public
+/// page; only failures to check it, such as Redis being down, are errors.
+pub struct OptionalAuthUser(pub Option<AccessTokenClaims>);
+
+#[async_trait]
+impl FromRequestParts<AppState> for OptionalAuthUser {
+    type Rejection = AppError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let Some(token) = request_token(&parts.headers, state) else {
+            return Ok(OptionalAuthUser(None));
+        };
+        match state.auth.authenticate(token).await {
+            Ok(claims) => Ok(OptionalAuthUser(Some(claims))),
+            Err(AppError::Unauthorized(_) | AppError::TokenExpired | AppError::BadRequest(_)) => {
+                Ok(OptionalAuthUser(None))
+            }
+            Err(err) => Err(err),
+        }
+    }
+}
+
+/// The bearer token, falling back to the access-token cookie when cookie
+/// delivery is enabled and no `Authorization` header was sent.
+pub(crate) fn request_token<'a>(headers: &'a HeaderMap, state: &AppState) -> Option<&'a str> {
+    bearer_token(headers).or_else(|| state.token_cookie.as_ref()?.read(headers))
+}
+
+pub(crate) fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    let value = headers.get(AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, token) = value.split_once(' ')?;
+
+    if scheme.eq_ignore_ascii_case("bearer") && !token.is_empty() {
+        Some(token.trim())
+    } else {
+        None
+    }
+}
+
+/// An [`AuthUser`] whose role is at least [`Role::HrAdmin`].
+///
+/// [`Role::HrAdmin`]: crate::auth::role::Role::HrAdmin
+pub struct AdminUser(pub AccessTokenClaims);
+
+#[async_trait]
+impl FromRequestParts<AppState> for AdminUser {
+    type Rejection = AppError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let AuthUser(claims) = AuthUser::from_request_parts(parts, state).await?;
+
+        if claims.act.is_some() {
+            let resource = route(parts);
+            return Err(state
+                .denials
+                .deny(&claims, &resource, "own session", IMPERSONATION_BLOCKED)
+                .await);
+        }
+        if !is_admin(&claims) {
+            let resource = route(parts);
+            return Err(state
+                .denials
+                .deny(&claims, &resource, "role:hr_admin", "admin role required")
+                .await);
+        }
+
+        Ok(AdminUser(claims))
+    }
+}
+
+/// An [`AuthUser`] who verified a second factor within the step-up
+/// window. Others get a 401 challenge to re-verify and retry.
+pub struct SteppedUpUser(pub AccessTokenClaims);
+
+#[async_trait]
+impl FromRequestParts<AppState> for SteppedUpUser {
+    type Rejection = AppError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let AuthUser(claims) = AuthUser::from_request_parts(parts, state).await?;
+        if claims.act.is_some() {
+            let resource = route(parts);
+            return Err(state
+                .denials
+                .deny(&claims, &resource, "own session", IMPERSONATION_BLOCKED)
+                .await);
+        }
+        state.step_up.require(&claims).await?;
+        Ok(SteppedUpUser(claims))
+    }
+}
+
+/// `METHOD /route/template`, so denials on `/users/1` and `/users/2` count
+/// as the same resource.
+pub(crate) fn route(parts: &Parts) -> String {
+    let path = parts
+        .extensions
+        .get::<MatchedPath>()
+        .map_or(parts.uri.path(), MatchedPath::as_str);
+    format!("{} {path}", parts.method)
+}
+
+/// A caller that presented a verified client certificate. Service routes
+/// take this instead of [`AuthUser`]; people keep using bearer tokens.
+pub struct ServiceClient(pub ClientIdentity);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for ServiceClient {
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Self::Rejection> {
+        parts
+            .extensions
+            .get::<ClientIdentity>()
+            .cloned()
+            .map(ServiceClient)
+            .ok_or(AppError::Unauthorized("client certificate required"))
+    }
+}
@@ -0,0 +1,165 @@
+//! Support impersonation: an admin acting as a user to reproduce an
+//! issue they reported.
+//!
+//! Only admins holding [`IMPERSONATE_SCOPE`] may start one, for at most
+//! [`MAX_IMPERSONATION`], and never with a role above their own. They get
+//! an access token for the user whose `act` claim names them, so every
+//! audited action carries both (see [`AuditEvent::by`]). While
+//! impersonating, admin routes and anything needing step-up are closed,
+//! and no further impersonation can be started.
+//!
+//! Each grant is recorded under the token's `jti` and audited when it
+//! starts and ends. Ending one early revokes its token. Impersonation
+//! tokens have no session behind them, so they can't be refreshed past
+//! their window.
+//!
+//! [`AuditEvent::by`]: crate::audit::AuditEvent::by
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+use crate::{
+    audit::{AuditEvent, AuditLog, AuditOutcome},
+    auth::{
+        ids::UserId,
+        role::Role,
+        token_service::{AccessTokenClaims, TokenService},
+    },
+    cache::{
+        cache_service::{CacheService, Persistence},
+        keys,
+    },
+    timestamp,
+};
+
+/// Scope an admin's token needs to start impersonating.
+pub const IMPERSONATE_SCOPE: &str = "support:impersonate";
+
+/// Longest window one impersonation may cover.
+pub const MAX_IMPERSONATION: Duration = Duration::from_secs(3600);
+
+/// The 403 reason for what an impersonator may not do.
+pub const IMPERSONATION_BLOCKED: &str = "not available while impersonating";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Impersonation {
+    /// The `jti` of the impersonation token.
+    pub id: String,
+    pub user_id: UserId,
+    pub role: Role,
+    pub impersonator: UserId,
+    pub reason: String,
+    #[serde(with = "crate::timestamp::millis")]
+    pub started_at: DateTime<Utc>,
+    #[serde(with = "crate::timestamp::millis")]
+    pub expires_at: DateTime<Utc>,
+}
+
+#[derive(Clone)]
+pub struct Impersonations {
+    tokens: TokenService,
+    cache: CacheService,
+    audit: AuditLog,
+}
+
+impl Impersonations {
+    pub fn new(tokens: TokenService, cache: CacheService, audit: AuditLog) -> Self {
+        Self {
+            tokens,
+            cache,
+            audit,
+        }
+    }
+
+    /// Lets `admin` act as `user_id` with `role` for `ttl`, and returns
+    /// the token to do it with. Callers check that `admin` may, and that
+    /// `ttl` is within [`MAX_IMPERSONATION`].
+    pub async fn start(
+        &self,
+        admin: &AccessTokenClaims,
+        user_id: UserId,
+        role: Role,
+        ttl: Duration,
+        reason: String,
+    ) -> Result<(String, Impersonation)> {
+        let (token, claims) = self
+            .tokens
+            .issue_impersonation_token(user_id, role, admin, ttl)?;
+        let impersonation = Impersonation {
+            started_at: claims.issued_at(),
+            expires_at: claims.expires_at(),
+            id: claims.jti,
+            user_id,
+            role,
+            impersonator: admin.sub,
+            reason,
+        };
+
+        self.cache
+            .set(
+                &keys::impersonation(&impersonation.id),
+                &impersonation,
+                Persistence::Ttl(ttl),
+            )
+            .await?;
+
+        self.audit
+            .record(
+                AuditEvent::new(
+                    admin.sub.to_string(),
+                    "auth.impersonation_started",
+                    AuditOutcome::Success,
+                )
+                .target(user_id.to_string())
+                .detail(serde_json::json!({
+                    "impersonation_id": impersonation.id,
+                    "role": role,
+                    "expires_at": timestamp::format(impersonation.expires_at),
+                    "reason": impersonation.reason,
+                })),
+            )
+            .await;
+        Ok((token, impersonation))
+    }
+
+    pub async fn get(&self, id: &str) -> Result<Option<Impersonation>> {
+        self.cache.get(&keys::impersonation(id)).await
+    }
+
+    /// Ends impersonation `id` early and revokes its token. Returns `None`
+    /// if it had already ended.
+    pub async fn end(&self, id: &str, ended_by: UserId) -> Result<Option<Impersonation>> {
+        let Some(impersonation) = self
+            .cache
+            .take::<Impersonation>(&keys::impersonation(id))
+            .await?
+        else {
+            return Ok(None);
+        };
+
+        let remaining = (impersonation.expires_at - Utc::now())
+            .to_std()
+            .unwrap_or_default();
+        if !remaining.is_zero() {
+            self.cache.blacklist_token(id, remaining).await?;
+        }
+
+        self.audit
+            .record(
+                AuditEvent::new(
+                    ended_by.to_string(),
+                    "auth.impersonation_ended",
+                    AuditOutcome::Success,
+                )
+                .target(impersonation.user_id.to_string())
+                .detail(serde_json::json!({
+                    "impersonation_id": impersonation.id,
+                    "impersonator": impersonation.impersonator,
+                })),
+            )
+            .await;
+        Ok(Some(impersonation))
+    }
+}
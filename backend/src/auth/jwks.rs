@@ -0,0 +1,335 @@
+//! Verifying access tokens issued by an external identity provider.
+//!
+//! The IdP publishes its public keys as a JWKS document. [`Jwks`] keeps
+//! them in memory by `kid`, refreshes them on a timer, and refetches early
+//! when a token names a key it hasn't seen, which is how a rotation shows
+//! up. A failed fetch keeps the keys already held, so an IdP outage only
+//! stops tokens signed with a brand new key.
+//!
+//! Each key is pinned to one algorithm by its type: RS256 for RSA keys,
+//! ES256 for P-256 keys. The `alg` a token claims must match, so a token
+//! can't pick a weaker algorithm than its key was published for. Keys of
+//! any other type are skipped.
+
+use anyhow::{Context, Result};
+use jsonwebtoken::{
+    decode, decode_header,
+    jwk::{AlgorithmParameters, EllipticCurve, Jwk},
+    Algorithm, DecodingKey, Validation,
+};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
+use tokio::sync::Mutex;
+
+use crate::{auth::token_service::TokenError, shutdown::ShutdownSignal};
+
+/// Unknown `kid`s trigger a refetch at most this often, so tokens with
+/// made-up key ids can't turn into a flood of requests to the IdP.
+const MIN_REFETCH_INTERVAL: Duration = Duration::from_secs(30);
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Claims of a verified IdP token. Beyond the registered claims, whatever
+/// the IdP includes is kept in `other` for the caller to map.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ExternalClaims {
+    pub sub: String,
+    pub iss: String,
+    pub aud: Audience,
+    pub exp: usize,
+    #[serde(default)]
+    pub iat: Option<usize>,
+    #[serde(default)]
+    pub email: Option<String>,
+    #[serde(flatten)]
+    pub other: Map<String, Value>,
+}
+
+/// A token's `aud`, which providers send as a single string or as a list.
+/// Verification accepts either, as long as our audience is present.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(untagged)]
+pub enum Audience {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl Audience {
+    pub fn contains(&self, audience: &str) -> bool {
+        self.iter().any(|aud| aud == audience)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &str> {
+        let all = match self {
+            Self::One(aud) => std::slice::from_ref(aud),
+            Self::Many(auds) => auds.as_slice(),
+        };
+        all.iter().map(String::as_str)
+    }
+}
+
+#[derive(Clone)]
+struct PinnedKey {
+    key: DecodingKey,
+    algorithm: Algorithm,
+}
+
+#[derive(Default)]
+struct KeySet {
+    keys: HashMap<String, PinnedKey>,
+    fetched_at: Option<Instant>,
+}
+
+/// The IdP's signing keys, cached by `kid`.
+#[derive(Clone)]
+pub struct Jwks {
+    url: String,
+    client: reqwest::Client,
+    keys: Arc<RwLock<KeySet>>,
+    /// Serializes fetches, so a burst of unknown-kid tokens fetches once.
+    fetching: Arc<Mutex<()>>,
+}
+
+impl Jwks {
+    pub fn new(url: impl Into<String>) -> Result<Self> {
+        Ok(Self {
+            url: url.into(),
+            client: reqwest::Client::builder()
+                .timeout(FETCH_TIMEOUT)
+                .build()
+                .context("failed to build JWKS client")?,
+            keys: Arc::default(),
+            fetching: Arc::default(),
+        })
+    }
+
+    /// Fetches the document and replaces the cached keys. On failure the
+    /// old keys stay in place.
+    pub async fn refresh(&self) -> Result<()> {
+        let _fetching = self.fetching.lock().await;
+        let keys = self.fetch().await?;
+        tracing::debug!(keys = keys.len(), url = %self.url, "refreshed JWKS");
+
+        let mut set = self.keys.write().unwrap_or_else(|e| e.into_inner());
+        set.keys = keys;
+        set.fetched_at = Some(Instant::now());
+        Ok(())
+    }
+
+    async fn fetch(&self) -> Result<HashMap<String, PinnedKey>> {
+        #[derive(Deserialize)]
+        struct Document {
+            keys: Vec<Value>,
+        }
+
+        let document: Document = self
+            .client
+            .get(&self.url)
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status)
+            .with_context(|| format!("failed to fetch JWKS from {}", self.url))?
+            .json()
+            .await
+            .context("JWKS document is not valid JSON")?;
+
+        // One malformed or unsupported key mustn't cost us the others.
+        Ok(document
+            .keys
+            .into_iter()
+            .filter_map(|raw| serde_json::from_value::<Jwk>(raw).ok())
+            .filter_map(|jwk| Some((jwk.common.key_id.clone()?, pin(&jwk)?)))
+            .collect())
+    }
+
+    /// The key for `kid`, refetching first if it isn't cached and the last
+    /// fetch wasn't too recent.
+    async fn key(&self, kid: &str) -> Option<PinnedKey> {
+        if let Some(key) = self.cached(kid) {
+            return Some(key);
+        }
+
+        let stale = {
+            let set = self.keys.read().unwrap_or_else(|e| e.into_inner());
+            set.fetched_at
+                .is_none_or(|at| at.elapsed() >= MIN_REFETCH_INTERVAL)
+        };
+        if stale {
+            if let Err(err) = self.refresh().await {
+                tracing::warn!(error = ?err, kid, "JWKS refetch for unknown key failed");
+            }
+        }
+        self.cached(kid)
+    }
+
+    fn cached(&self, kid: &str) -> Option<PinnedKey> {
+        let set = self.keys.read().unwrap_or_else(|e| e.into_inner());
+        set.keys.get(kid).cloned()
+    }
+
+    /// Refreshes the keys every `interval` until shutdown.
+    pub async fn run_refresher(
+        self,
+        interval: Duration,
+        mut shutdown: ShutdownSignal,
+    ) -> Result<()> {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            tokio::select! {
+                _ = ticker.tick() => {}
+                () = shutdown.cancelled() => return Ok(()),
+            }
+            if let Err(err) = self.refresh().await {
+                tracing::warn!(error = ?err, "JWKS refresh failed; keeping cached keys");
+            }
+        }
+    }
+}
+
+fn pin(jwk: &Jwk) -> Option<PinnedKey> {
+    let algorithm = match &jwk.algorithm {
+        AlgorithmParameters::RSA(_) => Algorithm::RS256,
+        AlgorithmParameters::EllipticCurve(params) if params.curve == EllipticCurve::P256 => {
+            Algorithm::ES256
+        }
+        _ => return None,
+    };
+    Some(PinnedKey {
+        key: DecodingKey::from_jwk(jwk).ok()?,
+        algorithm,
+    })
+}
+
+/// An IdP whose access tokens we accept: its keys, and the `iss` and
+/// `aud` its tokens must carry.
+#[derive(Clone)]
+pub struct ExternalIssuer {
+    jwks: Jwks,
+    issuer: String,
+    audience: String,
+}
+
+impl ExternalIssuer {
+    pub fn new(jwks: Jwks, issuer: impl Into<String>, audience: impl Into<String>) -> Self {
+        Self {
+            jwks,
+            issuer: issuer.into(),
+            audience: audience.into(),
+        }
+    }
+
+    pub fn jwks(&self) -> &Jwks {
+        &self.jwks
+    }
+
+    pub(crate) async fn verify(&self, token: &str) -> Result<ExternalClaims, TokenError> {
+        let header = decode_header(token)?;
+        let kid = header.kid.ok_or(TokenError::UnknownKey(None))?;
+        let pinned = self
+            .jwks
+            .key(&kid)
+            .await
+            .ok_or(TokenError::UnknownKey(Some(kid)))?;
+
+        let mut validation = Validation::new(pinned.algorithm);
+        validation.set_issuer(&[&self.issuer]);
+        validation.set_audience(&[&self.audience]);
+        validation.set_required_spec_claims(&["exp", "iss", "aud", "sub"]);
+
+        decode::<ExternalClaims>(token, &pinned.key, &validation)
+            .map(|data| data.claims)
+            .map_err(TokenError::from)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use serde_json::json;
+
+    use super::*;
+    use crate::timestamp::current_unix_seconds;
+
+    const SECRET: &[u8] = b"idp-test-secret";
+
+    /// An issuer whose JWKS already holds `kid` "k1", so nothing is
+    /// fetched.
+    fn issuer() -> ExternalIssuer {
+        let jwks = Jwks::new("http://jwks.invalid").unwrap();
+        {
+            let mut set = jwks.keys.write().unwrap();
+            set.keys.insert(
+                "k1".to_string(),
+                PinnedKey {
+                    key: DecodingKey::from_secret(SECRET),
+                    algorithm: Algorithm::HS256,
+                },
+            );
+            set.fetched_at = Some(Instant::now());
+        }
+        ExternalIssuer::new(jwks, "https://idp.example", "hr-api")
+    }
+
+    fn token(aud: Value) -> String {
+        let header = Header {
+            kid: Some("k1".to_string()),
+            ..Header::default()
+        };
+        let claims = json!({
+            "sub": "idp-user-1",
+            "iss": "https://idp.example",
+            "aud": aud,
+            "exp": current_unix_seconds() + 60,
+        });
+        encode(&header, &claims, &EncodingKey::from_secret(SECRET)).unwrap()
+    }
+
+    #[tokio::test]
+    async fn aud_as_a_string_is_accepted() {
+        let claims = issuer().verify(&token(json!("hr-api"))).await.unwrap();
+
+        assert_eq!(claims.aud, Audience::One("hr-api".to_string()));
+        assert!(claims.aud.contains("hr-api"));
+    }
+
+    #[tokio::test]
+    async fn aud_as_a_list_holding_ours_is_accepted() {
+        let claims = issuer()
+            .verify(&token(json!(["billing", "hr-api"])))
+            .await
+            .unwrap();
+
+        assert!(claims.aud.contains("hr-api"));
+        assert_eq!(claims.aud.iter().collect::<Vec<_>>(), ["billing", "hr-api"]);
+    }
+
+    #[tokio::test]
+    async fn aud_without_ours_is_refused() {
+        for aud in [json!("billing"), json!(["billing", "payroll"])] {
+            let refused = issuer().verify(&token(aud.clone())).await;
+            assert!(
+                matches!(&refused, Err(TokenError::Invalid(err))
+                    if *err.kind() == jsonwebtoken::errors::ErrorKind::InvalidAudience),
+                "aud {aud} gave {refused:?}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn unknown_kid_is_refused() {
+        let header = Header {
+            kid: Some("k2".to_string()),
+            ..Header::default()
+        };
+        let token = encode(&header, &json!({}), &EncodingKey::from_secret(SECRET)).unwrap();
+
+        assert!(matches!(
+            issuer().verify(&token).await,
+            Err(TokenError::UnknownKey(Some(kid))) if kid == "k2"
+        ));
+    }
+}
@@ -0,0 +1,117 @@
+//! Progressive delays between failed logins.
+//!
+//! A softer alternative to locking an account out: every failure doubles
+//! the time that must pass before the next attempt, up to a cap, and a
+//! successful login resets it. A legitimate user who mistypes a password
+//! twice waits a second or two; a guessing attack slows to one try per
+//! `max_delay`.
+//!
+//! Attempts are throttled per account and per client IP, so neither an
+//! attacker spreading guesses over many accounts nor one spreading them
+//! over many addresses escapes the delay.
+
+use anyhow::Result;
+use std::{net::IpAddr, time::Duration};
+
+use crate::{
+    cache::{
+        cache_service::{CacheService, KeyTtl, Persistence},
+        keys::{self, CacheKey},
+    },
+    error::AppError,
+    rate_limit::RateLimitResult,
+};
+
+#[derive(Clone)]
+pub struct LoginThrottle {
+    cache: CacheService,
+    base_delay: Duration,
+    max_delay: Duration,
+}
+
+impl LoginThrottle {
+    /// The first failure imposes `base_delay`, each further one twice the
+    /// previous, never more than `max_delay`.
+    pub fn new(cache: CacheService, base_delay: Duration, max_delay: Duration) -> Self {
+        Self {
+            cache,
+            base_delay,
+            max_delay: max_delay.max(base_delay),
+        }
+    }
+
+    /// Refuses the attempt with a 429 while either the account or the IP
+    /// is still serving a delay. `Retry-After` says how long is left.
+    pub async fn check(&self, account: &str, ip: IpAddr) -> Result<(), AppError> {
+        for (_, blocked) in subjects(account, ip) {
+            if let KeyTtl::Expires(remaining) = self.cache.ttl(&blocked).await? {
+                return Err(AppError::TooManyRequests(RateLimitResult {
+                    allowed: false,
+                    limit: 1,
+                    remaining: 0,
+                    reset_after: remaining,
+                }));
+            }
+        }
+        Ok(())
+    }
+
+    /// Counts a failed attempt against the account and the IP, and returns
+    /// the delay now imposed on the account.
+    pub async fn record_failure(&self, account: &str, ip: IpAddr) -> Result<Duration> {
+        let mut account_delay = Duration::ZERO;
+        for (i, (failures, blocked)) in subjects(account, ip).into_iter().enumerate() {
+            // Failures are remembered well past the longest delay, so
+            // waiting one out doesn't reset the count.
+            let failures = self
+                .cache
+                .increment(
+                    &failures,
+                    1,
+                    Persistence::Ttl(self.max_delay.saturating_mul(4)),
+                )
+                .await?;
+            let delay = self.delay_after(failures);
+            self.cache
+                .set(&blocked, &failures, Persistence::Ttl(delay))
+                .await?;
+            if i == 0 {
+                account_delay = delay;
+            }
+        }
+        Ok(account_delay)
+    }
+
+    /// Clears the account's failures after a successful login. The IP's
+    /// are left to expire, so logging into one account of your own can't
+    /// wipe out the delay earned guessing at others.
+    pub async fn record_success(&self, account: &str) -> Result<()> {
+        self.cache
+            .delete(&keys::login_failures_for_account(account))
+            .await?;
+        self.cache
+            .delete(&keys::login_blocked_for_account(account))
+            .await
+    }
+
+    fn delay_after(&self, failures: i64) -> Duration {
+        let doublings = u32::try_from(failures.saturating_sub(1)).unwrap_or(u32::MAX);
+        self.base_delay
+            .saturating_mul(2u32.saturating_pow(doublings))
+            .min(self.max_delay)
+    }
+}
+
+/// The failure count and block keys of the account, then of the IP.
+fn subjects(account: &str, ip: IpAddr) -> [(CacheKey, CacheKey); 2] {
+    [
+        (
+            keys::login_failures_for_account(account),
+            keys::login_blocked_for_account(account),
+        ),
+        (
+            keys::login_failures_for_ip(ip),
+            keys::login_blocked_for_ip(ip),
+        ),
+    ]
+}
@@ -0,0 +1,19 @@
+pub mod auth_service;
+pub mod client_cert;
+pub mod cookie;
+pub mod csrf;
+pub mod deactivation;
+pub mod elevation;
+pub mod extractor;
+pub mod ids;
+pub mod impersonation;
+pub mod jwks;
+pub mod login_throttle;
+pub mod password;
+pub mod policy;
+pub mod role;
+pub mod signed_url;
+pub mod signing;
+pub mod step_up;
+pub mod tenant_keys;
+pub mod token_service;
@@ -0,0 +1,377 @@
+use anyhow::{anyhow, ensure, Context, Result};
+use argon2::{
+    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Algorithm, Argon2, Params, Version,
+};
+use rand::rngs::OsRng;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, fmt, sync::OnceLock};
+use zeroize::Zeroizing;
+
+use crate::auth::signing::sign;
+
+/// Marks a hash made by [`Peppers::hash`]: `pepper:<id>:<argon2 PHC>`.
+const PEPPERED_PREFIX: &str = "pepper:";
+
+/// Hashes `password` into a PHC string with a fresh random salt.
+pub fn hash_password(password: &str) -> Result<String> {
+    hash_password_with(password, Params::default())
+}
+
+/// [`hash_password`] at a cost other than the default. The PHC string
+/// records `params`, so verifying needs nothing but the hash.
+pub fn hash_password_with(password: &str, params: Params) -> Result<String> {
+    let salt = SaltString::generate(&mut OsRng);
+
+    Ok(Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password(password.as_bytes(), &salt)
+        .map_err(|err| anyhow!("password hashing failed: {err}"))?
+        .to_string())
+}
+
+/// Checks `password` against a stored PHC string, at the cost recorded
+/// in it. Malformed hashes simply fail to verify.
+pub fn verify_password(password: &str, hash: &str) -> bool {
+    let Ok(parsed) = PasswordHash::new(hash) else {
+        return false;
+    };
+
+    hasher()
+        .verify_password(password.as_bytes(), &parsed)
+        .is_ok()
+}
+
+/// [`hash_password`] on Tokio's blocking pool. Argon2 is deliberately
+/// slow, and running it on a runtime worker stalls every other task on
+/// that thread; async callers should use this instead.
+pub async fn hash_password_async(password: &str) -> Result<String> {
+    let password = Zeroizing::new(password.to_string());
+    tokio::task::spawn_blocking(move || hash_password(&password)).await?
+}
+
+/// [`hash_password_with`] on the blocking pool; see
+/// [`hash_password_async`].
+pub async fn hash_password_with_async(password: &str, params: Params) -> Result<String> {
+    let password = Zeroizing::new(password.to_string());
+    tokio::task::spawn_blocking(move || hash_password_with(&password, params)).await?
+}
+
+/// [`verify_password`] on the blocking pool; see [`hash_password_async`].
+pub async fn verify_password_async(password: &str, hash: &str) -> bool {
+    let (password, hash) = (Zeroizing::new(password.to_string()), hash.to_string());
+    tokio::task::spawn_blocking(move || verify_password(&password, &hash))
+        .await
+        .unwrap_or(false)
+}
+
+/// Stands in for the stored hash of an account that doesn't exist; see
+/// [`Peppers::verify_account`]. Hashed once, on first use, with the
+/// current parameters, so verifying against it costs the same as
+/// verifying against a real hash.
+fn dummy_hash() -> &'static str {
+    static DUMMY: OnceLock<String> = OnceLock::new();
+    DUMMY.get_or_init(|| {
+        hash_password("no account has this password").expect("hashing a fixed password")
+    })
+}
+
+/// True when `hash` was not produced with the current algorithm, version
+/// and parameters, so it should be re-hashed after the next successful
+/// verification.
+pub fn needs_rehash(hash: &str) -> bool {
+    let Ok(parsed) = PasswordHash::new(hash) else {
+        return true;
+    };
+
+    let current = Params::default();
+    let algorithm_matches = parsed.algorithm == Algorithm::Argon2id.ident();
+    let version_matches = parsed.version == Some(Version::V0x13 as u32);
+
+    match Params::try_from(&parsed) {
+        Ok(params) => {
+            !(algorithm_matches
+                && version_matches
+                && params.m_cost() == current.m_cost()
+                && params.t_cost() == current.t_cost()
+                && params.p_cost() == current.p_cost())
+        }
+        Err(_) => true,
+    }
+}
+
+/// Server-side secrets mixed into passwords before Argon2, so a leaked
+/// hash store alone can't be cracked offline. The password is replaced by
+/// its HMAC-SHA256 under the pepper, and the pepper's id is stored in
+/// front of the hash so peppers can be rotated: new hashes use the
+/// current pepper, older ones keep verifying with the one they name
+/// until [`Peppers::needs_rehash`] moves them over.
+///
+/// Hashes without a pepper prefix still verify as plain Argon2, so
+/// enabling a pepper doesn't lock anyone out.
+#[derive(Clone)]
+pub struct Peppers {
+    current: Option<String>,
+    keys: HashMap<String, Zeroizing<Vec<u8>>>,
+    equalize_timing: bool,
+}
+
+impl Default for Peppers {
+    fn default() -> Self {
+        Self {
+            current: None,
+            keys: HashMap::new(),
+            equalize_timing: true,
+        }
+    }
+}
+
+impl Peppers {
+    /// Parses `id=secret` pairs separated by commas. The first pair is the
+    /// current pepper; the rest are kept for verifying older hashes. An
+    /// empty string means no pepper.
+    pub fn parse(raw: &str) -> Result<Self> {
+        let mut peppers = Self::default();
+
+        for entry in raw.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let (id, secret) = entry
+                .split_once('=')
+                .context("pepper entries must be id=secret")?;
+            ensure!(
+                !id.is_empty()
+                    && id
+                        .bytes()
+                        .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_')),
+                "pepper id {id} must be non-empty and alphanumeric"
+            );
+            ensure!(!secret.is_empty(), "pepper {id} has an empty secret");
+            ensure!(!peppers.keys.contains_key(id), "pepper id {id} is repeated");
+
+            peppers.current.get_or_insert_with(|| id.to_string());
+            peppers
+                .keys
+                .insert(id.to_string(), Zeroizing::new(secret.as_bytes().to_vec()));
+        }
+
+        Ok(peppers)
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.current.is_some()
+    }
+
+    /// Whether [`verify_account`](Self::verify_account) does a full verify
+    /// for accounts that don't exist. On by default; turning it off makes
+    /// unknown accounts fail faster, and so tells callers which accounts
+    /// exist.
+    pub fn with_timing_equalization(mut self, enabled: bool) -> Self {
+        self.equalize_timing = enabled;
+        self
+    }
+
+    /// Hashes with the current pepper, or as plain Argon2 if there is
+    /// none.
+    pub fn hash(&self, password: &str) -> Result<String> {
+        let Some(id) = &self.current else {
+            return hash_password(password);
+        };
+
+        let peppered = Zeroizing::new(sign(&self.keys[id], password.as_bytes()));
+        let phc = hash_password(&peppered)?;
+        Ok(format!("{PEPPERED_PREFIX}{id}:{phc}"))
+    }
+
+    /// Verifies either kind of hash. A hash naming a pepper that is no
+    /// longer configured fails.
+    pub fn verify(&self, password: &str, stored: &str) -> bool {
+        match split_peppered(stored) {
+            Some((id, phc)) => self
+                .keys
+                .get(id)
+                .is_some_and(|key| {
+                    let peppered = Zeroizing::new(sign(key, password.as_bytes()));
+                    verify_password(&peppered, phc)
+                }),
+            None => verify_password(password, stored),
+        }
+    }
+
+    /// Verifies a login attempt against `stored`, the account's hash, or
+    /// `None` when no account matched. Without an account the password is
+    /// still peppered and verified against a dummy hash, so an unknown
+    /// account takes as long to refuse as a wrong password and response
+    /// times don't reveal which accounts exist. Always false without one.
+    pub fn verify_account(&self, password: &str, stored: Option<&str>) -> bool {
+        match stored {
+            Some(stored) => self.verify(password, stored),
+            None => {
+                if self.equalize_timing {
+                    self.verify_dummy(password);
+                }
+                false
+            }
+        }
+    }
+
+    /// Does the work of [`verify`](Self::verify) for the current pepper,
+    /// against [`dummy_hash`].
+    fn verify_dummy(&self, password: &str) -> bool {
+        match &self.current {
+            Some(id) => {
+                let peppered = Zeroizing::new(sign(&self.keys[id], password.as_bytes()));
+                verify_password(&peppered, dummy_hash())
+            }
+            None => verify_password(password, dummy_hash()),
+        }
+    }
+
+    /// [`hash`](Self::hash) on the blocking pool; see
+    /// [`hash_password_async`].
+    pub async fn hash_async(&self, password: &str) -> Result<String> {
+        let (peppers, password) = (self.clone(), Zeroizing::new(password.to_string()));
+        tokio::task::spawn_blocking(move || peppers.hash(&password)).await?
+    }
+
+    /// [`verify`](Self::verify) on the blocking pool.
+    pub async fn verify_async(&self, password: &str, stored: &str) -> bool {
+        let password = Zeroizing::new(password.to_string());
+        let (peppers, stored) = (self.clone(), stored.to_string());
+        tokio::task::spawn_blocking(move || peppers.verify(&password, &stored))
+            .await
+            .unwrap_or(false)
+    }
+
+    /// [`verify_account`](Self::verify_account) on the blocking pool.
+    pub async fn verify_account_async(&self, password: &str, stored: Option<&str>) -> bool {
+        let password = Zeroizing::new(password.to_string());
+        let (peppers, stored) = (self.clone(), stored.map(str::to_string));
+        tokio::task::spawn_blocking(move || peppers.verify_account(&password, stored.as_deref()))
+            .await
+            .unwrap_or(false)
+    }
+
+    /// [`needs_rehash`], plus true when the hash isn't under the current
+    /// pepper (including unpeppered hashes once a pepper is set).
+    pub fn needs_rehash(&self, stored: &str) -> bool {
+        match split_peppered(stored) {
+            Some((id, phc)) => self.current.as_deref() != Some(id) || needs_rehash(phc),
+            None => self.is_enabled() || needs_rehash(stored),
+        }
+    }
+}
+
+impl fmt::Debug for Peppers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut ids: Vec<&String> = self.keys.keys().collect();
+        ids.sort();
+
+        f.debug_struct("Peppers")
+            .field("current", &self.current)
+            .field("ids", &ids)
+            .field("equalize_timing", &self.equalize_timing)
+            .finish()
+    }
+}
+
+fn split_peppered(stored: &str) -> Option<(&str, &str)> {
+    stored.strip_prefix(PEPPERED_PREFIX)?.split_once(':')
+}
+
+/// The most recent password hashes of one user, newest first, kept so a
+/// password change can refuse recently used passwords.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PasswordHistory {
+    hashes: Vec<String>,
+}
+
+impl PasswordHistory {
+    /// True if `password` matches any remembered hash. Each comparison is
+    /// a full Argon2 verify, so keep the history short.
+    pub fn contains(&self, password: &str, peppers: &Peppers) -> bool {
+        self.hashes.iter().any(|hash| peppers.verify(password, hash))
+    }
+
+    /// Remembers `hash` as the newest entry and forgets all but the
+    /// `keep` most recent.
+    pub fn record(&mut self, hash: String, keep: usize) {
+        self.hashes.insert(0, hash);
+        self.hashes.truncate(keep);
+    }
+
+    pub fn len(&self) -> usize {
+        self.hashes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hashes.is_empty()
+    }
+}
+
+fn hasher() -> Argon2<'static> {
+    Argon2::default()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::*;
+
+    fn peppers() -> Peppers {
+        Peppers::parse("p1=first-pepper-secret").unwrap()
+    }
+
+    fn timed(f: impl FnOnce() -> bool) -> (bool, Duration) {
+        let started = Instant::now();
+        let verified = f();
+        (verified, started.elapsed())
+    }
+
+    #[test]
+    fn verify_account_checks_the_stored_hash() {
+        let peppers = peppers();
+        let stored = peppers.hash("correct horse").unwrap();
+
+        assert!(peppers.verify_account("correct horse", Some(&stored)));
+        assert!(!peppers.verify_account("wrong horse", Some(&stored)));
+    }
+
+    #[test]
+    fn verify_account_refuses_unknown_accounts() {
+        assert!(!peppers().verify_account("anything", None));
+        assert!(!peppers()
+            .with_timing_equalization(false)
+            .verify_account("anything", None));
+        assert!(!Peppers::default().verify_account("anything", None));
+    }
+
+    #[test]
+    fn dummy_hash_uses_the_current_parameters() {
+        assert!(!needs_rehash(dummy_hash()));
+    }
+
+    #[test]
+    fn unknown_account_takes_about_as_long_as_a_wrong_password() {
+        let peppers = peppers();
+        let stored = peppers.hash("correct horse").unwrap();
+        // Paid once per process, not per refusal.
+        dummy_hash();
+
+        let (_, wrong) = timed(|| peppers.verify_account("guess", Some(&stored)));
+        let (_, unknown) = timed(|| peppers.verify_account("guess", None));
+        let (_, unequalized) = timed(|| {
+            peppers
+                .clone()
+                .with_timing_equalization(false)
+                .verify_account("guess", None)
+        });
+
+        assert!(
+            unknown >= wrong / 2,
+            "unknown account refused in {unknown:?}, wrong password in {wrong:?}"
+        );
+        assert!(
+            unequalized < wrong / 10,
+            "unequalized refusal took {unequalized:?}"
+        );
+    }
+}
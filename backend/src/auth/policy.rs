@@ -0,0 +1,172 @@
+//! Relational authorization rules.
+//!
+//! Role and scope checks answer "may this kind of user do X"; the rules
+//! here answer "may this user do X to that employee", which depends on the
+//! org hierarchy. Handlers should call these instead of comparing ids
+//! themselves, so every rule lives (and is tested) in one place.
+//!
+//! Whatever refuses an authenticated caller reports it through
+//! [`DenialAudit::deny`], so the audit trail can answer who was kept out
+//! of what.
+
+use anyhow::Result;
+use async_trait::async_trait;
+use std::time::Duration;
+
+use crate::{
+    audit::{AuditEvent, AuditLog, AuditOutcome},
+    auth::{ids::UserId, role::Role, token_service::AccessTokenClaims},
+    cache::{
+        cache_service::{CacheService, Persistence},
+        keys,
+    },
+    error::AppError,
+};
+
+/// Source of reporting lines.
+#[async_trait]
+pub trait OrgHierarchy: Send + Sync {
+    /// Managers of `employee_id`, nearest first, up to the top of the org.
+    async fn management_chain(&self, employee_id: UserId) -> Result<Vec<UserId>>;
+}
+
+/// Caches another hierarchy's answers for `ttl`, since reporting lines
+/// change rarely but are consulted on nearly every request.
+pub struct CachedOrgHierarchy<H> {
+    inner: H,
+    cache: CacheService,
+    ttl: Duration,
+}
+
+impl<H: OrgHierarchy> CachedOrgHierarchy<H> {
+    pub fn new(inner: H, cache: CacheService, ttl: Duration) -> Self {
+        Self { inner, cache, ttl }
+    }
+}
+
+#[async_trait]
+impl<H: OrgHierarchy> OrgHierarchy for CachedOrgHierarchy<H> {
+    async fn management_chain(&self, employee_id: UserId) -> Result<Vec<UserId>> {
+        let key = keys::org_chain(employee_id);
+
+        if let Some(chain) = self.cache.get(&key).await? {
+            return Ok(chain);
+        }
+
+        let chain = self.inner.management_chain(employee_id).await?;
+        self.cache
+            .set(&key, &chain, Persistence::Ttl(self.ttl))
+            .await?;
+        Ok(chain)
+    }
+}
+
+pub fn is_admin(actor: &AccessTokenClaims) -> bool {
+    actor.role.implies(Role::HrAdmin)
+}
+
+/// Employees may view themselves, managers anyone below them, and admins
+/// everyone.
+pub async fn can_view_employee(
+    actor: &AccessTokenClaims,
+    target_id: UserId,
+    hierarchy: &dyn OrgHierarchy,
+) -> Result<bool> {
+    if is_admin(actor) || actor.sub == target_id {
+        return Ok(true);
+    }
+
+    is_above(actor, target_id, hierarchy).await
+}
+
+/// Acting on an employee's records (approving leave, editing attendance)
+/// needs to be above them in the hierarchy; nobody manages themselves.
+pub async fn can_manage_employee(
+    actor: &AccessTokenClaims,
+    target_id: UserId,
+    hierarchy: &dyn OrgHierarchy,
+) -> Result<bool> {
+    if actor.sub == target_id {
+        return Ok(false);
+    }
+
+    if is_admin(actor) {
+        return Ok(true);
+    }
+
+    is_above(actor, target_id, hierarchy).await
+}
+
+/// Audits 403s. A scan probing one route over and over would bury
+/// everything else, so each actor's denials on a resource are recorded
+/// once per `window`.
+#[derive(Clone)]
+pub struct DenialAudit {
+    audit: AuditLog,
+    cache: CacheService,
+    window: Duration,
+}
+
+impl DenialAudit {
+    pub fn new(audit: AuditLog, cache: CacheService, window: Duration) -> Self {
+        Self {
+            audit,
+            cache,
+            window,
+        }
+    }
+
+    /// Records that `actor` was refused `resource` (e.g. `GET /admin/audit`
+    /// or `employee:42`) for lacking `required`, and returns the 403 to
+    /// send with `reason`.
+    pub async fn deny(
+        &self,
+        actor: &AccessTokenClaims,
+        resource: &str,
+        required: &str,
+        reason: &'static str,
+    ) -> AppError {
+        let key = keys::denial_audited(actor.sub, resource);
+        match self
+            .cache
+            .set_if_absent(&key, &true, Persistence::Ttl(self.window))
+            .await
+        {
+            Ok(true) => {
+                self.audit
+                    .record(
+                        AuditEvent::by(actor, "authz.denied", AuditOutcome::Denied)
+                            .target(resource)
+                            .detail(serde_json::json!({
+                                "required": required,
+                                "role": actor.role,
+                                "reason": reason,
+                            })),
+                    )
+                    .await;
+            }
+            Ok(false) => {}
+            Err(err) => tracing::warn!(error = ?err, "failed to check denial audit window"),
+        }
+        AppError::Forbidden(reason)
+    }
+}
+
+/// Turns a policy decision into the standard 403. Prefer
+/// [`DenialAudit::deny`] where the actor is at hand.
+pub fn ensure_allowed(allowed: bool) -> Result<(), AppError> {
+    if allowed {
+        Ok(())
+    } else {
+        Err(AppError::Forbidden("not permitted for this employee"))
+    }
+}
+
+async fn is_above(
+    actor: &AccessTokenClaims,
+    target_id: UserId,
+    hierarchy: &dyn OrgHierarchy,
+) -> Result<bool> {
+    let chain = hierarchy.management_chain(target_id).await?;
+    Ok(chain.contains(&actor.sub))
+}
@@ -0,0 +1,75 @@
+//! The fixed set of roles and how they nest.
+//!
+//! Roles are ordered from least to most privileged, and a role implies
+//! every role below it, so a check is always "at least this role" rather
+//! than a string comparison. Unknown role names are rejected wherever a
+//! role is parsed: in tokens, stored sessions and request bodies alike.
+
+use serde::{Deserialize, Serialize};
+use std::{fmt, str::FromStr};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    Employee,
+    Manager,
+    /// Accepts the old free-form `"admin"` so tokens and sessions minted
+    /// before roles were typed stay valid.
+    #[serde(alias = "admin")]
+    HrAdmin,
+    SuperAdmin,
+}
+
+impl Role {
+    pub const ALL: [Role; 4] = [
+        Role::Employee,
+        Role::Manager,
+        Role::HrAdmin,
+        Role::SuperAdmin,
+    ];
+
+    /// Whether holding `self` grants what `required` grants.
+    pub fn implies(self, required: Role) -> bool {
+        self >= required
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Role::Employee => "employee",
+            Role::Manager => "manager",
+            Role::HrAdmin => "hr_admin",
+            Role::SuperAdmin => "super_admin",
+        }
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug)]
+pub struct UnknownRole(pub String);
+
+impl fmt::Display for UnknownRole {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unknown role {:?}", self.0)
+    }
+}
+
+impl std::error::Error for UnknownRole {}
+
+impl FromStr for Role {
+    type Err = UnknownRole;
+
+    fn from_str(raw: &str) -> Result<Self, Self::Err> {
+        match raw {
+            "admin" => Ok(Role::HrAdmin),
+            _ => Role::ALL
+                .into_iter()
+                .find(|role| role.as_str() == raw)
+                .ok_or_else(|| UnknownRole(raw.to_string())),
+        }
+    }
+}
@@ -0,0 +1,110 @@
+//! Step-up: recent multi-factor verification for sensitive operations.
+//!
+//! A valid session isn't enough for the most sensitive actions, such as
+//! viewing payroll or erasing an employee's data; the caller must also
+//! have verified a second factor within the last `max_age`. That shows in
+//! one of two ways:
+//!
+//! - the token itself says so, with an `amr` claim listing `mfa` or `otp`
+//!   and an `auth_time` within `max_age`, as IdP-issued tokens do;
+//! - the caller re-verified in this session, after which the verifying
+//!   handler calls [`StepUp::record`], leaving a marker that lapses after
+//!   `max_age`.
+//!
+//! Markers belong to the session, not the user, so verifying on one device
+//! doesn't unlock the others. Handlers require step-up by taking
+//! [`SteppedUpUser`](crate::auth::extractor::SteppedUpUser); without it
+//! they answer 401 with an `insufficient_user_authentication` challenge
+//! (RFC 9470) carrying `max_age`.
+
+use anyhow::Result;
+use chrono::Utc;
+use std::time::Duration;
+
+use crate::{
+    auth::token_service::AccessTokenClaims,
+    cache::{
+        cache_service::{CacheService, Persistence},
+        keys,
+    },
+    error::AppError,
+    timestamp::current_unix_seconds,
+};
+
+/// `amr` values that count as a second factor.
+const MFA_METHODS: &[&str] = &["mfa", "otp"];
+
+#[derive(Clone)]
+pub struct StepUp {
+    cache: CacheService,
+    max_age: Duration,
+}
+
+impl StepUp {
+    pub fn new(cache: CacheService, max_age: Duration) -> Self {
+        Self { cache, max_age }
+    }
+
+    pub fn max_age(&self) -> Duration {
+        self.max_age
+    }
+
+    /// Records that the caller just verified a second factor. Call only
+    /// after checking it, e.g. a TOTP code.
+    pub async fn record(&self, claims: &AccessTokenClaims) -> Result<()> {
+        self.cache
+            .set(
+                &keys::step_up(binding(claims)),
+                &Utc::now().timestamp(),
+                Persistence::Ttl(self.max_age),
+            )
+            .await
+    }
+
+    /// Whether the caller verified a second factor within `max_age`.
+    pub async fn is_satisfied(&self, claims: &AccessTokenClaims) -> Result<bool> {
+        if self.token_shows_mfa(claims) {
+            return Ok(true);
+        }
+        self.cache.exists(&keys::step_up(binding(claims))).await
+    }
+
+    /// [`is_satisfied`](Self::is_satisfied), or the step-up challenge.
+    pub async fn require(&self, claims: &AccessTokenClaims) -> Result<(), AppError> {
+        if self.is_satisfied(claims).await? {
+            Ok(())
+        } else {
+            Err(AppError::StepUpRequired(self.max_age))
+        }
+    }
+
+    fn token_shows_mfa(&self, claims: &AccessTokenClaims) -> bool {
+        let used_mfa = claims
+            .extra
+            .get("amr")
+            .and_then(|amr| amr.as_array())
+            .is_some_and(|methods| {
+                methods
+                    .iter()
+                    .filter_map(|method| method.as_str())
+                    .any(|method| MFA_METHODS.contains(&method))
+            });
+        let recent = claims
+            .extra
+            .get("auth_time")
+            .and_then(|auth_time| auth_time.as_u64())
+            .is_some_and(|auth_time| {
+                (current_unix_seconds() as u64).saturating_sub(auth_time) <= self.max_age.as_secs()
+            });
+        used_mfa && recent
+    }
+}
+
+/// The session the token belongs to, or the token itself when it was
+/// minted outside one.
+fn binding(claims: &AccessTokenClaims) -> String {
+    match claims.sid {
+        Some(session_id) => session_id.to_string(),
+        None => format!("jti:{}", claims.jti),
+    }
+}
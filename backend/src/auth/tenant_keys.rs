@@ -0,0 +1,112 @@
+//! Per-tenant signing keys for access tokens.
+//!
+//! Some customers require tokens for their users to be signed with a key
+//! of their own. Such a tenant's tokens carry a `kid` of
+//! `{tenant}:{version}` and are signed with that key; everyone else's
+//! have no `kid` and use the default secret. Once a tenant has keys, its
+//! tokens are only accepted if signed with one of them, and a tenant key
+//! only ever vouches for its own tenant's tokens.
+//!
+//! Keys are listed current first. Tokens are issued with the current key,
+//! and the others keep verifying, so a rotation adds the new version at
+//! the front and drops the old one once its tokens have expired.
+
+use anyhow::{ensure, Context, Result};
+use jsonwebtoken::{DecodingKey, EncodingKey};
+use std::{collections::HashMap, fmt, sync::Arc};
+
+#[derive(Clone)]
+struct TenantKey {
+    version: String,
+    encoding: EncodingKey,
+    decoding: DecodingKey,
+}
+
+#[derive(Clone, Default)]
+pub struct TenantKeys {
+    /// Each tenant's keys, current first.
+    keys: Arc<HashMap<String, Vec<TenantKey>>>,
+}
+
+impl TenantKeys {
+    /// Parses `tenant:version=secret` entries, e.g.
+    /// `acme:2=...,acme:1=...,globex:1=...`; a tenant's first entry is its
+    /// current key.
+    pub fn parse(raw: &str) -> Result<Self> {
+        let mut keys: HashMap<String, Vec<TenantKey>> = HashMap::new();
+
+        for entry in raw.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let (kid, secret) = entry
+                .split_once('=')
+                .context("tenant key entries must be tenant:version=secret")?;
+            let (tenant, version) = kid
+                .split_once(':')
+                .context("tenant key ids must be tenant:version")?;
+            ensure!(
+                is_id(tenant) && is_id(version),
+                "tenant key id {kid} must be alphanumeric on both sides of the colon"
+            );
+            ensure!(!secret.is_empty(), "tenant key {kid} has an empty secret");
+
+            let versions = keys.entry(tenant.to_string()).or_default();
+            ensure!(
+                versions.iter().all(|key| key.version != version),
+                "tenant key {kid} is repeated"
+            );
+            versions.push(TenantKey {
+                version: version.to_string(),
+                encoding: EncodingKey::from_secret(secret.as_bytes()),
+                decoding: DecodingKey::from_secret(secret.as_bytes()),
+            });
+        }
+
+        Ok(Self {
+            keys: Arc::new(keys),
+        })
+    }
+
+    pub fn has_keys(&self, tenant: &str) -> bool {
+        self.keys.contains_key(tenant)
+    }
+
+    /// The `kid` and key to sign `tenant`'s tokens with, `None` if the
+    /// tenant uses the default key.
+    pub(crate) fn current(&self, tenant: &str) -> Option<(String, &EncodingKey)> {
+        let key = self.keys.get(tenant)?.first()?;
+        Some((format!("{tenant}:{}", key.version), &key.encoding))
+    }
+
+    /// The tenant `kid` belongs to and its key, `None` if it names no key.
+    pub(crate) fn for_kid<'a>(&'a self, kid: &'a str) -> Option<(&'a str, &'a DecodingKey)> {
+        let (tenant, version) = kid.split_once(':')?;
+        let key = self
+            .keys
+            .get(tenant)?
+            .iter()
+            .find(|key| key.version == version)?;
+        Some((tenant, &key.decoding))
+    }
+}
+
+impl fmt::Debug for TenantKeys {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut kids: Vec<String> = self
+            .keys
+            .iter()
+            .flat_map(|(tenant, keys)| {
+                keys.iter()
+                    .map(move |key| format!("{tenant}:{}", key.version))
+            })
+            .collect();
+        kids.sort();
+
+        f.debug_struct("TenantKeys").field("kids", &kids).finish()
+    }
+}
+
+fn is_id(id: &str) -> bool {
+    !id.is_empty()
+        && id
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_'))
+}
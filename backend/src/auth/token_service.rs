@@ -1,160 +1,1007 @@
-use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
+use argon2::Params;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{DateTime, Utc};
+use jsonwebtoken::{
+    decode, decode_header, encode, errors::ErrorKind, Algorithm, DecodingKey, EncodingKey, Header,
+    Validation,
+};
 use rand::{rngs::OsRng, RngCore};
 use serde::{Deserialize, Serialize};
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use serde_json::{Map, Value};
+use std::{fmt, time::Duration};
+use tracing::instrument;
 use uuid::Uuid;
+use zeroize::Zeroizing;
+
+use anyhow::{bail, ensure, Context, Result};
 
-use argon2::{
-    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
-    Argon2,
+use crate::{
+    auth::{
+        ids::{SessionId, UserId},
+        impersonation::MAX_IMPERSONATION,
+        jwks::{ExternalClaims, ExternalIssuer},
+        password::{hash_password_with_async, verify_password_async},
+        role::Role,
+        tenant_keys::TenantKeys,
+    },
+    middleware::request_id::current_request_id,
+    timestamp::{current_unix_seconds, from_unix_millis, from_unix_seconds, to_unix_seconds},
 };
 
-use anyhow::Result;
+/// Refresh secrets must carry at least 256 bits of entropy.
+pub const MIN_REFRESH_SECRET_BYTES: usize = 32;
+
+/// Encoded lengths of the smallest allowed secret and a generous ceiling.
+const MIN_SECRET_CHARS: usize = (MIN_REFRESH_SECRET_BYTES * 4).div_ceil(3);
+const MAX_SECRET_CHARS: usize = 512;
+
+const DEFAULT_REFRESH_TOKEN_TTL: Duration = Duration::from_secs(14 * 24 * 3600);
+const DEFAULT_REFRESH_TOKEN_ABSOLUTE_TTL: Duration = Duration::from_secs(90 * 24 * 3600);
 
+/// Clock skew tolerated on `exp` unless configured: none, so a token is
+/// refused the second it expires.
+pub const DEFAULT_LEEWAY: Duration = Duration::ZERO;
+
+/// Longest an action token may live. Long enough for an approval email
+/// read the next day, short enough that a forwarded link goes stale.
+pub const MAX_ACTION_TOKEN_TTL: Duration = Duration::from_secs(72 * 3600);
 
 #[derive(Clone)]
 pub struct TokenService {
-    encoding_key: EncodingKey,
+    /// `None` for a verify-only instance, which holds just a public key.
+    encoding_key: Option<EncodingKey>,
     decoding_key: DecodingKey,
+    algorithm: Algorithm,
     access_token_ttl: Duration,
+    refresh_token_ttl: Duration,
+    refresh_token_absolute_ttl: Duration,
+    refresh_secret_bytes: usize,
+    refresh_hash_params: Params,
+    max_token_age: Option<Duration>,
+    leeway: Duration,
+    external: Option<ExternalIssuer>,
+    tenant_keys: TenantKeys,
+}
+
+/// What a signed token may be used for. Every JWT this service issues
+/// carries one, so a token minted for one purpose can't be replayed as
+/// another even though they share a signing key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TokenUse {
+    Access,
+    Refresh,
+    Reset,
+    Action,
+}
+
+/// Why [`TokenService::verify_access_token`] or
+/// [`TokenService::verify_external_token`] rejected a token.
+#[derive(Debug)]
+pub enum TokenError {
+    /// Past its `exp`, even allowing for the configured leeway. The only
+    /// refusal a refresh recovers from.
+    Expired,
+    /// Well-formed, but not signed by a key we hold.
+    InvalidSignature,
+    /// Not a JWT at all: wrong shape, bad base64 or JSON, or missing a
+    /// required claim.
+    Malformed,
+    /// Validly signed and unexpired, but revoked: its `jti` is
+    /// blacklisted, or it predates the user's "log out everywhere".
+    Blacklisted,
+    /// Refused by `jsonwebtoken` for any other reason, e.g. an algorithm
+    /// we don't accept.
+    Invalid(jsonwebtoken::errors::Error),
+    /// Validly signed, but minted for another purpose.
+    WrongUse(TokenUse),
+    /// An action token for some other action than the one attempted.
+    WrongAction(String),
+    /// Not yet expired, but issued longer ago than the configured maximum
+    /// age allows.
+    TooOld { age: Duration, max_age: Duration },
+    /// An external token signed with a key the IdP doesn't publish, or
+    /// with no `kid` at all; or one of ours naming no tenant key.
+    UnknownKey(Option<String>),
+    /// An external token presented while no IdP is configured.
+    UnknownIssuer,
+    /// Signed with a tenant's key for another tenant's claims, or with the
+    /// default key for a tenant that has keys of its own.
+    KeyMismatch,
+}
+
+impl fmt::Display for TokenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Expired => f.write_str("token expired"),
+            Self::InvalidSignature => f.write_str("token signature is invalid"),
+            Self::Malformed => f.write_str("token is malformed"),
+            Self::Blacklisted => f.write_str("token was revoked"),
+            Self::Invalid(err) => write!(f, "invalid token: {err}"),
+            Self::WrongUse(token_use) => write!(f, "token was minted for {token_use:?} use"),
+            Self::WrongAction(action) => write!(f, "token is for action {action}"),
+            Self::TooOld { age, max_age } => write!(
+                f,
+                "token issued {}s ago exceeds the maximum age of {}s",
+                age.as_secs(),
+                max_age.as_secs()
+            ),
+            Self::UnknownKey(Some(kid)) => write!(f, "no published key with kid {kid}"),
+            Self::UnknownKey(None) => f.write_str("token names no signing key"),
+            Self::UnknownIssuer => f.write_str("no external issuer is configured"),
+            Self::KeyMismatch => f.write_str("token isn't signed with its tenant's key"),
+        }
+    }
+}
+
+impl From<jsonwebtoken::errors::Error> for TokenError {
+    fn from(err: jsonwebtoken::errors::Error) -> Self {
+        match err.kind() {
+            ErrorKind::ExpiredSignature => Self::Expired,
+            ErrorKind::InvalidSignature => Self::InvalidSignature,
+            ErrorKind::InvalidToken
+            | ErrorKind::Base64(_)
+            | ErrorKind::Json(_)
+            | ErrorKind::Utf8(_)
+            | ErrorKind::MissingRequiredClaim(_) => Self::Malformed,
+            _ => Self::Invalid(err),
+        }
+    }
+}
+
+impl std::error::Error for TokenError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Invalid(err) => Some(err),
+            Self::Expired
+            | Self::InvalidSignature
+            | Self::Malformed
+            | Self::Blacklisted
+            | Self::WrongUse(_)
+            | Self::WrongAction(_)
+            | Self::TooOld { .. }
+            | Self::UnknownKey(_)
+            | Self::UnknownIssuer
+            | Self::KeyMismatch => None,
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct AccessTokenClaims {
-    pub sub: String,
-    pub role: String,
+    pub sub: UserId,
+    pub role: Role,
+    #[serde(default)]
+    pub scopes: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant_id: Option<String>,
+    /// The refresh session the token was minted from, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sid: Option<SessionId>,
+    /// Set on impersonation tokens: who is really acting as `sub`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub act: Option<Actor>,
+    pub token_use: TokenUse,
+    pub jti: String,
     pub exp: usize,
     pub iat: usize,
+    /// `iat` to the millisecond, for comparisons whole seconds are too
+    /// coarse for. Absent on tokens minted before it was added.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub iat_ms: Option<i64>,
+    /// Integration-specific claims, e.g. an employee number; see
+    /// [`TokenService::issue_access_token_with_claims`].
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
 }
 
-#[derive(Debug)]
+/// Claims an integration can't set through
+/// [`TokenService::issue_access_token_with_claims`]: the registered ones,
+/// and every one [`AccessTokenClaims`] has a field for.
+pub const RESERVED_CLAIMS: &[&str] = &[
+    "sub", "exp", "iat", "nbf", "jti", "iss", "aud", "role", "scopes", "tenant_id", "sid",
+    "act", "token_use", "iat_ms",
+];
+
+/// The `act` claim (RFC 8693) of an impersonation token.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Actor {
+    pub sub: UserId,
+}
+
+/// A self-contained grant to perform one `action` on one resource, e.g.
+/// approving leave request 42 from a link in an email. Verifying needs no
+/// lookup beyond the revocation check.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ActionTokenClaims {
+    pub action: String,
+    pub resource_id: String,
+    pub token_use: TokenUse,
+    pub jti: String,
+    pub exp: usize,
+    pub iat: usize,
+}
+
+impl ActionTokenClaims {
+    pub fn expires_at(&self) -> DateTime<Utc> {
+        from_unix_seconds(self.exp as i64)
+    }
+}
+
+/// JWTs carry `iat`/`exp` as unix seconds; these convert them for the
+/// rest of the code, which works in [`DateTime<Utc>`].
+impl AccessTokenClaims {
+    pub fn issued_at(&self) -> DateTime<Utc> {
+        match self.iat_ms {
+            Some(millis) => from_unix_millis(millis),
+            None => from_unix_seconds(self.iat as i64),
+        }
+    }
+
+    pub fn expires_at(&self) -> DateTime<Utc> {
+        from_unix_seconds(self.exp as i64)
+    }
+
+    /// The admin acting as `sub`, when this is an impersonation token.
+    pub fn impersonator(&self) -> Option<UserId> {
+        self.act.map(|actor| actor.sub)
+    }
+}
+
+/// The secret is scrubbed from memory on drop and kept out of `Debug`.
 pub struct RefreshToken {
-    pub session_id: Uuid,
-    pub secret: String,
+    pub session_id: SessionId,
+    pub secret: Zeroizing<String>,
+}
+
+impl fmt::Debug for RefreshToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RefreshToken")
+            .field("session_id", &self.session_id)
+            .field("secret", &"<redacted>")
+            .finish()
+    }
 }
 
 #[derive(Debug)]
 pub struct RefreshTokenHash {
-    pub session_id: Uuid,
+    pub session_id: SessionId,
     pub hash: String,
+    /// When the secret stops being accepted: `refresh_token_ttl` after it
+    /// was minted.
+    pub expires_at: DateTime<Utc>,
 }
 
 
 impl TokenService {
+    /// Signs and verifies with the shared secret `jwt_secret` (HS256).
     pub fn new(jwt_secret: &str, access_token_ttl: Duration) -> Self {
+        Self::with_keys(
+            Some(EncodingKey::from_secret(jwt_secret.as_bytes())),
+            DecodingKey::from_secret(jwt_secret.as_bytes()),
+            Algorithm::HS256,
+            access_token_ttl,
+        )
+    }
+
+    /// Signs with the RSA private key and verifies with the public one
+    /// (RS256), so services that only verify tokens need just the public
+    /// key. Without `private_pem` the instance is verify-only, and issuing
+    /// anything fails.
+    pub fn from_rsa_pem(
+        private_pem: Option<&[u8]>,
+        public_pem: &[u8],
+        access_token_ttl: Duration,
+    ) -> Result<Self> {
+        let encoding_key = private_pem
+            .map(EncodingKey::from_rsa_pem)
+            .transpose()
+            .context("invalid RSA private key")?;
+        let decoding_key = DecodingKey::from_rsa_pem(public_pem).context("invalid RSA public key")?;
+        Ok(Self::with_keys(
+            encoding_key,
+            decoding_key,
+            Algorithm::RS256,
+            access_token_ttl,
+        ))
+    }
+
+    fn with_keys(
+        encoding_key: Option<EncodingKey>,
+        decoding_key: DecodingKey,
+        algorithm: Algorithm,
+        access_token_ttl: Duration,
+    ) -> Self {
         Self {
-            encoding_key: EncodingKey::from_secret(jwt_secret.as_bytes()),
-            decoding_key: DecodingKey::from_secret(jwt_secret.as_bytes()),
+            encoding_key,
+            decoding_key,
+            algorithm,
             access_token_ttl,
+            refresh_token_ttl: DEFAULT_REFRESH_TOKEN_TTL,
+            refresh_token_absolute_ttl: DEFAULT_REFRESH_TOKEN_ABSOLUTE_TTL,
+            refresh_secret_bytes: MIN_REFRESH_SECRET_BYTES,
+            refresh_hash_params: Params::default(),
+            max_token_age: None,
+            leeway: DEFAULT_LEEWAY,
+            external: None,
+            tenant_keys: TenantKeys::default(),
         }
     }
 
+    /// Rejects access tokens issued more than `max_age` ago even if their
+    /// `exp` is still ahead, capping token lifetime whatever TTL minted
+    /// them.
+    pub fn with_max_token_age(mut self, max_age: Duration) -> Self {
+        self.max_token_age = Some(max_age);
+        self
+    }
+
+    /// How far past `exp` a token is still accepted, to absorb clock drift
+    /// between the nodes issuing and verifying it.
+    pub fn with_leeway(mut self, leeway: Duration) -> Self {
+        self.leeway = leeway;
+        self
+    }
+
+    /// Also accepts access tokens from an external IdP, through
+    /// [`verify_external_token`](Self::verify_external_token). Our own
+    /// tokens are still issued and verified as before.
+    pub fn with_external_issuer(mut self, issuer: ExternalIssuer) -> Self {
+        self.external = Some(issuer);
+        self
+    }
+
+    pub fn external_issuer(&self) -> Option<&ExternalIssuer> {
+        self.external.as_ref()
+    }
+
+    /// Signs the access tokens of tenants in `keys` with their own keys.
+    pub fn with_tenant_keys(mut self, keys: TenantKeys) -> Self {
+        self.tenant_keys = keys;
+        self
+    }
+
+    /// Sets how long a refresh token stays valid after it was last issued
+    /// (`ttl`), and the hard cap on a session's total lifetime no matter
+    /// how often it is refreshed (`absolute_ttl`).
+    pub fn with_refresh_token_ttl(mut self, ttl: Duration, absolute_ttl: Duration) -> Self {
+        self.refresh_token_ttl = ttl;
+        self.refresh_token_absolute_ttl = absolute_ttl.max(ttl);
+        self
+    }
+
+    /// Overrides the number of random bytes used for refresh secrets.
+    /// Values below [`MIN_REFRESH_SECRET_BYTES`] are rejected.
+    pub fn with_refresh_secret_bytes(mut self, bytes: usize) -> Result<Self> {
+        ensure!(
+            bytes >= MIN_REFRESH_SECRET_BYTES,
+            "refresh secret must be at least {MIN_REFRESH_SECRET_BYTES} bytes, got {bytes}"
+        );
+
+        self.refresh_secret_bytes = bytes;
+        Ok(self)
+    }
+
+    /// Sets the Argon2 cost of hashing new refresh secrets, which happens
+    /// on every rotation. Existing hashes keep verifying at the cost they
+    /// were made with.
+    pub fn with_refresh_hash_params(mut self, params: Params) -> Self {
+        self.refresh_hash_params = params;
+        self
+    }
+
+    pub fn access_token_ttl(&self) -> Duration {
+        self.access_token_ttl
+    }
+
+    /// How long after issuance an access token of ours can still verify:
+    /// the longest lifetime anything mints one with, impersonation
+    /// included, plus the leeway allowed past `exp`.
+    pub fn longest_access_token_lifetime(&self) -> Duration {
+        self.access_token_ttl.max(MAX_IMPERSONATION) + self.leeway
+    }
+
+    pub fn refresh_token_ttl(&self) -> Duration {
+        self.refresh_token_ttl
+    }
+
+    pub fn refresh_token_absolute_ttl(&self) -> Duration {
+        self.refresh_token_absolute_ttl
+    }
+
+    /// Remaining lifetime for a refresh token issued now in a session that
+    /// started at `session_created_at`: the sliding TTL, clipped to the
+    /// session's absolute cap.
+    pub fn refresh_ttl_for(&self, session_created_at: DateTime<Utc>) -> Duration {
+        let age = (Utc::now() - session_created_at).to_std().unwrap_or_default();
+
+        self.refresh_token_ttl
+            .min(self.refresh_token_absolute_ttl.saturating_sub(age))
+    }
+
+    #[instrument(
+        name = "token.issue_access_token",
+        skip_all,
+        fields(correlation_id = %current_request_id())
+    )]
     pub fn issue_access_token(
         &self,
-        user_id: impl Into<String>,
-        role: impl Into<String>,
+        user_id: UserId,
+        role: Role,
     ) -> Result<String> {
-        let now = current_timestamp();
-        let exp = now + self.access_token_ttl.as_secs() as usize;
+        self.issue_scoped_access_token(user_id, role, Vec::new(), None)
+    }
 
+    #[instrument(
+        name = "token.issue_scoped_access_token",
+        skip_all,
+        fields(correlation_id = %current_request_id())
+    )]
+    pub fn issue_scoped_access_token(
+        &self,
+        user_id: UserId,
+        role: Role,
+        scopes: Vec<String>,
+        tenant_id: Option<String>,
+    ) -> Result<String> {
         let claims = AccessTokenClaims {
-            sub: user_id.into(),
-            role: role.into(),
-            iat: now,
-            exp,
+            scopes,
+            tenant_id,
+            ..self.access_claims(user_id, role)
+        };
+
+        self.encode_access(&claims)
+    }
+
+    /// Issues a throwaway access token and verifies it, to catch a broken
+    /// signing setup before the first real login does: encoding, signing
+    /// and the validation settings, and with RS256 a private key that
+    /// doesn't match the public one. Verify-only instances have nothing
+    /// to check.
+    pub fn self_check(&self) -> Result<()> {
+        if self.encoding_key.is_none() {
+            return Ok(());
+        }
+        let user_id = UserId::from(Uuid::nil());
+        let token = self.issue_access_token(user_id, Role::Employee)?;
+        let claims = self
+            .verify_access_token(&token)
+            .map_err(|err| anyhow::anyhow!("freshly issued token failed verification: {err}"))?;
+        ensure!(
+            claims.sub == user_id && claims.token_use == TokenUse::Access,
+            "freshly issued token came back with different claims"
+        );
+        Ok(())
+    }
+
+    /// Like [`issue_access_token`](Self::issue_access_token), with `extra`
+    /// merged into the claims for integrations that would otherwise look
+    /// them up on every request. Verification hands them back in
+    /// [`AccessTokenClaims::extra`]. Fails if `extra` names any of the
+    /// [`RESERVED_CLAIMS`].
+    ///
+    /// Extras aren't carried into tokens minted by refreshing a session;
+    /// re-issue them where they are needed.
+    #[instrument(
+        name = "token.issue_access_token_with_claims",
+        skip_all,
+        fields(correlation_id = %current_request_id())
+    )]
+    pub fn issue_access_token_with_claims(
+        &self,
+        user_id: UserId,
+        role: Role,
+        extra: Map<String, Value>,
+    ) -> Result<String> {
+        if let Some(reserved) = extra
+            .keys()
+            .find(|name| RESERVED_CLAIMS.contains(&name.as_str()))
+        {
+            bail!("claim {reserved} is reserved and can't be set as an extra");
+        }
+        let claims = AccessTokenClaims {
+            extra,
+            ..self.access_claims(user_id, role)
         };
 
-        Ok(encode(&Header::default(), &claims, &self.encoding_key)?)
+        self.encode_access(&claims)
     }
 
+    /// An access token tied to refresh session `session_id`, so requests
+    /// made with it count as activity on that session.
+    #[instrument(
+        name = "token.issue_session_access_token",
+        skip_all,
+        fields(correlation_id = %current_request_id())
+    )]
+    pub fn issue_session_access_token(
+        &self,
+        session_id: SessionId,
+        user_id: UserId,
+        role: Role,
+    ) -> Result<String> {
+        let claims = AccessTokenClaims {
+            sid: Some(session_id),
+            ..self.access_claims(user_id, role)
+        };
+
+        self.encode_access(&claims)
+    }
+
+    /// An access token letting `actor` act as `user_id` with `role` for
+    /// `ttl`, in `actor`'s tenant. It carries `actor` in `act` and can't
+    /// be refreshed. Callers check that `actor` may impersonate; see
+    /// [`Impersonations`](crate::auth::impersonation::Impersonations).
+    #[instrument(
+        name = "token.issue_impersonation_token",
+        skip_all,
+        fields(correlation_id = %current_request_id())
+    )]
+    pub fn issue_impersonation_token(
+        &self,
+        user_id: UserId,
+        role: Role,
+        actor: &AccessTokenClaims,
+        ttl: Duration,
+    ) -> Result<(String, AccessTokenClaims)> {
+        let mut claims = AccessTokenClaims {
+            tenant_id: actor.tenant_id.clone(),
+            act: Some(Actor { sub: actor.sub }),
+            ..self.access_claims(user_id, role)
+        };
+        claims.exp = claims.iat + ttl.as_secs() as usize;
+
+        let token = self.encode_access(&claims)?;
+        Ok((token, claims))
+    }
+
+    /// Signs `claims` with their tenant's current key when it has one,
+    /// naming it in `kid`, and with the default key otherwise.
+    fn encode_access(&self, claims: &AccessTokenClaims) -> Result<String> {
+        let tenant_key = claims
+            .tenant_id
+            .as_deref()
+            .and_then(|tenant| self.tenant_keys.current(tenant));
+
+        Ok(match tenant_key {
+            Some((kid, key)) => {
+                let header = Header {
+                    kid: Some(kid),
+                    ..Header::default()
+                };
+                encode(&header, claims, key)?
+            }
+            None => encode(&Header::new(self.algorithm), claims, self.signing_key()?)?,
+        })
+    }
+
+    fn signing_key(&self) -> Result<&EncodingKey> {
+        self.encoding_key
+            .as_ref()
+            .context("this token service only verifies; it has no private key to sign with")
+    }
+
+    fn validation(&self, algorithm: Algorithm) -> Validation {
+        let mut validation = Validation::new(algorithm);
+        validation.leeway = self.leeway.as_secs();
+        validation
+    }
+
+    fn access_claims(&self, user_id: UserId, role: Role) -> AccessTokenClaims {
+        let issued = Utc::now();
+        let now = to_unix_seconds(issued);
+
+        AccessTokenClaims {
+            sub: user_id,
+            role,
+            scopes: Vec::new(),
+            tenant_id: None,
+            sid: None,
+            act: None,
+            token_use: TokenUse::Access,
+            jti: Uuid::new_v4().to_string(),
+            iat: now,
+            iat_ms: Some(issued.timestamp_millis()),
+            exp: now + self.access_token_ttl.as_secs() as usize,
+            extra: Map::new(),
+        }
+    }
+
+    #[instrument(
+        name = "token.verify_access_token",
+        skip_all,
+        fields(correlation_id = %current_request_id())
+    )]
     pub fn verify_access_token(
         &self,
         token: &str,
-    ) -> Result<AccessTokenClaims> {
-        let data = decode::<AccessTokenClaims>(
+    ) -> Result<AccessTokenClaims, TokenError> {
+        let header = decode_header(token)?;
+        // Tenant keys are shared secrets whatever the default key is.
+        let (key_tenant, key, algorithm) = match header.kid.as_deref() {
+            Some(kid) => self
+                .tenant_keys
+                .for_kid(kid)
+                .map(|(tenant, key)| (Some(tenant), key, Algorithm::HS256))
+                .ok_or_else(|| TokenError::UnknownKey(Some(kid.to_string())))?,
+            None => (None, &self.decoding_key, self.algorithm),
+        };
+        let data = decode::<AccessTokenClaims>(token, key, &self.validation(algorithm))?;
+
+        let claims_tenant = data.claims.tenant_id.as_deref();
+        let key_fits = match key_tenant {
+            Some(tenant) => claims_tenant == Some(tenant),
+            None => !claims_tenant.is_some_and(|tenant| self.tenant_keys.has_keys(tenant)),
+        };
+        if !key_fits {
+            return Err(TokenError::KeyMismatch);
+        }
+
+        if data.claims.token_use != TokenUse::Access {
+            return Err(TokenError::WrongUse(data.claims.token_use));
+        }
+
+        if let Some(max_age) = self.max_token_age {
+            let age =
+                Duration::from_secs(current_unix_seconds().saturating_sub(data.claims.iat) as u64);
+            if age > max_age {
+                return Err(TokenError::TooOld { age, max_age });
+            }
+        }
+
+        Ok(data.claims)
+    }
+
+    /// Verifies an access token from the external IdP against its
+    /// published keys, `iss` and `aud`. Mapping the IdP's subject to one
+    /// of our users is up to the caller.
+    #[instrument(
+        name = "token.verify_external_token",
+        skip_all,
+        fields(correlation_id = %current_request_id())
+    )]
+    pub async fn verify_external_token(&self, token: &str) -> Result<ExternalClaims, TokenError> {
+        match &self.external {
+            Some(issuer) => issuer.verify(token).await,
+            None => Err(TokenError::UnknownIssuer),
+        }
+    }
+
+    /// Mints an action token for `action` on `resource_id`, valid for
+    /// `ttl`, which must be within [`MAX_ACTION_TOKEN_TTL`]. It can't be
+    /// used as an access token, nor for any other action.
+    #[instrument(
+        name = "token.issue_action_token",
+        skip_all,
+        fields(action = %action, correlation_id = %current_request_id())
+    )]
+    pub fn issue_action_token(
+        &self,
+        action: &str,
+        resource_id: &str,
+        ttl: Duration,
+    ) -> Result<String> {
+        ensure!(
+            !ttl.is_zero() && ttl <= MAX_ACTION_TOKEN_TTL,
+            "action token ttl must be between 1s and {}s",
+            MAX_ACTION_TOKEN_TTL.as_secs()
+        );
+
+        let now = current_unix_seconds();
+        let claims = ActionTokenClaims {
+            action: action.to_string(),
+            resource_id: resource_id.to_string(),
+            token_use: TokenUse::Action,
+            jti: Uuid::new_v4().to_string(),
+            iat: now,
+            exp: now + ttl.as_secs() as usize,
+        };
+
+        Ok(encode(&Header::new(self.algorithm), &claims, self.signing_key()?)?)
+    }
+
+    /// Checks signature, expiry and use, and that the token grants
+    /// `action`. Revocation is checked by
+    /// [`AuthService::verify_action_token`], which callers should use.
+    ///
+    /// [`AuthService::verify_action_token`]: crate::auth::auth_service::AuthService::verify_action_token
+    #[instrument(
+        name = "token.verify_action_token",
+        skip_all,
+        fields(action = %action, correlation_id = %current_request_id())
+    )]
+    pub fn verify_action_token(
+        &self,
+        token: &str,
+        action: &str,
+    ) -> Result<ActionTokenClaims, TokenError> {
+        let data = decode::<ActionTokenClaims>(
             token,
             &self.decoding_key,
-            &Validation::default(),
+            &self.validation(self.algorithm),
         )?;
 
+        if data.claims.token_use != TokenUse::Action {
+            return Err(TokenError::WrongUse(data.claims.token_use));
+        }
+        if data.claims.action != action {
+            return Err(TokenError::WrongAction(data.claims.action));
+        }
+
         Ok(data.claims)
     }
 
-    pub fn create_refresh_token(&self) -> (RefreshToken, RefreshTokenHash) {
-        let session_id = Uuid::new_v4();
-        let secret = generate_secret();
+    #[instrument(
+        name = "token.create_refresh_token",
+        skip_all,
+        fields(correlation_id = %current_request_id())
+    )]
+    pub async fn create_refresh_token(&self) -> Result<(RefreshToken, RefreshTokenHash)> {
+        self.reissue_refresh_token(SessionId::new()).await
+    }
 
-        let hash = hash_secret(&secret);
+    /// Single-use rotation: trades `presented` for a fresh secret in the
+    /// same session, provided it matches `stored_hash`. Fails without
+    /// minting anything otherwise.
+    #[instrument(
+        name = "token.rotate_refresh_token",
+        skip_all,
+        fields(correlation_id = %current_request_id())
+    )]
+    pub async fn rotate_refresh_token(
+        &self,
+        presented: &RefreshToken,
+        stored_hash: &RefreshTokenHash,
+    ) -> Result<(RefreshToken, RefreshTokenHash)> {
+        ensure!(
+            presented.session_id == stored_hash.session_id,
+            "refresh token belongs to another session"
+        );
+        ensure!(
+            self.verify_refresh_secret(&presented.secret, &stored_hash.hash)
+                .await,
+            "refresh token does not match the stored hash"
+        );
 
-        (
-            RefreshToken {
-                session_id,
-                secret: secret.clone(),
-            },
+        self.reissue_refresh_token(presented.session_id).await
+    }
+
+    /// A fresh secret for an existing session, for callers that have
+    /// checked the presented one themselves.
+    pub async fn reissue_refresh_token(
+        &self,
+        session_id: SessionId,
+    ) -> Result<(RefreshToken, RefreshTokenHash)> {
+        let secret = generate_secret(self.refresh_secret_bytes);
+
+        let hash = hash_password_with_async(&secret, self.refresh_hash_params.clone()).await?;
+
+        Ok((
+            RefreshToken { session_id, secret },
             RefreshTokenHash {
                 session_id,
                 hash,
+                expires_at: Utc::now() + self.refresh_token_ttl,
             },
-        )
+        ))
     }
 
     pub fn format_refresh_token(
         &self,
-        session_id: Uuid,
+        session_id: SessionId,
         secret: &str,
     ) -> String {
         format!("{}.{}", session_id, secret)
     }
 
+    /// Splits `<session id>.<secret>`. Also rejects secrets that could not
+    /// have been minted by [`generate_secret`] (wrong length or alphabet),
+    /// so garbage never costs a Redis round trip or an Argon2 verify.
     pub fn parse_refresh_token(token: &str) -> Option<RefreshToken> {
         let (id, secret) = token.split_once('.')?;
-        let session_id = Uuid::parse_str(id).ok()?;
+        let session_id: SessionId = id.parse().ok()?;
+
+        let plausible_length = (MIN_SECRET_CHARS..=MAX_SECRET_CHARS).contains(&secret.len());
+        let url_safe = secret
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_');
+
+        if !plausible_length || !url_safe {
+            return None;
+        }
 
         Some(RefreshToken {
             session_id,
-            secret: secret.to_string(),
+            secret: Zeroizing::new(secret.to_string()),
         })
     }
 
-    pub fn verify_refresh_secret(
+    #[instrument(
+        name = "token.verify_refresh_secret",
+        skip_all,
+        fields(correlation_id = %current_request_id())
+    )]
+    pub async fn verify_refresh_secret(
         &self,
         secret: &str,
         stored_hash: &str,
     ) -> bool {
-        verify_secret(secret, stored_hash)
+        verify_password_async(secret, stored_hash).await
     }
 }
 
-fn current_timestamp() -> usize {
-    SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .expect("time went backwards")
-        .as_secs() as usize
-}
-
-fn generate_secret() -> String {
-    let mut bytes = [0u8; 32];
+/// Secrets are URL-safe base64 without padding since refresh tokens
+/// are sometimes carried in query strings. Both the random bytes and the
+/// encoded secret are zeroed when dropped.
+pub(crate) fn generate_secret(len: usize) -> Zeroizing<String> {
+    let mut bytes = Zeroizing::new(vec![0u8; len]);
     OsRng.fill_bytes(&mut bytes);
-    base64::encode(bytes)
+    Zeroizing::new(URL_SAFE_NO_PAD.encode(&*bytes))
 }
 
-fn hash_secret(secret: &str) -> String {
-    let salt = SaltString::generate(&mut OsRng);
-    let argon2 = Argon2::default();
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    argon2
-        .hash_password(secret.as_bytes(), &salt)
-        .expect("hashing failed")
-        .to_string()
-}
+    const SECRET: &str = "test-secret-that-is-long-enough-for-hs256";
+
+    fn service() -> TokenService {
+        TokenService::new(SECRET, Duration::from_secs(900))
+    }
+
+    fn expired_token(tokens: &TokenService) -> String {
+        let mut claims = tokens.access_claims(UserId::new(), Role::Employee);
+        claims.exp = current_unix_seconds() - 1;
+        tokens.encode_access(&claims).unwrap()
+    }
+
+    #[test]
+    fn expired_token_is_refused_without_leeway() {
+        let tokens = service();
+        let token = expired_token(&tokens);
+
+        assert!(matches!(
+            tokens.verify_access_token(&token),
+            Err(TokenError::Expired)
+        ));
+    }
+
+    #[test]
+    fn leeway_accepts_a_token_just_past_exp() {
+        let tokens = service().with_leeway(Duration::from_secs(5));
+        let token = expired_token(&tokens);
+
+        assert!(tokens.verify_access_token(&token).is_ok());
+    }
+
+    #[test]
+    fn token_signed_with_another_secret_has_invalid_signature() {
+        let other = TokenService::new("some-other-secret-entirely", Duration::from_secs(900));
+        let token = other.issue_access_token(UserId::new(), Role::Employee).unwrap();
+
+        assert!(matches!(
+            service().verify_access_token(&token),
+            Err(TokenError::InvalidSignature)
+        ));
+    }
+
+    #[test]
+    fn garbage_is_malformed() {
+        let tokens = service();
+
+        for token in ["garbage", "a.b.c", ""] {
+            assert!(
+                matches!(tokens.verify_access_token(token), Err(TokenError::Malformed)),
+                "{token:?} was not refused as malformed"
+            );
+        }
+    }
+
+    #[test]
+    fn every_access_token_gets_its_own_jti() {
+        let tokens = service();
+        let user_id = UserId::new();
+        let first = tokens.issue_access_token(user_id, Role::Employee).unwrap();
+        let second = tokens.issue_access_token(user_id, Role::Employee).unwrap();
+
+        let first = tokens.verify_access_token(&first).unwrap();
+        let second = tokens.verify_access_token(&second).unwrap();
+
+        assert!(Uuid::parse_str(&first.jti).is_ok(), "jti was {}", first.jti);
+        assert_ne!(first.jti, second.jti);
+    }
 
-fn verify_secret(secret: &str, hash: &str) -> bool {
-    let parsed = PasswordHash::new(hash);
-    if parsed.is_err() {
-        return false;
+    #[test]
+    fn fresh_token_verifies() {
+        let tokens = service();
+        let user_id = UserId::new();
+        let token = tokens.issue_access_token(user_id, Role::Manager).unwrap();
+
+        let claims = tokens.verify_access_token(&token).unwrap();
+        assert_eq!(claims.sub, user_id);
+        assert_eq!(claims.role, Role::Manager);
+    }
+
+    #[tokio::test]
+    async fn refresh_secrets_are_hashed_at_the_configured_cost() {
+        let params = Params::new(8, 1, 1, None).unwrap();
+        let tokens = service().with_refresh_hash_params(params);
+
+        let (refresh, hash) = tokens.create_refresh_token().await.unwrap();
+
+        assert!(hash.hash.contains("m=8,t=1,p=1"), "hash was {}", hash.hash);
+        assert!(tokens.verify_refresh_secret(&refresh.secret, &hash.hash).await);
+        assert!(!tokens.verify_refresh_secret("not-the-secret", &hash.hash).await);
+    }
+
+    #[tokio::test]
+    async fn hashes_made_at_another_cost_still_verify() {
+        let cheap = service().with_refresh_hash_params(Params::new(8, 1, 1, None).unwrap());
+        let default = service();
+
+        let (refresh, hash) = default.create_refresh_token().await.unwrap();
+        assert!(cheap.verify_refresh_secret(&refresh.secret, &hash.hash).await);
+
+        let (refresh, hash) = cheap.create_refresh_token().await.unwrap();
+        assert!(default.verify_refresh_secret(&refresh.secret, &hash.hash).await);
+    }
+
+    fn cheap_service() -> TokenService {
+        service().with_refresh_hash_params(Params::new(8, 1, 1, None).unwrap())
+    }
+
+    #[tokio::test]
+    async fn refresh_tokens_expire_after_the_configured_ttl() {
+        let ttl = Duration::from_secs(3600);
+        let tokens = cheap_service().with_refresh_token_ttl(ttl, ttl * 24);
+
+        let before = Utc::now();
+        let (_, hash) = tokens.create_refresh_token().await.unwrap();
+
+        assert!(hash.expires_at >= before + ttl);
+        assert!(hash.expires_at <= Utc::now() + ttl);
     }
 
-    Argon2::default()
-        .verify_password(secret.as_bytes(), &parsed.unwrap())
-        .is_ok()
+    #[tokio::test]
+    async fn rotation_replaces_the_secret_within_the_session() {
+        let tokens = cheap_service();
+        let (presented, stored) = tokens.create_refresh_token().await.unwrap();
+
+        let (next, next_hash) = tokens
+            .rotate_refresh_token(&presented, &stored)
+            .await
+            .unwrap();
+
+        assert_eq!(next.session_id, presented.session_id);
+        assert_eq!(next_hash.session_id, presented.session_id);
+        assert_ne!(*next.secret, *presented.secret);
+        assert!(tokens.verify_refresh_secret(&next.secret, &next_hash.hash).await);
+        assert!(!tokens.verify_refresh_secret(&presented.secret, &next_hash.hash).await);
+        assert!(next_hash.expires_at >= stored.expires_at);
+    }
+
+    #[tokio::test]
+    async fn rotation_refuses_a_wrong_secret() {
+        let tokens = cheap_service();
+        let (presented, stored) = tokens.create_refresh_token().await.unwrap();
+        let wrong = RefreshToken {
+            session_id: presented.session_id,
+            secret: generate_secret(32),
+        };
+
+        assert!(tokens.rotate_refresh_token(&wrong, &stored).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn rotation_refuses_another_sessions_hash() {
+        let tokens = cheap_service();
+        let (presented, stored) = tokens.create_refresh_token().await.unwrap();
+        let (_, other) = tokens.create_refresh_token().await.unwrap();
+        let misfiled = RefreshTokenHash {
+            session_id: other.session_id,
+            ..stored
+        };
+
+        assert!(tokens.rotate_refresh_token(&presented, &misfiled).await.is_err());
+    }
 }
@@ -0,0 +1,627 @@
+//! Storage primitives behind [`CacheService`](super::cache_service::CacheService).
+//!
+//! Backends deal in fully-qualified keys and raw strings; prefixing, key
+//! hashing, JSON and tracing all stay in `CacheService`. Every method maps
+//! to a single Redis command or script, so implementations must make each
+//! call atomic on its own.
+
+use anyhow::Result;
+use async_trait::async_trait;
+use redis::AsyncCommands;
+use std::{fmt, ops::RangeInclusive, time::Duration};
+
+use crate::cache::{
+    cache_service::{BoundMode, BoundOutcome, BoundedIncr, KeyTtl},
+    redis_client::RedisClient,
+};
+
+#[async_trait]
+pub trait CacheBackend: Send + Sync {
+    async fn get(&self, key: &str) -> Result<Option<String>>;
+
+    /// Reads and deletes `key` in one step.
+    async fn get_del(&self, key: &str) -> Result<Option<String>>;
+
+    async fn set(&self, key: &str, value: &str, ttl: Option<Duration>) -> Result<()>;
+
+    /// Sets `key` only if it doesn't exist. Returns whether it was set.
+    async fn set_nx(&self, key: &str, value: &str, ttl: Option<Duration>) -> Result<bool>;
+
+    /// Like [`set_nx`](Self::set_nx), but also returns the value held
+    /// afterwards (ours if we won, the existing one otherwise).
+    async fn set_nx_or_get(&self, key: &str, value: &str, ttl: Duration) -> Result<(bool, String)>;
+
+    /// [`set_nx`](Self::set_nx) with a TTL that, only when it sets `key`,
+    /// also increments the counter at `counter_key` in the same atomic
+    /// step and returns the new count.
+    async fn set_nx_and_incr(
+        &self,
+        key: &str,
+        counter_key: &str,
+        value: &str,
+        ttl: Duration,
+    ) -> Result<Option<u64>>;
+
+    /// Sets `key` to `new` only while it holds exactly `expected`. A
+    /// missing key never matches.
+    async fn compare_and_set(
+        &self,
+        key: &str,
+        expected: &str,
+        new: &str,
+        ttl: Option<Duration>,
+    ) -> Result<bool>;
+
+    async fn delete(&self, key: &str) -> Result<()>;
+
+    /// Deletes every key matching the glob `pattern` and returns how many
+    /// were removed, walking every primary in cluster mode. Not atomic:
+    /// keys written while it runs may survive.
+    async fn delete_matching(&self, pattern: &str) -> Result<u64>;
+
+    /// How many keys match the glob `pattern`, and the first `sample` of
+    /// them, as [`delete_matching`](Self::delete_matching) would find them.
+    /// Approximate while keys are being written, like the deletion itself.
+    async fn count_matching(&self, pattern: &str, sample: usize) -> Result<KeyMatches>;
+
+    /// Approximate bytes `key` and its value take up in memory, per
+    /// `MEMORY USAGE`; `None` if it doesn't exist.
+    async fn memory_usage(&self, key: &str) -> Result<Option<u64>>;
+
+    /// `MGET`: the value of each key, in input order; `None` for keys that
+    /// are missing or don't hold a string.
+    async fn get_many(&self, keys: &[String]) -> Result<Vec<Option<String>>>;
+
+    /// Pipelined [`set`](Self::set) of each `(key, value)`, all with `ttl`.
+    async fn set_many(&self, entries: &[(String, String)], ttl: Option<Duration>) -> Result<()>;
+
+    /// Existence of each key, in input order.
+    async fn exists_many(&self, keys: &[String]) -> Result<Vec<bool>>;
+
+    /// Sends `message` to subscribers of `channel`.
+    async fn publish(&self, channel: &str, message: &str) -> Result<()>;
+
+    async fn ttl(&self, key: &str) -> Result<KeyTtl>;
+
+    async fn expire(&self, key: &str, ttl: Duration) -> Result<()>;
+
+    /// Pipelined [`expire`](Self::expire) for each key. Returns how many
+    /// existed and were extended.
+    async fn expire_many(&self, keys: &[String], ttl: Duration) -> Result<u64>;
+
+    /// Resets the TTL of `key` only while it holds `value`.
+    async fn expire_if_equals(&self, key: &str, value: &str, ttl: Duration) -> Result<bool>;
+
+    /// Deletes `key` only while it holds `value`, as one atomic step.
+    async fn delete_if_equals(&self, key: &str, value: &str) -> Result<bool>;
+
+    /// Adds `by` (which may be negative) to the integer at `key`, treating
+    /// a missing key as 0. `ttl` is set, in the same atomic step, only if
+    /// the key is created.
+    async fn incr_by(&self, key: &str, by: i64, ttl: Option<Duration>) -> Result<i64>;
+
+    /// Adds `by` to the integer at `key` (missing counts as 0) unless the
+    /// result would leave `bounds`, in which case `mode` decides. `ttl` is
+    /// set only if the key is created.
+    async fn incr_bounded(
+        &self,
+        key: &str,
+        by: i64,
+        bounds: RangeInclusive<i64>,
+        mode: BoundMode,
+        ttl: Option<Duration>,
+    ) -> Result<BoundedIncr>;
+
+    async fn zadd(&self, key: &str, member: &str, score: i64) -> Result<()>;
+
+    /// Drops members of the sorted set at `key` scored below `now`, then
+    /// adds `member` with `score` if it is already present or fewer than
+    /// `capacity` members remain. Returns whether `member` is in the set
+    /// afterwards. Scores are unix milliseconds, and the set expires with
+    /// its highest one.
+    async fn zadd_within_capacity(
+        &self,
+        key: &str,
+        member: &str,
+        score: i64,
+        now: i64,
+        capacity: u64,
+    ) -> Result<bool>;
+
+    /// Members with scores, lowest score first.
+    async fn zrange_with_scores(&self, key: &str) -> Result<Vec<(String, i64)>>;
+
+    async fn zrem(&self, key: &str, members: &[&str]) -> Result<()>;
+}
+
+#[derive(Clone)]
+pub struct RedisBackend {
+    redis: RedisClient,
+}
+
+impl RedisBackend {
+    pub fn new(redis: RedisClient) -> Self {
+        Self { redis }
+    }
+}
+
+#[async_trait]
+impl CacheBackend for RedisBackend {
+    async fn get(&self, key: &str) -> Result<Option<String>> {
+        let mut conn = self.redis.connection();
+        Ok(conn.get(key).await?)
+    }
+
+    async fn get_del(&self, key: &str) -> Result<Option<String>> {
+        let mut conn = self.redis.connection();
+        Ok(redis::cmd("GETDEL").arg(key).query_async(&mut conn).await?)
+    }
+
+    async fn set(&self, key: &str, value: &str, ttl: Option<Duration>) -> Result<()> {
+        let mut conn = self.redis.connection();
+        let mut cmd = redis::cmd("SET");
+        cmd.arg(key).arg(value);
+        if let Some(ttl) = ttl {
+            cmd.arg("PX").arg(millis(ttl));
+        }
+
+        cmd.query_async::<_, ()>(&mut conn).await?;
+        Ok(())
+    }
+
+    async fn set_nx(&self, key: &str, value: &str, ttl: Option<Duration>) -> Result<bool> {
+        let mut conn = self.redis.connection();
+        let mut cmd = redis::cmd("SET");
+        cmd.arg(key).arg(value).arg("NX");
+        if let Some(ttl) = ttl {
+            cmd.arg("PX").arg(millis(ttl));
+        }
+
+        let set: Option<String> = cmd.query_async(&mut conn).await?;
+        Ok(set.is_some())
+    }
+
+    async fn set_nx_or_get(&self, key: &str, value: &str, ttl: Duration) -> Result<(bool, String)> {
+        let mut conn = self.redis.connection();
+        let (won, holder): (i64, String) = redis::Script::new(SET_NX_OR_GET_SCRIPT)
+            .key(key)
+            .arg(value)
+            .arg(millis(ttl))
+            .invoke_async(&mut conn)
+            .await?;
+
+        Ok((won == 1, holder))
+    }
+
+    async fn set_nx_and_incr(
+        &self,
+        key: &str,
+        counter_key: &str,
+        value: &str,
+        ttl: Duration,
+    ) -> Result<Option<u64>> {
+        let mut conn = self.redis.connection();
+        Ok(redis::Script::new(SET_NX_AND_INCR_SCRIPT)
+            .key(key)
+            .key(counter_key)
+            .arg(value)
+            .arg(millis(ttl))
+            .invoke_async(&mut conn)
+            .await?)
+    }
+
+    async fn compare_and_set(
+        &self,
+        key: &str,
+        expected: &str,
+        new: &str,
+        ttl: Option<Duration>,
+    ) -> Result<bool> {
+        let mut conn = self.redis.connection();
+        let swapped: i64 = redis::Script::new(COMPARE_AND_SET_SCRIPT)
+            .key(key)
+            .arg(expected)
+            .arg(new)
+            .arg(ttl.map_or(0, millis))
+            .invoke_async(&mut conn)
+            .await?;
+
+        Ok(swapped == 1)
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        let mut conn = self.redis.connection();
+        conn.del::<_, ()>(key).await?;
+        Ok(())
+    }
+
+    async fn delete_matching(&self, pattern: &str) -> Result<u64> {
+        // Deletes go through the shared connection, which in cluster mode
+        // splits them by slot.
+        let mut conn = self.redis.connection();
+        let mut removed = 0;
+
+        // SCAN rather than KEYS so a large keyspace never blocks Redis.
+        for mut node in self.redis.primaries().await? {
+            let mut cursor: u64 = 0;
+            loop {
+                let (next, keys): (u64, Vec<String>) = redis::cmd("SCAN")
+                    .arg(cursor)
+                    .arg("MATCH")
+                    .arg(pattern)
+                    .arg("COUNT")
+                    .arg(SCAN_BATCH)
+                    .query_async(&mut node)
+                    .await?;
+
+                if !keys.is_empty() {
+                    removed += redis::cmd("UNLINK")
+                        .arg(&keys)
+                        .query_async::<_, u64>(&mut conn)
+                        .await?;
+                }
+
+                if next == 0 {
+                    break;
+                }
+                cursor = next;
+            }
+        }
+        Ok(removed)
+    }
+
+    async fn count_matching(&self, pattern: &str, sample: usize) -> Result<KeyMatches> {
+        let mut matches = KeyMatches::default();
+
+        for mut node in self.redis.primaries().await? {
+            let mut cursor: u64 = 0;
+            loop {
+                let (next, keys): (u64, Vec<String>) = redis::cmd("SCAN")
+                    .arg(cursor)
+                    .arg("MATCH")
+                    .arg(pattern)
+                    .arg("COUNT")
+                    .arg(SCAN_BATCH)
+                    .query_async(&mut node)
+                    .await?;
+
+                matches.count += keys.len() as u64;
+                let room = sample.saturating_sub(matches.sample.len());
+                matches.sample.extend(keys.into_iter().take(room));
+
+                if next == 0 {
+                    break;
+                }
+                cursor = next;
+            }
+        }
+        Ok(matches)
+    }
+
+    async fn memory_usage(&self, key: &str) -> Result<Option<u64>> {
+        let mut conn = self.redis.connection();
+        Ok(redis::cmd("MEMORY")
+            .arg("USAGE")
+            .arg(key)
+            .query_async(&mut conn)
+            .await?)
+    }
+
+    async fn publish(&self, channel: &str, message: &str) -> Result<()> {
+        let mut conn = self.redis.connection();
+        let _: u64 = conn.publish(channel, message).await?;
+        Ok(())
+    }
+
+    async fn get_many(&self, keys: &[String]) -> Result<Vec<Option<String>>> {
+        if keys.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let mut conn = self.redis.connection();
+        Ok(redis::cmd("MGET").arg(keys).query_async(&mut conn).await?)
+    }
+
+    async fn set_many(&self, entries: &[(String, String)], ttl: Option<Duration>) -> Result<()> {
+        if entries.is_empty() {
+            return Ok(());
+        }
+
+        let mut conn = self.redis.connection();
+        let mut pipe = redis::pipe();
+        for (key, value) in entries {
+            let cmd = pipe.cmd("SET").arg(key).arg(value);
+            if let Some(ttl) = ttl {
+                cmd.arg("PX").arg(millis(ttl));
+            }
+            cmd.ignore();
+        }
+
+        pipe.query_async::<_, ()>(&mut conn).await?;
+        Ok(())
+    }
+
+    async fn exists_many(&self, keys: &[String]) -> Result<Vec<bool>> {
+        if keys.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let mut conn = self.redis.connection();
+        let mut pipe = redis::pipe();
+        for key in keys {
+            pipe.exists(key);
+        }
+
+        Ok(pipe.query_async(&mut conn).await?)
+    }
+
+    async fn ttl(&self, key: &str) -> Result<KeyTtl> {
+        let mut conn = self.redis.connection();
+        let millis: i64 = conn.pttl(key).await?;
+
+        Ok(match millis {
+            -2 => KeyTtl::Missing,
+            -1 => KeyTtl::Persistent,
+            ms => KeyTtl::Expires(Duration::from_millis(ms.max(0) as u64)),
+        })
+    }
+
+    async fn expire(&self, key: &str, ttl: Duration) -> Result<()> {
+        let mut conn = self.redis.connection();
+        conn.pexpire::<_, ()>(key, millis(ttl)).await?;
+        Ok(())
+    }
+
+    async fn expire_many(&self, keys: &[String], ttl: Duration) -> Result<u64> {
+        if keys.is_empty() {
+            return Ok(0);
+        }
+
+        let mut conn = self.redis.connection();
+        let mut pipe = redis::pipe();
+        for key in keys {
+            pipe.pexpire(key, millis(ttl));
+        }
+
+        let extended: Vec<bool> = pipe.query_async(&mut conn).await?;
+        Ok(extended.into_iter().filter(|extended| *extended).count() as u64)
+    }
+
+    async fn expire_if_equals(&self, key: &str, value: &str, ttl: Duration) -> Result<bool> {
+        let mut conn = self.redis.connection();
+        let extended: i64 = redis::Script::new(EXPIRE_IF_EQUALS_SCRIPT)
+            .key(key)
+            .arg(value)
+            .arg(millis(ttl))
+            .invoke_async(&mut conn)
+            .await?;
+
+        Ok(extended == 1)
+    }
+
+    async fn delete_if_equals(&self, key: &str, value: &str) -> Result<bool> {
+        let mut conn = self.redis.connection();
+        let deleted: i64 = redis::Script::new(DELETE_IF_EQUALS_SCRIPT)
+            .key(key)
+            .arg(value)
+            .invoke_async(&mut conn)
+            .await?;
+
+        Ok(deleted == 1)
+    }
+
+    async fn incr_by(&self, key: &str, by: i64, ttl: Option<Duration>) -> Result<i64> {
+        let mut conn = self.redis.connection();
+        let Some(ttl) = ttl else {
+            return Ok(conn.incr(key, by).await?);
+        };
+
+        Ok(redis::Script::new(INCR_WITH_TTL_SCRIPT)
+            .key(key)
+            .arg(by)
+            .arg(millis(ttl))
+            .invoke_async(&mut conn)
+            .await?)
+    }
+
+    async fn incr_bounded(
+        &self,
+        key: &str,
+        by: i64,
+        bounds: RangeInclusive<i64>,
+        mode: BoundMode,
+        ttl: Option<Duration>,
+    ) -> Result<BoundedIncr> {
+        let mut conn = self.redis.connection();
+        let (value, outcome): (i64, i64) = redis::Script::new(INCR_BOUNDED_SCRIPT)
+            .key(key)
+            .arg(by)
+            .arg(*bounds.start())
+            .arg(*bounds.end())
+            .arg(match mode {
+                BoundMode::Reject => "reject",
+                BoundMode::Clamp => "clamp",
+            })
+            .arg(ttl.map_or(0, millis))
+            .invoke_async(&mut conn)
+            .await?;
+
+        let outcome = match outcome {
+            0 => BoundOutcome::Applied,
+            1 => BoundOutcome::Clamped,
+            _ => BoundOutcome::Rejected,
+        };
+        Ok(BoundedIncr { value, outcome })
+    }
+
+    async fn zadd(&self, key: &str, member: &str, score: i64) -> Result<()> {
+        let mut conn = self.redis.connection();
+        conn.zadd::<_, _, _, ()>(key, member, score).await?;
+        Ok(())
+    }
+
+    async fn zadd_within_capacity(
+        &self,
+        key: &str,
+        member: &str,
+        score: i64,
+        now: i64,
+        capacity: u64,
+    ) -> Result<bool> {
+        let mut conn = self.redis.connection();
+        let added: i64 = redis::Script::new(ZADD_WITHIN_CAPACITY_SCRIPT)
+            .key(key)
+            .arg(member)
+            .arg(score)
+            .arg(now)
+            .arg(capacity)
+            .invoke_async(&mut conn)
+            .await?;
+        Ok(added == 1)
+    }
+
+    async fn zrange_with_scores(&self, key: &str) -> Result<Vec<(String, i64)>> {
+        let mut conn = self.redis.connection();
+        Ok(conn.zrange_withscores(key, 0, -1).await?)
+    }
+
+    async fn zrem(&self, key: &str, members: &[&str]) -> Result<()> {
+        if members.is_empty() {
+            return Ok(());
+        }
+
+        let mut conn = self.redis.connection();
+        conn.zrem::<_, _, ()>(key, members).await?;
+        Ok(())
+    }
+}
+
+const SCAN_BATCH: usize = 500;
+
+/// Result of [`CacheBackend::count_matching`].
+#[derive(Debug, Clone, Default)]
+pub struct KeyMatches {
+    pub count: u64,
+    pub sample: Vec<String>,
+}
+
+/// A write Redis refused because it reached `maxmemory` under the
+/// `noeviction` policy. Attached as context to the underlying error.
+#[derive(Debug)]
+pub struct OutOfMemory;
+
+impl fmt::Display for OutOfMemory {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Redis is out of memory (maxmemory reached) and refused the write")
+    }
+}
+
+impl std::error::Error for OutOfMemory {}
+
+/// Whether `err` is, or was caused by, Redis's `OOM` reply.
+pub fn is_out_of_memory(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| {
+        cause
+            .downcast_ref::<redis::RedisError>()
+            .is_some_and(|err| err.code() == Some("OOM"))
+    })
+}
+
+/// Redis rejects a zero expiry, so sub-millisecond TTLs round up. Huge
+/// ones saturate rather than wrap; `CacheService` refuses anything past
+/// [`MAX_TTL`](super::cache_service::MAX_TTL) before it gets here.
+fn millis(ttl: Duration) -> i64 {
+    i64::try_from(ttl.as_millis()).unwrap_or(i64::MAX).max(1)
+}
+
+const SET_NX_OR_GET_SCRIPT: &str = r#"
+if redis.call("set", KEYS[1], ARGV[1], "NX", "PX", ARGV[2]) then
+    return {1, ARGV[1]}
+else
+    return {0, redis.call("get", KEYS[1])}
+end
+"#;
+
+const SET_NX_AND_INCR_SCRIPT: &str = r#"
+if redis.call("set", KEYS[1], ARGV[1], "NX", "PX", ARGV[2]) then
+    return redis.call("incr", KEYS[2])
+else
+    return false
+end
+"#;
+
+// ARGV[3] is the TTL in milliseconds, 0 for none.
+const COMPARE_AND_SET_SCRIPT: &str = r#"
+if redis.call("get", KEYS[1]) ~= ARGV[1] then
+    return 0
+end
+if tonumber(ARGV[3]) > 0 then
+    redis.call("set", KEYS[1], ARGV[2], "PX", ARGV[3])
+else
+    redis.call("set", KEYS[1], ARGV[2])
+end
+return 1
+"#;
+
+const INCR_WITH_TTL_SCRIPT: &str = r#"
+local created = redis.call("exists", KEYS[1]) == 0
+local value = redis.call("incrby", KEYS[1], ARGV[1])
+if created then
+    redis.call("pexpire", KEYS[1], ARGV[2])
+end
+return value
+"#;
+
+// Replies {value, outcome}: 0 applied, 1 clamped, 2 rejected. The value
+// is written with %d, as a large Lua number would otherwise be formatted
+// in exponent notation.
+const INCR_BOUNDED_SCRIPT: &str = r#"
+local raw = redis.call("get", KEYS[1])
+if raw and not string.match(raw, "^-?%d+$") then
+    return redis.error_reply("ERR value is not an integer")
+end
+local current = tonumber(raw or "0")
+local min, max = tonumber(ARGV[2]), tonumber(ARGV[3])
+local next = current + tonumber(ARGV[1])
+local outcome = 0
+if next < min or next > max then
+    if ARGV[4] == "reject" then
+        return {current, 2}
+    end
+    next = math.min(math.max(next, min), max)
+    outcome = 1
+end
+redis.call("set", KEYS[1], string.format("%d", next), "KEEPTTL")
+if not raw and tonumber(ARGV[5]) > 0 then
+    redis.call("pexpire", KEYS[1], ARGV[5])
+end
+return {next, outcome}
+"#;
+
+// "(" makes the bound exclusive: only scores strictly below `now` go.
+const ZADD_WITHIN_CAPACITY_SCRIPT: &str = r#"
+redis.call("zremrangebyscore", KEYS[1], "-inf", "(" .. ARGV[3])
+if not redis.call("zscore", KEYS[1], ARGV[1])
+    and redis.call("zcard", KEYS[1]) >= tonumber(ARGV[4]) then
+    return 0
+end
+redis.call("zadd", KEYS[1], ARGV[2], ARGV[1])
+redis.call("pexpireat", KEYS[1], redis.call("zrange", KEYS[1], -1, -1, "withscores")[2])
+return 1
+"#;
+
+const EXPIRE_IF_EQUALS_SCRIPT: &str = r#"
+if redis.call("get", KEYS[1]) == ARGV[1] then
+    return redis.call("pexpire", KEYS[1], ARGV[2])
+else
+    return 0
+end
+"#;
+
+const DELETE_IF_EQUALS_SCRIPT: &str = r#"
+if redis.call("get", KEYS[1]) == ARGV[1] then
+    return redis.call("del", KEYS[1])
+else
+    return 0
+end
+"#;
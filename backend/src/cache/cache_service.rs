@@ -1,142 +1,1460 @@
-use anyhow::Result;
-use redis::AsyncCommands;
-use serde::{de::DeserializeOwned, Serialize};
-use std::time::Duration;
+use anyhow::{anyhow, ensure, Result};
+use chrono::{DateTime, Utc};
+use rand::Rng;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{future::Future, ops::RangeInclusive, sync::Arc, time::Duration};
+use tokio::time::Instant;
+use tracing::instrument;
 use uuid::Uuid;
 
-use crate::redis_client::RedisClient;
+use crate::{
+    cache::{
+        backend::{is_out_of_memory, CacheBackend, KeyMatches, OutOfMemory, RedisBackend},
+        codec::{decode, to_canonical_json},
+        keys,
+        local::{LocalCache, INVALIDATE_ALL},
+        redis_client::RedisClient,
+        singleflight::{self, Role, SingleFlight},
+        slow_log::SlowLog,
+    },
+    metrics,
+    middleware::request_id::current_request_id,
+};
 
+/// Remaining lifetime of a key, as reported by `PTTL`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyTtl {
+    Missing,
+    Persistent,
+    Expires(Duration),
+}
+
+/// How long a written key lives. `Persist` asks for no expiry at all, so
+/// it is never confused with a TTL someone forgot to pass.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Persistence {
+    Ttl(Duration),
+    Persist,
+}
+
+impl Persistence {
+    /// The TTL to apply, checked against [`MAX_TTL`].
+    fn expiry(self) -> Result<Option<Duration>> {
+        match self {
+            Self::Ttl(ttl) => check_ttl(ttl).map(Some),
+            Self::Persist => Ok(None),
+        }
+    }
+}
+
+/// Longest TTL accepted, a century. Redis stores expiries as milliseconds
+/// since the epoch in an `i64`; anything this long is a bug rather than
+/// an intent, and should be [`Persistence::Persist`].
+pub const MAX_TTL: Duration = Duration::from_secs(100 * 365 * 24 * 3600);
+
+fn check_ttl(ttl: Duration) -> Result<Duration> {
+    ensure!(
+        ttl <= MAX_TTL,
+        "TTL of {ttl:?} exceeds the maximum of {MAX_TTL:?}"
+    );
+    Ok(ttl)
+}
+
+/// What [`CacheService::bounded_incr`] does with a change that would
+/// leave the allowed range.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BoundMode {
+    /// Leave the value as it is.
+    Reject,
+    /// Move it as far as the nearest bound.
+    Clamp,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BoundOutcome {
+    Applied,
+    Clamped,
+    Rejected,
+}
+
+/// Result of [`CacheService::bounded_incr`]. `value` is what the key
+/// holds afterwards, so on rejection it is the unchanged current value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BoundedIncr {
+    pub value: i64,
+    pub outcome: BoundOutcome,
+}
+
+/// A held lock. `value` identifies the holder for
+/// [`CacheService::owns_lock`], [`CacheService::extend_lock`] and
+/// [`CacheService::release_lock`]. `fencing_token` grows with every
+/// acquisition of the same key, so storage written under the lock can
+/// reject a write carrying a smaller token than one it has already seen,
+/// e.g. from a holder that was paused past its TTL.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AcquiredLock {
+    pub value: String,
+    pub fencing_token: u64,
+}
+
+/// Result of [`CacheService::key_footprint`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct KeyFootprint {
+    pub count: u64,
+    /// Estimated from a sample of the keys, so only as good as the
+    /// sample is typical.
+    pub approx_bytes: u64,
+}
+
+/// One of a semaphore's permits, from [`CacheService::acquire_permit`].
+/// `holder` identifies it for [`CacheService::extend_permit`] and
+/// [`CacheService::release_permit`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SemaphorePermit {
+    pub holder: String,
+}
+
+/// Outcome of [`CacheService::acquire_lock_explained`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LockAttempt {
+    Acquired(AcquiredLock),
+    /// Someone else holds the lock. Read just after the failed attempt,
+    /// so by the time the caller looks it may have changed hands or been
+    /// released (`holder` is then `None`).
+    Held { holder: Option<String>, ttl: KeyTtl },
+}
+
+/// Sub-prefixes that keep features out of each other's keys, so a bulk
+/// cache purge can't take locks, rate-limit windows or sessions with it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyNamespaces {
+    pub values: String,
+    pub locks: String,
+    pub rate_limits: String,
+    pub auth: String,
+}
+
+impl Default for KeyNamespaces {
+    fn default() -> Self {
+        Self {
+            values: "cache".to_string(),
+            locks: "lock".to_string(),
+            rate_limits: "rl".to_string(),
+            auth: "auth".to_string(),
+        }
+    }
+}
+
+/// Prefixed, JSON-encoded access to Redis, or to any other
+/// [`CacheBackend`] (see [`CacheService::from_backend`]).
+///
+/// # Namespaces
+///
+/// A service created with [`new`](Self::new) addresses keys directly
+/// under its prefix. [`values`](Self::values), [`rate_limits`](Self::rate_limits)
+/// and [`auth`](Self::auth) return views scoped to `{prefix}:{namespace}`;
+/// patterns given to [`delete_by_pattern`](Self::delete_by_pattern) stay
+/// inside the view's namespace. Locks always live under
+/// `{prefix}:{locks}`, whichever view takes them, and are unaffected by
+/// the schema version.
+///
+/// Keys the application reads and writes are built by the functions in
+/// [`keys`], never by hand.
+///
+/// # Redis Cluster
+///
+/// Every key is hashed to a cluster slot on its own. Any operation that
+/// touches several keys at once (multi-key Lua scripts, `MULTI` blocks)
+/// must only be given keys that share a slot, or the cluster will reject
+/// it with `CROSSSLOT`; `MGET` is the exception, as the cluster client
+/// splits it by slot. Build such keys with
+/// [`CacheService::tagged`], which puts the shared part inside `{...}` so
+/// only that part is hashed. Single-key operations need no co-location.
 #[derive(Clone)]
 pub struct CacheService {
-    redis: RedisClient,
+    backend: Arc<dyn CacheBackend>,
     prefix: String,
+    /// `prefix`, plus the namespace when this is a view.
+    scope: String,
+    /// Set on a [`for_tenant`](Self::for_tenant) view.
+    tenant: Option<String>,
+    namespaces: KeyNamespaces,
+    schema_version: Option<u32>,
+    max_key_len: Option<usize>,
+    canonical_json: bool,
+    evict_undecodable: bool,
+    decode_snippet_len: Option<usize>,
+    allow_destructive: bool,
+    best_effort_writes: bool,
+    local: Option<LocalCache>,
+    flights: Arc<SingleFlight>,
 }
 
 impl CacheService {
     pub fn new(redis: RedisClient, prefix: impl Into<String>) -> Self {
+        Self::from_backend(Arc::new(RedisBackend::new(redis)), prefix)
+    }
+
+    /// A service over any storage, e.g. [`MemoryBackend`] in tests.
+    ///
+    /// [`MemoryBackend`]: crate::cache::memory::MemoryBackend
+    pub fn from_backend(backend: Arc<dyn CacheBackend>, prefix: impl Into<String>) -> Self {
+        let prefix = prefix.into();
         Self {
-            redis,
-            prefix: prefix.into(),
+            backend,
+            scope: prefix.clone(),
+            prefix,
+            tenant: None,
+            namespaces: KeyNamespaces::default(),
+            schema_version: None,
+            max_key_len: None,
+            canonical_json: false,
+            evict_undecodable: false,
+            decode_snippet_len: None,
+            allow_destructive: false,
+            best_effort_writes: false,
+            local: None,
+            flights: Arc::default(),
         }
     }
 
+    pub fn with_namespaces(mut self, namespaces: KeyNamespaces) -> Self {
+        self.namespaces = namespaces;
+        self
+    }
+
+    /// View for cached values; the only namespace bulk invalidation
+    /// should ever run against.
+    pub fn values(&self) -> Self {
+        self.view(&self.namespaces.values)
+    }
+
+    /// View over lock keys, e.g. for inspecting a held lock. Taking locks
+    /// doesn't need it.
+    pub fn locks(&self) -> Self {
+        self.view(&self.namespaces.locks)
+    }
+
+    pub fn rate_limits(&self) -> Self {
+        self.view(&self.namespaces.rate_limits)
+    }
+
+    /// View for sessions, token blacklists and other auth state.
+    pub fn auth(&self) -> Self {
+        self.view(&self.namespaces.auth)
+    }
+
+    /// View where every key is put through
+    /// [`tenant_key`](Self::tenant_key) for `tenant`, so callers can't
+    /// forget to. Patterns and locks are not scoped; use
+    /// [`purge_tenant`](Self::purge_tenant) to clear a tenant's keys.
+    pub fn for_tenant(&self, tenant: &str) -> Self {
+        Self {
+            tenant: Some(tenant.to_string()),
+            ..self.clone()
+        }
+    }
+
+    fn view(&self, namespace: &str) -> Self {
+        Self {
+            scope: format!("{}:{}", self.prefix, namespace),
+            ..self.clone()
+        }
+    }
+
+    /// Serialize values with sorted object keys (see
+    /// [`to_canonical_json`]) so the same value always yields the same
+    /// stored bytes. Costs an extra pass through `serde_json::Value`.
+    pub fn with_canonical_json(mut self, enabled: bool) -> Self {
+        self.canonical_json = enabled;
+        self
+    }
+
+    /// Keys longer than `max_len` are replaced by a readable head plus the
+    /// blake3 hash of the full key, e.g. `report:q=dept%3D...~9f86d08...`.
+    /// A leading `{tag}` is kept intact so cluster co-location survives.
+    pub fn with_key_hashing(mut self, max_len: usize) -> Self {
+        self.max_key_len = Some(max_len);
+        self
+    }
+
+    /// Folds `version` into every key, so bumping it makes all entries
+    /// written under the previous version miss. Meant for services caching
+    /// DTOs; don't version a service that also holds blacklists or other
+    /// coordination keys, as a bump would silently drop them too. Locks
+    /// are not versioned.
+    pub fn with_schema_version(mut self, version: u32) -> Self {
+        self.schema_version = Some(version);
+        self
+    }
+
+    /// When enabled, a value that no longer deserializes (e.g. written by
+    /// an older schema) is logged, deleted and reported as a miss by
+    /// [`get`](Self::get), instead of failing the read.
+    pub fn with_poison_handling(mut self, enabled: bool) -> Self {
+        self.evict_undecodable = enabled;
+        self
+    }
+
+    /// Logs a warning for every backend call that takes `threshold` or
+    /// longer. Applies to this service and all views taken from it
+    /// afterwards.
+    pub fn with_slow_op_threshold(mut self, threshold: Duration) -> Self {
+        self.backend = Arc::new(SlowLog::new(self.backend, threshold));
+        self
+    }
+
+    /// Include the first `max_len` bytes of the raw value in
+    /// [`DecodeError`]s. For debugging only: cached values can hold
+    /// personal data, and errors end up in logs.
+    ///
+    /// [`DecodeError`]: crate::cache::codec::DecodeError
+    pub fn with_decode_snippets(mut self, max_len: usize) -> Self {
+        self.decode_snippet_len = Some(max_len);
+        self
+    }
+
+    /// Lets [`set`](Self::set) carry on when Redis is out of memory, for
+    /// services whose values can always be recomputed. Every other write
+    /// still fails, with an [`OutOfMemory`] error.
+    pub fn with_best_effort_writes(mut self, enabled: bool) -> Self {
+        self.best_effort_writes = enabled;
+        self
+    }
+
+    /// Serves the keys `local` opts in from memory; see [`LocalCache`].
+    /// Views taken afterwards share it.
+    pub fn with_local_cache(mut self, local: LocalCache) -> Self {
+        self.local = Some(local);
+        self
+    }
+
+    /// Permits [`flush_prefix`](Self::flush_prefix). For tests and local
+    /// development only; leave it off anywhere the data matters.
+    pub fn with_allow_destructive(mut self, allowed: bool) -> Self {
+        self.allow_destructive = allowed;
+        self
+    }
+
     fn key(&self, key: &str) -> String {
-        format!("{}:{}", self.prefix, key)
+        match &self.tenant {
+            Some(tenant) => self.namespaced(&self.bounded(&Self::tenant_key(tenant, key))),
+            None => self.namespaced(&self.bounded(key)),
+        }
+    }
+
+    fn bounded(&self, key: &str) -> String {
+        match self.max_key_len {
+            Some(max_len) if key.len() > max_len => bounded_key(key),
+            _ => key.to_string(),
+        }
+    }
+
+    /// Prefix and schema version only, without key hashing, so glob
+    /// patterns keep their meaning.
+    fn namespaced(&self, key: &str) -> String {
+        match self.schema_version {
+            Some(version) => format!("{}:v{}:{}", self.scope, version, key),
+            None => format!("{}:{}", self.scope, key),
+        }
+    }
+
+    fn lock_key(&self, key: &str) -> String {
+        format!("{}:{}:{}", self.prefix, self.namespaces.locks, self.bounded(key))
+    }
+
+    fn encode<T: Serialize>(&self, value: &T) -> Result<String> {
+        if self.canonical_json {
+            to_canonical_json(value)
+        } else {
+            Ok(serde_json::to_string(value)?)
+        }
+    }
+
+    /// Builds a relative key whose cluster slot is decided by `tag` alone,
+    /// e.g. `tagged("user:42", "rl:login")` gives `{user:42}:rl:login`.
+    /// Keys built with the same tag always land in the same slot.
+    pub fn tagged(tag: &str, key: &str) -> String {
+        format!("{{{}}}:{}", tag.replace(['{', '}'], ""), key)
+    }
+
+    /// Relative key in `tenant`'s namespace, e.g. `{tenant:acme}:report:7`.
+    /// Anything cached on behalf of a tenant should be keyed with this so
+    /// [`purge_tenant`](Self::purge_tenant) can find it.
+    pub fn tenant_key(tenant: &str, key: &str) -> String {
+        Self::tagged(&format!("tenant:{tenant}"), key)
+    }
+
+    /// Deletes every key matching the glob `pattern`, given relative to
+    /// this service's prefix like any other key, and returns how many were
+    /// removed. Walks the keyspace with `SCAN`, so it is meant for admin
+    /// operations, not request paths.
+    #[instrument(
+        name = "cache.delete_by_pattern",
+        skip_all,
+        fields(pattern = %pattern, correlation_id = %current_request_id())
+    )]
+    pub async fn delete_by_pattern(&self, pattern: &str) -> Result<u64> {
+        let deleted = self
+            .backend
+            .delete_matching(&self.namespaced(pattern))
+            .await?;
+        self.invalidate_local_all().await;
+        Ok(deleted)
+    }
+
+    /// Deletes every key starting with `prefix`, e.g. all the rollups
+    /// cached under one department, and returns how many were removed.
+    /// Unlike [`delete_by_pattern`](Self::delete_by_pattern), `prefix` is
+    /// taken literally, so an id containing `*` or `[` can't widen the
+    /// match; on a [`for_tenant`](Self::for_tenant) view it stays inside
+    /// the tenant. Keys long enough to have been hashed (see
+    /// [`with_key_hashing`](Self::with_key_hashing)) no longer carry their
+    /// prefix and aren't found.
+    pub async fn delete_prefix(&self, prefix: &str) -> Result<u64> {
+        let prefix = escape_glob(prefix);
+        let pattern = match &self.tenant {
+            Some(tenant) => Self::tenant_key(&escape_glob(tenant), &prefix),
+            None => prefix,
+        };
+        self.delete_by_pattern(&format!("{pattern}*")).await
+    }
+
+    /// What [`delete_by_pattern`](Self::delete_by_pattern) would remove:
+    /// the number of matching keys and up to `sample` of them, relative to
+    /// this service's prefix like `pattern`.
+    #[instrument(
+        name = "cache.count_by_pattern",
+        skip_all,
+        fields(pattern = %pattern, correlation_id = %current_request_id())
+    )]
+    pub async fn count_by_pattern(&self, pattern: &str, sample: usize) -> Result<KeyMatches> {
+        let mut matches = self
+            .backend
+            .count_matching(&self.namespaced(pattern), sample)
+            .await?;
+        let scope = self.namespaced("");
+        for key in &mut matches.sample {
+            if let Some(relative) = key.strip_prefix(&scope) {
+                *key = relative.to_string();
+            }
+        }
+        Ok(matches)
+    }
+
+    /// How many keys match `pattern` (relative like in
+    /// [`count_by_pattern`](Self::count_by_pattern)) and roughly how much
+    /// memory they take, extrapolated from `MEMORY USAGE` of the first
+    /// `sample` found. Scans the whole keyspace; meant for periodic
+    /// monitoring, not request paths.
+    #[instrument(
+        name = "cache.key_footprint",
+        skip_all,
+        fields(pattern = %pattern, correlation_id = %current_request_id())
+    )]
+    pub async fn key_footprint(&self, pattern: &str, sample: usize) -> Result<KeyFootprint> {
+        let matches = self
+            .backend
+            .count_matching(&self.namespaced(pattern), sample)
+            .await?;
+
+        let mut sampled = 0u64;
+        let mut sampled_bytes = 0u64;
+        for key in &matches.sample {
+            // Keys expiring mid-scan just drop out of the sample.
+            if let Some(bytes) = self.backend.memory_usage(key).await? {
+                sampled += 1;
+                sampled_bytes += bytes;
+            }
+        }
+
+        let approx_bytes = match sampled {
+            0 => 0,
+            sampled => sampled_bytes / sampled * matches.count,
+        };
+        Ok(KeyFootprint {
+            count: matches.count,
+            approx_bytes,
+        })
     }
 
+    /// Deletes every key under this service's prefix, across all
+    /// namespaces, schema versions and locks, and returns how many were
+    /// removed. Resets state between test runs without `FLUSHDB`, which
+    /// would take other prefixes with it. Refused unless the service was
+    /// built [`with_allow_destructive`](Self::with_allow_destructive).
+    #[instrument(
+        name = "cache.flush_prefix",
+        skip_all,
+        fields(prefix = %self.prefix, correlation_id = %current_request_id())
+    )]
+    pub async fn flush_prefix(&self) -> Result<u64> {
+        ensure!(
+            self.allow_destructive,
+            "flush_prefix is disabled; enable it with with_allow_destructive"
+        );
+        ensure!(!self.prefix.is_empty(), "refusing to flush an empty prefix");
+
+        let pattern = format!("{}:*", escape_glob(&self.prefix));
+        let deleted = self.backend.delete_matching(&pattern).await?;
+        self.invalidate_local_all().await;
+        Ok(deleted)
+    }
+
+    /// Deletes everything keyed with [`tenant_key`](Self::tenant_key) for
+    /// `tenant`. Glob characters in the tenant id are escaped, and the
+    /// closing `}` of the tag keeps `acme` from matching `acme2`.
+    #[instrument(
+        name = "cache.purge_tenant",
+        skip_all,
+        fields(tenant = %tenant, correlation_id = %current_request_id())
+    )]
+    pub async fn purge_tenant(&self, tenant: &str) -> Result<u64> {
+        ensure!(!tenant.is_empty(), "tenant id must not be empty");
+
+        let pattern = Self::tenant_key(&escape_glob(tenant), "*");
+        self.delete_by_pattern(&pattern).await
+    }
+
+    /// What [`purge_tenant`](Self::purge_tenant) would remove, without
+    /// removing it.
+    pub async fn preview_tenant_purge(&self, tenant: &str, sample: usize) -> Result<KeyMatches> {
+        ensure!(!tenant.is_empty(), "tenant id must not be empty");
+
+        let pattern = Self::tenant_key(&escape_glob(tenant), "*");
+        self.count_by_pattern(&pattern, sample).await
+    }
+
+    /// Stores `value` at `key`. On a service built
+    /// [`with_best_effort_writes`](Self::with_best_effort_writes), a write
+    /// Redis refuses for lack of memory is logged and skipped.
+    #[instrument(
+        name = "cache.set",
+        skip_all,
+        fields(key = %key, correlation_id = %current_request_id())
+    )]
     pub async fn set<T: Serialize>(
         &self,
         key: &str,
         value: &T,
-        ttl: Option<Duration>,
+        persistence: Persistence,
     ) -> Result<()> {
-        let mut conn = self.redis.connection();
-        let payload = serde_json::to_string(value)?;
+        self.store(key, value, persistence, self.best_effort_writes)
+            .await
+    }
 
-        match ttl {
-            Some(ttl) => conn
-                .set_ex(self.key(key), payload, ttl.as_secs() as usize)
-                .await?,
-            None => conn.set(self.key(key), payload).await?,
+    /// Stores each `(key, value)` with the same `persistence`, pipelined
+    /// into one round trip. Not atomic: a failure can leave some written.
+    #[instrument(
+        name = "cache.set_many",
+        skip_all,
+        fields(count = entries.len(), correlation_id = %current_request_id())
+    )]
+    pub async fn set_many<T: Serialize>(
+        &self,
+        entries: &[(&str, T)],
+        persistence: Persistence,
+    ) -> Result<()> {
+        let ttl = persistence.expiry()?;
+        let payloads = entries
+            .iter()
+            .map(|(key, value)| Ok((self.key(key), self.encode(value)?)))
+            .collect::<Result<Vec<_>>>()?;
+        let result = match self.backend.set_many(&payloads, ttl).await {
+            Err(err) if self.best_effort_writes && is_out_of_memory(&err) => {
+                tracing::warn!(
+                    count = entries.len(),
+                    "Redis is out of memory; cache writes skipped"
+                );
+                metrics::cache_oom_write(true);
+                Ok(())
+            }
+            result => result.map_err(refused_write),
+        };
+        for (key, _) in entries {
+            self.invalidate_local(key).await;
         }
+        result
+    }
 
-        Ok(())
+    async fn store<T: Serialize>(
+        &self,
+        key: &str,
+        value: &T,
+        persistence: Persistence,
+        best_effort: bool,
+    ) -> Result<()> {
+        let payload = self.encode(value)?;
+        let result = match self
+            .backend
+            .set(&self.key(key), &payload, persistence.expiry()?)
+            .await
+        {
+            Err(err) if best_effort && is_out_of_memory(&err) => {
+                tracing::warn!(key, "Redis is out of memory; cache write skipped");
+                metrics::cache_oom_write(true);
+                Ok(())
+            }
+            result => result.map_err(refused_write),
+        };
+        self.invalidate_local(key).await;
+        result
+    }
+
+    /// Replaces the value at `key` with `new` only if it currently holds
+    /// `expected`, for optimistic updates without a lock. Returns `false`
+    /// when the value had changed (or is missing); re-read and retry.
+    ///
+    /// Values are compared in their serialized form, so for types whose
+    /// JSON isn't stable (maps, mostly) enable
+    /// [`with_canonical_json`](Self::with_canonical_json).
+    #[instrument(
+        name = "cache.compare_and_set",
+        skip_all,
+        fields(key = %key, correlation_id = %current_request_id())
+    )]
+    pub async fn compare_and_set<T: Serialize>(
+        &self,
+        key: &str,
+        expected: &T,
+        new: &T,
+        persistence: Persistence,
+    ) -> Result<bool> {
+        let expected = self.encode(expected)?;
+        let new = self.encode(new)?;
+        let swapped = self
+            .backend
+            .compare_and_set(&self.key(key), &expected, &new, persistence.expiry()?)
+            .await
+            .map_err(refused_write)?;
+        if swapped {
+            self.invalidate_local(key).await;
+        }
+        Ok(swapped)
+    }
+
+    /// [`compare_and_set`](Self::compare_and_set) against the string as
+    /// stored, e.g. from [`get_raw`](Self::get_raw), so a record whose
+    /// JSON wouldn't re-encode byte for byte (one written before a field
+    /// was added, say) still matches.
+    #[instrument(
+        name = "cache.compare_raw_and_set",
+        skip_all,
+        fields(key = %key, correlation_id = %current_request_id())
+    )]
+    pub async fn compare_raw_and_set<T: Serialize>(
+        &self,
+        key: &str,
+        expected: &str,
+        new: &T,
+        persistence: Persistence,
+    ) -> Result<bool> {
+        let new = self.encode(new)?;
+        let swapped = self
+            .backend
+            .compare_and_set(&self.key(key), expected, &new, persistence.expiry()?)
+            .await
+            .map_err(refused_write)?;
+        if swapped {
+            self.invalidate_local(key).await;
+        }
+        Ok(swapped)
     }
 
+    #[instrument(
+        name = "cache.get",
+        skip_all,
+        fields(key = %key, correlation_id = %current_request_id())
+    )]
     pub async fn get<T: DeserializeOwned>(&self, key: &str) -> Result<Option<T>> {
-        let mut conn = self.redis.connection();
-        let value: Option<String> = conn.get(self.key(key)).await?;
+        let full_key = self.key(key);
+        let local = self.local.as_ref().filter(|local| local.covers(key));
+        let raw = match local.and_then(|local| local.get(&full_key)) {
+            Some(raw) => raw.to_string(),
+            None => {
+                let Some(raw) = self.backend.get(&full_key).await? else {
+                    return Ok(None);
+                };
+                if let Some(local) = local {
+                    local.insert(full_key.clone(), &raw);
+                }
+                raw
+            }
+        };
+
+        match decode(&full_key, &raw, self.decode_snippet_len) {
+            Ok(value) => Ok(Some(value)),
+            Err(err) if self.evict_undecodable => {
+                tracing::warn!(error = %err, cause = %err.source, "evicting undecodable cache entry");
+                self.backend.delete(&full_key).await?;
+                self.invalidate_local(key).await;
+                Ok(None)
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
 
-        match value {
-            Some(v) => Ok(Some(serde_json::from_str(&v)?)),
-            None => Ok(None),
+    /// [`get`](Self::get) for each key in one `MGET`, in input order. A key
+    /// that is missing, or whose value doesn't decode, is `None` in its
+    /// slot instead of failing the batch; undecodable entries are logged,
+    /// and evicted on a service built
+    /// [`with_poison_handling`](Self::with_poison_handling).
+    #[instrument(
+        name = "cache.get_many",
+        skip_all,
+        fields(count = keys.len(), correlation_id = %current_request_id())
+    )]
+    pub async fn get_many<T: DeserializeOwned>(&self, keys: &[&str]) -> Result<Vec<Option<T>>> {
+        let full_keys: Vec<String> = keys.iter().map(|key| self.key(key)).collect();
+        let local = |key: &str| self.local.as_ref().filter(|local| local.covers(key));
+
+        let mut raws: Vec<Option<String>> = keys
+            .iter()
+            .zip(&full_keys)
+            .map(|(key, full_key)| Some(local(key)?.get(full_key)?.to_string()))
+            .collect();
+        let (misses, miss_keys): (Vec<usize>, Vec<String>) = raws
+            .iter()
+            .enumerate()
+            .filter(|(_, raw)| raw.is_none())
+            .map(|(i, _)| (i, full_keys[i].clone()))
+            .unzip();
+        if !miss_keys.is_empty() {
+            let fetched = self.backend.get_many(&miss_keys).await?;
+            for ((i, full_key), raw) in misses.into_iter().zip(miss_keys).zip(fetched) {
+                if let (Some(local), Some(raw)) = (local(keys[i]), &raw) {
+                    local.insert(full_key, raw);
+                }
+                raws[i] = raw;
+            }
+        }
+
+        let mut values = Vec::with_capacity(keys.len());
+        for ((key, full_key), raw) in keys.iter().zip(&full_keys).zip(raws) {
+            let Some(raw) = raw else {
+                values.push(None);
+                continue;
+            };
+            match decode(full_key, &raw, self.decode_snippet_len) {
+                Ok(value) => values.push(Some(value)),
+                Err(err) => {
+                    tracing::warn!(
+                        error = %err,
+                        cause = %err.source,
+                        "skipping undecodable cache entry"
+                    );
+                    if self.evict_undecodable {
+                        self.backend.delete(full_key).await?;
+                        self.invalidate_local(key).await;
+                    }
+                    values.push(None);
+                }
+            }
         }
+        Ok(values)
+    }
+
+    /// Returns the cached value, or runs `loader`, caches its result for
+    /// `ttl` and returns that. Guards against stampedes at two levels:
+    /// concurrent misses in this process share one in-flight `loader`
+    /// call, and across processes a short fill lock lets one instance
+    /// load while the others wait for its result (or, if it takes longer
+    /// than the lock, load themselves).
+    ///
+    /// A loader error is handed to every caller waiting on it, and nothing
+    /// is cached. A loader that panics releases its waiters, and one of
+    /// them retries.
+    #[instrument(
+        name = "cache.get_or_set",
+        skip_all,
+        fields(key = %key, correlation_id = %current_request_id())
+    )]
+    pub async fn get_or_set<T, F, Fut>(&self, key: &str, ttl: Duration, loader: F) -> Result<T>
+    where
+        T: Serialize + DeserializeOwned,
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        self.load_through(key, Persistence::Ttl(ttl), loader).await
+    }
+
+    /// [`get_or_set`](Self::get_or_set) for callers holding an optional
+    /// TTL: `None` caches the loaded value without expiry.
+    #[instrument(
+        name = "cache.get_or_set_with",
+        skip_all,
+        fields(key = %key, correlation_id = %current_request_id())
+    )]
+    pub async fn get_or_set_with<T, F, Fut>(
+        &self,
+        key: &str,
+        ttl: Option<Duration>,
+        loader: F,
+    ) -> Result<T>
+    where
+        T: Serialize + DeserializeOwned,
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let persistence = ttl.map_or(Persistence::Persist, Persistence::Ttl);
+        self.load_through(key, persistence, loader).await
+    }
+
+    async fn load_through<T, F, Fut>(
+        &self,
+        key: &str,
+        persistence: Persistence,
+        loader: F,
+    ) -> Result<T>
+    where
+        T: Serialize + DeserializeOwned,
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        persistence.expiry()?;
+        loop {
+            if let Some(value) = self.get(key).await? {
+                return Ok(value);
+            }
+
+            let flight = match self.flights.join(&self.key(key)) {
+                Role::Leader(flight) => flight,
+                Role::Follower(slot) => match singleflight::wait(slot).await {
+                    Some(Ok(raw)) => return Ok(serde_json::from_str(&raw)?),
+                    Some(Err(message)) => return Err(anyhow!(message)),
+                    None => continue,
+                },
+            };
+
+            let result = self.fill(key, persistence, loader).await;
+            flight.finish(match &result {
+                Ok(value) => self.encode(value).map_err(|err| format!("{err:#}")),
+                Err(err) => Err(format!("{err:#}")),
+            });
+            return result;
+        }
+    }
+
+    async fn fill<T, F, Fut>(&self, key: &str, persistence: Persistence, loader: F) -> Result<T>
+    where
+        T: Serialize + DeserializeOwned,
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let lock_key = format!("fill:{key}");
+        let lock = self
+            .acquire_lock_wait(&lock_key, FILL_LOCK_TTL, FILL_LOCK_TTL, FILL_LOCK_POLL)
+            .await?;
+
+        // Whoever held the lock before us has probably filled the key.
+        if lock.is_some() {
+            if let Some(value) = self.get(key).await? {
+                self.release_fill_lock(&lock_key, lock).await;
+                return Ok(value);
+            }
+        }
+
+        let result = match loader().await {
+            Ok(value) => self
+                .set(key, &value, persistence)
+                .await
+                .map(|()| value),
+            Err(err) => Err(err),
+        };
+        self.release_fill_lock(&lock_key, lock).await;
+        result
+    }
+
+    /// Like [`get_or_set`](Self::get_or_set), but a value loaded more than
+    /// `fresh_for` ago is still returned straight away, for up to `ttl`,
+    /// while one task across all instances reloads it in the background.
+    /// Only a missing value makes the caller wait for `loader`.
+    ///
+    /// Values are stored together with when they go stale, so a key used
+    /// here must only be read through this method. A failed refresh is
+    /// logged and the stale value served on, until a later read's refresh
+    /// succeeds or `ttl` runs out.
+    #[instrument(
+        name = "cache.get_or_set_stale",
+        skip_all,
+        fields(key = %key, correlation_id = %current_request_id())
+    )]
+    pub async fn get_or_set_stale<T, F, Fut>(
+        &self,
+        key: &str,
+        fresh_for: Duration,
+        ttl: Duration,
+        loader: F,
+    ) -> Result<T>
+    where
+        T: Serialize + DeserializeOwned + Send + Sync + 'static,
+        F: FnOnce() -> Fut + Send + 'static,
+        Fut: Future<Output = Result<T>> + Send + 'static,
+    {
+        ensure!(fresh_for <= ttl, "fresh_for must not exceed the TTL");
+
+        if let Some(stored) = self.get::<Revalidating<T>>(key).await? {
+            if stored.is_stale() {
+                let cache = self.clone();
+                let key = key.to_string();
+                tokio::spawn(async move {
+                    if let Err(err) = cache.revalidate(&key, fresh_for, ttl, loader).await {
+                        tracing::warn!(error = ?err, key, "background cache refresh failed");
+                    }
+                });
+            }
+            return Ok(stored.value);
+        }
+
+        let stored = self
+            .get_or_set(key, ttl, || async move {
+                Ok(Revalidating::new(loader().await?, fresh_for))
+            })
+            .await?;
+        Ok(stored.value)
+    }
+
+    async fn revalidate<T, F, Fut>(
+        &self,
+        key: &str,
+        fresh_for: Duration,
+        ttl: Duration,
+        loader: F,
+    ) -> Result<()>
+    where
+        T: Serialize + DeserializeOwned,
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let lock_key = format!("refresh:{key}");
+        let Some(lock) = self.acquire_lock(&lock_key, REFRESH_LOCK_TTL).await? else {
+            return Ok(());
+        };
+
+        let result = async {
+            // Someone may have refreshed it between our read and the lock.
+            let current = self.get::<Revalidating<T>>(key).await?;
+            if current.is_some_and(|current| !current.is_stale()) {
+                return Ok(());
+            }
+
+            let value = Revalidating::new(loader().await?, fresh_for);
+            self.set(key, &value, Persistence::Ttl(ttl)).await
+        }
+        .await;
+        self.release_fill_lock(&lock_key, Some(lock)).await;
+        result
+    }
+
+    async fn release_fill_lock(&self, lock_key: &str, lock: Option<AcquiredLock>) {
+        let Some(lock) = lock else {
+            return;
+        };
+        // It expires on its own; failing to release only delays others.
+        if let Err(err) = self.release_lock(lock_key, &lock.value).await {
+            tracing::warn!(error = ?err, "failed to release cache fill lock");
+        }
+    }
+
+    /// The stored string as-is, without deserializing.
+    #[instrument(
+        name = "cache.get_raw",
+        skip_all,
+        fields(key = %key, correlation_id = %current_request_id())
+    )]
+    pub async fn get_raw(&self, key: &str) -> Result<Option<String>> {
+        self.backend.get(&self.key(key)).await
+    }
+
+    /// Reads and deletes `key` atomically, so of several concurrent
+    /// callers only one gets the value. Meant for single-use tokens.
+    #[instrument(
+        name = "cache.take",
+        skip_all,
+        fields(key = %key, correlation_id = %current_request_id())
+    )]
+    pub async fn take<T: DeserializeOwned>(&self, key: &str) -> Result<Option<T>> {
+        let full_key = self.key(key);
+        let Some(raw) = self.backend.get_del(&full_key).await? else {
+            return Ok(None);
+        };
+        self.invalidate_local(key).await;
+
+        Ok(Some(decode(&full_key, &raw, self.decode_snippet_len)?))
     }
 
+    #[instrument(
+        name = "cache.delete",
+        skip_all,
+        fields(key = %key, correlation_id = %current_request_id())
+    )]
     pub async fn delete(&self, key: &str) -> Result<()> {
-        let mut conn = self.redis.connection();
-        conn.del(self.key(key)).await?;
+        self.backend.delete(&self.key(key)).await?;
+        self.invalidate_local(key).await;
         Ok(())
     }
 
+    /// Drops `key` from the local cache, here and, via pub/sub, on every
+    /// other instance. Best-effort: a lost message only means peers serve
+    /// the old value until their copy expires.
+    async fn invalidate_local(&self, key: &str) {
+        let Some(local) = self.local.as_ref().filter(|local| local.covers(key)) else {
+            return;
+        };
+        let full_key = self.key(key);
+        local.invalidate(&full_key);
+        if let Err(err) = self.backend.publish(local.channel(), &full_key).await {
+            tracing::warn!(key, error = ?err, "failed to publish local cache invalidation");
+        }
+    }
+
+    /// Like [`invalidate_local`](Self::invalidate_local), for every key.
+    async fn invalidate_local_all(&self) {
+        let Some(local) = &self.local else {
+            return;
+        };
+        local.invalidate_all();
+        if let Err(err) = self.backend.publish(local.channel(), INVALIDATE_ALL).await {
+            tracing::warn!(error = ?err, "failed to publish local cache invalidation");
+        }
+    }
+
+    #[instrument(
+        name = "cache.exists",
+        skip_all,
+        fields(key = %key, correlation_id = %current_request_id())
+    )]
     pub async fn exists(&self, key: &str) -> Result<bool> {
-        let mut conn = self.redis.connection();
-        Ok(conn.exists(self.key(key)).await?)
+        let found = self.backend.exists_many(&[self.key(key)]).await?;
+        Ok(found.first().copied().unwrap_or(false))
     }
 
-    pub async fn increment(&self, key: &str, by: i64, ttl: Option<Duration>) -> Result<i64> {
-        let mut conn = self.redis.connection();
-        let full_key = self.key(key);
+    /// Pipelined `EXISTS` for each key, in input order.
+    #[instrument(
+        name = "cache.exists_many",
+        skip_all,
+        fields(count = keys.len(), correlation_id = %current_request_id())
+    )]
+    pub async fn exists_many(&self, keys: &[&str]) -> Result<Vec<bool>> {
+        let keys: Vec<String> = keys.iter().map(|key| self.key(key)).collect();
+        self.backend.exists_many(&keys).await
+    }
 
-        let value: i64 = conn.incr(&full_key, by).await?;
+    #[instrument(
+        name = "cache.ttl",
+        skip_all,
+        fields(key = %key, correlation_id = %current_request_id())
+    )]
+    pub async fn ttl(&self, key: &str) -> Result<KeyTtl> {
+        self.backend.ttl(&self.key(key)).await
+    }
 
-        if value == by {
-            if let Some(ttl) = ttl {
-                conn.expire(&full_key, ttl.as_secs() as usize).await?;
-            }
-        }
+    /// Resets the TTL of each key to `ttl` in one round trip, e.g. to keep
+    /// a batch of related keys alive together. Keys that don't exist are
+    /// skipped; returns how many were extended.
+    #[instrument(
+        name = "cache.expire_many",
+        skip_all,
+        fields(count = keys.len(), correlation_id = %current_request_id())
+    )]
+    pub async fn expire_many(&self, keys: &[&str], ttl: Duration) -> Result<u64> {
+        let ttl = check_ttl(ttl)?;
+        let keys: Vec<String> = keys.iter().map(|key| self.key(key)).collect();
+        self.backend.expire_many(&keys, ttl).await
+    }
 
-        Ok(value)
+    /// Adds `by` to the counter at `key`, a missing key counting as 0.
+    /// `persistence` is applied only when this call creates the key, in
+    /// the same atomic step, so concurrent increments never push a
+    /// counter's expiry back, and a counter never ends up without one.
+    #[instrument(
+        name = "cache.increment",
+        skip_all,
+        fields(key = %key, correlation_id = %current_request_id())
+    )]
+    pub async fn increment(
+        &self,
+        key: &str,
+        by: i64,
+        persistence: Persistence,
+    ) -> Result<i64> {
+        self.backend
+            .incr_by(&self.key(key), by, persistence.expiry()?)
+            .await
+            .map_err(refused_write)
+    }
+
+    /// Adds `by` (which may be negative) to the counter at `key` as one
+    /// atomic step, keeping it within `bounds`; a missing key counts as 0.
+    /// A change that would leave the range is refused or clamped per
+    /// `mode`. Like [`increment`](Self::increment), `persistence` is only
+    /// applied when this call creates the key.
+    ///
+    /// Redis evaluates the bounds in Lua, whose numbers are doubles, so
+    /// values are only exact within ±2^53.
+    #[instrument(
+        name = "cache.bounded_incr",
+        skip_all,
+        fields(key = %key, correlation_id = %current_request_id())
+    )]
+    pub async fn bounded_incr(
+        &self,
+        key: &str,
+        by: i64,
+        bounds: RangeInclusive<i64>,
+        mode: BoundMode,
+        persistence: Persistence,
+    ) -> Result<BoundedIncr> {
+        ensure!(
+            bounds.start() <= bounds.end(),
+            "empty bounds {}..={}",
+            bounds.start(),
+            bounds.end()
+        );
+        self.backend
+            .incr_bounded(&self.key(key), by, bounds, mode, persistence.expiry()?)
+            .await
+            .map_err(refused_write)
     }
 
+    #[instrument(
+        name = "cache.decrement",
+        skip_all,
+        fields(key = %key, correlation_id = %current_request_id())
+    )]
     pub async fn decrement(&self, key: &str, by: i64) -> Result<i64> {
-        let mut conn = self.redis.connection();
-        Ok(conn.decr(self.key(key), by).await?)
+        self.backend
+            .incr_by(&self.key(key), -by, None)
+            .await
+            .map_err(refused_write)
+    }
+
+    /// Typed [`set`](Self::set) that only writes if `key` is missing, and
+    /// says whether it did. Pairs with
+    /// [`compare_and_set`](Self::compare_and_set) for the first write.
+    #[instrument(
+        name = "cache.set_if_absent",
+        skip_all,
+        fields(key = %key, correlation_id = %current_request_id())
+    )]
+    pub async fn set_if_absent<T: Serialize>(
+        &self,
+        key: &str,
+        value: &T,
+        persistence: Persistence,
+    ) -> Result<bool> {
+        let payload = self.encode(value)?;
+        self.backend
+            .set_nx(&self.key(key), &payload, persistence.expiry()?)
+            .await
+            .map_err(refused_write)
     }
 
+    #[instrument(
+        name = "cache.set_if_not_exists",
+        skip_all,
+        fields(key = %key, correlation_id = %current_request_id())
+    )]
     pub async fn set_if_not_exists(
         &self,
         key: &str,
         value: &str,
         ttl: Duration,
     ) -> Result<bool> {
-        let mut conn = self.redis.connection();
-        let result: bool = conn
-            .set_nx(self.key(key), value)
-            .await?;
+        self.backend
+            .set_nx(&self.key(key), value, Some(check_ttl(ttl)?))
+            .await
+            .map_err(refused_write)
+    }
 
-        if result {
-            conn.expire(self.key(key), ttl.as_secs() as usize)
-                .await?;
+    /// Atomically sets `key` to `value` with `ttl` unless it exists.
+    /// Returns whether this call set it, plus the value now held, so a
+    /// losing contender learns who won (e.g. the current leader's id).
+    #[instrument(
+        name = "cache.set_if_not_exists_or_get",
+        skip_all,
+        fields(key = %key, correlation_id = %current_request_id())
+    )]
+    pub async fn set_if_not_exists_or_get(
+        &self,
+        key: &str,
+        value: &str,
+        ttl: Duration,
+    ) -> Result<(bool, String)> {
+        self.backend
+            .set_nx_or_get(&self.key(key), value, check_ttl(ttl)?)
+            .await
+            .map_err(refused_write)
+    }
+
+    /// Sets `key` without an expiry unless it already exists. Used for
+    /// uniqueness claims that must outlive any TTL.
+    #[instrument(
+        name = "cache.claim",
+        skip_all,
+        fields(key = %key, correlation_id = %current_request_id())
+    )]
+    pub async fn claim(&self, key: &str, value: &str) -> Result<bool> {
+        self.backend
+            .set_nx(&self.key(key), value, None)
+            .await
+            .map_err(refused_write)
+    }
+
+    /// Adds `member` to the sorted set at `key` with `score`. A
+    /// [`Persistence::Ttl`] (re)sets the set's expiry; `Persist` leaves
+    /// whatever expiry it already has.
+    #[instrument(
+        name = "cache.sorted_add",
+        skip_all,
+        fields(key = %key, correlation_id = %current_request_id())
+    )]
+    pub async fn sorted_add(
+        &self,
+        key: &str,
+        member: &str,
+        score: i64,
+        persistence: Persistence,
+    ) -> Result<()> {
+        let ttl = persistence.expiry()?;
+        let full_key = self.key(key);
+
+        self.backend
+            .zadd(&full_key, member, score)
+            .await
+            .map_err(refused_write)?;
+        if let Some(ttl) = ttl {
+            self.backend.expire(&full_key, ttl).await?;
         }
 
-        Ok(result)
+        Ok(())
+    }
+
+    /// Members of the sorted set at `key`, lowest score first.
+    #[instrument(
+        name = "cache.sorted_members",
+        skip_all,
+        fields(key = %key, correlation_id = %current_request_id())
+    )]
+    pub async fn sorted_members(&self, key: &str) -> Result<Vec<String>> {
+        let members = self.backend.zrange_with_scores(&self.key(key)).await?;
+        Ok(members.into_iter().map(|(member, _)| member).collect())
+    }
+
+    /// Members of the sorted set at `key` with their scores, lowest first.
+    #[instrument(
+        name = "cache.sorted_members_with_scores",
+        skip_all,
+        fields(key = %key, correlation_id = %current_request_id())
+    )]
+    pub async fn sorted_members_with_scores(&self, key: &str) -> Result<Vec<(String, i64)>> {
+        self.backend.zrange_with_scores(&self.key(key)).await
+    }
+
+    #[instrument(
+        name = "cache.sorted_remove",
+        skip_all,
+        fields(key = %key, correlation_id = %current_request_id())
+    )]
+    pub async fn sorted_remove(&self, key: &str, members: &[&str]) -> Result<()> {
+        self.backend.zrem(&self.key(key), members).await
     }
 
+    #[instrument(
+        name = "cache.acquire_lock",
+        skip_all,
+        fields(key = %key, correlation_id = %current_request_id())
+    )]
     pub async fn acquire_lock(
         &self,
         key: &str,
         ttl: Duration,
-    ) -> Result<Option<String>> {
+    ) -> Result<Option<AcquiredLock>> {
         let lock_value = Uuid::new_v4().to_string();
-        let mut conn = self.redis.connection();
+        let full_key = self.lock_key(key);
 
-        let acquired: bool = conn
-            .set_nx(self.key(key), &lock_value)
-            .await?;
+        // Value, TTL and fencing token are set in one atomic call, so a
+        // caller that is cancelled mid-acquire can never leave a lock
+        // behind without a TTL, and tokens are ordered by acquisition.
+        let fencing_token = self
+            .backend
+            .set_nx_and_incr(&full_key, &fence_key(&full_key), &lock_value, check_ttl(ttl)?)
+            .await
+            .map_err(refused_write)?;
 
-        if acquired {
-            conn.expire(self.key(key), ttl.as_secs() as usize)
-                .await?;
-            Ok(Some(lock_value))
-        } else {
-            Ok(None)
+        Ok(fencing_token.map(|fencing_token| AcquiredLock {
+            value: lock_value,
+            fencing_token,
+        }))
+    }
+
+    /// Like [`acquire_lock`](Self::acquire_lock), but on contention also
+    /// reports the current holder and how long its lock has left. Costs
+    /// two extra reads when the lock is taken; meant for debugging
+    /// contention, not for hot paths.
+    #[instrument(
+        name = "cache.acquire_lock_explained",
+        skip_all,
+        fields(key = %key, correlation_id = %current_request_id())
+    )]
+    pub async fn acquire_lock_explained(&self, key: &str, ttl: Duration) -> Result<LockAttempt> {
+        if let Some(lock) = self.acquire_lock(key, ttl).await? {
+            return Ok(LockAttempt::Acquired(lock));
         }
+
+        let full_key = self.lock_key(key);
+        Ok(LockAttempt::Held {
+            holder: self.backend.get(&full_key).await?,
+            ttl: self.backend.ttl(&full_key).await?,
+        })
     }
 
-    pub async fn release_lock(
+    /// Retries [`acquire_lock`](Self::acquire_lock) with jittered
+    /// exponential backoff (starting at `poll_interval`, capped at four
+    /// times it) until the lock is acquired or `max_wait` has elapsed.
+    ///
+    /// Dropping the returned future, e.g. from a losing `tokio::select!`
+    /// branch, stops polling immediately.
+    #[instrument(
+        name = "cache.acquire_lock_wait",
+        skip_all,
+        fields(key = %key, correlation_id = %current_request_id())
+    )]
+    pub async fn acquire_lock_wait(
         &self,
         key: &str,
-        lock_value: &str,
-    ) -> Result<()> {
-        let mut conn = self.redis.connection();
-        let current: Option<String> = conn.get(self.key(key)).await?;
+        ttl: Duration,
+        max_wait: Duration,
+        poll_interval: Duration,
+    ) -> Result<Option<AcquiredLock>> {
+        retry_with_backoff(max_wait, poll_interval, || self.acquire_lock(key, ttl)).await
+    }
 
-        if current.as_deref() == Some(lock_value) {
-            conn.del(self.key(key)).await?;
-        }
+    /// True while `lock_value` still holds the lock. Long-running jobs
+    /// should check this before committing side effects.
+    #[instrument(
+        name = "cache.owns_lock",
+        skip_all,
+        fields(key = %key, correlation_id = %current_request_id())
+    )]
+    pub async fn owns_lock(&self, key: &str, lock_value: &str) -> Result<bool> {
+        let current = self.backend.get(&self.lock_key(key)).await?;
+        Ok(current.as_deref() == Some(lock_value))
+    }
 
-        Ok(())
+    /// Resets the lock's TTL to `ttl` if `lock_value` still holds it.
+    /// Returns false (and changes nothing) if the lock was lost.
+    #[instrument(
+        name = "cache.extend_lock",
+        skip_all,
+        fields(key = %key, correlation_id = %current_request_id())
+    )]
+    pub async fn extend_lock(&self, key: &str, lock_value: &str, ttl: Duration) -> Result<bool> {
+        self.backend
+            .expire_if_equals(&self.lock_key(key), lock_value, check_ttl(ttl)?)
+            .await
+            .map_err(refused_write)
+    }
+
+    /// Releases the lock if `lock_value` still holds it, checking and
+    /// deleting in one step so a lock that expired and was taken by
+    /// someone else is left alone. Returns whether this call released it.
+    #[instrument(
+        name = "cache.release_lock",
+        skip_all,
+        fields(key = %key, correlation_id = %current_request_id())
+    )]
+    pub async fn release_lock(&self, key: &str, lock_value: &str) -> Result<bool> {
+        self.backend
+            .delete_if_equals(&self.lock_key(key), lock_value)
+            .await
+            .map_err(refused_write)
+    }
+
+    /// Takes one of `capacity` permits of the semaphore at `key`, shared
+    /// by every instance. A permit is leased for `lease` and is given to
+    /// someone else once that lapses, so a holder that crashes can't keep
+    /// it; holders that run longer must [`extend_permit`](Self::extend_permit).
+    /// Semaphores live alongside locks, outside any namespace.
+    #[instrument(
+        name = "cache.acquire_permit",
+        skip_all,
+        fields(key = %key, correlation_id = %current_request_id())
+    )]
+    pub async fn acquire_permit(
+        &self,
+        key: &str,
+        capacity: u64,
+        lease: Duration,
+    ) -> Result<Option<SemaphorePermit>> {
+        let holder = Uuid::new_v4().to_string();
+        let acquired = self.lease_permit(key, &holder, capacity, lease).await?;
+        Ok(acquired.then_some(SemaphorePermit { holder }))
+    }
+
+    /// Retries [`acquire_permit`](Self::acquire_permit) with the same
+    /// backoff as [`acquire_lock_wait`](Self::acquire_lock_wait), until a
+    /// permit is free or `max_wait` has elapsed.
+    #[instrument(
+        name = "cache.acquire_permit_wait",
+        skip_all,
+        fields(key = %key, correlation_id = %current_request_id())
+    )]
+    pub async fn acquire_permit_wait(
+        &self,
+        key: &str,
+        capacity: u64,
+        lease: Duration,
+        max_wait: Duration,
+        poll_interval: Duration,
+    ) -> Result<Option<SemaphorePermit>> {
+        retry_with_backoff(max_wait, poll_interval, || {
+            self.acquire_permit(key, capacity, lease)
+        })
+        .await
+    }
+
+    /// Renews the permit's lease to `lease` from now. Returns false if it
+    /// had already lapsed and was given to someone else.
+    #[instrument(
+        name = "cache.extend_permit",
+        skip_all,
+        fields(key = %key, correlation_id = %current_request_id())
+    )]
+    pub async fn extend_permit(
+        &self,
+        key: &str,
+        permit: &SemaphorePermit,
+        capacity: u64,
+        lease: Duration,
+    ) -> Result<bool> {
+        self.lease_permit(key, &permit.holder, capacity, lease).await
+    }
+
+    #[instrument(
+        name = "cache.release_permit",
+        skip_all,
+        fields(key = %key, correlation_id = %current_request_id())
+    )]
+    pub async fn release_permit(&self, key: &str, permit: &SemaphorePermit) -> Result<()> {
+        self.backend
+            .zrem(&self.lock_key(key), &[&permit.holder])
+            .await
+    }
+
+    async fn lease_permit(
+        &self,
+        key: &str,
+        holder: &str,
+        capacity: u64,
+        lease: Duration,
+    ) -> Result<bool> {
+        let now = Utc::now().timestamp_millis();
+        let lease = i64::try_from(check_ttl(lease)?.as_millis()).unwrap_or(i64::MAX);
+        self.backend
+            .zadd_within_capacity(&self.lock_key(key), holder, now + lease, now, capacity)
+            .await
+            .map_err(refused_write)
     }
 
     pub async fn blacklist_token(
@@ -144,15 +1462,484 @@ impl CacheService {
         jti: &str,
         ttl: Duration,
     ) -> Result<()> {
-        self.set(
-            &format!("jwt:blacklist:{jti}"),
+        // Never best-effort: a skipped blacklist entry is a live token.
+        self.store(
+            &keys::token_blacklist(jti),
             &true,
-            Some(ttl),
+            Persistence::Ttl(ttl),
+            false,
         )
         .await
     }
 
     pub async fn is_token_blacklisted(&self, jti: &str) -> Result<bool> {
-        self.exists(&format!("jwt:blacklist:{jti}")).await
+        self.exists(&keys::token_blacklist(jti)).await
+    }
+}
+
+/// Tags a write Redis refused for lack of memory as [`OutOfMemory`], so
+/// callers see why rather than a bare command error, and counts it.
+fn refused_write(err: anyhow::Error) -> anyhow::Error {
+    if !is_out_of_memory(&err) {
+        return err;
+    }
+    metrics::cache_oom_write(false);
+    err.context(OutOfMemory)
+}
+
+/// A value stored by [`CacheService::get_or_set_stale`].
+#[derive(Serialize, Deserialize)]
+struct Revalidating<T> {
+    value: T,
+    #[serde(with = "crate::timestamp::millis")]
+    stale_at: DateTime<Utc>,
+}
+
+impl<T> Revalidating<T> {
+    fn new(value: T, fresh_for: Duration) -> Self {
+        Self {
+            value,
+            stale_at: Utc::now()
+                + chrono::Duration::from_std(fresh_for).unwrap_or(chrono::Duration::MAX),
+        }
+    }
+
+    fn is_stale(&self) -> bool {
+        self.stale_at <= Utc::now()
+    }
+}
+
+/// Calls `attempt` until it yields a value or `max_wait` has elapsed,
+/// sleeping with jittered exponential backoff in between, from
+/// `poll_interval` up to four times it.
+async fn retry_with_backoff<T, F, Fut>(
+    max_wait: Duration,
+    poll_interval: Duration,
+    mut attempt: F,
+) -> Result<Option<T>>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<Option<T>>>,
+{
+    let deadline = Instant::now() + max_wait;
+    let max_backoff = poll_interval * 4;
+    let mut backoff = poll_interval;
+
+    loop {
+        if let Some(value) = attempt().await? {
+            return Ok(Some(value));
+        }
+
+        let now = Instant::now();
+        if now >= deadline {
+            return Ok(None);
+        }
+
+        let jitter = rand::thread_rng().gen_range(0..=backoff.as_millis() as u64 / 2);
+        let delay = (backoff + Duration::from_millis(jitter)).min(deadline - now);
+        tokio::time::sleep(delay).await;
+
+        backoff = (backoff * 2).min(max_backoff);
+    }
+}
+
+/// Longest a cross-process fill may hold other instances back.
+const FILL_LOCK_TTL: Duration = Duration::from_secs(10);
+/// Held by a background refresh. If the loader takes longer, a second
+/// refresh may start; that costs a duplicate load, not a wrong value.
+const REFRESH_LOCK_TTL: Duration = Duration::from_secs(30);
+const FILL_LOCK_POLL: Duration = Duration::from_millis(50);
+
+fn escape_glob(raw: &str) -> String {
+    let mut escaped = String::with_capacity(raw.len());
+    for c in raw.chars() {
+        if matches!(c, '*' | '?' | '[' | ']' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Companion counter for a lock's fencing tokens. It never expires, so
+/// tokens keep growing across lock lifetimes. It shares the lock key's
+/// cluster slot: an existing `{tag}` is kept, otherwise the whole lock key
+/// becomes the tag.
+fn fence_key(lock_key: &str) -> String {
+    let has_tag = lock_key
+        .split_once('{')
+        .and_then(|(_, rest)| rest.split_once('}'))
+        .is_some_and(|(tag, _)| !tag.is_empty());
+
+    if has_tag {
+        format!("{lock_key}:fence")
+    } else {
+        format!("{{{lock_key}}}:fence")
+    }
+}
+
+const HASHED_KEY_HEAD_LEN: usize = 32;
+
+fn bounded_key(key: &str) -> String {
+    let (tag, rest) = match key.strip_prefix('{').and_then(|k| k.split_once('}')) {
+        Some((tag, rest)) => (format!("{{{tag}}}"), rest),
+        None => (String::new(), key),
+    };
+
+    let mut head_len = HASHED_KEY_HEAD_LEN.min(rest.len());
+    while !rest.is_char_boundary(head_len) {
+        head_len -= 1;
+    }
+
+    let hash = blake3::hash(rest.as_bytes()).to_hex();
+    format!("{tag}{}~{hash}", &rest[..head_len])
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::cache::memory::{ManualClock, MemoryBackend};
+
+    fn cache() -> CacheService {
+        CacheService::from_backend(Arc::new(MemoryBackend::new()), "test")
+    }
+
+    #[tokio::test]
+    async fn set_then_get_round_trips() {
+        let cache = cache();
+        cache
+            .set("greeting", &"hello".to_string(), Persistence::Persist)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            cache.get::<String>("greeting").await.unwrap().as_deref(),
+            Some("hello")
+        );
+        assert_eq!(cache.get::<String>("missing").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn ttl_is_reported_and_expires_the_key() {
+        let cache = cache();
+        cache
+            .set("short", &1, Persistence::Ttl(Duration::from_millis(50)))
+            .await
+            .unwrap();
+        cache.set("forever", &1, Persistence::Persist).await.unwrap();
+
+        assert!(matches!(
+            cache.ttl("short").await.unwrap(),
+            KeyTtl::Expires(ttl) if ttl <= Duration::from_millis(50)
+        ));
+        assert_eq!(cache.ttl("forever").await.unwrap(), KeyTtl::Persistent);
+        assert_eq!(cache.ttl("missing").await.unwrap(), KeyTtl::Missing);
+
+        tokio::time::sleep(Duration::from_millis(80)).await;
+        assert_eq!(cache.get::<i64>("short").await.unwrap(), None);
+        assert_eq!(cache.ttl("short").await.unwrap(), KeyTtl::Missing);
+    }
+
+    #[tokio::test]
+    async fn ttl_beyond_the_maximum_is_refused() {
+        let result = cache()
+            .set("key", &1, Persistence::Ttl(MAX_TTL + Duration::from_secs(1)))
+            .await;
+
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn lock_is_exclusive_until_released_by_its_holder() {
+        let cache = cache();
+        let ttl = Duration::from_secs(10);
+        let lock = cache.acquire_lock("job", ttl).await.unwrap().unwrap();
+
+        assert!(cache.acquire_lock("job", ttl).await.unwrap().is_none());
+        assert!(cache.owns_lock("job", &lock.value).await.unwrap());
+        assert!(!cache.release_lock("job", "someone-else").await.unwrap());
+        assert!(cache.release_lock("job", &lock.value).await.unwrap());
+
+        let next = cache.acquire_lock("job", ttl).await.unwrap().unwrap();
+        assert!(next.fencing_token > lock.fencing_token);
+    }
+
+    #[tokio::test]
+    async fn lock_lapses_after_its_ttl() {
+        let cache = cache();
+        let lock = cache
+            .acquire_lock("job", Duration::from_millis(50))
+            .await
+            .unwrap()
+            .unwrap();
+
+        tokio::time::sleep(Duration::from_millis(80)).await;
+
+        assert!(!cache.owns_lock("job", &lock.value).await.unwrap());
+        assert!(!cache
+            .extend_lock("job", &lock.value, Duration::from_secs(10))
+            .await
+            .unwrap());
+        assert!(cache
+            .acquire_lock("job", Duration::from_secs(10))
+            .await
+            .unwrap()
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn stale_holder_cannot_release_a_reacquired_lock() {
+        let cache = cache();
+        let stale = cache
+            .acquire_lock("job", Duration::from_millis(50))
+            .await
+            .unwrap()
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(80)).await;
+        let current = cache
+            .acquire_lock("job", Duration::from_secs(10))
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert!(!cache.release_lock("job", &stale.value).await.unwrap());
+        assert!(cache.owns_lock("job", &current.value).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn compare_and_set_swaps_only_the_expected_value() {
+        let cache = cache();
+        cache.set("counter", &1, Persistence::Persist).await.unwrap();
+
+        assert!(!cache
+            .compare_and_set("counter", &2, &3, Persistence::Persist)
+            .await
+            .unwrap());
+        assert!(cache
+            .compare_and_set("counter", &1, &2, Persistence::Persist)
+            .await
+            .unwrap());
+        assert_eq!(cache.get::<i64>("counter").await.unwrap(), Some(2));
+
+        assert!(!cache
+            .compare_and_set("missing", &1, &2, Persistence::Persist)
+            .await
+            .unwrap());
+    }
+
+    #[tokio::test]
+    async fn compare_raw_and_set_matches_the_stored_string() {
+        let cache = cache();
+        // Not how serde_json would write it, as a record from an older
+        // version might be.
+        cache
+            .backend
+            .set(&cache.key("record"), r#"{ "a": 1 }"#, None)
+            .await
+            .unwrap();
+
+        let stored = cache.get_raw("record").await.unwrap().unwrap();
+        assert!(cache
+            .compare_raw_and_set("record", &stored, &2, Persistence::Persist)
+            .await
+            .unwrap());
+        assert!(!cache
+            .compare_raw_and_set("record", &stored, &3, Persistence::Persist)
+            .await
+            .unwrap());
+        assert_eq!(cache.get::<i64>("record").await.unwrap(), Some(2));
+    }
+
+    #[tokio::test]
+    async fn get_or_set_caches_the_loaded_value() {
+        let cache = cache();
+        let loads = AtomicUsize::new(0);
+        let load = || async {
+            loads.fetch_add(1, Ordering::SeqCst);
+            Ok(42)
+        };
+
+        let ttl = Duration::from_secs(10);
+        assert_eq!(cache.get_or_set("answer", ttl, load).await.unwrap(), 42);
+        assert_eq!(cache.get_or_set("answer", ttl, load).await.unwrap(), 42);
+        assert_eq!(loads.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn concurrent_get_or_set_misses_share_one_load() {
+        let cache = cache();
+        let loads = AtomicUsize::new(0);
+        let load = || async {
+            loads.fetch_add(1, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            Ok(7)
+        };
+
+        let ttl = Duration::from_secs(10);
+        let results = futures_util::future::join_all(
+            (0..8).map(|_| cache.get_or_set("shared", ttl, load)),
+        )
+        .await;
+
+        assert!(results.into_iter().all(|result| result.unwrap() == 7));
+        assert_eq!(loads.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn get_or_set_loader_error_is_not_cached() {
+        let cache = cache();
+        let ttl = Duration::from_secs(10);
+
+        let failed = cache
+            .get_or_set::<i64, _, _>("flaky", ttl, || async { Err(anyhow!("down")) })
+            .await;
+        assert!(failed.is_err());
+        assert_eq!(cache.get::<i64>("flaky").await.unwrap(), None);
+
+        let loaded = cache.get_or_set("flaky", ttl, || async { Ok(1) }).await;
+        assert_eq!(loaded.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn get_or_set_with_takes_an_optional_ttl() {
+        let cache = cache();
+        let loads = AtomicUsize::new(0);
+        let load = || async {
+            loads.fetch_add(1, Ordering::SeqCst);
+            Ok(42)
+        };
+
+        let ttl = Some(Duration::from_secs(10));
+        assert_eq!(cache.get_or_set_with("expiring", ttl, load).await.unwrap(), 42);
+        assert_eq!(cache.get_or_set_with("expiring", ttl, load).await.unwrap(), 42);
+        assert_eq!(loads.load(Ordering::SeqCst), 1);
+        assert!(matches!(cache.ttl("expiring").await.unwrap(), KeyTtl::Expires(_)));
+
+        assert_eq!(cache.get_or_set_with("kept", None, load).await.unwrap(), 42);
+        assert_eq!(cache.ttl("kept").await.unwrap(), KeyTtl::Persistent);
+    }
+
+    #[tokio::test]
+    async fn get_or_set_with_writes_nothing_when_the_loader_fails() {
+        let cache = cache();
+
+        let failed = cache
+            .get_or_set_with::<i64, _, _>("flaky", None, || async { Err(anyhow!("down")) })
+            .await;
+
+        assert!(failed.is_err());
+        assert_eq!(cache.ttl("flaky").await.unwrap(), KeyTtl::Missing);
+    }
+
+    #[tokio::test]
+    async fn increment_sets_the_window_once_when_creating_the_counter() {
+        let clock = ManualClock::new();
+        let cache =
+            CacheService::from_backend(Arc::new(MemoryBackend::with_clock(clock.clone())), "test");
+        let window = Persistence::Ttl(Duration::from_secs(60));
+
+        assert_eq!(cache.increment("hits", 1, window).await.unwrap(), 1);
+        assert_eq!(
+            cache.ttl("hits").await.unwrap(),
+            KeyTtl::Expires(Duration::from_secs(60))
+        );
+
+        clock.advance(Duration::from_secs(20));
+        let counts = futures_util::future::join_all(
+            (0..5).map(|_| cache.increment("hits", 1, window)),
+        )
+        .await;
+        let mut counts: Vec<i64> = counts.into_iter().map(Result::unwrap).collect();
+        counts.sort_unstable();
+        assert_eq!(counts, [2, 3, 4, 5, 6]);
+        assert_eq!(
+            cache.ttl("hits").await.unwrap(),
+            KeyTtl::Expires(Duration::from_secs(40))
+        );
+
+        clock.advance(Duration::from_secs(40));
+        assert_eq!(cache.ttl("hits").await.unwrap(), KeyTtl::Missing);
+        assert_eq!(cache.increment("hits", 1, window).await.unwrap(), 1);
+        assert_eq!(
+            cache.ttl("hits").await.unwrap(),
+            KeyTtl::Expires(Duration::from_secs(60))
+        );
+    }
+
+    #[tokio::test]
+    async fn counter_back_at_zero_keeps_its_window() {
+        let clock = ManualClock::new();
+        let cache =
+            CacheService::from_backend(Arc::new(MemoryBackend::with_clock(clock.clone())), "test");
+        let window = Persistence::Ttl(Duration::from_secs(60));
+
+        cache.increment("slots", 1, window).await.unwrap();
+        clock.advance(Duration::from_secs(30));
+        assert_eq!(cache.increment("slots", -1, window).await.unwrap(), 0);
+        assert_eq!(cache.increment("slots", 1, window).await.unwrap(), 1);
+
+        assert_eq!(
+            cache.ttl("slots").await.unwrap(),
+            KeyTtl::Expires(Duration::from_secs(30))
+        );
+    }
+
+    async fn set_all(cache: &CacheService, keys: &[&str]) {
+        for key in keys {
+            cache.set(key, &1, Persistence::Persist).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn delete_prefix_removes_only_keys_under_the_prefix() {
+        let cache = cache();
+        set_all(
+            &cache,
+            &["rollup:dept:7:a", "rollup:dept:7:b", "rollup:dept:70:a", "other:dept:7:a"],
+        )
+        .await;
+
+        assert_eq!(cache.delete_prefix("rollup:dept:7:").await.unwrap(), 2);
+
+        assert!(!cache.exists("rollup:dept:7:a").await.unwrap());
+        assert!(cache.exists("rollup:dept:70:a").await.unwrap());
+        assert!(cache.exists("other:dept:7:a").await.unwrap());
+        assert_eq!(cache.delete_prefix("rollup:dept:7:").await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn delete_prefix_takes_glob_characters_literally() {
+        let cache = cache();
+        set_all(
+            &cache,
+            &["id:*:a", "id:x:a", "id:?:a", "id:[ab]:a", "id:a:a", "id:\\:a"],
+        )
+        .await;
+
+        for (prefix, survivor) in [
+            ("id:*:", "id:x:a"),
+            ("id:?:", "id:a:a"),
+            ("id:[ab]:", "id:a:a"),
+            ("id:\\:", "id:x:a"),
+        ] {
+            assert_eq!(cache.delete_prefix(prefix).await.unwrap(), 1, "{prefix}");
+            assert!(cache.exists(survivor).await.unwrap(), "{prefix} took {survivor}");
+        }
+    }
+
+    #[tokio::test]
+    async fn delete_prefix_stays_inside_the_tenant() {
+        let cache = cache();
+        let acme = cache.for_tenant("acme");
+        let globex = cache.for_tenant("globex");
+        set_all(&acme, &["report:1"]).await;
+        set_all(&globex, &["report:1"]).await;
+
+        assert_eq!(acme.delete_prefix("report:").await.unwrap(), 1);
+
+        assert!(!acme.exists("report:1").await.unwrap());
+        assert!(globex.exists("report:1").await.unwrap());
     }
 }
@@ -0,0 +1,89 @@
+use anyhow::Result;
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::{Map, Value};
+use std::fmt;
+
+/// Serializes `value` as JSON with object keys sorted at every level, so
+/// equal values always produce identical bytes regardless of field
+/// declaration order or map iteration order. Suitable for ETags and for
+/// keys derived from request bodies.
+pub fn to_canonical_json<T: Serialize + ?Sized>(value: &T) -> Result<String> {
+    let value = canonicalize(serde_json::to_value(value)?);
+    Ok(serde_json::to_string(&value)?)
+}
+
+fn canonicalize(value: Value) -> Value {
+    match value {
+        Value::Object(map) => {
+            let mut entries: Vec<(String, Value)> = map.into_iter().collect();
+            entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+            Value::Object(
+                entries
+                    .into_iter()
+                    .map(|(key, value)| (key, canonicalize(value)))
+                    .collect::<Map<_, _>>(),
+            )
+        }
+        Value::Array(items) => Value::Array(items.into_iter().map(canonicalize).collect()),
+        other => other,
+    }
+}
+
+/// A stored value that doesn't deserialize into the type asked for.
+/// Returned (inside `anyhow::Error`) by reads on
+/// [`CacheService`](super::cache_service::CacheService), so callers can
+/// downcast to it.
+#[derive(Debug)]
+pub struct DecodeError {
+    /// Full key, prefix included, as it appears in Redis.
+    pub key: String,
+    pub type_name: &'static str,
+    /// Start of the raw value. Only captured when the service was built
+    /// with decode snippets, since values may hold personal data.
+    pub snippet: Option<String>,
+    pub source: serde_json::Error,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "cannot decode {} as {}", self.key, self.type_name)?;
+        if let Some(snippet) = &self.snippet {
+            write!(f, " (value starts {snippet:?})")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for DecodeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+/// Deserializes `raw`, read from `key`, keeping up to `snippet_len` bytes
+/// of it in the error when given.
+pub(crate) fn decode<T: DeserializeOwned>(
+    key: &str,
+    raw: &str,
+    snippet_len: Option<usize>,
+) -> Result<T, DecodeError> {
+    serde_json::from_str(raw).map_err(|source| DecodeError {
+        key: key.to_string(),
+        type_name: std::any::type_name::<T>(),
+        snippet: snippet_len.map(|len| snippet(raw, len)),
+        source,
+    })
+}
+
+fn snippet(raw: &str, max_len: usize) -> String {
+    if raw.len() <= max_len {
+        return raw.to_string();
+    }
+
+    let mut end = max_len;
+    while !raw.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}...", &raw[..end])
+}
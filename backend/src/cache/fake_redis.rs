@@ -0,0 +1,218 @@
+//! An in-process stand-in for a Redis server, for tests of code that talks
+//! to Redis directly rather than through a [`CacheBackend`].
+//!
+//! It speaks RESP over a real socket, so the code under test runs
+//! unchanged on a [`RedisClient`], but understands only the commands that
+//! code sends: `PING`, the `CLIENT` handshake, and `XADD` and `XREVRANGE`
+//! on streams. Anything else is answered with an error.
+//!
+//! [`CacheBackend`]: crate::cache::backend::CacheBackend
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+use crate::cache::redis_client::RedisClient;
+
+type EntryId = (u64, u64);
+type Entry = (EntryId, Vec<Vec<u8>>);
+type Streams = Arc<Mutex<HashMap<Vec<u8>, Vec<Entry>>>>;
+
+/// A client connected to a fresh, empty fake server.
+pub async fn client() -> RedisClient {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let streams = Streams::default();
+
+    tokio::spawn(async move {
+        while let Ok((socket, _)) = listener.accept().await {
+            tokio::spawn(serve(socket, streams.clone()));
+        }
+    });
+
+    RedisClient::new(format!("redis://{addr}")).await.unwrap()
+}
+
+async fn serve(mut socket: TcpStream, streams: Streams) {
+    let mut buf = Vec::new();
+    let mut chunk = [0; 4096];
+
+    while let Ok(len @ 1..) = socket.read(&mut chunk).await {
+        buf.extend_from_slice(&chunk[..len]);
+
+        let mut replies = Vec::new();
+        while let Some((args, used)) = parse_command(&buf) {
+            buf.drain(..used);
+            execute(&args, &streams, &mut replies);
+        }
+        if socket.write_all(&replies).await.is_err() {
+            return;
+        }
+    }
+}
+
+/// One complete `*<n>` array of bulk strings from the front of `buf`, and
+/// how many bytes it took; `None` until all of it has arrived.
+fn parse_command(buf: &[u8]) -> Option<(Vec<Vec<u8>>, usize)> {
+    let (count, mut at) = parse_header(buf, b'*')?;
+    let mut args = Vec::with_capacity(count);
+
+    for _ in 0..count {
+        let (len, start) = parse_header(&buf[at..], b'$')?;
+        let start = at + start;
+        let end = start + len;
+        if buf.len() < end + 2 {
+            return None;
+        }
+        args.push(buf[start..end].to_vec());
+        at = end + 2;
+    }
+    Some((args, at))
+}
+
+/// `<marker><number>\r\n`: the number and the offset just past it.
+fn parse_header(buf: &[u8], marker: u8) -> Option<(usize, usize)> {
+    if buf.first() != Some(&marker) {
+        return None;
+    }
+    let line_end = buf.windows(2).position(|w| w == b"\r\n")?;
+    let number = std::str::from_utf8(&buf[1..line_end]).ok()?.parse().ok()?;
+    Some((number, line_end + 2))
+}
+
+fn execute(args: &[Vec<u8>], streams: &Streams, out: &mut Vec<u8>) {
+    let command = args
+        .first()
+        .map(|name| name.to_ascii_uppercase())
+        .unwrap_or_default();
+
+    match (command.as_slice(), args) {
+        (b"PING", _) => out.extend_from_slice(b"+PONG\r\n"),
+        (b"CLIENT", _) => out.extend_from_slice(b"+OK\r\n"),
+        (b"XADD", [_, key, rest @ ..]) => {
+            // Skip MAXLEN/approximation options up to the `*` id; the
+            // fake never trims.
+            let Some(star) = rest.iter().position(|arg| arg == b"*") else {
+                return error(out, "only auto-generated ids are supported");
+            };
+            let mut streams = streams.lock().unwrap();
+            let entries = streams.entry(key.clone()).or_default();
+            let id = next_id(entries.last().map(|(id, _)| *id));
+            entries.push((id, rest[star + 1..].to_vec()));
+            bulk(out, format_id(id).as_bytes());
+        }
+        (b"XREVRANGE", [_, key, end, start, rest @ ..]) => {
+            let (Some(end), Some(start)) = (parse_bound(end, u64::MAX), parse_bound(start, 0))
+            else {
+                return error(out, "invalid stream id");
+            };
+            let count = match rest {
+                [] => usize::MAX,
+                [option, count] if option.eq_ignore_ascii_case(b"COUNT") => {
+                    match std::str::from_utf8(count).ok().and_then(|c| c.parse().ok()) {
+                        Some(count) => count,
+                        None => return error(out, "invalid COUNT"),
+                    }
+                }
+                _ => return error(out, "syntax error"),
+            };
+
+            let streams = streams.lock().unwrap();
+            let matched: Vec<&Entry> = streams
+                .get(key)
+                .into_iter()
+                .flatten()
+                .rev()
+                .filter(|(id, _)| end.admits_above(*id) && start.admits_below(*id))
+                .take(count)
+                .collect();
+
+            out.extend_from_slice(format!("*{}\r\n", matched.len()).as_bytes());
+            for (id, fields) in matched {
+                out.extend_from_slice(b"*2\r\n");
+                bulk(out, format_id(*id).as_bytes());
+                out.extend_from_slice(format!("*{}\r\n", fields.len()).as_bytes());
+                for field in fields {
+                    bulk(out, field);
+                }
+            }
+        }
+        _ => error(out, "unknown command"),
+    }
+}
+
+/// A range bound: `-`, `+`, `<ms>` or `<ms>-<seq>`, exclusive with a `(`
+/// in front. A bare `<ms>` means `<ms>-<default_seq>`.
+struct Bound {
+    id: EntryId,
+    exclusive: bool,
+}
+
+impl Bound {
+    /// Whether `id` is on the low side of this bound, used as an end.
+    fn admits_above(&self, id: EntryId) -> bool {
+        if self.exclusive {
+            id < self.id
+        } else {
+            id <= self.id
+        }
+    }
+
+    /// Whether `id` is on the high side of this bound, used as a start.
+    fn admits_below(&self, id: EntryId) -> bool {
+        if self.exclusive {
+            id > self.id
+        } else {
+            id >= self.id
+        }
+    }
+}
+
+fn parse_bound(raw: &[u8], default_seq: u64) -> Option<Bound> {
+    let raw = std::str::from_utf8(raw).ok()?;
+    let (exclusive, raw) = match raw.strip_prefix('(') {
+        Some(rest) => (true, rest),
+        None => (false, raw),
+    };
+    let id = match raw {
+        "-" => (0, 0),
+        "+" => (u64::MAX, u64::MAX),
+        _ => match raw.split_once('-') {
+            Some((ms, seq)) => (ms.parse().ok()?, seq.parse().ok()?),
+            None => (raw.parse().ok()?, default_seq),
+        },
+    };
+    Some(Bound { id, exclusive })
+}
+
+/// The current time as an id, kept above `last` as Redis does.
+fn next_id(last: Option<EntryId>) -> EntryId {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64;
+    match last {
+        Some((ms, seq)) if ms >= now => (ms, seq + 1),
+        _ => (now, 0),
+    }
+}
+
+fn format_id((ms, seq): EntryId) -> String {
+    format!("{ms}-{seq}")
+}
+
+fn bulk(out: &mut Vec<u8>, value: &[u8]) {
+    out.extend_from_slice(format!("${}\r\n", value.len()).as_bytes());
+    out.extend_from_slice(value);
+    out.extend_from_slice(b"\r\n");
+}
+
+fn error(out: &mut Vec<u8>, message: &str) {
+    out.extend_from_slice(format!("-ERR {message}\r\n").as_bytes());
+}
@@ -0,0 +1,105 @@
+//! Periodic counts of the keys the auth machinery leaves behind.
+//!
+//! Token blacklist entries, sessions and locks all expire on their own,
+//! but churn can still pile them up faster than they expire. The sampler
+//! scans for each group every so often and reports how many keys exist
+//! and roughly how much memory they take, as the `cache_keys` and
+//! `cache_key_bytes` gauges, so growth shows up well before Redis runs
+//! out of memory. Nothing is deleted.
+
+use anyhow::Result;
+use std::time::Duration;
+
+use crate::{
+    cache::{cache_service::CacheService, keys},
+    metrics,
+    shutdown::ShutdownSignal,
+};
+
+/// Keys per group whose memory usage is looked up.
+const FOOTPRINT_SAMPLE: usize = 50;
+
+struct KeyGroup {
+    name: &'static str,
+    cache: CacheService,
+    pattern: &'static str,
+}
+
+pub struct KeySampler {
+    /// Where the sampling lock is taken.
+    cache: CacheService,
+    groups: Vec<KeyGroup>,
+}
+
+impl KeySampler {
+    pub fn new(cache: CacheService) -> Self {
+        Self {
+            cache,
+            groups: Vec::new(),
+        }
+    }
+
+    /// Blacklisted tokens and sessions in `auth`, and the locks kept on
+    /// the same Redis.
+    pub fn auth_groups(cache: CacheService, auth: &CacheService) -> Self {
+        Self::new(cache)
+            .with_group(
+                "token_blacklist",
+                auth.clone(),
+                keys::TOKEN_BLACKLIST_PATTERN,
+            )
+            .with_group("sessions", auth.clone(), keys::SESSION_PATTERN)
+            .with_group("locks", auth.locks(), "*")
+    }
+
+    /// Reports keys matching `pattern` in `cache` as `name`.
+    pub fn with_group(
+        mut self,
+        name: &'static str,
+        cache: CacheService,
+        pattern: &'static str,
+    ) -> Self {
+        self.groups.push(KeyGroup {
+            name,
+            cache,
+            pattern,
+        });
+        self
+    }
+
+    /// Samples every `interval` until shutdown. One instance at a time
+    /// does the scanning.
+    pub async fn run_sampler(self, interval: Duration, mut shutdown: ShutdownSignal) -> Result<()> {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            tokio::select! {
+                _ = ticker.tick() => {}
+                () = shutdown.cancelled() => return Ok(()),
+            }
+            if let Err(err) = self.sample(interval).await {
+                tracing::warn!(error = ?err, "cache key sampling failed");
+            }
+        }
+    }
+
+    async fn sample(&self, interval: Duration) -> Result<()> {
+        let lock = keys::key_sample_lock();
+        if self
+            .cache
+            .acquire_lock(&lock, interval / 2)
+            .await?
+            .is_none()
+        {
+            return Ok(());
+        }
+
+        for group in &self.groups {
+            let footprint = group
+                .cache
+                .key_footprint(group.pattern, FOOTPRINT_SAMPLE)
+                .await?;
+            metrics::cache_key_footprint(group.name, footprint.count, footprint.approx_bytes);
+        }
+        Ok(())
+    }
+}
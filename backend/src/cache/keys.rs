@@ -0,0 +1,239 @@
+//! Every cache key the application uses, built in one place.
+//!
+//! Code that writes a key and code that reads it both call the same
+//! function here, so the two can't drift apart into a silent cache miss.
+//! A [`CacheKey`] dereferences to `&str`, so it is passed to
+//! [`CacheService`] like any other key, and is relative to the service's
+//! prefix and namespace like any other key.
+//!
+//! Add new key formats here rather than with `format!` at the call site.
+//!
+//! [`CacheService`]: crate::cache::cache_service::CacheService
+
+use std::{fmt, net::IpAddr, ops::Deref};
+
+use crate::auth::ids::{SessionId, UserId};
+
+/// A relative cache key built by one of the functions in this module.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CacheKey(String);
+
+impl CacheKey {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Deref for CacheKey {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for CacheKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+fn key(raw: String) -> CacheKey {
+    CacheKey(raw)
+}
+
+// Sessions and tokens. Session ids may come straight from an index, as
+// strings, hence the `Display` bounds.
+
+pub fn session(session_id: impl fmt::Display) -> CacheKey {
+    key(format!("session:{session_id}"))
+}
+
+/// The previous refresh secret during its reuse grace window.
+pub fn session_grace(session_id: SessionId) -> CacheKey {
+    key(format!("session:grace:{session_id}"))
+}
+
+pub fn session_last_seen(session_id: SessionId) -> CacheKey {
+    key(format!("session:seen:{session_id}"))
+}
+
+pub fn session_touch_throttle(session_id: SessionId) -> CacheKey {
+    key(format!("session:seen:throttle:{session_id}"))
+}
+
+/// Sorted set of a user's session ids.
+pub fn user_sessions(user_id: impl fmt::Display) -> CacheKey {
+    key(format!("user:sessions:{user_id}"))
+}
+
+/// Sorted set of users with sessions, by when their last one opened.
+pub fn session_users() -> CacheKey {
+    key("sessions:users".to_string())
+}
+
+pub fn session_prune_lock() -> CacheKey {
+    key("sessions:prune".to_string())
+}
+
+/// Watermark below which a user's access tokens are rejected.
+pub fn tokens_invalidated(user_id: UserId) -> CacheKey {
+    key(format!("jwt:invalidated:{user_id}"))
+}
+
+pub fn token_blacklist(jti: &str) -> CacheKey {
+    key(format!("jwt:blacklist:{jti}"))
+}
+
+/// Matches every [`token_blacklist`] key.
+pub const TOKEN_BLACKLIST_PATTERN: &str = "jwt:blacklist:*";
+/// Matches [`session`] keys and those kept alongside them.
+pub const SESSION_PATTERN: &str = "session:*";
+
+// Authorization.
+
+/// A user's role as last changed by an admin, for refreshes to pick up.
+pub fn user_role(user_id: UserId) -> CacheKey {
+    key(format!("user:role:{user_id}"))
+}
+
+pub fn elevation(user_id: UserId) -> CacheKey {
+    key(format!("elevation:{user_id}"))
+}
+
+/// Users with a grant, scored by when it expires.
+pub fn elevations_expiring() -> CacheKey {
+    key("elevations:expiring".to_string())
+}
+
+pub fn elevation_sweep_lock() -> CacheKey {
+    key("elevations:sweep".to_string())
+}
+
+/// A support impersonation, by the `jti` of its token.
+pub fn impersonation(id: &str) -> CacheKey {
+    key(format!("impersonation:{id}"))
+}
+
+/// A pending deactivation, until it is carried out or cancelled.
+pub fn deactivation(user_id: UserId) -> CacheKey {
+    key(format!("account:deactivation:{user_id}"))
+}
+
+/// Users with a pending deactivation, scored by when it is due.
+pub fn deactivations_due() -> CacheKey {
+    key("accounts:deactivations".to_string())
+}
+
+pub fn deactivation_sweep_lock() -> CacheKey {
+    key("accounts:deactivations:sweep".to_string())
+}
+
+pub fn account_deactivated(user_id: UserId) -> CacheKey {
+    key(format!("account:deactivated:{user_id}"))
+}
+
+pub fn org_chain(employee_id: UserId) -> CacheKey {
+    key(format!("org:chain:{employee_id}"))
+}
+
+/// Matches every [`org_chain`] key.
+pub const ORG_CHAIN_PATTERN: &str = "org:chain:*";
+
+/// A session's recent second-factor verification; lapses on its own.
+pub fn step_up(binding: impl fmt::Display) -> CacheKey {
+    key(format!("stepup:{binding}"))
+}
+
+/// Marks a denial as audited for the current window.
+pub fn denial_audited(user_id: UserId, resource: &str) -> CacheKey {
+    key(format!("authz:denied:{user_id}:{resource}"))
+}
+
+// Login throttling, per account and per client address. Accounts are
+// compared case-insensitively, like the email addresses they usually are.
+
+pub fn login_failures_for_account(account: &str) -> CacheKey {
+    key(format!("login:failures:{}", login_account(account)))
+}
+
+pub fn login_blocked_for_account(account: &str) -> CacheKey {
+    key(format!("login:blocked:{}", login_account(account)))
+}
+
+pub fn login_failures_for_ip(ip: IpAddr) -> CacheKey {
+    key(format!("login:failures:ip:{ip}"))
+}
+
+pub fn login_blocked_for_ip(ip: IpAddr) -> CacheKey {
+    key(format!("login:blocked:ip:{ip}"))
+}
+
+fn login_account(account: &str) -> String {
+    format!("account:{}", account.trim().to_lowercase())
+}
+
+// Users and notifications.
+
+/// Claims a normalized email address for one account.
+pub fn user_email(normalized: &str) -> CacheKey {
+    key(format!("user:email:{normalized}"))
+}
+
+pub fn notification_preferences(user_id: UserId) -> CacheKey {
+    key(format!("notify:prefs:{user_id}"))
+}
+
+pub fn notification_inbox(user_id: UserId) -> CacheKey {
+    key(format!("notify:inbox:{user_id}"))
+}
+
+/// Keyed by hash so the ticket itself never shows up in a key listing.
+pub fn ws_ticket(ticket: &str) -> CacheKey {
+    key(format!(
+        "ws:ticket:{}",
+        blake3::hash(ticket.as_bytes()).to_hex()
+    ))
+}
+
+// Webhook circuit breakers, keyed by a hash of the endpoint URL.
+
+pub fn webhook_failures(url: &str) -> CacheKey {
+    key(format!("webhook:failures:{}", webhook_endpoint(url)))
+}
+
+pub fn webhook_open(url: &str) -> CacheKey {
+    key(format!("webhook:open:{}", webhook_endpoint(url)))
+}
+
+pub fn webhook_probe(url: &str) -> CacheKey {
+    key(format!("webhook:probe:{}", webhook_endpoint(url)))
+}
+
+fn webhook_endpoint(url: &str) -> blake3::Hash {
+    blake3::hash(url.as_bytes())
+}
+
+// Operations.
+
+/// Semaphore capping concurrent report generation cluster-wide.
+pub fn report_slots() -> CacheKey {
+    key("reports:slots".to_string())
+}
+
+pub fn key_sample_lock() -> CacheKey {
+    key("cache:key-sample".to_string())
+}
+
+pub fn maintenance_flag() -> CacheKey {
+    key("maintenance".to_string())
+}
+
+pub fn usage_count(counter: &str) -> CacheKey {
+    key(format!("usage:count:{counter}"))
+}
+
+/// Sorted set of counters with unflushed counts.
+pub fn usage_pending() -> CacheKey {
+    key("usage:pending".to_string())
+}
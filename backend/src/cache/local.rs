@@ -0,0 +1,130 @@
+//! In-process cache in front of Redis for hot, rarely changing keys.
+//!
+//! Feature flags and tenant settings are read on nearly every request and
+//! change a few times a day, so a Redis round trip for each read is
+//! wasted. A [`CacheService`] given a [`LocalCache`] keeps the raw values
+//! of opted-in keys in memory for a short TTL: reads go to memory, then
+//! Redis, then the loader.
+//!
+//! Writes through the service drop the entry here and publish its key, so
+//! other instances drop their copies too once
+//! [`run_invalidation_listener`](LocalCache::run_invalidation_listener)
+//! picks the message up. Pub/sub is fire-and-forget: an instance that
+//! misses a message serves the old value until the TTL runs out, which is
+//! what bounds how stale a read can be.
+//!
+//! Only keys written with `set`, `compare_and_set`, `delete` and `take`
+//! are invalidated. Don't opt in keys changed by counters, sorted sets or
+//! scripts.
+//!
+//! [`CacheService`]: crate::cache::cache_service::CacheService
+
+use anyhow::Result;
+use futures_util::StreamExt;
+use moka::sync::Cache;
+use std::{sync::Arc, time::Duration};
+
+use crate::{cache::redis_client::RedisClient, metrics, shutdown::ShutdownSignal};
+
+/// Published instead of a key when every entry should go, e.g. after a
+/// pattern delete whose keys aren't known one by one.
+pub(crate) const INVALIDATE_ALL: &str = "*";
+/// Wait before resubscribing after the pub/sub connection drops.
+const RESUBSCRIBE_DELAY: Duration = Duration::from_secs(1);
+
+#[derive(Clone)]
+pub struct LocalCache {
+    entries: Cache<String, Arc<str>>,
+    /// Relative key prefixes that opt a key in.
+    prefixes: Arc<Vec<String>>,
+    channel: String,
+}
+
+impl LocalCache {
+    /// Holds up to `capacity` values, each for at most `ttl`. Invalidations
+    /// are published on a channel under `prefix`, so every instance
+    /// sharing the prefix hears them.
+    pub fn new(prefix: &str, capacity: u64, ttl: Duration) -> Self {
+        Self {
+            entries: Cache::builder()
+                .max_capacity(capacity)
+                .time_to_live(ttl)
+                .build(),
+            prefixes: Arc::default(),
+            channel: format!("{prefix}:cache:local-invalidate"),
+        }
+    }
+
+    /// Opts in keys starting with `prefix`, e.g. `flags:`. Matched against
+    /// the key as the caller passes it, before any tenant scoping.
+    pub fn with_keys(mut self, prefix: impl Into<String>) -> Self {
+        Arc::make_mut(&mut self.prefixes).push(prefix.into());
+        self
+    }
+
+    pub(crate) fn covers(&self, key: &str) -> bool {
+        self.prefixes.iter().any(|prefix| key.starts_with(prefix))
+    }
+
+    pub(crate) fn channel(&self) -> &str {
+        &self.channel
+    }
+
+    pub(crate) fn get(&self, full_key: &str) -> Option<Arc<str>> {
+        let value = self.entries.get(full_key);
+        metrics::cache_local_lookup(value.is_some());
+        value
+    }
+
+    pub(crate) fn insert(&self, full_key: String, raw: &str) {
+        self.entries.insert(full_key, Arc::from(raw));
+    }
+
+    pub(crate) fn invalidate(&self, full_key: &str) {
+        self.entries.invalidate(full_key);
+    }
+
+    pub(crate) fn invalidate_all(&self) {
+        self.entries.invalidate_all();
+    }
+
+    /// Applies invalidations published by other instances until shutdown.
+    /// Everything is dropped whenever the subscription is (re)established,
+    /// since messages sent while it was down are lost.
+    pub async fn run_invalidation_listener(
+        self,
+        redis: RedisClient,
+        mut shutdown: ShutdownSignal,
+    ) -> Result<()> {
+        loop {
+            let listened = async {
+                let mut pubsub = redis.pubsub().await?;
+                pubsub.subscribe(&self.channel).await?;
+                self.entries.invalidate_all();
+
+                let mut messages = pubsub.into_on_message();
+                while let Some(message) = messages.next().await {
+                    match message.get_payload::<String>() {
+                        Ok(key) if key == INVALIDATE_ALL => self.invalidate_all(),
+                        Ok(key) => self.invalidate(&key),
+                        Err(_) => {}
+                    }
+                }
+                anyhow::Ok(())
+            };
+
+            tokio::select! {
+                result = listened => {
+                    if let Err(err) = result {
+                        tracing::warn!(error = ?err, "local cache invalidation subscription failed");
+                    }
+                }
+                () = shutdown.cancelled() => return Ok(()),
+            }
+            tokio::select! {
+                _ = tokio::time::sleep(RESUBSCRIBE_DELAY) => {}
+                () = shutdown.cancelled() => return Ok(()),
+            }
+        }
+    }
+}
@@ -0,0 +1,607 @@
+//! In-process [`CacheBackend`] for tests and local tooling.
+//!
+//! Expiry is simulated against a clock, which is either wall time or a
+//! [`ManualClock`] the test advances itself, so TTL behaviour can be
+//! checked without sleeping. Expired entries are dropped lazily on access,
+//! like Redis does.
+
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use std::{
+    collections::HashMap,
+    ops::RangeInclusive,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::cache::{
+    backend::{CacheBackend, KeyMatches},
+    cache_service::{BoundMode, BoundOutcome, BoundedIncr, KeyTtl},
+};
+
+/// A clock that only moves when told to.
+#[derive(Clone, Default)]
+pub struct ManualClock {
+    elapsed: Arc<Mutex<Duration>>,
+}
+
+impl ManualClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.elapsed.lock().unwrap() += by;
+    }
+
+    fn now(&self) -> Duration {
+        *self.elapsed.lock().unwrap()
+    }
+}
+
+enum Value {
+    String(String),
+    SortedSet(HashMap<String, i64>),
+}
+
+struct Entry {
+    value: Value,
+    expires_at: Option<Duration>,
+}
+
+pub struct MemoryBackend {
+    entries: Mutex<HashMap<String, Entry>>,
+    clock: Option<ManualClock>,
+    started: Instant,
+}
+
+impl Default for MemoryBackend {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemoryBackend {
+    pub fn new() -> Self {
+        Self {
+            entries: Mutex::new(HashMap::new()),
+            clock: None,
+            started: Instant::now(),
+        }
+    }
+
+    /// A backend whose TTLs only elapse as `clock` is advanced.
+    pub fn with_clock(clock: ManualClock) -> Self {
+        Self {
+            clock: Some(clock),
+            ..Self::new()
+        }
+    }
+
+    fn now(&self) -> Duration {
+        match &self.clock {
+            Some(clock) => clock.now(),
+            None => self.started.elapsed(),
+        }
+    }
+
+    /// Runs `f` on the live entries, with anything already expired at
+    /// `key` removed first.
+    fn with_entries<R>(
+        &self,
+        key: &str,
+        f: impl FnOnce(&mut HashMap<String, Entry>, Duration) -> R,
+    ) -> R {
+        let now = self.now();
+        let mut entries = self.entries.lock().unwrap();
+
+        if entries
+            .get(key)
+            .is_some_and(|entry| entry.expires_at.is_some_and(|at| at <= now))
+        {
+            entries.remove(key);
+        }
+
+        f(&mut entries, now)
+    }
+}
+
+#[async_trait]
+impl CacheBackend for MemoryBackend {
+    async fn get(&self, key: &str) -> Result<Option<String>> {
+        self.with_entries(key, |entries, _| match entries.get(key) {
+            None => Ok(None),
+            Some(Entry {
+                value: Value::String(value),
+                ..
+            }) => Ok(Some(value.clone())),
+            Some(_) => bail!("WRONGTYPE {key} is not a string"),
+        })
+    }
+
+    async fn get_del(&self, key: &str) -> Result<Option<String>> {
+        self.with_entries(key, |entries, _| {
+            if let Some(Entry {
+                value: Value::SortedSet(_),
+                ..
+            }) = entries.get(key)
+            {
+                bail!("WRONGTYPE {key} is not a string");
+            }
+
+            Ok(match entries.remove(key) {
+                Some(Entry {
+                    value: Value::String(value),
+                    ..
+                }) => Some(value),
+                _ => None,
+            })
+        })
+    }
+
+    async fn set(&self, key: &str, value: &str, ttl: Option<Duration>) -> Result<()> {
+        self.with_entries(key, |entries, now| {
+            entries.insert(
+                key.to_string(),
+                Entry {
+                    value: Value::String(value.to_string()),
+                    expires_at: ttl.map(|ttl| now + ttl),
+                },
+            );
+        });
+        Ok(())
+    }
+
+    async fn set_nx(&self, key: &str, value: &str, ttl: Option<Duration>) -> Result<bool> {
+        Ok(self.with_entries(key, |entries, now| {
+            if entries.contains_key(key) {
+                return false;
+            }
+
+            entries.insert(
+                key.to_string(),
+                Entry {
+                    value: Value::String(value.to_string()),
+                    expires_at: ttl.map(|ttl| now + ttl),
+                },
+            );
+            true
+        }))
+    }
+
+    async fn set_nx_or_get(&self, key: &str, value: &str, ttl: Duration) -> Result<(bool, String)> {
+        self.with_entries(key, |entries, now| match entries.get(key) {
+            Some(Entry {
+                value: Value::String(holder),
+                ..
+            }) => Ok((false, holder.clone())),
+            Some(_) => bail!("WRONGTYPE {key} is not a string"),
+            None => {
+                entries.insert(
+                    key.to_string(),
+                    Entry {
+                        value: Value::String(value.to_string()),
+                        expires_at: Some(now + ttl),
+                    },
+                );
+                Ok((true, value.to_string()))
+            }
+        })
+    }
+
+    async fn set_nx_and_incr(
+        &self,
+        key: &str,
+        counter_key: &str,
+        value: &str,
+        ttl: Duration,
+    ) -> Result<Option<u64>> {
+        self.with_entries(key, |entries, now| {
+            if entries.contains_key(key) {
+                return Ok(None);
+            }
+
+            let counter = entries.entry(counter_key.to_string()).or_insert(Entry {
+                value: Value::String("0".to_string()),
+                expires_at: None,
+            });
+            let Value::String(raw) = &mut counter.value else {
+                bail!("WRONGTYPE {counter_key} is not a string");
+            };
+            let Ok(next) = raw.parse::<u64>().map(|count| count + 1) else {
+                bail!("ERR value at {counter_key} is not an integer");
+            };
+            *raw = next.to_string();
+
+            entries.insert(
+                key.to_string(),
+                Entry {
+                    value: Value::String(value.to_string()),
+                    expires_at: Some(now + ttl),
+                },
+            );
+            Ok(Some(next))
+        })
+    }
+
+    async fn compare_and_set(
+        &self,
+        key: &str,
+        expected: &str,
+        new: &str,
+        ttl: Option<Duration>,
+    ) -> Result<bool> {
+        Ok(
+            self.with_entries(key, |entries, now| match entries.get_mut(key) {
+                Some(entry) if matches!(&entry.value, Value::String(held) if held == expected) => {
+                    *entry = Entry {
+                        value: Value::String(new.to_string()),
+                        expires_at: ttl.map(|ttl| now + ttl),
+                    };
+                    true
+                }
+                _ => false,
+            }),
+        )
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        self.with_entries(key, |entries, _| entries.remove(key));
+        Ok(())
+    }
+
+    async fn delete_matching(&self, pattern: &str) -> Result<u64> {
+        let now = self.now();
+        let mut entries = self.entries.lock().unwrap();
+        let mut removed = 0;
+
+        // Expired entries are dropped too, but only live matches count,
+        // as they are the only ones Redis would still have held.
+        entries.retain(|key, entry| {
+            if entry.expires_at.is_some_and(|at| at <= now) {
+                return false;
+            }
+            if glob_match(pattern.as_bytes(), key.as_bytes()) {
+                removed += 1;
+                return false;
+            }
+            true
+        });
+
+        Ok(removed)
+    }
+
+    async fn count_matching(&self, pattern: &str, sample: usize) -> Result<KeyMatches> {
+        let now = self.now();
+        let entries = self.entries.lock().unwrap();
+        let mut matches = KeyMatches::default();
+
+        for (key, entry) in entries.iter() {
+            if entry.expires_at.is_some_and(|at| at <= now)
+                || !glob_match(pattern.as_bytes(), key.as_bytes())
+            {
+                continue;
+            }
+            matches.count += 1;
+            if matches.sample.len() < sample {
+                matches.sample.push(key.clone());
+            }
+        }
+
+        Ok(matches)
+    }
+
+    /// Only counts the key and payload bytes, so runs lower than Redis.
+    async fn memory_usage(&self, key: &str) -> Result<Option<u64>> {
+        self.with_entries(key, |entries, _| {
+            Ok(entries.get(key).map(|entry| {
+                let value = match &entry.value {
+                    Value::String(value) => value.len(),
+                    Value::SortedSet(members) => members.keys().map(|m| m.len() + 8).sum(),
+                };
+                (key.len() + value) as u64
+            }))
+        })
+    }
+
+    /// There is no one to subscribe in one process.
+    async fn publish(&self, _channel: &str, _message: &str) -> Result<()> {
+        Ok(())
+    }
+
+    async fn get_many(&self, keys: &[String]) -> Result<Vec<Option<String>>> {
+        Ok(keys
+            .iter()
+            .map(|key| {
+                self.with_entries(key, |entries, _| match entries.get(key) {
+                    Some(Entry {
+                        value: Value::String(value),
+                        ..
+                    }) => Some(value.clone()),
+                    _ => None,
+                })
+            })
+            .collect())
+    }
+
+    async fn set_many(&self, entries: &[(String, String)], ttl: Option<Duration>) -> Result<()> {
+        for (key, value) in entries {
+            self.set(key, value, ttl).await?;
+        }
+        Ok(())
+    }
+
+    async fn exists_many(&self, keys: &[String]) -> Result<Vec<bool>> {
+        Ok(keys
+            .iter()
+            .map(|key| self.with_entries(key, |entries, _| entries.contains_key(key)))
+            .collect())
+    }
+
+    async fn ttl(&self, key: &str) -> Result<KeyTtl> {
+        Ok(
+            self.with_entries(key, |entries, now| match entries.get(key) {
+                None => KeyTtl::Missing,
+                Some(Entry {
+                    expires_at: None, ..
+                }) => KeyTtl::Persistent,
+                Some(Entry {
+                    expires_at: Some(at),
+                    ..
+                }) => KeyTtl::Expires(at.saturating_sub(now)),
+            }),
+        )
+    }
+
+    async fn expire(&self, key: &str, ttl: Duration) -> Result<()> {
+        self.with_entries(key, |entries, now| {
+            if let Some(entry) = entries.get_mut(key) {
+                entry.expires_at = Some(now + ttl);
+            }
+        });
+        Ok(())
+    }
+
+    async fn expire_many(&self, keys: &[String], ttl: Duration) -> Result<u64> {
+        Ok(keys
+            .iter()
+            .filter(|key| {
+                self.with_entries(key, |entries, now| match entries.get_mut(key.as_str()) {
+                    Some(entry) => {
+                        entry.expires_at = Some(now + ttl);
+                        true
+                    }
+                    None => false,
+                })
+            })
+            .count() as u64)
+    }
+
+    async fn expire_if_equals(&self, key: &str, value: &str, ttl: Duration) -> Result<bool> {
+        Ok(
+            self.with_entries(key, |entries, now| match entries.get_mut(key) {
+                Some(entry) if matches!(&entry.value, Value::String(held) if held == value) => {
+                    entry.expires_at = Some(now + ttl);
+                    true
+                }
+                _ => false,
+            }),
+        )
+    }
+
+    async fn delete_if_equals(&self, key: &str, value: &str) -> Result<bool> {
+        Ok(self.with_entries(key, |entries, _| {
+            let held = matches!(
+                entries.get(key),
+                Some(entry) if matches!(&entry.value, Value::String(held) if held == value)
+            );
+            if held {
+                entries.remove(key);
+            }
+            held
+        }))
+    }
+
+    async fn incr_by(&self, key: &str, by: i64, ttl: Option<Duration>) -> Result<i64> {
+        self.with_entries(key, |entries, now| {
+            let entry = entries.entry(key.to_string()).or_insert(Entry {
+                value: Value::String("0".to_string()),
+                expires_at: ttl.map(|ttl| now + ttl),
+            });
+
+            let Value::String(raw) = &mut entry.value else {
+                bail!("WRONGTYPE {key} is not a string");
+            };
+            let Ok(current) = raw.parse::<i64>() else {
+                bail!("ERR value at {key} is not an integer");
+            };
+            let Some(next) = current.checked_add(by) else {
+                bail!("ERR increment at {key} would overflow");
+            };
+
+            *raw = next.to_string();
+            Ok(next)
+        })
+    }
+
+    async fn incr_bounded(
+        &self,
+        key: &str,
+        by: i64,
+        bounds: RangeInclusive<i64>,
+        mode: BoundMode,
+        ttl: Option<Duration>,
+    ) -> Result<BoundedIncr> {
+        self.with_entries(key, |entries, now| {
+            let current = match entries.get(key) {
+                None => None,
+                Some(Entry {
+                    value: Value::String(raw),
+                    ..
+                }) => match raw.parse::<i64>() {
+                    Ok(current) => Some(current),
+                    Err(_) => bail!("ERR value at {key} is not an integer"),
+                },
+                Some(_) => bail!("WRONGTYPE {key} is not a string"),
+            };
+
+            let base = current.unwrap_or(0);
+            let Some(next) = base.checked_add(by) else {
+                bail!("ERR increment at {key} would overflow");
+            };
+            let (next, outcome) = if bounds.contains(&next) {
+                (next, BoundOutcome::Applied)
+            } else if mode == BoundMode::Reject {
+                return Ok(BoundedIncr {
+                    value: base,
+                    outcome: BoundOutcome::Rejected,
+                });
+            } else {
+                (
+                    next.clamp(*bounds.start(), *bounds.end()),
+                    BoundOutcome::Clamped,
+                )
+            };
+
+            match entries.get_mut(key) {
+                Some(entry) => entry.value = Value::String(next.to_string()),
+                None => {
+                    entries.insert(
+                        key.to_string(),
+                        Entry {
+                            value: Value::String(next.to_string()),
+                            expires_at: ttl.map(|ttl| now + ttl),
+                        },
+                    );
+                }
+            }
+            Ok(BoundedIncr {
+                value: next,
+                outcome,
+            })
+        })
+    }
+
+    async fn zadd(&self, key: &str, member: &str, score: i64) -> Result<()> {
+        self.with_entries(key, |entries, _| {
+            let entry = entries.entry(key.to_string()).or_insert(Entry {
+                value: Value::SortedSet(HashMap::new()),
+                expires_at: None,
+            });
+
+            let Value::SortedSet(members) = &mut entry.value else {
+                bail!("WRONGTYPE {key} is not a sorted set");
+            };
+
+            members.insert(member.to_string(), score);
+            Ok(())
+        })
+    }
+
+    async fn zadd_within_capacity(
+        &self,
+        key: &str,
+        member: &str,
+        score: i64,
+        now: i64,
+        capacity: u64,
+    ) -> Result<bool> {
+        self.with_entries(key, |entries, clock| {
+            let entry = entries.entry(key.to_string()).or_insert(Entry {
+                value: Value::SortedSet(HashMap::new()),
+                expires_at: None,
+            });
+
+            let Value::SortedSet(members) = &mut entry.value else {
+                bail!("WRONGTYPE {key} is not a sorted set");
+            };
+
+            members.retain(|_, held| *held >= now);
+            if !members.contains_key(member) && members.len() as u64 >= capacity {
+                if members.is_empty() {
+                    entries.remove(key);
+                }
+                return Ok(false);
+            }
+            members.insert(member.to_string(), score);
+
+            // Scores are wall-clock, expiry runs on the backend's clock.
+            let highest = members.values().copied().max().unwrap_or(score);
+            let remaining = u64::try_from(highest.saturating_sub(now)).unwrap_or(0);
+            entry.expires_at = Some(clock + Duration::from_millis(remaining));
+            Ok(true)
+        })
+    }
+
+    async fn zrange_with_scores(&self, key: &str) -> Result<Vec<(String, i64)>> {
+        self.with_entries(key, |entries, _| match entries.get(key) {
+            None => Ok(Vec::new()),
+            Some(Entry {
+                value: Value::SortedSet(members),
+                ..
+            }) => {
+                let mut members: Vec<(String, i64)> = members
+                    .iter()
+                    .map(|(member, score)| (member.clone(), *score))
+                    .collect();
+                // Same order as Redis: by score, ties broken by member.
+                members.sort_by(|a, b| a.1.cmp(&b.1).then_with(|| a.0.cmp(&b.0)));
+                Ok(members)
+            }
+            Some(_) => bail!("WRONGTYPE {key} is not a sorted set"),
+        })
+    }
+
+    async fn zrem(&self, key: &str, members: &[&str]) -> Result<()> {
+        self.with_entries(key, |entries, _| {
+            let Some(entry) = entries.get_mut(key) else {
+                return Ok(());
+            };
+            let Value::SortedSet(set) = &mut entry.value else {
+                bail!("WRONGTYPE {key} is not a sorted set");
+            };
+
+            for member in members {
+                set.remove(*member);
+            }
+
+            // Redis deletes a sorted set once its last member is gone.
+            if set.is_empty() {
+                entries.remove(key);
+            }
+            Ok(())
+        })
+    }
+}
+
+/// Redis-style glob supporting `*`, `?` and `\` escapes. Character classes
+/// are not supported.
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => text.is_empty(),
+        Some((b'*', rest)) => (0..=text.len()).any(|skip| glob_match(rest, &text[skip..])),
+        Some((b'?', rest)) => !text.is_empty() && glob_match(rest, &text[1..]),
+        Some((b'\\', [literal, rest @ ..])) => {
+            text.first() == Some(literal) && glob_match(rest, &text[1..])
+        }
+        Some((literal, rest)) => text.first() == Some(literal) && glob_match(rest, &text[1..]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn delete_if_equals_only_removes_a_matching_value() {
+        let backend = MemoryBackend::new();
+        backend.set("lock", "mine", None).await.unwrap();
+
+        assert!(!backend.delete_if_equals("lock", "theirs").await.unwrap());
+        assert_eq!(backend.get("lock").await.unwrap().as_deref(), Some("mine"));
+
+        assert!(backend.delete_if_equals("lock", "mine").await.unwrap());
+        assert_eq!(backend.get("lock").await.unwrap(), None);
+        assert!(!backend.delete_if_equals("lock", "mine").await.unwrap());
+    }
+}
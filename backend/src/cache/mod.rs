@@ -0,0 +1,12 @@
+pub mod backend;
+pub mod cache_service;
+pub mod codec;
+#[cfg(test)]
+pub mod fake_redis;
+pub mod key_stats;
+pub mod keys;
+pub mod local;
+pub mod memory;
+pub mod redis_client;
+mod singleflight;
+pub mod slow_log;
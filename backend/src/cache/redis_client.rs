@@ -1,19 +1,321 @@
-use anyhow::Result;
-use redis::{aio::ConnectionManager, Client};
+use anyhow::{bail, ensure, Context, Result};
+use redis::{
+    aio::{ConnectionLike, ConnectionManager, PubSub},
+    cluster::ClusterClient,
+    cluster_async::ClusterConnection,
+    Client, Cmd, ConnectionAddr, ConnectionInfo, ErrorKind, IntoConnectionInfo, Pipeline,
+    RedisError, RedisFuture, Value,
+};
+use std::time::Duration;
+
+/// Where to connect: one standalone server, or the seed nodes of a
+/// Redis Cluster.
+#[derive(Debug, Clone)]
+pub enum RedisTarget {
+    Single(ConnectionInfo),
+    Cluster(Vec<ConnectionInfo>),
+}
 
 #[derive(Clone)]
 pub struct RedisClient {
-    conn: ConnectionManager,
+    conn: RedisConnection,
+    /// Where [`pubsub`](Self::pubsub) connections go.
+    pubsub_node: ConnectionInfo,
 }
 
 impl RedisClient {
-    pub async fn new(redis_url: &str) -> Result<Self> {
-        let client = Client::open(redis_url)?;
+    pub async fn new(info: impl IntoConnectionInfo) -> Result<Self> {
+        let info = info.into_connection_info()?;
+        let client = Client::open(info.clone())?;
         let conn = ConnectionManager::new(client).await?;
-        Ok(Self { conn })
+        Ok(Self {
+            conn: RedisConnection::Single(conn),
+            pubsub_node: info,
+        })
+    }
+
+    /// Connects to a cluster through `nodes`. The cluster connection
+    /// follows `MOVED`/`ASK` redirects itself.
+    pub async fn new_cluster(nodes: Vec<ConnectionInfo>) -> Result<Self> {
+        let pubsub_node = nodes
+            .first()
+            .cloned()
+            .context("a cluster needs at least one node")?;
+        let client = ClusterClient::new(nodes)?;
+        let conn = client.get_async_connection().await?;
+        Ok(Self {
+            conn: RedisConnection::Cluster(conn),
+            pubsub_node,
+        })
     }
 
-    pub fn connection(&self) -> ConnectionManager {
+    async fn connect(target: &RedisTarget) -> Result<Self> {
+        match target {
+            RedisTarget::Single(info) => Self::new(info.clone()).await,
+            RedisTarget::Cluster(nodes) => Self::new_cluster(nodes.clone()).await,
+        }
+    }
+
+    /// Connects and pings until Redis answers, giving up after `attempts`
+    /// tries spaced `delay` apart. Used as a readiness gate at startup so
+    /// we never accept traffic before Redis is reachable.
+    pub async fn connect_when_ready(
+        target: RedisTarget,
+        attempts: u32,
+        delay: Duration,
+    ) -> Result<Self> {
+        let mut last_error = None;
+
+        for attempt in 1..=attempts.max(1) {
+            match Self::connect(&target).await {
+                Ok(client) => match client.ping().await {
+                    Ok(()) => {
+                        client.warn_if_unexpected_cluster().await;
+                        return Ok(client);
+                    }
+                    Err(err) => last_error = Some(err),
+                },
+                Err(err) => last_error = Some(err),
+            }
+
+            if attempt < attempts {
+                tokio::time::sleep(delay).await;
+            }
+        }
+
+        match last_error {
+            Some(err) => Err(err.context(format!("redis not ready after {attempts} attempts"))),
+            None => bail!("redis not ready after {attempts} attempts"),
+        }
+    }
+
+    pub async fn ping(&self) -> Result<()> {
+        let mut conn = self.connection();
+        redis::cmd("PING").query_async::<_, ()>(&mut conn).await?;
+        Ok(())
+    }
+
+    pub fn connection(&self) -> RedisConnection {
         self.conn.clone()
     }
+
+    /// A dedicated connection for `SUBSCRIBE`, which can't share the
+    /// multiplexed one. In cluster mode it goes to the first seed node;
+    /// classic pub/sub messages are broadcast to every node anyway.
+    pub async fn pubsub(&self) -> Result<PubSub> {
+        let client = Client::open(self.pubsub_node.clone())?;
+        Ok(client.get_async_pubsub().await?)
+    }
+
+    /// A connection to every primary: the server itself when standalone,
+    /// each primary node of a cluster, which only iterates the keys in
+    /// its own slots. What a keyspace walk like `SCAN` has to visit.
+    /// Cluster nodes are connected to afresh, as `CLUSTER NODES` lists
+    /// them right now.
+    pub async fn primaries(&self) -> Result<Vec<RedisConnection>> {
+        let RedisConnection::Cluster(_) = &self.conn else {
+            return Ok(vec![self.connection()]);
+        };
+
+        let mut conn = self.connection();
+        let nodes: String = redis::cmd("CLUSTER")
+            .arg("NODES")
+            .query_async(&mut conn)
+            .await?;
+
+        let mut primaries = Vec::new();
+        for (host, port) in primary_addrs(&nodes) {
+            let info = ConnectionInfo {
+                addr: node_addr(&self.pubsub_node.addr, host, port),
+                redis: self.pubsub_node.redis.clone(),
+            };
+            let conn = ConnectionManager::new(Client::open(info)?)
+                .await
+                .with_context(|| format!("failed to connect to cluster node {host}:{port}"))?;
+            primaries.push(RedisConnection::Single(conn));
+        }
+        ensure!(!primaries.is_empty(), "CLUSTER NODES listed no primary");
+        Ok(primaries)
+    }
+
+    /// A cluster node answers `PING` like any server, and only fails once
+    /// a key hashes to a slot it doesn't own. Catch that misconfiguration
+    /// at startup instead. Best-effort: servers that restrict `INFO` are
+    /// skipped silently.
+    async fn warn_if_unexpected_cluster(&self) {
+        let RedisConnection::Single(_) = &self.conn else {
+            return;
+        };
+
+        let mut conn = self.connection();
+        let info: Result<String, _> = redis::cmd("INFO")
+            .arg("cluster")
+            .query_async(&mut conn)
+            .await;
+
+        if info.is_ok_and(|info| info.contains("cluster_enabled:1")) {
+            tracing::warn!("{CLUSTER_HINT}");
+        }
+    }
+}
+
+/// A client per workload, so a noisy cache can't evict sessions or
+/// rate-limit counters. Concerns without their own instance share the
+/// primary, which also carries everything else (audit, queues, pub/sub,
+/// the maintenance flag).
+#[derive(Clone)]
+pub struct RedisClients {
+    pub primary: RedisClient,
+    pub cache: RedisClient,
+    pub rate_limits: RedisClient,
+    pub sessions: RedisClient,
+}
+
+impl RedisClients {
+    /// Everything on one instance.
+    pub fn shared(primary: RedisClient) -> Self {
+        Self {
+            cache: primary.clone(),
+            rate_limits: primary.clone(),
+            sessions: primary.clone(),
+            primary,
+        }
+    }
+
+    /// Pings every instance; a shared one just answers more than once.
+    pub async fn ping(&self) -> Result<()> {
+        tokio::try_join!(
+            self.primary.ping(),
+            self.cache.ping(),
+            self.rate_limits.ping(),
+            self.sessions.ping(),
+        )?;
+        Ok(())
+    }
+}
+
+/// `host:port` of each healthy primary in a `CLUSTER NODES` reply, whose
+/// lines read `<id> <host:port@cport[,hostname]> <flags> ...`.
+fn primary_addrs(nodes: &str) -> Vec<(&str, u16)> {
+    nodes
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let addr = fields.nth(1)?;
+            let flags = fields.next()?;
+            let primary = flags.split(',').any(|flag| flag == "master")
+                && !flags
+                    .split(',')
+                    .any(|flag| matches!(flag, "fail" | "noaddr" | "handshake"));
+            if !primary {
+                return None;
+            }
+            let addr = addr.split(['@', ',']).next()?;
+            let (host, port) = addr.rsplit_once(':')?;
+            Some((host, port.parse().ok()?)).filter(|(host, _)| !host.is_empty())
+        })
+        .collect()
+}
+
+/// `host:port` reached the way `seed` is, TLS settings included.
+fn node_addr(seed: &ConnectionAddr, host: &str, port: u16) -> ConnectionAddr {
+    match seed {
+        ConnectionAddr::TcpTls {
+            insecure,
+            tls_params,
+            ..
+        } => ConnectionAddr::TcpTls {
+            host: host.to_string(),
+            port,
+            insecure: *insecure,
+            tls_params: tls_params.clone(),
+        },
+        _ => ConnectionAddr::Tcp(host.to_string(), port),
+    }
+}
+
+const CLUSTER_HINT: &str = "REDIS_URL points at a Redis Cluster node but cluster mode is off; \
+     set REDIS_CLUSTER=true so redirects are followed";
+
+/// A standalone or cluster connection. Both are cheap to clone and share
+/// one underlying connection (pool).
+///
+/// On a standalone connection, `MOVED`/`ASK` replies can only mean the
+/// server is a cluster node we weren't told about, so they are turned into
+/// an error saying so rather than surfacing the raw redirect.
+#[derive(Clone)]
+pub enum RedisConnection {
+    Single(ConnectionManager),
+    Cluster(ClusterConnection),
+}
+
+impl ConnectionLike for RedisConnection {
+    fn req_packed_command<'a>(&'a mut self, cmd: &'a Cmd) -> RedisFuture<'a, Value> {
+        match self {
+            Self::Single(conn) => {
+                Box::pin(async move { conn.req_packed_command(cmd).await.map_err(explain_redirect) })
+            }
+            Self::Cluster(conn) => conn.req_packed_command(cmd),
+        }
+    }
+
+    fn req_packed_commands<'a>(
+        &'a mut self,
+        cmd: &'a Pipeline,
+        offset: usize,
+        count: usize,
+    ) -> RedisFuture<'a, Vec<Value>> {
+        match self {
+            Self::Single(conn) => Box::pin(async move {
+                conn.req_packed_commands(cmd, offset, count)
+                    .await
+                    .map_err(explain_redirect)
+            }),
+            Self::Cluster(conn) => conn.req_packed_commands(cmd, offset, count),
+        }
+    }
+
+    fn get_db(&self) -> i64 {
+        match self {
+            Self::Single(conn) => conn.get_db(),
+            Self::Cluster(conn) => conn.get_db(),
+        }
+    }
+}
+
+fn explain_redirect(err: RedisError) -> RedisError {
+    match err.kind() {
+        ErrorKind::Moved | ErrorKind::Ask => {
+            RedisError::from((ErrorKind::ClientError, CLUSTER_HINT, err.to_string()))
+        }
+        _ => err,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn primary_addrs_skips_replicas_and_failed_nodes() {
+        let nodes = "\
+07c37dfeb235213a872192d90877d0cd55635b91 127.0.0.1:30004@31004,node-4 slave e7d1eecce10fd6bb5eb35b9f99a514335d9ba9ca 0 1426238317239 4 connected
+67ed2db8d677e59ec4a4cefb06858cf2a1a89fa1 127.0.0.1:30002@31002,node-2 master - 0 1426238316232 2 connected 5461-10922
+292f8b365bb7edb5e285caf0b7e6ddc7265d2f4f 127.0.0.1:30003@31003 master,fail - 0 1426238318243 3 connected 10923-16383
+e7d1eecce10fd6bb5eb35b9f99a514335d9ba9ca 127.0.0.1:30001@31001 myself,master - 0 0 1 connected 0-5460
+6ec23923021cf3ffec47632106199cb7f496ce01 :0@0 master,noaddr - 0 0 5 disconnected
+";
+
+        assert_eq!(
+            primary_addrs(nodes),
+            [("127.0.0.1", 30002), ("127.0.0.1", 30001)]
+        );
+    }
+
+    #[test]
+    fn primary_addrs_reads_ipv6_hosts() {
+        let nodes = "e7d1eecce10fd6bb5eb35b9f99a514335d9ba9ca ::1:30001@31001 master - 0 0 1 connected 0-16383\n";
+
+        assert_eq!(primary_addrs(nodes), [("::1", 30001)]);
+    }
 }
@@ -0,0 +1,77 @@
+//! In-process deduplication of concurrent cache fills.
+//!
+//! The first caller to miss a key becomes its leader and runs the loader;
+//! callers that miss the same key while that is in flight wait for the
+//! leader's outcome instead of running the loader again. Outcomes are
+//! shared as encoded JSON, so one table serves every value type.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, PoisonError},
+};
+use tokio::sync::watch;
+
+/// Encoded value, or the leader's error message.
+pub(crate) type Outcome = Result<String, String>;
+
+type Slot = watch::Receiver<Option<Outcome>>;
+
+#[derive(Default)]
+pub(crate) struct SingleFlight {
+    flights: Arc<Mutex<HashMap<String, Slot>>>,
+}
+
+pub(crate) enum Role {
+    Leader(Flight),
+    Follower(Slot),
+}
+
+impl SingleFlight {
+    pub(crate) fn join(&self, key: &str) -> Role {
+        let mut flights = self.flights.lock().unwrap_or_else(PoisonError::into_inner);
+
+        if let Some(slot) = flights.get(key) {
+            return Role::Follower(slot.clone());
+        }
+
+        let (sender, slot) = watch::channel(None);
+        flights.insert(key.to_string(), slot);
+        Role::Leader(Flight {
+            key: key.to_string(),
+            sender,
+            flights: self.flights.clone(),
+        })
+    }
+}
+
+/// Waits for the leader. `None` means it went away without an outcome
+/// (its loader panicked or its future was dropped); the caller should
+/// start over and may become the leader itself.
+pub(crate) async fn wait(mut slot: Slot) -> Option<Outcome> {
+    let outcome = slot.wait_for(Option::is_some).await.ok()?;
+    outcome.clone()
+}
+
+/// A leader's claim on a key. Dropping it, including while unwinding from
+/// a panic, ends the flight so waiters are released rather than stuck.
+pub(crate) struct Flight {
+    key: String,
+    sender: watch::Sender<Option<Outcome>>,
+    flights: Arc<Mutex<HashMap<String, Slot>>>,
+}
+
+impl Flight {
+    pub(crate) fn finish(self, outcome: Outcome) {
+        // Nobody waiting is fine.
+        let _ = self.sender.send(Some(outcome));
+    }
+}
+
+impl Drop for Flight {
+    fn drop(&mut self) {
+        self.flights
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&self.key);
+    }
+}
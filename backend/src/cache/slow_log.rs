@@ -0,0 +1,249 @@
+//! [`CacheBackend`] decorator that warns about slow calls.
+//!
+//! Cheaper than full tracing for finding Redis hotspots: nothing is
+//! emitted unless a call takes at least the threshold, and then only one
+//! `warn` line with the command, key and duration.
+
+use anyhow::Result;
+use async_trait::async_trait;
+use std::{
+    future::Future,
+    ops::RangeInclusive,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use crate::cache::{
+    backend::{CacheBackend, KeyMatches},
+    cache_service::{BoundMode, BoundedIncr, KeyTtl},
+};
+
+pub struct SlowLog {
+    inner: Arc<dyn CacheBackend>,
+    threshold: Duration,
+}
+
+impl SlowLog {
+    pub fn new(inner: Arc<dyn CacheBackend>, threshold: Duration) -> Self {
+        Self { inner, threshold }
+    }
+
+    async fn timed<T>(&self, op: &'static str, key: &str, call: impl Future<Output = T>) -> T {
+        let started = Instant::now();
+        let output = call.await;
+        let elapsed = started.elapsed();
+
+        if elapsed >= self.threshold {
+            tracing::warn!(
+                op,
+                key,
+                elapsed_ms = elapsed.as_millis() as u64,
+                threshold_ms = self.threshold.as_millis() as u64,
+                "slow cache operation"
+            );
+        }
+
+        output
+    }
+}
+
+#[async_trait]
+impl CacheBackend for SlowLog {
+    async fn get(&self, key: &str) -> Result<Option<String>> {
+        self.timed("get", key, self.inner.get(key)).await
+    }
+
+    async fn get_del(&self, key: &str) -> Result<Option<String>> {
+        self.timed("get_del", key, self.inner.get_del(key)).await
+    }
+
+    async fn set(&self, key: &str, value: &str, ttl: Option<Duration>) -> Result<()> {
+        self.timed("set", key, self.inner.set(key, value, ttl))
+            .await
+    }
+
+    async fn set_nx(&self, key: &str, value: &str, ttl: Option<Duration>) -> Result<bool> {
+        self.timed("set_nx", key, self.inner.set_nx(key, value, ttl))
+            .await
+    }
+
+    async fn set_nx_or_get(&self, key: &str, value: &str, ttl: Duration) -> Result<(bool, String)> {
+        self.timed(
+            "set_nx_or_get",
+            key,
+            self.inner.set_nx_or_get(key, value, ttl),
+        )
+        .await
+    }
+
+    async fn set_nx_and_incr(
+        &self,
+        key: &str,
+        counter_key: &str,
+        value: &str,
+        ttl: Duration,
+    ) -> Result<Option<u64>> {
+        self.timed(
+            "set_nx_and_incr",
+            key,
+            self.inner.set_nx_and_incr(key, counter_key, value, ttl),
+        )
+        .await
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        self.timed("delete", key, self.inner.delete(key)).await
+    }
+
+    async fn delete_matching(&self, pattern: &str) -> Result<u64> {
+        self.timed(
+            "delete_matching",
+            pattern,
+            self.inner.delete_matching(pattern),
+        )
+        .await
+    }
+
+    async fn count_matching(&self, pattern: &str, sample: usize) -> Result<KeyMatches> {
+        self.timed(
+            "count_matching",
+            pattern,
+            self.inner.count_matching(pattern, sample),
+        )
+        .await
+    }
+
+    async fn memory_usage(&self, key: &str) -> Result<Option<u64>> {
+        self.timed("memory_usage", key, self.inner.memory_usage(key))
+            .await
+    }
+
+    async fn publish(&self, channel: &str, message: &str) -> Result<()> {
+        self.timed("publish", channel, self.inner.publish(channel, message))
+            .await
+    }
+
+    async fn get_many(&self, keys: &[String]) -> Result<Vec<Option<String>>> {
+        let first = keys.first().map(String::as_str).unwrap_or_default();
+        self.timed("get_many", first, self.inner.get_many(keys))
+            .await
+    }
+
+    async fn set_many(&self, entries: &[(String, String)], ttl: Option<Duration>) -> Result<()> {
+        let first = entries
+            .first()
+            .map(|(key, _)| key.as_str())
+            .unwrap_or_default();
+        self.timed("set_many", first, self.inner.set_many(entries, ttl))
+            .await
+    }
+
+    async fn exists_many(&self, keys: &[String]) -> Result<Vec<bool>> {
+        let first = keys.first().map(String::as_str).unwrap_or_default();
+        self.timed("exists_many", first, self.inner.exists_many(keys))
+            .await
+    }
+
+    async fn ttl(&self, key: &str) -> Result<KeyTtl> {
+        self.timed("ttl", key, self.inner.ttl(key)).await
+    }
+
+    async fn expire(&self, key: &str, ttl: Duration) -> Result<()> {
+        self.timed("expire", key, self.inner.expire(key, ttl)).await
+    }
+
+    async fn expire_many(&self, keys: &[String], ttl: Duration) -> Result<u64> {
+        let first = keys.first().map(String::as_str).unwrap_or_default();
+        self.timed("expire_many", first, self.inner.expire_many(keys, ttl))
+            .await
+    }
+
+    async fn compare_and_set(
+        &self,
+        key: &str,
+        expected: &str,
+        new: &str,
+        ttl: Option<Duration>,
+    ) -> Result<bool> {
+        self.timed(
+            "compare_and_set",
+            key,
+            self.inner.compare_and_set(key, expected, new, ttl),
+        )
+        .await
+    }
+
+    async fn expire_if_equals(&self, key: &str, value: &str, ttl: Duration) -> Result<bool> {
+        self.timed(
+            "expire_if_equals",
+            key,
+            self.inner.expire_if_equals(key, value, ttl),
+        )
+        .await
+    }
+
+    async fn delete_if_equals(&self, key: &str, value: &str) -> Result<bool> {
+        self.timed(
+            "delete_if_equals",
+            key,
+            self.inner.delete_if_equals(key, value),
+        )
+        .await
+    }
+
+    async fn incr_by(&self, key: &str, by: i64, ttl: Option<Duration>) -> Result<i64> {
+        self.timed("incr_by", key, self.inner.incr_by(key, by, ttl))
+            .await
+    }
+
+    async fn incr_bounded(
+        &self,
+        key: &str,
+        by: i64,
+        bounds: RangeInclusive<i64>,
+        mode: BoundMode,
+        ttl: Option<Duration>,
+    ) -> Result<BoundedIncr> {
+        self.timed(
+            "incr_bounded",
+            key,
+            self.inner.incr_bounded(key, by, bounds, mode, ttl),
+        )
+        .await
+    }
+
+    async fn zadd(&self, key: &str, member: &str, score: i64) -> Result<()> {
+        self.timed("zadd", key, self.inner.zadd(key, member, score))
+            .await
+    }
+
+    async fn zadd_within_capacity(
+        &self,
+        key: &str,
+        member: &str,
+        score: i64,
+        now: i64,
+        capacity: u64,
+    ) -> Result<bool> {
+        self.timed(
+            "zadd_within_capacity",
+            key,
+            self.inner
+                .zadd_within_capacity(key, member, score, now, capacity),
+        )
+        .await
+    }
+
+    async fn zrange_with_scores(&self, key: &str) -> Result<Vec<(String, i64)>> {
+        self.timed(
+            "zrange_with_scores",
+            key,
+            self.inner.zrange_with_scores(key),
+        )
+        .await
+    }
+
+    async fn zrem(&self, key: &str, members: &[&str]) -> Result<()> {
+        self.timed("zrem", key, self.inner.zrem(key, members)).await
+    }
+}
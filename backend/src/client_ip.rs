@@ -0,0 +1,125 @@
+//! Working out which address a request really came from.
+//!
+//! Behind a load balancer the TCP peer is the proxy, and the client is in
+//! `X-Forwarded-For`. That header is only believed when the peer is one of
+//! the configured trusted proxies, and only as far back as the hops
+//! through trusted proxies go, so a client can't spoof its address by
+//! sending the header itself.
+
+use anyhow::{bail, Context};
+use axum::http::HeaderMap;
+use std::{net::IpAddr, str::FromStr, sync::Arc};
+
+/// A CIDR block such as `10.0.0.0/8` or `2001:db8::/32`. A bare address
+/// is a block of one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpRange {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl IpRange {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+            IpAddr::V4(_) => ip,
+        };
+
+        match (self.network, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                mask_v4(u32::from(ip), self.prefix) == u32::from(network)
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                mask_v6(u128::from(ip), self.prefix) == u128::from(network)
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpRange {
+    type Err = anyhow::Error;
+
+    fn from_str(raw: &str) -> Result<Self, Self::Err> {
+        let (address, prefix) = match raw.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (raw, None),
+        };
+        let address: IpAddr = address
+            .parse()
+            .with_context(|| format!("{raw} is not an IP address or CIDR range"))?;
+        let max = if address.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse()
+                .with_context(|| format!("{raw} has an invalid prefix length"))?,
+            None => max,
+        };
+        if prefix > max {
+            bail!("{raw} has a prefix longer than {max} bits");
+        }
+
+        // Store the network address itself, so `10.1.2.3/8` means `10.0.0.0/8`.
+        let network = match address {
+            IpAddr::V4(v4) => IpAddr::V4(mask_v4(u32::from(v4), prefix).into()),
+            IpAddr::V6(v6) => IpAddr::V6(mask_v6(u128::from(v6), prefix).into()),
+        };
+        Ok(Self { network, prefix })
+    }
+}
+
+fn mask_v4(bits: u32, prefix: u8) -> u32 {
+    bits & u32::MAX.checked_shl(32 - u32::from(prefix)).unwrap_or(0)
+}
+
+fn mask_v6(bits: u128, prefix: u8) -> u128 {
+    bits & u128::MAX.checked_shl(128 - u32::from(prefix)).unwrap_or(0)
+}
+
+/// Whether `ip` falls in any of `ranges`.
+pub fn in_any(ranges: &[IpRange], ip: IpAddr) -> bool {
+    ranges.iter().any(|range| range.contains(ip))
+}
+
+/// Resolves the client address from the peer and `X-Forwarded-For`.
+#[derive(Debug, Clone, Default)]
+pub struct ClientIpResolver {
+    trusted_proxies: Arc<[IpRange]>,
+}
+
+impl ClientIpResolver {
+    pub fn new(trusted_proxies: Vec<IpRange>) -> Self {
+        Self {
+            trusted_proxies: trusted_proxies.into(),
+        }
+    }
+
+    /// Walks `X-Forwarded-For` from the nearest hop back while each hop
+    /// is a trusted proxy. The first address that isn't one is the
+    /// client. An untrusted peer is the client itself, whatever it sent.
+    pub fn resolve(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        if !in_any(&self.trusted_proxies, peer) {
+            return peer;
+        }
+
+        let hops: Vec<&str> = headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .collect();
+
+        let mut client = peer;
+        for hop in hops.into_iter().rev() {
+            let Ok(ip) = hop.parse::<IpAddr>() else {
+                break;
+            };
+            client = ip;
+            if !in_any(&self.trusted_proxies, ip) {
+                break;
+            }
+        }
+        client
+    }
+}
@@ -0,0 +1,1010 @@
+pub mod report;
+pub mod secrets;
+
+use anyhow::{anyhow, Context, Result};
+use argon2::Params;
+use axum::http::{
+    header::{
+        CONTENT_SECURITY_POLICY, REFERRER_POLICY, STRICT_TRANSPORT_SECURITY,
+        X_CONTENT_TYPE_OPTIONS, X_FRAME_OPTIONS,
+    },
+    HeaderValue,
+};
+use redis::{ConnectionInfo, IntoConnectionInfo};
+use std::{
+    collections::{HashMap, HashSet},
+    env, fmt,
+    net::SocketAddr,
+    str::FromStr,
+    time::Duration,
+};
+
+use self::{
+    report::ConfigReport,
+    secrets::{load_secret, NoSecretProvider, SecretProvider},
+};
+use crate::{
+    auth::{
+        auth_service::{ReuseResponse, SessionLimitPolicy, ACTIVITY_TOUCH_INTERVAL},
+        cookie::{SameSite, TokenCookie},
+        csrf::CsrfProtection,
+        ids::UserId,
+        jwks::{ExternalIssuer, Jwks},
+        password::Peppers,
+        signed_url::SignedUrls,
+        tenant_keys::TenantKeys,
+        token_service::{TokenService, DEFAULT_LEEWAY},
+    },
+    cache::{
+        cache_service::{CacheService, KeyNamespaces},
+        local::LocalCache,
+        redis_client::RedisTarget,
+    },
+    client_ip::IpRange,
+    error::ErrorFormat,
+    field_encryption::{BlindIndex, FieldCipher},
+    middleware::{
+        body_logging::BodyLogging,
+        compression::CompressionAlgorithm,
+        security_headers::{PlaintextHttp, SecurityHeaders},
+    },
+    notifications::webhook::WebhookSigner,
+    rate_limit::{KeyPart, KeyStrategy, RouteRateLimit},
+};
+
+/// Workloads that can be given their own Redis instance.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RedisConcern {
+    /// Cached values and their fill locks.
+    Cache,
+    /// Request and token-issuance rate limits.
+    RateLimits,
+    /// Refresh sessions, token revocation and websocket tickets.
+    Sessions,
+}
+
+impl RedisConcern {
+    pub const ALL: [RedisConcern; 3] = [
+        RedisConcern::Cache,
+        RedisConcern::RateLimits,
+        RedisConcern::Sessions,
+    ];
+
+    pub fn env_var(self) -> &'static str {
+        match self {
+            RedisConcern::Cache => "CACHE_REDIS_URL",
+            RedisConcern::RateLimits => "RATE_LIMIT_REDIS_URL",
+            RedisConcern::Sessions => "SESSION_REDIS_URL",
+        }
+    }
+}
+
+/// HS256 keys shorter than the hash output weaken the signature.
+const MIN_JWT_SECRET_BYTES: usize = 32;
+
+#[derive(Clone)]
+pub struct Config {
+    pub bind_addr: String,
+    /// How long in-flight requests get to finish once shutdown starts.
+    pub request_drain_timeout: Duration,
+    /// How long background tasks then get to finish.
+    pub shutdown_grace: Duration,
+    /// Where Prometheus scrapes metrics from; `None` (the default) serves
+    /// none.
+    pub metrics_addr: Option<SocketAddr>,
+    pub redis_url: String,
+    pub redis_password: Option<String>,
+    /// With cluster mode on, `redis_url` may list several comma-separated
+    /// seed nodes.
+    pub redis_cluster: bool,
+    /// Separate instances per concern; each falls back to `redis_url`.
+    /// Password and cluster mode are shared with the primary.
+    pub cache_redis_url: Option<String>,
+    pub rate_limit_redis_url: Option<String>,
+    pub session_redis_url: Option<String>,
+    pub cache_prefix: String,
+    pub cache_namespaces: KeyNamespaces,
+    /// Bytes of the raw value to quote in cache decode errors; 0 (the
+    /// default) quotes nothing.
+    pub cache_decode_snippet_bytes: usize,
+    /// Cache calls at least this slow are logged; `None` turns it off.
+    pub cache_slow_op_threshold: Option<Duration>,
+    /// Prefixes of value keys also cached in memory for
+    /// `cache_local_ttl`; empty (the default) disables it. See
+    /// [`LocalCache`].
+    pub cache_local_keys: Vec<String>,
+    pub cache_local_ttl: Duration,
+    pub cache_local_capacity: u64,
+    pub redis_startup_attempts: u32,
+    pub redis_startup_delay: Duration,
+    pub jwt_secret: String,
+    /// PEM keys for signing access tokens with RS256 instead of
+    /// `jwt_secret`, which then only backs the CSRF and document link
+    /// secrets.
+    pub jwt_private_key: Option<String>,
+    pub jwt_public_key: Option<String>,
+    /// `tenant:version=secret` pairs, current first per tenant, for
+    /// tenants whose tokens are signed with their own key; see
+    /// [`TenantKeys`].
+    pub jwt_tenant_keys: TenantKeys,
+    pub access_token_ttl: Duration,
+    /// Hard cap on access-token age regardless of `exp`; `None` is off.
+    pub max_access_token_age: Option<Duration>,
+    /// Clock skew allowed on access and action token expiry.
+    pub jwt_leeway: Duration,
+    pub refresh_token_ttl: Duration,
+    pub refresh_token_absolute_ttl: Duration,
+    /// Argon2 cost of hashing refresh secrets, paid on every rotation.
+    pub refresh_hash_params: Params,
+    /// How long after a rotation the previous refresh secret still gets
+    /// the rotated token back instead of counting as reuse; zero disables.
+    pub refresh_reuse_grace: Duration,
+    /// How far a detected refresh token reuse reaches.
+    pub refresh_reuse_response: ReuseResponse,
+    /// JWKS of an external IdP whose access tokens are also accepted;
+    /// `None` (the default) accepts only our own.
+    pub external_jwks_url: Option<String>,
+    /// `iss` and `aud` the IdP's tokens must carry.
+    pub external_token_issuer: String,
+    pub external_token_audience: String,
+    pub external_jwks_refresh: Duration,
+    pub audit_max_len: usize,
+    /// How often buffered usage counts are flushed; `None` (the default)
+    /// doesn't meter usage at all.
+    pub usage_flush_interval: Option<Duration>,
+    /// How often blacklist, session and lock keys are counted for the
+    /// `cache_keys` gauges; `None` doesn't count them.
+    pub cache_key_sample_interval: Option<Duration>,
+    pub canonicalize_gmail: bool,
+    pub rate_limit_window: Duration,
+    pub rate_limit_default: u64,
+    pub rate_limit_anonymous: u64,
+    pub rate_limit_tenant_quotas: HashMap<String, u64>,
+    /// Client ranges that are never rate limited.
+    pub rate_limit_bypass: Vec<IpRange>,
+    /// Limits of their own for some routes, keyed by route template.
+    pub rate_limit_routes: HashMap<String, RouteRateLimit>,
+    /// Requests handled at once across the API before further ones are
+    /// shed with a 503; 0 means unlimited. Health checks never count.
+    pub max_in_flight: usize,
+    /// Per-route caps on top of `max_in_flight`, keyed by route template.
+    pub max_in_flight_by_route: HashMap<String, usize>,
+    /// Reports generated at once across all instances; 0 means unlimited.
+    pub report_max_concurrent: u64,
+    /// How long a report waits for a free slot before a 429.
+    pub report_queue_wait: Duration,
+    /// Proxies whose `X-Forwarded-For` is believed; empty trusts none.
+    pub trusted_proxies: Vec<IpRange>,
+    /// Domain whose subdomains name tenants, e.g. `app.com` for
+    /// `acme.app.com`; `None` takes tenants from `X-Tenant-Id` only.
+    pub tenant_base_domain: Option<String>,
+    pub auth_cookie_enabled: bool,
+    pub auth_cookie_name: String,
+    pub auth_cookie_domain: Option<String>,
+    pub auth_cookie_path: String,
+    pub auth_cookie_same_site: SameSite,
+    /// Per-origin overrides of `auth_cookie_same_site`.
+    pub auth_cookie_same_site_by_origin: HashMap<String, SameSite>,
+    /// Signs CSRF tokens; falls back to the JWT secret.
+    pub csrf_secret: Option<String>,
+    pub csrf_token_ttl: Duration,
+    /// Key for signed document links; falls back to `jwt_secret`.
+    pub document_url_secret: Option<String>,
+    pub document_url_ttl: Duration,
+    /// How recently a second factor must have been verified for
+    /// operations that require step-up.
+    pub step_up_max_age: Duration,
+    /// Access tokens one user may be issued per `token_issuance_window`,
+    /// across logins and refreshes; 0 means unlimited.
+    pub token_issuance_limit: u64,
+    pub token_issuance_window: Duration,
+    /// Users the issuance limit doesn't apply to, e.g. service accounts.
+    pub token_issuance_exempt: HashSet<UserId>,
+    /// Live refresh sessions allowed per user; 0 means unlimited.
+    pub max_sessions_per_user: usize,
+    pub session_limit_policy: SessionLimitPolicy,
+    /// Sessions with no activity for this long are ended; `None` is off.
+    pub session_idle_timeout: Option<Duration>,
+    /// Empty disables response compression.
+    pub compression_algorithms: Vec<CompressionAlgorithm>,
+    /// Default shape of error bodies; see [`ErrorFormat`].
+    pub error_format: ErrorFormat,
+    pub compression_min_bytes: u16,
+    /// Security headers sent on every response; `None` leaves one out.
+    pub strict_transport_security: Option<HeaderValue>,
+    pub content_security_policy: Option<HeaderValue>,
+    pub frame_options: Option<HeaderValue>,
+    pub referrer_policy: Option<HeaderValue>,
+    /// What to do with requests trusted proxies received over plain HTTP.
+    pub plaintext_http: PlaintextHttp,
+    /// Logs request and response bodies, redacted; never on by default.
+    pub body_logging: bool,
+    /// Field-name terms whose values are masked in logged bodies.
+    pub body_logging_redact: Vec<String>,
+    pub body_logging_max_bytes: usize,
+    /// `id=secret` pairs, current first, and whether logins for unknown
+    /// accounts are slowed to match (`PASSWORD_EQUALIZE_TIMING`); see
+    /// [`Peppers`].
+    pub password_peppers: Peppers,
+    /// Secrets webhook deliveries are signed with, newest first; see
+    /// [`WebhookSigner`].
+    pub webhook_signing: WebhookSigner,
+    /// Consecutive failed deliveries after which an endpoint's breaker
+    /// opens, and how long it stays open before a probe.
+    pub webhook_breaker_threshold: u32,
+    pub webhook_breaker_cooldown: Duration,
+    /// `id=key` pairs for sensitive columns, current first; see
+    /// [`FieldCipher`].
+    pub field_encryption: FieldCipher,
+    pub field_blind_index: Option<BlindIndex>,
+}
+
+impl Config {
+    pub fn from_env() -> Result<Self> {
+        Self::from_env_with(&NoSecretProvider)
+    }
+
+    /// Like [`Config::from_env`], but consults `secrets` for secret values
+    /// before falling back to plain environment variables. Every problem
+    /// found is returned together as a [`ConfigReport`].
+    pub fn from_env_with(secrets: &dyn SecretProvider) -> Result<Self> {
+        let mut report = ConfigReport::default();
+        let r = &mut report;
+
+        let config = Self {
+            bind_addr: env_or("BIND_ADDR", "127.0.0.1:3000"),
+            request_drain_timeout: Duration::from_secs(r.take(parse_or("REQUEST_DRAIN_SECS", 30))),
+            shutdown_grace: Duration::from_secs(r.take(parse_or("SHUTDOWN_GRACE_SECS", 30))),
+            metrics_addr: r.take(parse_optional("METRICS_ADDR")),
+            redis_url: r
+                .take(load_secret("REDIS_URL", secrets))
+                .unwrap_or_else(|| "redis://127.0.0.1:6379".to_string()),
+            redis_password: r.take(load_secret("REDIS_PASSWORD", secrets)),
+            redis_cluster: r.take(parse_or("REDIS_CLUSTER", false)),
+            cache_redis_url: r.take(load_secret(RedisConcern::Cache.env_var(), secrets)),
+            rate_limit_redis_url: r.take(load_secret(RedisConcern::RateLimits.env_var(), secrets)),
+            session_redis_url: r.take(load_secret(RedisConcern::Sessions.env_var(), secrets)),
+            cache_prefix: env_or("CACHE_PREFIX", "hrapp"),
+            cache_namespaces: parse_namespaces(),
+            cache_decode_snippet_bytes: r.take(parse_or("CACHE_DECODE_SNIPPET_BYTES", 0)),
+            cache_slow_op_threshold: match r.take(parse_or("CACHE_SLOW_OP_MS", 0)) {
+                0 => None,
+                ms => Some(Duration::from_millis(ms)),
+            },
+            cache_local_keys: parse_prefixes(&env_or("CACHE_LOCAL_KEYS", "")),
+            cache_local_ttl: Duration::from_millis(r.take(parse_or("CACHE_LOCAL_TTL_MS", 5000))),
+            cache_local_capacity: r.take(parse_or("CACHE_LOCAL_CAPACITY", 10_000)),
+            redis_startup_attempts: r.take(parse_or("REDIS_STARTUP_ATTEMPTS", 30)),
+            redis_startup_delay: Duration::from_millis(r.take(parse_or(
+                "REDIS_STARTUP_DELAY_MS",
+                1000,
+            ))),
+            jwt_secret: r.take(load_secret("JWT_SECRET", secrets).and_then(|secret| {
+                secret.ok_or_else(|| anyhow!("JWT_SECRET or JWT_SECRET_FILE must be set"))
+            })),
+            jwt_private_key: r.take(load_secret("JWT_PRIVATE_KEY", secrets)),
+            jwt_public_key: r.take(load_secret("JWT_PUBLIC_KEY", secrets)),
+            jwt_tenant_keys: r.take(load_secret("JWT_TENANT_KEYS", secrets).and_then(|raw| {
+                TenantKeys::parse(&raw.unwrap_or_default()).context("JWT_TENANT_KEYS is invalid")
+            })),
+            access_token_ttl: Duration::from_secs(r.take(parse_or("ACCESS_TOKEN_TTL_SECS", 900))),
+            max_access_token_age: match r.take(parse_or("MAX_ACCESS_TOKEN_AGE_SECS", 0)) {
+                0 => None,
+                secs => Some(Duration::from_secs(secs)),
+            },
+            jwt_leeway: Duration::from_secs(r.take(parse_or(
+                "JWT_LEEWAY_SECS",
+                DEFAULT_LEEWAY.as_secs(),
+            ))),
+            refresh_token_ttl: Duration::from_secs(r.take(parse_or(
+                "REFRESH_TOKEN_TTL_SECS",
+                14 * 24 * 3600,
+            ))),
+            refresh_token_absolute_ttl: Duration::from_secs(r.take(parse_or(
+                "REFRESH_TOKEN_ABSOLUTE_TTL_SECS",
+                90 * 24 * 3600,
+            ))),
+            refresh_hash_params: r.take(refresh_hash_params()),
+            refresh_reuse_grace: Duration::from_secs(r.take(parse_or(
+                "REFRESH_REUSE_GRACE_SECS",
+                10,
+            ))),
+            refresh_reuse_response: r.take(
+                ReuseResponse::parse(&env_or("REFRESH_REUSE_RESPONSE", "kill_session"))
+                    .context("REFRESH_REUSE_RESPONSE must be kill_session or kill_all"),
+            ),
+            external_jwks_url: env::var("EXTERNAL_JWKS_URL")
+                .ok()
+                .filter(|url| !url.is_empty()),
+            external_token_issuer: env_or("EXTERNAL_TOKEN_ISSUER", ""),
+            external_token_audience: env_or("EXTERNAL_TOKEN_AUDIENCE", ""),
+            external_jwks_refresh: Duration::from_secs(r.take(parse_or(
+                "EXTERNAL_JWKS_REFRESH_SECS",
+                3600,
+            ))),
+            audit_max_len: r.take(parse_or("AUDIT_MAX_LEN", 100_000)),
+            usage_flush_interval: match r.take(parse_or("USAGE_FLUSH_INTERVAL_SECS", 0)) {
+                0 => None,
+                secs => Some(Duration::from_secs(secs)),
+            },
+            cache_key_sample_interval: match r.take(parse_or("CACHE_KEY_SAMPLE_INTERVAL_SECS", 300)) {
+                0 => None,
+                secs => Some(Duration::from_secs(secs)),
+            },
+            canonicalize_gmail: r.take(parse_or("EMAIL_CANONICALIZE_GMAIL", false)),
+            rate_limit_window: Duration::from_secs(r.take(parse_or("RATE_LIMIT_WINDOW_SECS", 60))),
+            rate_limit_default: r.take(parse_or("RATE_LIMIT_DEFAULT", 600)),
+            rate_limit_anonymous: r.take(parse_or("RATE_LIMIT_ANONYMOUS", 60)),
+            rate_limit_tenant_quotas: r.take(parse_quotas(&env_or("RATE_LIMIT_TENANT_QUOTAS", ""))),
+            rate_limit_bypass: r.take(parse_ranges("RATE_LIMIT_BYPASS_CIDRS")),
+            rate_limit_routes: r.take(parse_route_rate_limits(&env_or("RATE_LIMIT_ROUTES", ""))),
+            max_in_flight: r.take(parse_or("MAX_IN_FLIGHT_REQUESTS", 0)),
+            max_in_flight_by_route: r.take(parse_route_limits(&env_or("MAX_IN_FLIGHT_ROUTES", ""))),
+            report_max_concurrent: r.take(parse_or("REPORT_MAX_CONCURRENT", 4)),
+            report_queue_wait: Duration::from_secs(r.take(parse_or("REPORT_QUEUE_WAIT_SECS", 10))),
+            trusted_proxies: r.take(parse_ranges("TRUSTED_PROXY_CIDRS")),
+            tenant_base_domain: env::var("TENANT_BASE_DOMAIN").ok(),
+            auth_cookie_enabled: r.take(parse_or("AUTH_COOKIE_ENABLED", false)),
+            auth_cookie_name: env_or("AUTH_COOKIE_NAME", "access_token"),
+            auth_cookie_domain: env::var("AUTH_COOKIE_DOMAIN").ok(),
+            auth_cookie_path: env_or("AUTH_COOKIE_PATH", "/"),
+            auth_cookie_same_site: r.take(
+                SameSite::parse(&env_or("AUTH_COOKIE_SAMESITE", "lax"))
+                    .context("AUTH_COOKIE_SAMESITE must be strict, lax or none"),
+            ),
+            auth_cookie_same_site_by_origin: r.take(parse_same_site_by_origin(&env_or(
+                "AUTH_COOKIE_SAMESITE_BY_ORIGIN",
+                "",
+            ))),
+            csrf_secret: r.take(load_secret("CSRF_SECRET", secrets)),
+            csrf_token_ttl: Duration::from_secs(r.take(parse_or("CSRF_TOKEN_TTL_SECS", 86_400))),
+            document_url_secret: r.take(load_secret("DOCUMENT_URL_SECRET", secrets)),
+            document_url_ttl: Duration::from_secs(r.take(parse_or("DOCUMENT_URL_TTL_SECS", 300))),
+            step_up_max_age: Duration::from_secs(r.take(parse_or("STEP_UP_MAX_AGE_SECS", 600))),
+            token_issuance_limit: r.take(parse_or("TOKEN_ISSUANCE_LIMIT", 0)),
+            token_issuance_window: Duration::from_secs(r.take(parse_or(
+                "TOKEN_ISSUANCE_WINDOW_SECS",
+                3600,
+            ))),
+            token_issuance_exempt: r.take(parse_user_ids(&env_or("TOKEN_ISSUANCE_EXEMPT", ""))),
+            max_sessions_per_user: r.take(parse_or("MAX_SESSIONS_PER_USER", 0)),
+            session_limit_policy: r.take(
+                SessionLimitPolicy::parse(&env_or("SESSION_LIMIT_POLICY", "evict_oldest"))
+                    .context("SESSION_LIMIT_POLICY must be reject or evict_oldest"),
+            ),
+            session_idle_timeout: match r.take(parse_or("SESSION_IDLE_TIMEOUT_SECS", 0)) {
+                0 => None,
+                secs => Some(Duration::from_secs(secs)),
+            },
+            error_format: r.take(
+                ErrorFormat::parse(&env_or("ERROR_FORMAT", "simple"))
+                    .context("ERROR_FORMAT must be simple or problem"),
+            ),
+            compression_algorithms: r.take(parse_compression(&env_or(
+                "COMPRESSION_ALGORITHMS",
+                "gzip,br",
+            ))),
+            compression_min_bytes: r.take(parse_or("COMPRESSION_MIN_BYTES", 1024)),
+            strict_transport_security: r.take(parse_header_value(
+                "STRICT_TRANSPORT_SECURITY",
+                "max-age=31536000; includeSubDomains",
+            )),
+            content_security_policy: r.take(parse_header_value(
+                "CONTENT_SECURITY_POLICY",
+                "default-src 'none'; frame-ancestors 'none'",
+            )),
+            frame_options: r.take(parse_header_value("X_FRAME_OPTIONS", "DENY")),
+            referrer_policy: r.take(parse_header_value("REFERRER_POLICY", "no-referrer")),
+            plaintext_http: r.take(
+                PlaintextHttp::parse(&env_or("PLAINTEXT_HTTP", "allow"))
+                    .context("PLAINTEXT_HTTP must be allow, redirect or reject"),
+            ),
+            body_logging: r.take(parse_or("BODY_LOGGING", false)),
+            body_logging_redact: parse_prefixes(&env_or(
+                "BODY_LOGGING_REDACT",
+                "password,secret,ssn,token,authorization,cookie",
+            )),
+            body_logging_max_bytes: r.take(parse_or("BODY_LOGGING_MAX_BYTES", 4096)),
+            password_peppers: r.take(
+                load_secret("PASSWORD_PEPPERS", secrets).and_then(|raw| {
+                    let peppers = Peppers::parse(&raw.unwrap_or_default())
+                        .context("PASSWORD_PEPPERS is invalid")?;
+                    let equalize = parse_or("PASSWORD_EQUALIZE_TIMING", true)?;
+                    Ok(peppers.with_timing_equalization(equalize))
+                }),
+            ),
+            webhook_signing: r.take(
+                load_secret("WEBHOOK_SIGNING_SECRETS", secrets).and_then(|raw| {
+                    WebhookSigner::parse(&raw.unwrap_or_default())
+                        .context("WEBHOOK_SIGNING_SECRETS is invalid")
+                }),
+            ),
+            webhook_breaker_threshold: r.take(parse_or("WEBHOOK_BREAKER_THRESHOLD", 5)),
+            webhook_breaker_cooldown: Duration::from_secs(r.take(parse_or(
+                "WEBHOOK_BREAKER_COOLDOWN_SECS",
+                60,
+            ))),
+            field_encryption: r.take(
+                load_secret("FIELD_ENCRYPTION_KEYS", secrets).and_then(|raw| {
+                    FieldCipher::parse(&raw.unwrap_or_default())
+                        .context("FIELD_ENCRYPTION_KEYS is invalid")
+                }),
+            ),
+            field_blind_index: r.take(
+                load_secret("FIELD_BLIND_INDEX_KEY", secrets).and_then(|raw| {
+                    raw.map(|secret| BlindIndex::new(&secret))
+                        .transpose()
+                        .context("FIELD_BLIND_INDEX_KEY is invalid")
+                }),
+            ),
+        };
+
+        config.validate(&mut report);
+        Ok(report.finish(config)?)
+    }
+
+    /// Checks that only make sense across fields, or on values that parsed
+    /// but can't work.
+    fn validate(&self, report: &mut ConfigReport) {
+        // An empty secret was already reported as missing.
+        let secret_len = self.jwt_secret.len();
+        report.check(secret_len == 0 || secret_len >= MIN_JWT_SECRET_BYTES, || {
+            format!("JWT_SECRET must be at least {MIN_JWT_SECRET_BYTES} bytes, got {secret_len}")
+        });
+
+        report.check(self.jwt_leeway < self.access_token_ttl, || {
+            "JWT_LEEWAY_SECS must be less than ACCESS_TOKEN_TTL_SECS".to_string()
+        });
+        report.check(
+            self.jwt_private_key.is_none() || self.jwt_public_key.is_some(),
+            || "JWT_PRIVATE_KEY needs JWT_PUBLIC_KEY to verify with".to_string(),
+        );
+        if let Err(err) = self.token_service() {
+            report.check(false, || format!("{err:#}"));
+        }
+
+        if let Err(err) = self.redis_target() {
+            report.check(false, || format!("{err:#}"));
+        }
+        for concern in RedisConcern::ALL {
+            if let Err(err) = self.redis_target_for(concern) {
+                report.check(false, || format!("{err:#}"));
+            }
+        }
+
+        report.check(!self.access_token_ttl.is_zero(), || {
+            "ACCESS_TOKEN_TTL_SECS must be greater than 0".to_string()
+        });
+        report.check(self.refresh_token_ttl > self.access_token_ttl, || {
+            format!(
+                "REFRESH_TOKEN_TTL_SECS must be longer than ACCESS_TOKEN_TTL_SECS, got {} <= {}",
+                self.refresh_token_ttl.as_secs(),
+                self.access_token_ttl.as_secs()
+            )
+        });
+        report.check(
+            self.refresh_token_ttl <= self.refresh_token_absolute_ttl,
+            || {
+                format!(
+                    "REFRESH_TOKEN_ABSOLUTE_TTL_SECS must be at least REFRESH_TOKEN_TTL_SECS, got {} < {}",
+                    self.refresh_token_absolute_ttl.as_secs(),
+                    self.refresh_token_ttl.as_secs()
+                )
+            },
+        );
+        let namespaces = &self.cache_namespaces;
+        let names = [
+            &namespaces.values,
+            &namespaces.locks,
+            &namespaces.rate_limits,
+            &namespaces.auth,
+        ];
+        report.check(names.iter().all(|name| !name.is_empty()), || {
+            "CACHE_*_NAMESPACE values must not be empty".to_string()
+        });
+        report.check(
+            names
+                .iter()
+                .enumerate()
+                .all(|(i, name)| !names[i + 1..].contains(name)),
+            || format!("CACHE_*_NAMESPACE values must be distinct, got {namespaces:?}"),
+        );
+
+        report.check(
+            self.token_issuance_limit == 0 || !self.token_issuance_window.is_zero(),
+            || "TOKEN_ISSUANCE_WINDOW_SECS must be greater than 0".to_string(),
+        );
+        report.check(
+            self.session_idle_timeout.is_none_or(|idle| idle >= ACTIVITY_TOUCH_INTERVAL),
+            || {
+                format!(
+                    "SESSION_IDLE_TIMEOUT_SECS must be at least {}s, the activity recording interval",
+                    ACTIVITY_TOUCH_INTERVAL.as_secs()
+                )
+            },
+        );
+        if self.external_jwks_url.is_some() {
+            report.check(
+                !self.external_token_issuer.is_empty() && !self.external_token_audience.is_empty(),
+                || {
+                    "EXTERNAL_TOKEN_ISSUER and EXTERNAL_TOKEN_AUDIENCE are required with EXTERNAL_JWKS_URL"
+                        .to_string()
+                },
+            );
+            report.check(!self.external_jwks_refresh.is_zero(), || {
+                "EXTERNAL_JWKS_REFRESH_SECS must be greater than 0".to_string()
+            });
+        }
+        report.check(!self.rate_limit_window.is_zero(), || {
+            "RATE_LIMIT_WINDOW_SECS must be greater than 0".to_string()
+        });
+        report.check(!self.csrf_token_ttl.is_zero(), || {
+            "CSRF_TOKEN_TTL_SECS must be greater than 0".to_string()
+        });
+        report.check(!self.step_up_max_age.is_zero(), || {
+            "STEP_UP_MAX_AGE_SECS must be greater than 0".to_string()
+        });
+        report.check(self.webhook_breaker_threshold > 0, || {
+            "WEBHOOK_BREAKER_THRESHOLD must be greater than 0".to_string()
+        });
+        report.check(!self.webhook_breaker_cooldown.is_zero(), || {
+            "WEBHOOK_BREAKER_COOLDOWN_SECS must be greater than 0".to_string()
+        });
+        report.check(
+            !self.auth_cookie_enabled || !self.auth_cookie_name.is_empty(),
+            || "AUTH_COOKIE_NAME must not be empty when AUTH_COOKIE_ENABLED is set".to_string(),
+        );
+    }
+
+    /// Connection info for Redis, with `REDIS_PASSWORD` applied on top of
+    /// whatever credentials the URL carries.
+    pub fn redis_connection_info(&self) -> Result<ConnectionInfo> {
+        self.node_connection_info(&self.redis_url, "REDIS_URL")
+    }
+
+    /// The standalone server, or every cluster seed node listed in
+    /// `REDIS_URL` when `REDIS_CLUSTER` is set.
+    pub fn redis_target(&self) -> Result<RedisTarget> {
+        self.target_from(&self.redis_url, "REDIS_URL")
+    }
+
+    /// The dedicated instance for `concern`, or `None` when it shares the
+    /// primary.
+    pub fn redis_target_for(&self, concern: RedisConcern) -> Result<Option<RedisTarget>> {
+        let url = match concern {
+            RedisConcern::Cache => &self.cache_redis_url,
+            RedisConcern::RateLimits => &self.rate_limit_redis_url,
+            RedisConcern::Sessions => &self.session_redis_url,
+        };
+
+        url.as_deref()
+            .map(|url| self.target_from(url, concern.env_var()))
+            .transpose()
+    }
+
+    fn target_from(&self, urls: &str, var: &str) -> Result<RedisTarget> {
+        if !self.redis_cluster {
+            return Ok(RedisTarget::Single(self.node_connection_info(urls, var)?));
+        }
+
+        let nodes = urls
+            .split(',')
+            .map(str::trim)
+            .filter(|url| !url.is_empty())
+            .map(|url| self.node_connection_info(url, var))
+            .collect::<Result<Vec<_>>>()?;
+
+        if nodes.is_empty() {
+            return Err(anyhow!("{var} must list at least one cluster node"));
+        }
+
+        Ok(RedisTarget::Cluster(nodes))
+    }
+
+    fn node_connection_info(&self, url: &str, var: &str) -> Result<ConnectionInfo> {
+        let mut info = url
+            .into_connection_info()
+            .with_context(|| format!("{var} is not a valid redis url"))?;
+
+        if let Some(password) = &self.redis_password {
+            info.redis.password = Some(password.clone());
+        }
+
+        Ok(info)
+    }
+
+    /// Cookie settings for access tokens, or `None` when cookie delivery
+    /// is switched off.
+    pub fn token_cookie(&self) -> Option<TokenCookie> {
+        self.auth_cookie_enabled.then(|| {
+            TokenCookie::new(
+                &self.auth_cookie_name,
+                self.auth_cookie_domain.clone(),
+                &self.auth_cookie_path,
+                self.auth_cookie_same_site,
+            )
+            .with_same_site_by_origin(self.auth_cookie_same_site_by_origin.clone())
+        })
+    }
+
+    /// The external IdP to accept tokens from, or `None` when none is
+    /// configured.
+    pub fn external_issuer(&self) -> Result<Option<ExternalIssuer>> {
+        self.external_jwks_url
+            .as_deref()
+            .map(|url| {
+                Ok(ExternalIssuer::new(
+                    Jwks::new(url)?,
+                    &self.external_token_issuer,
+                    &self.external_token_audience,
+                ))
+            })
+            .transpose()
+    }
+
+    /// Security headers to send, with `X-Content-Type-Options: nosniff`
+    /// always among them, and the plain-HTTP policy.
+    pub fn security_headers(&self) -> SecurityHeaders {
+        let configured = [
+            (STRICT_TRANSPORT_SECURITY, &self.strict_transport_security),
+            (CONTENT_SECURITY_POLICY, &self.content_security_policy),
+            (X_FRAME_OPTIONS, &self.frame_options),
+            (REFERRER_POLICY, &self.referrer_policy),
+        ];
+        configured
+            .into_iter()
+            .filter_map(|(name, value)| Some((name, value.clone()?)))
+            .fold(
+                SecurityHeaders::new()
+                    .with_header(X_CONTENT_TYPE_OPTIONS, HeaderValue::from_static("nosniff")),
+                |headers, (name, value)| headers.with_header(name, value),
+            )
+            .with_plaintext_http(self.plaintext_http, self.trusted_proxies.clone())
+    }
+
+    /// Body logging, if switched on.
+    pub fn body_logging(&self) -> Option<BodyLogging> {
+        self.body_logging.then(|| {
+            self.body_logging_redact
+                .iter()
+                .fold(BodyLogging::new(self.body_logging_max_bytes), |logging, term| {
+                    logging.with_redacted(term)
+                })
+        })
+    }
+
+    /// Access tokens signed with RS256 when `JWT_PUBLIC_KEY` is set,
+    /// with `JWT_SECRET` otherwise.
+    pub fn token_service(&self) -> Result<TokenService> {
+        let tokens = match &self.jwt_public_key {
+            Some(public_pem) => TokenService::from_rsa_pem(
+                self.jwt_private_key.as_deref().map(str::as_bytes),
+                public_pem.as_bytes(),
+                self.access_token_ttl,
+            )
+            .context("JWT_PRIVATE_KEY or JWT_PUBLIC_KEY is invalid")?,
+            None => TokenService::new(&self.jwt_secret, self.access_token_ttl),
+        };
+        Ok(tokens
+            .with_leeway(self.jwt_leeway)
+            .with_refresh_hash_params(self.refresh_hash_params.clone()))
+    }
+
+    pub fn csrf_protection(&self) -> CsrfProtection {
+        let secret = self.csrf_secret.as_deref().unwrap_or(&self.jwt_secret);
+        CsrfProtection::new(secret.as_bytes(), self.csrf_token_ttl)
+    }
+
+    /// The in-process cache for the values namespace, if any keys opt in.
+    pub fn local_cache(&self) -> Option<LocalCache> {
+        if self.cache_local_keys.is_empty() {
+            return None;
+        }
+        let local = LocalCache::new(
+            &self.cache_prefix,
+            self.cache_local_capacity,
+            self.cache_local_ttl,
+        );
+        Some(
+            self.cache_local_keys
+                .iter()
+                .fold(local, |local, prefix| local.with_keys(prefix.clone())),
+        )
+    }
+
+    /// `cache` must be the session cache, where revoked tokens are kept.
+    pub fn signed_urls(&self, cache: CacheService) -> SignedUrls {
+        let secret = self.document_url_secret.as_deref().unwrap_or(&self.jwt_secret);
+        SignedUrls::new(secret.as_bytes(), self.document_url_ttl, cache)
+    }
+}
+
+impl fmt::Debug for Config {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Config")
+            .field("bind_addr", &self.bind_addr)
+            .field("request_drain_timeout", &self.request_drain_timeout)
+            .field("shutdown_grace", &self.shutdown_grace)
+            .field("metrics_addr", &self.metrics_addr)
+            .field("redis_url", &"<redacted>")
+            .field("redis_password", &self.redis_password.as_ref().map(|_| "<redacted>"))
+            .field("redis_cluster", &self.redis_cluster)
+            .field("cache_redis_url", &self.cache_redis_url.as_ref().map(|_| "<redacted>"))
+            .field(
+                "rate_limit_redis_url",
+                &self.rate_limit_redis_url.as_ref().map(|_| "<redacted>"),
+            )
+            .field("session_redis_url", &self.session_redis_url.as_ref().map(|_| "<redacted>"))
+            .field("cache_prefix", &self.cache_prefix)
+            .field("cache_namespaces", &self.cache_namespaces)
+            .field("cache_decode_snippet_bytes", &self.cache_decode_snippet_bytes)
+            .field("cache_slow_op_threshold", &self.cache_slow_op_threshold)
+            .field("cache_local_keys", &self.cache_local_keys)
+            .field("cache_local_ttl", &self.cache_local_ttl)
+            .field("cache_local_capacity", &self.cache_local_capacity)
+            .field("redis_startup_attempts", &self.redis_startup_attempts)
+            .field("redis_startup_delay", &self.redis_startup_delay)
+            .field("jwt_secret", &"<redacted>")
+            .field(
+                "jwt_private_key",
+                &self.jwt_private_key.as_ref().map(|_| "<redacted>"),
+            )
+            .field("jwt_public_key", &self.jwt_public_key.is_some())
+            .field("jwt_tenant_keys", &self.jwt_tenant_keys)
+            .field("access_token_ttl", &self.access_token_ttl)
+            .field("max_access_token_age", &self.max_access_token_age)
+            .field("jwt_leeway", &self.jwt_leeway)
+            .field("refresh_token_ttl", &self.refresh_token_ttl)
+            .field("refresh_token_absolute_ttl", &self.refresh_token_absolute_ttl)
+            .field("refresh_hash_params", &self.refresh_hash_params)
+            .field("refresh_reuse_grace", &self.refresh_reuse_grace)
+            .field("refresh_reuse_response", &self.refresh_reuse_response)
+            .field("external_jwks_url", &self.external_jwks_url)
+            .field("external_token_issuer", &self.external_token_issuer)
+            .field("external_token_audience", &self.external_token_audience)
+            .field("external_jwks_refresh", &self.external_jwks_refresh)
+            .field("audit_max_len", &self.audit_max_len)
+            .field("usage_flush_interval", &self.usage_flush_interval)
+            .field("cache_key_sample_interval", &self.cache_key_sample_interval)
+            .field("canonicalize_gmail", &self.canonicalize_gmail)
+            .field("rate_limit_window", &self.rate_limit_window)
+            .field("rate_limit_default", &self.rate_limit_default)
+            .field("rate_limit_anonymous", &self.rate_limit_anonymous)
+            .field("rate_limit_tenant_quotas", &self.rate_limit_tenant_quotas)
+            .field("rate_limit_bypass", &self.rate_limit_bypass)
+            .field("rate_limit_routes", &self.rate_limit_routes)
+            .field("max_in_flight", &self.max_in_flight)
+            .field("max_in_flight_by_route", &self.max_in_flight_by_route)
+            .field("report_max_concurrent", &self.report_max_concurrent)
+            .field("report_queue_wait", &self.report_queue_wait)
+            .field("trusted_proxies", &self.trusted_proxies)
+            .field("tenant_base_domain", &self.tenant_base_domain)
+            .field("auth_cookie_enabled", &self.auth_cookie_enabled)
+            .field("auth_cookie_name", &self.auth_cookie_name)
+            .field("auth_cookie_domain", &self.auth_cookie_domain)
+            .field("auth_cookie_path", &self.auth_cookie_path)
+            .field("auth_cookie_same_site", &self.auth_cookie_same_site)
+            .field(
+                "auth_cookie_same_site_by_origin",
+                &self.auth_cookie_same_site_by_origin,
+            )
+            .field("csrf_secret", &self.csrf_secret.as_ref().map(|_| "<redacted>"))
+            .field("csrf_token_ttl", &self.csrf_token_ttl)
+            .field(
+                "document_url_secret",
+                &self.document_url_secret.as_ref().map(|_| "<redacted>"),
+            )
+            .field("document_url_ttl", &self.document_url_ttl)
+            .field("step_up_max_age", &self.step_up_max_age)
+            .field("token_issuance_limit", &self.token_issuance_limit)
+            .field("token_issuance_window", &self.token_issuance_window)
+            .field("token_issuance_exempt", &self.token_issuance_exempt)
+            .field("max_sessions_per_user", &self.max_sessions_per_user)
+            .field("session_limit_policy", &self.session_limit_policy)
+            .field("session_idle_timeout", &self.session_idle_timeout)
+            .field("compression_algorithms", &self.compression_algorithms)
+            .field("error_format", &self.error_format)
+            .field("compression_min_bytes", &self.compression_min_bytes)
+            .field("strict_transport_security", &self.strict_transport_security)
+            .field("content_security_policy", &self.content_security_policy)
+            .field("frame_options", &self.frame_options)
+            .field("referrer_policy", &self.referrer_policy)
+            .field("plaintext_http", &self.plaintext_http)
+            .field("body_logging", &self.body_logging)
+            .field("body_logging_redact", &self.body_logging_redact)
+            .field("body_logging_max_bytes", &self.body_logging_max_bytes)
+            .field("password_peppers", &self.password_peppers)
+            .field("webhook_signing", &self.webhook_signing)
+            .field("webhook_breaker_threshold", &self.webhook_breaker_threshold)
+            .field("webhook_breaker_cooldown", &self.webhook_breaker_cooldown)
+            .field("field_encryption", &self.field_encryption)
+            .field("field_blind_index", &self.field_blind_index)
+            .finish()
+    }
+}
+
+fn env_or(name: &str, default: &str) -> String {
+    env::var(name).unwrap_or_else(|_| default.to_string())
+}
+
+/// `argon2`'s default cost unless `REFRESH_HASH_*` override it.
+fn refresh_hash_params() -> Result<Params> {
+    let memory_kib = parse_or("REFRESH_HASH_MEMORY_KIB", Params::DEFAULT_M_COST)?;
+    let iterations = parse_or("REFRESH_HASH_ITERATIONS", Params::DEFAULT_T_COST)?;
+    let parallelism = parse_or("REFRESH_HASH_PARALLELISM", Params::DEFAULT_P_COST)?;
+    Params::new(memory_kib, iterations, parallelism, None)
+        .map_err(|err| anyhow!("REFRESH_HASH_* parameters are invalid: {err}"))
+}
+
+fn parse_or<T>(name: &str, default: T) -> Result<T>
+where
+    T: FromStr,
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    match env::var(name) {
+        Ok(raw) => raw
+            .parse()
+            .with_context(|| format!("{name} has an invalid value: {raw}")),
+        Err(_) => Ok(default),
+    }
+}
+
+/// Like [`parse_or`], with unset or empty meaning `None`.
+fn parse_optional<T>(name: &str) -> Result<Option<T>>
+where
+    T: FromStr,
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    match env::var(name) {
+        Ok(raw) if !raw.is_empty() => raw
+            .parse()
+            .map(Some)
+            .with_context(|| format!("{name} has an invalid value: {raw}")),
+        _ => Ok(None),
+    }
+}
+
+fn parse_namespaces() -> KeyNamespaces {
+    let defaults = KeyNamespaces::default();
+    KeyNamespaces {
+        values: env_or("CACHE_VALUE_NAMESPACE", &defaults.values),
+        locks: env_or("CACHE_LOCK_NAMESPACE", &defaults.locks),
+        rate_limits: env_or("CACHE_RATE_LIMIT_NAMESPACE", &defaults.rate_limits),
+        auth: env_or("CACHE_AUTH_NAMESPACE", &defaults.auth),
+    }
+}
+
+/// Parses a comma-separated list of key prefixes such as `flags:,tenant:config:`.
+fn parse_prefixes(raw: &str) -> Vec<String> {
+    raw.split(',')
+        .map(str::trim)
+        .filter(|prefix| !prefix.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// Parses a comma-separated list of user ids.
+fn parse_user_ids(raw: &str) -> Result<HashSet<UserId>> {
+    raw.split(',')
+        .map(str::trim)
+        .filter(|id| !id.is_empty())
+        .map(|id| {
+            id.parse()
+                .with_context(|| format!("TOKEN_ISSUANCE_EXEMPT has an invalid user id {id}"))
+        })
+        .collect()
+}
+
+/// Parses `tenant=limit` pairs separated by commas.
+/// Comma-separated CIDR ranges or bare addresses.
+fn parse_ranges(name: &str) -> Result<Vec<IpRange>> {
+    env_or(name, "")
+        .split(',')
+        .map(str::trim)
+        .filter(|range| !range.is_empty())
+        .map(|range| range.parse().with_context(|| format!("{name} has an invalid entry")))
+        .collect()
+}
+
+fn parse_quotas(raw: &str) -> Result<HashMap<String, u64>> {
+    raw.split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let (tenant, limit) = entry.split_once('=').with_context(|| {
+                format!("RATE_LIMIT_TENANT_QUOTAS entry {entry} is not tenant=limit")
+            })?;
+            let limit = limit.trim().parse().with_context(|| {
+                format!("RATE_LIMIT_TENANT_QUOTAS has an invalid limit for {tenant}")
+            })?;
+            Ok((tenant.trim().to_string(), limit))
+        })
+        .collect()
+}
+
+/// Parses `route=limit` pairs such as `/admin/audit=4,/users/:id=200`.
+fn parse_route_limits(raw: &str) -> Result<HashMap<String, usize>> {
+    raw.split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let (route, limit) = entry.split_once('=').with_context(|| {
+                format!("MAX_IN_FLIGHT_ROUTES entry {entry} is not route=limit")
+            })?;
+            let limit = limit.trim().parse().with_context(|| {
+                format!("MAX_IN_FLIGHT_ROUTES has an invalid limit for {route}")
+            })?;
+            Ok((route.trim().to_string(), limit))
+        })
+        .collect()
+}
+
+/// Parses `route=limit:parts` entries such as
+/// `/auth/login=10:ip+route,/reports=30:user`, where the parts are some
+/// of `ip`, `user`, `tenant`, `route` and `method`.
+fn parse_route_rate_limits(raw: &str) -> Result<HashMap<String, RouteRateLimit>> {
+    raw.split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let (route, (limit, parts)) = entry
+                .split_once('=')
+                .and_then(|(route, rule)| Some((route, rule.split_once(':')?)))
+                .with_context(|| {
+                    format!("RATE_LIMIT_ROUTES entry {entry} is not route=limit:parts")
+                })?;
+            let limit = limit.trim().parse().with_context(|| {
+                format!("RATE_LIMIT_ROUTES has an invalid limit for {route}")
+            })?;
+            let key = parts.split('+').try_fold(KeyStrategy::new(), |key, part| {
+                KeyPart::parse(part.trim()).map(|part| key.by(part)).with_context(|| {
+                    format!("RATE_LIMIT_ROUTES has an unknown key part {part} for {route}")
+                })
+            })?;
+            Ok((route.trim().to_string(), RouteRateLimit::new(limit, key)))
+        })
+        .collect()
+}
+
+/// Parses `origin=mode` pairs such as
+/// `https://app.example.com=none,https://admin.example.com=strict`.
+fn parse_same_site_by_origin(raw: &str) -> Result<HashMap<String, SameSite>> {
+    raw.split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let (origin, mode) = entry.split_once('=').with_context(|| {
+                format!("AUTH_COOKIE_SAMESITE_BY_ORIGIN entry {entry} is not origin=mode")
+            })?;
+            let mode = SameSite::parse(mode.trim()).with_context(|| {
+                format!("AUTH_COOKIE_SAMESITE_BY_ORIGIN mode for {origin} must be strict, lax or none")
+            })?;
+            Ok((origin.trim().trim_end_matches('/').to_string(), mode))
+        })
+        .collect()
+}
+
+/// Parses a comma-separated list such as `gzip,br`; `none` or an empty
+/// value turns compression off.
+/// A header value, defaulting to `default`; set to an empty string to
+/// send no header at all.
+fn parse_header_value(name: &str, default: &str) -> Result<Option<HeaderValue>> {
+    let raw = env_or(name, default);
+    if raw.trim().is_empty() {
+        return Ok(None);
+    }
+    HeaderValue::from_str(raw.trim())
+        .map(Some)
+        .with_context(|| format!("{name} is not a valid header value"))
+}
+
+fn parse_compression(raw: &str) -> Result<Vec<CompressionAlgorithm>> {
+    raw.split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty() && !name.eq_ignore_ascii_case("none"))
+        .map(|name| {
+            CompressionAlgorithm::parse(name)
+                .with_context(|| format!("COMPRESSION_ALGORITHMS has an unknown algorithm {name}"))
+        })
+        .collect()
+}
@@ -0,0 +1,57 @@
+//! Collects every configuration problem instead of stopping at the first,
+//! so a fresh deployment can be fixed in one pass rather than one restart
+//! per mistake.
+
+use std::fmt;
+
+#[derive(Debug, Default)]
+pub struct ConfigReport {
+    problems: Vec<String>,
+}
+
+impl ConfigReport {
+    /// Records the error, if any, and falls back to `T::default()` so the
+    /// remaining settings still get checked.
+    pub(crate) fn take<T: Default>(&mut self, result: anyhow::Result<T>) -> T {
+        result.unwrap_or_else(|err| {
+            self.problems.push(format!("{err:#}"));
+            T::default()
+        })
+    }
+
+    /// Records `problem` unless `ok` holds.
+    pub(crate) fn check(&mut self, ok: bool, problem: impl FnOnce() -> String) {
+        if !ok {
+            self.problems.push(problem());
+        }
+    }
+
+    pub fn problems(&self) -> &[String] {
+        &self.problems
+    }
+
+    pub(crate) fn finish<T>(self, value: T) -> Result<T, Self> {
+        if self.problems.is_empty() {
+            Ok(value)
+        } else {
+            Err(self)
+        }
+    }
+}
+
+impl fmt::Display for ConfigReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let count = self.problems.len();
+        write!(
+            f,
+            "{count} configuration problem{}:",
+            if count == 1 { "" } else { "s" }
+        )?;
+        for problem in &self.problems {
+            write!(f, "\n  - {problem}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigReport {}
@@ -0,0 +1,44 @@
+use anyhow::{Context, Result};
+use std::{env, fs};
+
+/// A source of secrets other than the process environment, e.g. Vault or
+/// a cloud secrets manager.
+pub trait SecretProvider: Send + Sync {
+    fn get_secret(&self, name: &str) -> Result<Option<String>>;
+}
+
+/// Provider used when no secrets manager is configured.
+pub struct NoSecretProvider;
+
+impl SecretProvider for NoSecretProvider {
+    fn get_secret(&self, _name: &str) -> Result<Option<String>> {
+        Ok(None)
+    }
+}
+
+/// Resolves a secret by name, in order of preference:
+///
+/// 1. `{NAME}_FILE`, a path to a mounted secret (Docker/Kubernetes),
+/// 2. the pluggable `provider`,
+/// 3. the `{NAME}` environment variable.
+///
+/// File contents are trimmed of surrounding whitespace, since most
+/// tooling writes a trailing newline.
+pub fn load_secret(name: &str, provider: &dyn SecretProvider) -> Result<Option<String>> {
+    let file_var = format!("{name}_FILE");
+
+    if let Ok(path) = env::var(&file_var) {
+        let contents = fs::read_to_string(&path)
+            .with_context(|| format!("failed to read {file_var} at {path}"))?;
+        return Ok(Some(contents.trim().to_string()));
+    }
+
+    if let Some(secret) = provider
+        .get_secret(name)
+        .with_context(|| format!("secret provider failed for {name}"))?
+    {
+        return Ok(Some(secret));
+    }
+
+    Ok(env::var(name).ok())
+}
@@ -0,0 +1,343 @@
+use axum::{
+    http::{header::WWW_AUTHENTICATE, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Serialize;
+use serde_json::json;
+use std::{fmt, time::Duration};
+
+use crate::rate_limit::{too_many_requests, RateLimitResult};
+
+#[derive(Debug)]
+pub enum AppError {
+    BadRequest(&'static str),
+    /// The request carried no credentials at all. Answered with a bare
+    /// `WWW-Authenticate: Bearer` challenge, per RFC 6750.
+    MissingCredentials(&'static str),
+    /// Credentials that were presented but refused, e.g. a malformed or
+    /// revoked token; the reason becomes the challenge's
+    /// `error_description`.
+    Unauthorized(&'static str),
+    /// An access token past its `exp`, even allowing for clock skew.
+    /// Unlike other refusals, refreshing and retrying will help.
+    TokenExpired,
+    /// Signed in, but without the recent multi-factor verification the
+    /// operation needs; challenges with the age allowed, per RFC 9470.
+    StepUpRequired(Duration),
+    Forbidden(&'static str),
+    NotFound(&'static str),
+    Conflict(&'static str),
+    /// Well-formed input that breaks a rule, reported per field as a 422.
+    Invalid(Vec<FieldError>),
+    TooManyRequests(RateLimitResult),
+    Internal(anyhow::Error),
+}
+
+/// One rejected input field, reported with [`AppError::Invalid`].
+#[derive(Debug, Clone, Serialize)]
+pub struct FieldError {
+    pub field: &'static str,
+    pub message: String,
+}
+
+/// The catalog of error codes, sent as `code` in every error body so
+/// clients can branch on an error without matching its message.
+///
+/// Codes are stable: once released, one is never renamed or reused for a
+/// different error. New codes may be added, so clients should treat an
+/// unknown one like the status code alone. Lowercased, a code is also
+/// the last segment of the problem `type` URI.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ErrorCode {
+    /// 400: the request is malformed or can't be carried out as asked.
+    BadRequest,
+    /// 401: credentials are missing, invalid or revoked.
+    AuthUnauthorized,
+    /// 401: the access token expired; refresh it and retry.
+    AuthTokenExpired,
+    /// 401: signed in, but the operation needs a recent second factor;
+    /// verify one and retry.
+    AuthStepUpRequired,
+    /// 403: authenticated, but not allowed to do this.
+    AuthForbidden,
+    /// 404.
+    NotFound,
+    /// 409: conflicts with the current state, e.g. a taken email address.
+    Conflict,
+    /// 422: well-formed input breaking a rule; see `fields`.
+    ValidationFailed,
+    /// 429: a rate limit or quota was hit; see `Retry-After`.
+    RateLimited,
+    /// 500.
+    Internal,
+    /// 503: the API is in maintenance mode.
+    Maintenance,
+    /// 503: too many requests in flight; retry shortly.
+    Overloaded,
+}
+
+impl ErrorCode {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ErrorCode::BadRequest => "BAD_REQUEST",
+            ErrorCode::AuthUnauthorized => "AUTH_UNAUTHORIZED",
+            ErrorCode::AuthTokenExpired => "AUTH_TOKEN_EXPIRED",
+            ErrorCode::AuthStepUpRequired => "AUTH_STEP_UP_REQUIRED",
+            ErrorCode::AuthForbidden => "AUTH_FORBIDDEN",
+            ErrorCode::NotFound => "NOT_FOUND",
+            ErrorCode::Conflict => "CONFLICT",
+            ErrorCode::ValidationFailed => "VALIDATION_FAILED",
+            ErrorCode::RateLimited => "RATE_LIMITED",
+            ErrorCode::Internal => "INTERNAL",
+            ErrorCode::Maintenance => "MAINTENANCE",
+            ErrorCode::Overloaded => "OVERLOADED",
+        }
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl AppError {
+    /// Each variant has exactly one code.
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            AppError::BadRequest(_) => ErrorCode::BadRequest,
+            AppError::MissingCredentials(_) | AppError::Unauthorized(_) => {
+                ErrorCode::AuthUnauthorized
+            }
+            AppError::TokenExpired => ErrorCode::AuthTokenExpired,
+            AppError::StepUpRequired(_) => ErrorCode::AuthStepUpRequired,
+            AppError::Forbidden(_) => ErrorCode::AuthForbidden,
+            AppError::NotFound(_) => ErrorCode::NotFound,
+            AppError::Conflict(_) => ErrorCode::Conflict,
+            AppError::Invalid(_) => ErrorCode::ValidationFailed,
+            AppError::TooManyRequests(_) => ErrorCode::RateLimited,
+            AppError::Internal(_) => ErrorCode::Internal,
+        }
+    }
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        let code = self.code();
+        let (status, message) = match self {
+            AppError::BadRequest(reason) => (StatusCode::BAD_REQUEST, reason),
+            AppError::MissingCredentials(reason) => {
+                return unauthorized(code, reason, HeaderValue::from_static("Bearer"));
+            }
+            AppError::Unauthorized(reason) => return invalid_token(code, reason),
+            AppError::TokenExpired => return invalid_token(code, TOKEN_EXPIRED_MESSAGE),
+            AppError::StepUpRequired(max_age) => {
+                let challenge = format!(
+                    "Bearer error=\"insufficient_user_authentication\", \
+                     error_description=\"{STEP_UP_MESSAGE}\", max_age={}",
+                    max_age.as_secs()
+                );
+                let challenge = HeaderValue::from_str(&challenge)
+                    .expect("step-up challenge is a valid header value");
+                return unauthorized(code, STEP_UP_MESSAGE, challenge);
+            }
+            AppError::Forbidden(reason) => (StatusCode::FORBIDDEN, reason),
+            AppError::NotFound(reason) => (StatusCode::NOT_FOUND, reason),
+            AppError::Conflict(reason) => (StatusCode::CONFLICT, reason),
+            AppError::Invalid(fields) => return validation_response(fields),
+            AppError::TooManyRequests(result) => return too_many_requests(&result),
+            AppError::Internal(err) => {
+                tracing::error!(error = ?err, "internal error");
+                (StatusCode::INTERNAL_SERVER_ERROR, "internal server error")
+            }
+        };
+
+        error_response(status, code, message)
+    }
+}
+
+impl From<anyhow::Error> for AppError {
+    fn from(err: anyhow::Error) -> Self {
+        AppError::Internal(err)
+    }
+}
+
+/// What an error response was built from, kept as a response extension
+/// so [`problem_details`](crate::middleware::problem::problem_details) can
+/// re-render it without parsing the body.
+#[derive(Debug, Clone)]
+pub struct ErrorInfo {
+    pub code: ErrorCode,
+    pub detail: String,
+    /// Set for [`AppError::Invalid`]; empty otherwise.
+    pub fields: Vec<FieldError>,
+}
+
+/// The `{"error": message, "code": code}` body every error response
+/// starts out as.
+pub fn error_response(status: StatusCode, code: ErrorCode, message: &str) -> Response {
+    let mut response = (status, Json(json!({ "error": message, "code": code }))).into_response();
+    response.extensions_mut().insert(ErrorInfo {
+        code,
+        detail: message.to_string(),
+        fields: Vec::new(),
+    });
+    response
+}
+
+const STEP_UP_MESSAGE: &str = "recent multi-factor verification required";
+const TOKEN_EXPIRED_MESSAGE: &str = "token expired";
+
+/// A 401 with its `WWW-Authenticate` challenge.
+fn unauthorized(code: ErrorCode, reason: &str, challenge: HeaderValue) -> Response {
+    let mut response = error_response(StatusCode::UNAUTHORIZED, code, reason);
+    response.headers_mut().insert(WWW_AUTHENTICATE, challenge);
+    response
+}
+
+/// A 401 with an RFC 6750 `invalid_token` challenge describing `reason`.
+fn invalid_token(code: ErrorCode, reason: &str) -> Response {
+    let challenge = format!(
+        "Bearer error=\"invalid_token\", error_description=\"{}\"",
+        challenge_description(reason)
+    );
+    let challenge = HeaderValue::from_str(&challenge)
+        .unwrap_or_else(|_| HeaderValue::from_static("Bearer error=\"invalid_token\""));
+    unauthorized(code, reason, challenge)
+}
+
+/// `reason` reduced to what RFC 6750 allows in `error_description`:
+/// printable ASCII other than `"` and `\`.
+fn challenge_description(reason: &str) -> String {
+    reason
+        .chars()
+        .filter(|c| matches!(c, ' '..='~') && !matches!(c, '"' | '\\'))
+        .collect()
+}
+
+/// `{"error": "invalid input", "code": "VALIDATION_FAILED", "fields":
+/// [...]}` with a 422.
+fn validation_response(fields: Vec<FieldError>) -> Response {
+    const MESSAGE: &str = "invalid input";
+    let code = ErrorCode::ValidationFailed;
+
+    let mut response = (
+        StatusCode::UNPROCESSABLE_ENTITY,
+        Json(json!({ "error": MESSAGE, "code": code, "fields": fields })),
+    )
+        .into_response();
+    response.extensions_mut().insert(ErrorInfo {
+        code,
+        detail: MESSAGE.to_string(),
+        fields,
+    });
+    response
+}
+
+/// How error bodies are shaped. Clients sending
+/// `Accept: application/problem+json` get problem details either way.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ErrorFormat {
+    /// `{"error": message, "code": code}`.
+    #[default]
+    Simple,
+    /// RFC 7807 `application/problem+json`.
+    Problem,
+}
+
+impl ErrorFormat {
+    pub fn parse(raw: &str) -> Option<Self> {
+        match raw.to_ascii_lowercase().as_str() {
+            "simple" => Some(Self::Simple),
+            "problem" => Some(Self::Problem),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+    use axum::http::header::RETRY_AFTER;
+    use serde_json::Value;
+
+    async fn body(response: Response) -> Value {
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    #[tokio::test]
+    async fn not_found_is_a_404_with_message_and_code() {
+        let response = AppError::NotFound("user not found").into_response();
+
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(
+            body(response).await,
+            json!({ "error": "user not found", "code": "NOT_FOUND" })
+        );
+    }
+
+    #[tokio::test]
+    async fn too_many_requests_is_a_429_saying_when_to_retry() {
+        let response = AppError::TooManyRequests(RateLimitResult {
+            allowed: false,
+            limit: 10,
+            remaining: 0,
+            reset_after: Duration::from_millis(1500),
+        })
+        .into_response();
+
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[RETRY_AFTER], "2");
+        assert_eq!(body(response).await["code"], "RATE_LIMITED");
+    }
+
+    #[tokio::test]
+    async fn internal_error_hides_its_cause() {
+        let response = AppError::Internal(anyhow!("redis password is hunter2")).into_response();
+
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let body = body(response).await;
+        assert_eq!(
+            body,
+            json!({ "error": "internal server error", "code": "INTERNAL" })
+        );
+        assert!(!body.to_string().contains("hunter2"));
+    }
+
+    #[tokio::test]
+    async fn expired_token_challenges_with_invalid_token() {
+        let response = AppError::TokenExpired.into_response();
+
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            response.headers()[WWW_AUTHENTICATE],
+            "Bearer error=\"invalid_token\", error_description=\"token expired\""
+        );
+        assert_eq!(body(response).await["code"], "AUTH_TOKEN_EXPIRED");
+    }
+
+    #[tokio::test]
+    async fn invalid_input_lists_the_fields() {
+        let response = AppError::Invalid(vec![FieldError {
+            field: "email",
+            message: "must be an email address".to_string(),
+        }])
+        .into_response();
+
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(
+            body(response).await,
+            json!({
+                "error": "invalid input",
+                "code": "VALIDATION_FAILED",
+                "fields": [{ "field": "email", "message": "must be an email address" }],
+            })
+        );
+    }
+}
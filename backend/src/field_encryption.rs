@@ -0,0 +1,214 @@
+//! Application-level encryption for sensitive columns (national ids,
+//! bank details and the like).
+//!
+//! Values are sealed with AES-256-GCM before they are written and opened
+//! after they are read, so the database and its backups only ever hold
+//! ciphertext. A stored value looks like `enc:<key id>:<base64url>`, where
+//! the payload is the 96-bit nonce followed by the ciphertext and tag.
+//! Keys are rotated like [`Peppers`]: new values use the current key,
+//! older ones keep opening with the key they name until they are
+//! re-encrypted (see [`FieldCipher::needs_rotation`]).
+//!
+//! Every value is encrypted under a context, e.g.
+//! `employees.national_id:<employee id>`, which is authenticated but not
+//! stored. A ciphertext copied into another row or column then fails to
+//! open instead of decrypting to someone else's data.
+//!
+//! # Searching
+//!
+//! Ciphertext can't be searched: the same value encrypts differently
+//! every time. Columns that need exact-match lookups store a
+//! [`BlindIndex`] alongside. Prefix, range and fuzzy searches on
+//! encrypted columns are not supported.
+//!
+//! [`Peppers`]: crate::auth::password::Peppers
+
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    Aes256Gcm, Key, Nonce,
+};
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use base64::{
+    engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD},
+    Engine,
+};
+use std::{collections::HashMap, fmt};
+use zeroize::Zeroizing;
+
+use crate::auth::signing::sign;
+
+const ENCRYPTED_PREFIX: &str = "enc:";
+const NONCE_LEN: usize = 12;
+
+/// The keys sensitive fields are encrypted with.
+#[derive(Clone, Default)]
+pub struct FieldCipher {
+    current: Option<String>,
+    keys: HashMap<String, Aes256Gcm>,
+}
+
+impl FieldCipher {
+    /// Parses `id=key` pairs separated by commas, where each key is 32
+    /// bytes in standard base64. The first pair is the current key; the
+    /// rest are kept for opening older values. An empty string means no
+    /// keys, and every [`encrypt`](Self::encrypt) fails.
+    pub fn parse(raw: &str) -> Result<Self> {
+        let mut cipher = Self::default();
+
+        for entry in raw.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let (id, key) = entry
+                .split_once('=')
+                .context("field encryption keys must be id=key")?;
+            ensure!(
+                !id.is_empty()
+                    && id
+                        .bytes()
+                        .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_')),
+                "key id {id} must be non-empty and alphanumeric"
+            );
+            ensure!(!cipher.keys.contains_key(id), "key id {id} is repeated");
+
+            let key = Zeroizing::new(
+                STANDARD
+                    .decode(key)
+                    .with_context(|| format!("key {id} is not valid base64"))?,
+            );
+            ensure!(key.len() == 32, "key {id} must be 32 bytes");
+
+            cipher.current.get_or_insert_with(|| id.to_string());
+            cipher.keys.insert(
+                id.to_string(),
+                Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key)),
+            );
+        }
+
+        Ok(cipher)
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.current.is_some()
+    }
+
+    /// Seals `plaintext` under the current key, bound to `context`.
+    pub fn encrypt(&self, plaintext: &str, context: &str) -> Result<String> {
+        let id = self
+            .current
+            .as_ref()
+            .context("no field encryption key is configured")?;
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let sealed = self.keys[id]
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: plaintext.as_bytes(),
+                    aad: context.as_bytes(),
+                },
+            )
+            .map_err(|_| anyhow!("field encryption failed"))?;
+
+        let mut payload = nonce.to_vec();
+        payload.extend_from_slice(&sealed);
+        Ok(format!(
+            "{ENCRYPTED_PREFIX}{id}:{}",
+            URL_SAFE_NO_PAD.encode(payload)
+        ))
+    }
+
+    /// Opens a value written by [`encrypt`](Self::encrypt) with the same
+    /// `context`. Fails if it was tampered with, moved to another
+    /// context, or names a key that is no longer configured.
+    pub fn decrypt(&self, stored: &str, context: &str) -> Result<Zeroizing<String>> {
+        let Some((id, payload)) = stored
+            .strip_prefix(ENCRYPTED_PREFIX)
+            .and_then(|rest| rest.split_once(':'))
+        else {
+            bail!("value is not an encrypted field");
+        };
+        let cipher = self
+            .keys
+            .get(id)
+            .with_context(|| format!("unknown field encryption key {id}"))?;
+
+        let payload = URL_SAFE_NO_PAD
+            .decode(payload)
+            .context("encrypted field is not valid base64")?;
+        ensure!(payload.len() > NONCE_LEN, "encrypted field is truncated");
+        let (nonce, sealed) = payload.split_at(NONCE_LEN);
+
+        let plaintext = Zeroizing::new(
+            cipher
+                .decrypt(
+                    Nonce::from_slice(nonce),
+                    Payload {
+                        msg: sealed,
+                        aad: context.as_bytes(),
+                    },
+                )
+                .map_err(|_| anyhow!("encrypted field failed authentication"))?,
+        );
+        Ok(Zeroizing::new(
+            String::from_utf8(plaintext.to_vec()).context("decrypted field is not UTF-8")?,
+        ))
+    }
+
+    /// True when `stored` was not encrypted under the current key, so it
+    /// should be re-encrypted the next time its row is written.
+    pub fn needs_rotation(&self, stored: &str) -> bool {
+        let id = stored
+            .strip_prefix(ENCRYPTED_PREFIX)
+            .and_then(|rest| rest.split_once(':'))
+            .map(|(id, _)| id);
+        id != self.current.as_deref()
+    }
+}
+
+/// Shows which key ids are loaded, never the keys.
+impl fmt::Debug for FieldCipher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut ids: Vec<&String> = self.keys.keys().collect();
+        ids.sort();
+
+        f.debug_struct("FieldCipher")
+            .field("current", &self.current)
+            .field("ids", &ids)
+            .finish()
+    }
+}
+
+/// Keyed hash of a sensitive value, stored next to its ciphertext so exact
+/// matches can be found without decrypting every row. The key must differ
+/// from the encryption keys; without it the index can't be computed, so
+/// it can't be used to guess values offline.
+///
+/// Callers normalize values before indexing (trim, case-fold, strip
+/// separators), as `123-45-6789` and `123456789` index differently.
+#[derive(Clone)]
+pub struct BlindIndex {
+    key: Zeroizing<Vec<u8>>,
+}
+
+impl BlindIndex {
+    pub fn new(secret: &str) -> Result<Self> {
+        ensure!(
+            secret.len() >= 32,
+            "blind index secret must be at least 32 characters"
+        );
+        Ok(Self {
+            key: Zeroizing::new(secret.as_bytes().to_vec()),
+        })
+    }
+
+    /// The index of `value` within `column`, e.g. `employees.national_id`.
+    /// Columns index separately, so equal values in different columns
+    /// can't be correlated.
+    pub fn index(&self, column: &str, value: &str) -> String {
+        let message = Zeroizing::new(format!("{column}\0{value}"));
+        sign(&self.key, message.as_bytes())
+    }
+}
+
+impl fmt::Debug for BlindIndex {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("BlindIndex(<redacted>)")
+    }
+}
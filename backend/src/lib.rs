@@ -0,0 +1,25 @@
+pub mod audit;
+pub mod auth;
+pub mod cache;
+pub mod client_ip;
+pub mod config;
+pub mod error;
+pub mod field_encryption;
+pub mod maintenance;
+pub mod metrics;
+pub mod middleware;
+#[cfg(feature = "mtls")]
+pub mod mtls;
+pub mod notifications;
+pub mod queue;
+pub mod rate_limit;
+pub mod reports;
+pub mod routes;
+pub mod shutdown;
+pub mod state;
+pub mod telemetry;
+pub mod tenant;
+pub mod timestamp;
+pub mod usage;
+pub mod users;
+pub mod validation;
@@ -1,57 +1,337 @@
-use axum::{
-    routing::{get, post},
-    Json, Router,
+use anyhow::{Context, Result};
+use axum::{middleware, routing::get, Router};
+use backend::{
+    audit::AuditLog,
+    auth::{
+        auth_service::{AuthService, IssuanceLimit},
+        deactivation::Deactivations,
+        elevation::Elevations,
+        impersonation::Impersonations,
+        policy::DenialAudit,
+        step_up::StepUp,
+    },
+    cache::{
+        cache_service::CacheService,
+        key_stats::KeySampler,
+        redis_client::{RedisClient, RedisClients},
+    },
+    client_ip::ClientIpResolver,
+    config::{Config, RedisConcern},
+    maintenance::Maintenance,
+    middleware::{
+        body_logging::log_bodies,
+        compression::compression_layer,
+        concurrency::{shed_load, ConcurrencyLimits},
+        csrf::csrf,
+        maintenance::maintenance,
+        problem::problem_details,
+        rate_limit::rate_limit,
+        request_id::request_id,
+        security_headers::security_headers,
+    },
+    notifications::{
+        breaker::WebhookBreaker, dispatch::Dispatcher, inbox::Inbox, preferences::PreferenceStore,
+        Notifier,
+    },
+    queue::JobQueue,
+    rate_limit::{RateLimiter, StaticQuotas, TenantRateLimits},
+    reports::ReportSlots,
+    routes,
+    shutdown::{self, Shutdown},
+    state::AppState,
+    telemetry,
+    tenant::TenantResolver,
+    usage::{LogSink, UsageCounters},
 };
-use serde::{Deserialize, Serialize};
+use std::{net::SocketAddr, sync::Arc, time::Duration};
 use tokio::net::TcpListener;
 
+/// How soon after a role elevation lapses its expiry is audited.
+const ELEVATION_SWEEP_INTERVAL: Duration = Duration::from_secs(30);
+/// How soon after its scheduled time an account is deactivated.
+const DEACTIVATION_SWEEP_INTERVAL: Duration = Duration::from_secs(30);
+/// How often expired refresh sessions are dropped from the session indexes.
+const SESSION_PRUNE_INTERVAL: Duration = Duration::from_secs(600);
+/// How long repeat 403s for one user on one resource go unaudited.
+const DENIAL_AUDIT_WINDOW: Duration = Duration::from_secs(300);
+
 #[tokio::main]
-async fn main() {
+async fn main() -> Result<()> {
+    let _telemetry = telemetry::init().context("failed to set up telemetry")?;
+
+    let config = Config::from_env()?;
+    if let Some(addr) = config.metrics_addr {
+        backend::metrics::install(addr).context("failed to start the metrics exporter")?;
+    }
+
+    let primary = RedisClient::connect_when_ready(
+        config.redis_target()?,
+        config.redis_startup_attempts,
+        config.redis_startup_delay,
+    )
+    .await
+    .context("failed to connect to Redis")?;
+
+    let mut redis = RedisClients::shared(primary);
+    redis.cache = connect_dedicated(&config, RedisConcern::Cache, &redis.primary).await?;
+    redis.rate_limits =
+        connect_dedicated(&config, RedisConcern::RateLimits, &redis.primary).await?;
+    redis.sessions = connect_dedicated(&config, RedisConcern::Sessions, &redis.primary).await?;
+
+    let cache = cache_service(&config, redis.primary.clone());
+    let mut cache_values = cache_service(&config, redis.cache.clone())
+        .values()
+        .with_best_effort_writes(true);
+    let rate_limit_cache = cache_service(&config, redis.rate_limits.clone()).rate_limits();
+    let session_cache = cache_service(&config, redis.sessions.clone()).auth();
+    let mut tokens = config
+        .token_service()?
+        .with_refresh_token_ttl(config.refresh_token_ttl, config.refresh_token_absolute_ttl)
+        .with_tenant_keys(config.jwt_tenant_keys.clone());
+    if let Some(max_age) = config.max_access_token_age {
+        tokens = tokens.with_max_token_age(max_age);
+    }
+    tokens.self_check().context("JWT signing self-check failed")?;
+
+    // Background workers are started through this so a deploy lets them
+    // finish in-flight work instead of killing them.
+    let background = Shutdown::new();
+
+    if let Some(local) = config.local_cache() {
+        cache_values = cache_values.with_local_cache(local.clone());
+        background.spawn("local-cache-invalidation", {
+            let redis = redis.cache.clone();
+            move |signal| local.run_invalidation_listener(redis, signal)
+        });
+    }
+
+    if let Some(issuer) = config.external_issuer()? {
+        background.spawn("jwks-refresh", {
+            let jwks = issuer.jwks().clone();
+            let interval = config.external_jwks_refresh;
+            move |signal| jwks.run_refresher(interval, signal)
+        });
+        tokens = tokens.with_external_issuer(issuer);
+    }
+
+    let audit = AuditLog::new(
+        redis.primary.clone(),
+        &config.cache_prefix,
+        config.audit_max_len,
+    );
+
+    let elevations = Elevations::new(session_cache.clone(), audit.clone());
+    background.spawn("elevation-expiry", {
+        let elevations = elevations.clone();
+        move |signal| elevations.run_expiry_sweeper(ELEVATION_SWEEP_INTERVAL, signal)
+    });
+
+    let notifier = Notifier::new(
+        redis.primary.clone(),
+        session_cache.clone(),
+        &config.cache_prefix,
+    );
+    let notifications = Dispatcher::new(
+        PreferenceStore::new(cache.clone()),
+        Inbox::new(cache.clone()),
+        notifier.clone(),
+        JobQueue::new(redis.primary.clone(), config.cache_prefix.clone()),
+    );
+    let webhook_breaker = WebhookBreaker::new(
+        cache.clone(),
+        config.webhook_breaker_threshold,
+        config.webhook_breaker_cooldown,
+    );
+
+    let signed_urls = config.signed_urls(session_cache.clone());
+    let step_up = StepUp::new(session_cache.clone(), config.step_up_max_age);
+    let impersonations = Impersonations::new(tokens.clone(), session_cache.clone(), audit.clone());
+    let deactivations = Deactivations::new(session_cache.clone(), audit.clone());
+    if let Some(interval) = config.cache_key_sample_interval {
+        let sampler = KeySampler::auth_groups(cache.clone(), &session_cache);
+        background.spawn("cache-key-sample", move |signal| {
+            sampler.run_sampler(interval, signal)
+        });
+    }
+    let mut auth = AuthService::new(tokens, session_cache, audit.clone())
+        .with_refresh_reuse_grace(config.refresh_reuse_grace)
+        .with_reuse_response(config.refresh_reuse_response)
+        .with_security_notifications(notifications.clone())
+        .with_elevations(elevations)
+        .with_deactivations(deactivations);
+    if config.max_sessions_per_user > 0 {
+        auth = auth.with_session_limit(config.max_sessions_per_user, config.session_limit_policy);
+    }
+    if let Some(idle_timeout) = config.session_idle_timeout {
+        auth = auth.with_idle_timeout(idle_timeout);
+    }
+    if config.token_issuance_limit > 0 {
+        auth = auth.with_issuance_limit(IssuanceLimit::new(
+            RateLimiter::new(
+                rate_limit_cache.clone(),
+                config.token_issuance_limit,
+                config.token_issuance_window,
+            ),
+            audit.clone(),
+            config.token_issuance_exempt.clone(),
+        ));
+    }
+    background.spawn("deactivation-sweep", {
+        let auth = auth.clone();
+        move |signal| auth.run_deactivation_sweeper(DEACTIVATION_SWEEP_INTERVAL, signal)
+    });
+    background.spawn("session-prune", {
+        let auth = auth.clone();
+        move |signal| auth.run_session_pruner(SESSION_PRUNE_INTERVAL, signal)
+    });
+
+    let usage = config.usage_flush_interval.map(|interval| {
+        let usage = UsageCounters::new(cache.clone());
+        let flusher = usage.clone();
+        background.spawn("usage-flush", move |signal| {
+            flusher.run_flusher(Arc::new(LogSink), interval, signal)
+        });
+        usage
+    });
+
+    let denials = DenialAudit::new(audit.clone(), rate_limit_cache.clone(), DENIAL_AUDIT_WINDOW);
+    let state = AppState {
+        auth,
+        rate_limits: TenantRateLimits::new(
+            rate_limit_cache,
+            config.rate_limit_window,
+            Arc::new(StaticQuotas::new(config.rate_limit_tenant_quotas.clone())),
+            config.rate_limit_default,
+            config.rate_limit_anonymous,
+        )
+        .with_bypass(config.rate_limit_bypass.clone())
+        .with_route_limits(config.rate_limit_routes.clone()),
+        client_ip: ClientIpResolver::new(config.trusted_proxies.clone()),
+        tenants: TenantResolver::new(config.tenant_base_domain.clone()),
+        audit,
+        denials,
+        notifier,
+        notifications,
+        webhook_breaker,
+        reports: ReportSlots::new(
+            cache.clone(),
+            config.report_max_concurrent,
+            config.report_queue_wait,
+        ),
+        claims: cache.auth(),
+        maintenance: Maintenance::new(cache),
+        canonicalize_gmail: config.canonicalize_gmail,
+        token_cookie: config.token_cookie(),
+        csrf: config.csrf_protection(),
+        signed_urls,
+        step_up,
+        impersonations,
+        cache: cache_values,
+        usage,
+        redis,
+    };
+
+    let api = Router::new()
+        .nest("/users", routes::users::router())
+        .nest("/auth", routes::auth::router())
+        .nest("/me/notifications", routes::notifications::router())
+        .nest("/admin", routes::admin::router())
+        .nest("/ws", routes::ws::router())
+        .layer(middleware::from_fn_with_state(state.clone(), csrf))
+        .layer(middleware::from_fn_with_state(state.clone(), maintenance))
+        .layer(middleware::from_fn_with_state(state.clone(), rate_limit))
+        .layer(middleware::from_fn_with_state(
+            ConcurrencyLimits::new(config.max_in_flight, &config.max_in_flight_by_route),
+            shed_load,
+        ));
+
     let app = Router::new()
         .route("/", get(root))
-        .route("/health", get(health))
-        .route("/users", post(create_user));
+        .nest("/health", routes::health::router())
+        .merge(api)
+        .layer(middleware::from_fn_with_state(
+            config.error_format,
+            problem_details,
+        ))
+        .layer(middleware::from_fn_with_state(
+            config.body_logging(),
+            log_bodies,
+        ))
+        .layer(middleware::from_fn_with_state(
+            config.security_headers(),
+            security_headers,
+        ))
+        .layer(middleware::from_fn(request_id))
+        .layer(compression_layer(
+            &config.compression_algorithms,
+            config.compression_min_bytes,
+        ))
+        .with_state(state);
 
-    let listener = TcpListener::bind("127.0.0.1:3000")
+    let listener = TcpListener::bind(&config.bind_addr)
         .await
-        .unwrap();
+        .with_context(|| format!("failed to bind {}", config.bind_addr))?;
 
-    println!("🚀 Server running at http://127.0.0.1:3000");
-
-    axum::serve(listener, app).await.unwrap();
-}
+    tracing::info!(addr = %config.bind_addr, "listening");
 
-async fn root() -> &'static str {
-    "Rust API is running"
-}
+    let served = shutdown::serve_until_terminated(config.request_drain_timeout, |signal| async {
+        #[cfg(feature = "mtls")]
+        if let Some(tls) = backend::mtls::server_config_from_env()? {
+            return backend::mtls::serve(listener, app, tls, signal).await;
+        }
 
-async fn health() -> Json<HealthResponse> {
-    Json(HealthResponse {
-        status: "ok".to_string(),
+        let mut signal = signal;
+        axum::serve(
+            listener,
+            app.into_make_service_with_connect_info::<SocketAddr>(),
+        )
+        .with_graceful_shutdown(async move { signal.cancelled().await })
+        .await
+        .map_err(anyhow::Error::from)
     })
-}
+    .await
+    .context("server failed");
 
-async fn create_user(
-    Json(payload): Json<CreateUserRequest>,
-) -> Json<UserResponse> {
-    Json(UserResponse {
-        id: 1,
-        email: payload.email,
-    })
+    // Workers stop only now, so requests being drained could still rely
+    // on them. The Redis connections close as the last clients drop.
+    background.drain(config.shutdown_grace).await;
+    served
 }
 
-#[derive(Serialize)]
-struct HealthResponse {
-    status: String,
+/// Every cache service gets the same namespaces and diagnostics, whichever
+/// instance it talks to.
+fn cache_service(config: &Config, redis: RedisClient) -> CacheService {
+    let mut cache = CacheService::new(redis, config.cache_prefix.clone())
+        .with_namespaces(config.cache_namespaces.clone());
+    if config.cache_decode_snippet_bytes > 0 {
+        cache = cache.with_decode_snippets(config.cache_decode_snippet_bytes);
+    }
+    if let Some(threshold) = config.cache_slow_op_threshold {
+        cache = cache.with_slow_op_threshold(threshold);
+    }
+    cache
 }
 
-#[derive(Deserialize)]
-struct CreateUserRequest {
-    email: String,
+/// A client for the instance configured for `concern`, or the primary
+/// when there is none.
+async fn connect_dedicated(
+    config: &Config,
+    concern: RedisConcern,
+    primary: &RedisClient,
+) -> Result<RedisClient> {
+    let Some(target) = config.redis_target_for(concern)? else {
+        return Ok(primary.clone());
+    };
+
+    RedisClient::connect_when_ready(
+        target,
+        config.redis_startup_attempts,
+        config.redis_startup_delay,
+    )
+    .await
+    .with_context(|| format!("failed to connect to the Redis at {}", concern.env_var()))
 }
 
-#[derive(Serialize)]
-struct UserResponse {
-    id: i32,
-    email: String,
+async fn root() -> &'static str {
+    "Rust API is running"
 }
@@ -0,0 +1,62 @@
+//! Cluster-wide read-only mode.
+//!
+//! The flag lives in Redis so every instance sees the same state. While it
+//! is set, the maintenance middleware turns writes away with a 503 and
+//! lets reads through, e.g. to run a migration without full downtime.
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::cache::{
+    cache_service::{CacheService, Persistence},
+    keys,
+};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MaintenanceState {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    /// Sent to clients as `Retry-After`.
+    pub retry_after_secs: u64,
+    #[serde(with = "crate::timestamp::millis")]
+    pub since: DateTime<Utc>,
+}
+
+#[derive(Clone)]
+pub struct Maintenance {
+    cache: CacheService,
+}
+
+impl Maintenance {
+    /// `cache` should be the unscoped service, not a namespace view, so a
+    /// cache purge can't switch maintenance off.
+    pub fn new(cache: CacheService) -> Self {
+        Self { cache }
+    }
+
+    pub async fn current(&self) -> Result<Option<MaintenanceState>> {
+        self.cache.get(&keys::maintenance_flag()).await
+    }
+
+    /// Stays on until [`disable`](Self::disable) is called.
+    pub async fn enable(
+        &self,
+        reason: Option<String>,
+        retry_after_secs: u64,
+    ) -> Result<MaintenanceState> {
+        let state = MaintenanceState {
+            reason,
+            retry_after_secs,
+            since: Utc::now(),
+        };
+        self.cache
+            .set(&keys::maintenance_flag(), &state, Persistence::Persist)
+            .await?;
+        Ok(state)
+    }
+
+    pub async fn disable(&self) -> Result<()> {
+        self.cache.delete(&keys::maintenance_flag()).await
+    }
+}
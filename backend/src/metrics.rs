@@ -0,0 +1,231 @@
+//! Prometheus metrics.
+//!
+//! Counters go through the `metrics` facade, which discards them until an
+//! exporter is installed. [`install`] serves them on their own listener
+//! (`METRICS_ADDR`), so the scrape endpoint is never reachable through the
+//! API. Label values only ever come from the enums below, which keeps
+//! cardinality bounded: nothing is labelled per user, session or token.
+
+use anyhow::{Context, Result};
+use metrics::{counter, describe_counter, describe_gauge, gauge};
+use metrics_exporter_prometheus::PrometheusBuilder;
+use std::net::SocketAddr;
+
+/// Starts the Prometheus exporter on `addr`. Call once, inside the
+/// runtime.
+pub fn install(addr: SocketAddr) -> Result<()> {
+    PrometheusBuilder::new()
+        .with_http_listener(addr)
+        .install()
+        .context("failed to start the metrics exporter")?;
+
+    for step in AuthStep::ALL {
+        describe_counter!(step.metric(), step.description());
+    }
+    describe_gauge!(
+        SESSIONS_ACTIVE,
+        "Live refresh sessions, as of the last pruning pass."
+    );
+    describe_counter!(SESSIONS_REVOKED, "Refresh sessions ended before expiry.");
+    describe_counter!(
+        SESSIONS_PRUNED,
+        "Index entries of expired sessions removed by the pruner."
+    );
+    describe_counter!(
+        CACHE_OOM_WRITES,
+        "Writes Redis refused for lack of memory, skipped or failed."
+    );
+    describe_counter!(
+        CACHE_LOCAL_LOOKUPS,
+        "Reads of opted-in keys from the in-process cache, by hit or miss."
+    );
+    describe_counter!(
+        REQUESTS_SHED,
+        "Requests refused with a 503 because an in-flight limit was reached."
+    );
+    describe_gauge!(
+        CACHE_KEYS,
+        "Keys of each group, as of the last sampling pass."
+    );
+    describe_gauge!(
+        CACHE_KEY_BYTES,
+        "Approximate memory taken by each group's keys, as of the last sampling pass."
+    );
+    describe_counter!(
+        REPORTS_REJECTED,
+        "Report generations refused with a 429 because no report slot freed up in time."
+    );
+    describe_counter!(
+        WEBHOOK_BREAKER_TRANSITIONS,
+        "Webhook endpoint breakers opening or closing again."
+    );
+    describe_counter!(
+        WEBHOOK_SHORT_CIRCUITED,
+        "Webhook deliveries held back because the endpoint's breaker was open."
+    );
+    Ok(())
+}
+
+const SESSIONS_ACTIVE: &str = "auth_sessions_active";
+const SESSIONS_REVOKED: &str = "auth_sessions_revoked_total";
+const SESSIONS_PRUNED: &str = "auth_sessions_pruned_total";
+const CACHE_OOM_WRITES: &str = "cache_oom_writes_total";
+const CACHE_LOCAL_LOOKUPS: &str = "cache_local_lookups_total";
+const REQUESTS_SHED: &str = "http_requests_shed_total";
+const REPORTS_REJECTED: &str = "reports_rejected_total";
+const CACHE_KEYS: &str = "cache_keys";
+const CACHE_KEY_BYTES: &str = "cache_key_bytes";
+const WEBHOOK_BREAKER_TRANSITIONS: &str = "webhook_breaker_transitions_total";
+const WEBHOOK_SHORT_CIRCUITED: &str = "webhook_deliveries_short_circuited_total";
+
+/// Which auth counter an outcome is recorded on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthStep {
+    /// Opening a session: `auth_login_total`.
+    Login,
+    /// Exchanging a refresh token: `auth_refresh_total`.
+    Refresh,
+    /// Verifying an access token on a request: `auth_token_total`.
+    Token,
+}
+
+impl AuthStep {
+    const ALL: [AuthStep; 3] = [AuthStep::Login, AuthStep::Refresh, AuthStep::Token];
+
+    fn metric(self) -> &'static str {
+        match self {
+            AuthStep::Login => "auth_login_total",
+            AuthStep::Refresh => "auth_refresh_total",
+            AuthStep::Token => "auth_token_total",
+        }
+    }
+
+    fn description(self) -> &'static str {
+        match self {
+            AuthStep::Login => "Sessions opened or refused, by result and reason.",
+            AuthStep::Refresh => "Refresh token exchanges, by result and reason.",
+            AuthStep::Token => "Access token checks, by result and reason.",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthSuccess {
+    /// A new session and its first tokens.
+    Issued,
+    /// A refresh token spent and replaced.
+    Rotated,
+    /// A retry within the reuse grace window, handed the rotated token.
+    GraceRetry,
+    /// An access token accepted.
+    Verified,
+}
+
+impl AuthSuccess {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            AuthSuccess::Issued => "issued",
+            AuthSuccess::Rotated => "rotated",
+            AuthSuccess::GraceRetry => "grace_retry",
+            AuthSuccess::Verified => "verified",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthFailure {
+    Malformed,
+    InvalidToken,
+    Expired,
+    WrongUse,
+    TooOld,
+    /// Blacklisted, or issued before the user's invalidation watermark.
+    Revoked,
+    /// No live session for the refresh token.
+    UnknownSession,
+    Idle,
+    BadSecret,
+    /// A spent refresh secret presented again; the session is ended.
+    ReuseDetected,
+    IssuanceLimited,
+    SessionLimit,
+    Deactivated,
+}
+
+impl AuthFailure {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            AuthFailure::Malformed => "malformed",
+            AuthFailure::InvalidToken => "invalid_token",
+            AuthFailure::Expired => "expired",
+            AuthFailure::WrongUse => "wrong_use",
+            AuthFailure::TooOld => "too_old",
+            AuthFailure::Revoked => "revoked",
+            AuthFailure::UnknownSession => "unknown_session",
+            AuthFailure::Idle => "idle",
+            AuthFailure::BadSecret => "bad_secret",
+            AuthFailure::ReuseDetected => "reuse_detected",
+            AuthFailure::IssuanceLimited => "issuance_limited",
+            AuthFailure::SessionLimit => "session_limit",
+            AuthFailure::Deactivated => "deactivated",
+        }
+    }
+}
+
+pub fn auth_success(step: AuthStep, reason: AuthSuccess) {
+    counter!(step.metric(), "result" => "success", "reason" => reason.as_str()).increment(1);
+}
+
+pub fn auth_failure(step: AuthStep, reason: AuthFailure) {
+    counter!(step.metric(), "result" => "failure", "reason" => reason.as_str()).increment(1);
+}
+
+pub fn sessions_active(count: u64) {
+    gauge!(SESSIONS_ACTIVE).set(count as f64);
+}
+
+pub fn sessions_revoked(count: u64) {
+    counter!(SESSIONS_REVOKED).increment(count);
+}
+
+pub fn sessions_pruned(count: u64) {
+    counter!(SESSIONS_PRUNED).increment(count);
+}
+
+/// A write refused with `OOM`; `skipped` when it was a best-effort cache
+/// write the caller never saw fail.
+pub fn cache_oom_write(skipped: bool) {
+    let handling = if skipped { "skipped" } else { "failed" };
+    counter!(CACHE_OOM_WRITES, "handling" => handling).increment(1);
+}
+
+pub fn cache_local_lookup(hit: bool) {
+    let result = if hit { "hit" } else { "miss" };
+    counter!(CACHE_LOCAL_LOOKUPS, "result" => result).increment(1);
+}
+
+/// A request turned away by load shedding. `limit` is `global` or a route
+/// from `MAX_IN_FLIGHT_ROUTES`, so it is bounded by configuration.
+pub fn requests_shed(limit: &str) {
+    counter!(REQUESTS_SHED, "limit" => limit.to_string()).increment(1);
+}
+
+/// A sampled key group's size. `group` names a fixed group, not a key.
+pub fn cache_key_footprint(group: &'static str, count: u64, approx_bytes: u64) {
+    gauge!(CACHE_KEYS, "group" => group).set(count as f64);
+    gauge!(CACHE_KEY_BYTES, "group" => group).set(approx_bytes as f64);
+}
+
+pub fn report_rejected() {
+    counter!(REPORTS_REJECTED).increment(1);
+}
+
+/// A breaker opening or closing; `to` is `open` or `closed`. Endpoints
+/// aren't labelled, as there is one per subscriber.
+pub fn webhook_breaker_transition(to: &'static str) {
+    counter!(WEBHOOK_BREAKER_TRANSITIONS, "to" => to).increment(1);
+}
+
+pub fn webhook_short_circuited() {
+    counter!(WEBHOOK_SHORT_CIRCUITED).increment(1);
+}
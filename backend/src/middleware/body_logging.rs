@@ -0,0 +1,162 @@
+//! Opt-in logging of request and response bodies, for debugging
+//! integrations.
+//!
+//! Bodies carry PII and secrets, so only JSON is logged, and only after
+//! every value under a field whose name contains one of the configured
+//! terms (`password`, `token`, ...) has been masked, at any depth. Other
+//! bodies are logged by size and type alone. Headers are never logged, so
+//! neither `Authorization` nor cookies can leak this way.
+//!
+//! Bodies are buffered to be logged; those over [`MAX_BUFFERED_BYTES`],
+//! and streamed ones of unknown length, pass through unread.
+
+use axum::{
+    body::{to_bytes, Body, Bytes, HttpBody},
+    extract::{Request, State},
+    http::{header::CONTENT_TYPE, HeaderMap},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde_json::Value;
+use std::sync::Arc;
+
+use crate::error::AppError;
+
+/// Largest body read into memory for logging.
+pub const MAX_BUFFERED_BYTES: u64 = 1024 * 1024;
+
+const REDACTED: &str = "<redacted>";
+
+#[derive(Debug, Clone)]
+pub struct BodyLogging {
+    /// Lowercased terms; a field is masked if its name contains one.
+    redact: Arc<Vec<String>>,
+    /// Logged bodies are cut off after this many bytes.
+    max_logged_bytes: usize,
+}
+
+impl BodyLogging {
+    pub fn new(max_logged_bytes: usize) -> Self {
+        Self {
+            redact: Arc::default(),
+            max_logged_bytes,
+        }
+    }
+
+    pub fn with_redacted(mut self, term: &str) -> Self {
+        Arc::make_mut(&mut self.redact).push(term.to_ascii_lowercase());
+        self
+    }
+
+    /// What to log for `body`: redacted, truncated JSON, or a summary.
+    fn describe(&self, headers: &HeaderMap, body: &[u8]) -> String {
+        if body.is_empty() {
+            return String::new();
+        }
+        let content_type = headers
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or("unknown type");
+        let json = content_type.contains("json");
+
+        match serde_json::from_slice::<Value>(body).ok().filter(|_| json) {
+            Some(mut value) => {
+                self.redact_value(&mut value);
+                truncate(value.to_string(), self.max_logged_bytes)
+            }
+            None => format!("<{} bytes of {content_type}>", body.len()),
+        }
+    }
+
+    fn redact_value(&self, value: &mut Value) {
+        match value {
+            Value::Object(fields) => {
+                for (name, field) in fields.iter_mut() {
+                    let name = name.to_ascii_lowercase();
+                    if self.redact.iter().any(|term| name.contains(term.as_str())) {
+                        *field = Value::String(REDACTED.to_string());
+                    } else {
+                        self.redact_value(field);
+                    }
+                }
+            }
+            Value::Array(items) => items.iter_mut().for_each(|item| self.redact_value(item)),
+            _ => {}
+        }
+    }
+}
+
+/// Logs the request's and response's bodies at `info` under
+/// `http.body`, when body logging is configured.
+pub async fn log_bodies(
+    State(logging): State<Option<BodyLogging>>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let Some(logging) = logging else {
+        return next.run(req).await;
+    };
+
+    let (parts, body) = req.into_parts();
+    let body = match buffer(body).await {
+        Ok(Buffered::Read(bytes)) => {
+            tracing::info!(
+                target: "http.body",
+                method = %parts.method,
+                path = %parts.uri.path(),
+                body = %logging.describe(&parts.headers, &bytes),
+                "request body"
+            );
+            Body::from(bytes)
+        }
+        Ok(Buffered::Skipped(body)) => body,
+        Err(_) => return AppError::BadRequest("failed to read request body").into_response(),
+    };
+
+    let response = next.run(Request::from_parts(parts, body)).await;
+
+    let (parts, body) = response.into_parts();
+    let body = match buffer(body).await {
+        Ok(Buffered::Read(bytes)) => {
+            tracing::info!(
+                target: "http.body",
+                status = parts.status.as_u16(),
+                body = %logging.describe(&parts.headers, &bytes),
+                "response body"
+            );
+            Body::from(bytes)
+        }
+        Ok(Buffered::Skipped(body)) => body,
+        Err(err) => return AppError::Internal(anyhow::Error::new(err)).into_response(),
+    };
+    Response::from_parts(parts, body)
+}
+
+enum Buffered {
+    Read(Bytes),
+    /// Too large or of unknown length; left as it was.
+    Skipped(Body),
+}
+
+async fn buffer(body: Body) -> Result<Buffered, axum::Error> {
+    match body.size_hint().exact() {
+        Some(len) if len <= MAX_BUFFERED_BYTES => {
+            to_bytes(body, len as usize).await.map(Buffered::Read)
+        }
+        _ => Ok(Buffered::Skipped(body)),
+    }
+}
+
+fn truncate(mut logged: String, max_bytes: usize) -> String {
+    if logged.len() <= max_bytes {
+        return logged;
+    }
+    let mut end = max_bytes;
+    while !logged.is_char_boundary(end) {
+        end -= 1;
+    }
+    let total = logged.len();
+    logged.truncate(end);
+    logged.push_str(&format!("... ({total} bytes)"));
+    logged
+}
@@ -0,0 +1,40 @@
+//! Response compression, negotiated from the client's `Accept-Encoding`.
+//!
+//! The layer sits outermost, so anything inside it (handlers, and any
+//! response cache added later) only ever sees uncompressed bodies. A cache
+//! therefore stores one copy per response, and each encoding is produced
+//! on the way out.
+
+use tower_http::compression::{
+    predicate::{DefaultPredicate, Predicate, SizeAbove},
+    CompressionLayer,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompressionAlgorithm {
+    Gzip,
+    Brotli,
+}
+
+impl CompressionAlgorithm {
+    pub fn parse(raw: &str) -> Option<Self> {
+        match raw.to_ascii_lowercase().as_str() {
+            "gzip" => Some(Self::Gzip),
+            "br" | "brotli" => Some(Self::Brotli),
+            _ => None,
+        }
+    }
+}
+
+/// Compresses with the enabled `algorithms` only. Bodies below `min_size`
+/// bytes, images, gRPC and event streams are passed through untouched.
+/// With no algorithms enabled the layer never compresses.
+pub fn compression_layer(
+    algorithms: &[CompressionAlgorithm],
+    min_size: u16,
+) -> CompressionLayer<impl Predicate> {
+    CompressionLayer::new()
+        .gzip(algorithms.contains(&CompressionAlgorithm::Gzip))
+        .br(algorithms.contains(&CompressionAlgorithm::Brotli))
+        .compress_when(DefaultPredicate::new().and(SizeAbove::new(min_size)))
+}
@@ -0,0 +1,98 @@
+//! Load shedding.
+//!
+//! Caps how many requests are handled at once, overall and per route.
+//! A request arriving while its limit is reached is turned away at once
+//! with a 503 and `Retry-After`, rather than queued: under a spike, fast
+//! refusals keep the requests already admitted fast too. Only the API is
+//! wrapped, so health checks are never shed.
+
+use axum::{
+    extract::{MatchedPath, Request, State},
+    http::{header::RETRY_AFTER, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde_json::json;
+use std::{collections::HashMap, sync::Arc};
+use tokio::sync::Semaphore;
+
+use crate::{
+    error::{ErrorCode, ErrorInfo},
+    metrics,
+};
+
+const OVERLOADED_MESSAGE: &str = "server is overloaded; try again shortly";
+
+/// In-flight limits. Routes are named by their template, as registered,
+/// e.g. `/admin/audit` or `/users/:id`.
+#[derive(Clone, Default)]
+pub struct ConcurrencyLimits {
+    global: Option<Arc<Semaphore>>,
+    routes: Arc<HashMap<String, Arc<Semaphore>>>,
+}
+
+impl ConcurrencyLimits {
+    /// A limit of 0 means unlimited, for `global` and per route alike.
+    pub fn new(global: usize, routes: &HashMap<String, usize>) -> Self {
+        Self {
+            global: (global > 0).then(|| Arc::new(Semaphore::new(global))),
+            routes: Arc::new(
+                routes
+                    .iter()
+                    .filter(|(_, limit)| **limit > 0)
+                    .map(|(route, limit)| (route.clone(), Arc::new(Semaphore::new(*limit))))
+                    .collect(),
+            ),
+        }
+    }
+}
+
+/// Admits the request if both the route's limit and the global one have
+/// room, holding a slot in each until the response is produced.
+pub async fn shed_load(
+    State(limits): State<ConcurrencyLimits>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .and_then(|path| limits.routes.get_key_value(path.as_str()));
+
+    let _route_slot = match route {
+        Some((name, semaphore)) => match semaphore.clone().try_acquire_owned() {
+            Ok(permit) => Some(permit),
+            Err(_) => return overloaded(name),
+        },
+        None => None,
+    };
+    let _global_slot = match &limits.global {
+        Some(semaphore) => match semaphore.clone().try_acquire_owned() {
+            Ok(permit) => Some(permit),
+            Err(_) => return overloaded("global"),
+        },
+        None => None,
+    };
+
+    next.run(req).await
+}
+
+fn overloaded(limit: &str) -> Response {
+    metrics::requests_shed(limit);
+
+    let mut response = (
+        StatusCode::SERVICE_UNAVAILABLE,
+        Json(json!({ "error": OVERLOADED_MESSAGE, "code": ErrorCode::Overloaded })),
+    )
+        .into_response();
+    response.extensions_mut().insert(ErrorInfo {
+        code: ErrorCode::Overloaded,
+        detail: OVERLOADED_MESSAGE.to_string(),
+        fields: Vec::new(),
+    });
+    response
+        .headers_mut()
+        .insert(RETRY_AFTER, HeaderValue::from_static("1"));
+    response
+}
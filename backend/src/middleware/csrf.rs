@@ -0,0 +1,46 @@
+use axum::{
+    extract::{Request, State},
+    http::Method,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+use crate::{
+    auth::{csrf, extractor::bearer_token},
+    error::AppError,
+    state::AppState,
+};
+
+/// Requires a valid CSRF token on state-changing requests authenticated
+/// by the access-token cookie. Everything else passes untouched:
+///
+/// - safe methods, which must not change state anyway;
+/// - bearer-authenticated calls, since a cross-site page can't make the
+///   browser attach an `Authorization` header;
+/// - requests without a usable token cookie, which the handler will
+///   reject (or serve anonymously) on its own.
+pub async fn csrf(State(state): State<AppState>, req: Request, next: Next) -> Response {
+    let safe = matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS);
+    let Some(cookie) = state.token_cookie.as_ref().filter(|_| !safe) else {
+        return next.run(req).await;
+    };
+    if bearer_token(req.headers()).is_some() {
+        return next.run(req).await;
+    }
+
+    let claims = cookie
+        .read(req.headers())
+        .and_then(|token| state.auth.tokens().verify_access_token(token).ok());
+    let Some(claims) = claims else {
+        return next.run(req).await;
+    };
+
+    if !state
+        .csrf
+        .verify_request(req.headers(), &csrf::binding(&claims))
+    {
+        return AppError::Forbidden("missing or invalid CSRF token").into_response();
+    }
+
+    next.run(req).await
+}
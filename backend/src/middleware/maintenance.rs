@@ -0,0 +1,63 @@
+use axum::{
+    extract::{Request, State},
+    http::{header::RETRY_AFTER, HeaderValue, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde_json::json;
+
+use crate::{
+    error::{ErrorCode, ErrorInfo},
+    state::AppState,
+};
+
+const MAINTENANCE_MESSAGE: &str = "service is in maintenance mode; writes are disabled";
+
+/// Still writable during maintenance, or there would be no way out.
+const EXEMPT_PATHS: &[&str] = &["/admin/maintenance"];
+
+/// Rejects writes with a 503 while maintenance mode is on. Safe methods
+/// are passed through without touching Redis. If the flag can't be read
+/// the request is let through, the same trade-off the rate limiter
+/// makes.
+pub async fn maintenance(State(state): State<AppState>, req: Request, next: Next) -> Response {
+    let read_only = matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS);
+    if read_only || EXEMPT_PATHS.contains(&req.uri().path()) {
+        return next.run(req).await;
+    }
+
+    let current = match state.maintenance.current().await {
+        Ok(current) => current,
+        Err(err) => {
+            tracing::warn!(error = ?err, "maintenance flag unavailable, allowing request");
+            return next.run(req).await;
+        }
+    };
+
+    let Some(current) = current else {
+        return next.run(req).await;
+    };
+
+    let mut response = (
+        StatusCode::SERVICE_UNAVAILABLE,
+        Json(json!({
+            "error": MAINTENANCE_MESSAGE,
+            "code": ErrorCode::Maintenance,
+            "reason": current.reason,
+        })),
+    )
+        .into_response();
+    response.extensions_mut().insert(ErrorInfo {
+        code: ErrorCode::Maintenance,
+        detail: match &current.reason {
+            Some(reason) => format!("{MAINTENANCE_MESSAGE} ({reason})"),
+            None => MAINTENANCE_MESSAGE.to_string(),
+        },
+        fields: Vec::new(),
+    });
+    response
+        .headers_mut()
+        .insert(RETRY_AFTER, HeaderValue::from(current.retry_after_secs));
+    response
+}
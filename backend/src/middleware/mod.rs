@@ -0,0 +1,10 @@
+pub mod body_logging;
+pub mod compression;
+pub mod concurrency;
+pub mod csrf;
+pub mod maintenance;
+pub mod problem;
+pub mod rate_limit;
+pub mod request_id;
+pub mod security_headers;
+pub mod signed_url;
@@ -0,0 +1,78 @@
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    http::{
+        header::{ACCEPT, CONTENT_LENGTH, CONTENT_TYPE},
+        HeaderMap, HeaderValue,
+    },
+    middleware::Next,
+    response::Response,
+};
+use serde_json::json;
+
+use crate::error::{ErrorFormat, ErrorInfo};
+
+const PROBLEM_JSON: &str = "application/problem+json";
+
+/// Problem `type` URIs are `urn:hrapp:problem:<code>`, with the
+/// [`ErrorCode`](crate::error::ErrorCode) lowercased, and as stable as
+/// the code.
+const PROBLEM_TYPE_PREFIX: &str = "urn:hrapp:problem:";
+
+/// Rewrites error responses as RFC 7807 problem details when configured
+/// to, or when the client asks for them. Only responses built through
+/// [`error_response`](crate::error::error_response) are touched; their
+/// status and headers (`Retry-After` and the like) are kept.
+pub async fn problem_details(
+    State(format): State<ErrorFormat>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let wanted = format == ErrorFormat::Problem || accepts_problem(req.headers());
+    let instance = req.uri().path().to_string();
+
+    let mut response = next.run(req).await;
+    if !wanted {
+        return response;
+    }
+    let Some(info) = response.extensions_mut().remove::<ErrorInfo>() else {
+        return response;
+    };
+
+    let status = response.status();
+    let mut body = json!({
+        "type": format!(
+            "{PROBLEM_TYPE_PREFIX}{}",
+            info.code.as_str().to_ascii_lowercase()
+        ),
+        "code": info.code,
+        "title": status.canonical_reason().unwrap_or("Error"),
+        "status": status.as_u16(),
+        "detail": info.detail,
+        "instance": instance,
+    });
+    if !info.fields.is_empty() {
+        body["errors"] = json!(info.fields);
+    }
+
+    let (mut parts, _) = response.into_parts();
+    parts.headers.remove(CONTENT_LENGTH);
+    parts
+        .headers
+        .insert(CONTENT_TYPE, HeaderValue::from_static(PROBLEM_JSON));
+    Response::from_parts(parts, Body::from(body.to_string()))
+}
+
+fn accepts_problem(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|media| {
+            media
+                .split(';')
+                .next()
+                .is_some_and(|media| media.trim().eq_ignore_ascii_case(PROBLEM_JSON))
+        })
+}
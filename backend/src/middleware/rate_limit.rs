@@ -0,0 +1,183 @@
+use axum::{
+    extract::{ConnectInfo, MatchedPath, Request, State},
+    middleware::Next,
+    response::Response,
+};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+use crate::{
+    auth::{extractor::request_token, token_service::AccessTokenClaims},
+    rate_limit::{
+        apply_rate_limit_headers, too_many_requests, RateLimitIdentity, RateLimitResult,
+        RequestAttributes,
+    },
+    state::AppState,
+};
+
+/// Counts the request against its tenant's quota, or its client IP when
+/// it carries no valid token, and against its route's own limit if it has
+/// one; it must be within both. Admitted tenant requests are also metered
+/// when usage metering is on, off the request path. Clients in the bypass
+/// allowlist aren't counted at all.
+///
+/// Only the token signature is checked here, not revocation: a revoked
+/// token is still rejected later by the auth extractor, and skipping the
+/// blacklist lookup keeps the limiter to a single Redis round trip. If
+/// Redis is unavailable the request is let through rather than failing
+/// every call.
+pub async fn rate_limit(State(state): State<AppState>, req: Request, next: Next) -> Response {
+    let client = client_ip(&state, &req);
+    if state.rate_limits.bypasses(client) {
+        tracing::debug!(%client, "client is allowlisted, skipping rate limit");
+        return next.run(req).await;
+    }
+
+    let claims = request_token(req.headers(), &state)
+        .and_then(|token| state.auth.tokens().verify_access_token(token).ok());
+    let identity = resolve_identity(claims.as_ref(), client);
+
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or(req.uri().path(), MatchedPath::as_str)
+        .to_string();
+    let method = req.method().clone();
+    let (user, tenant) = match &claims {
+        Some(claims) => (Some(claims.sub.to_string()), claims.tenant_id.clone()),
+        None => (None, None),
+    };
+    let request = RequestAttributes {
+        ip: client,
+        user,
+        tenant,
+        route: &route,
+        method: &method,
+    };
+
+    let result = match check(&state, &identity, &request).await {
+        Ok(result) => result,
+        Err(err) => {
+            tracing::warn!(error = ?err, "rate limiter unavailable, allowing request");
+            return next.run(req).await;
+        }
+    };
+
+    if !result.allowed {
+        return too_many_requests(&result);
+    }
+
+    if let (Some(usage), RateLimitIdentity::Tenant(tenant)) = (&state.usage, &identity) {
+        let usage = usage.clone();
+        let counter = format!("api:tenant:{tenant}");
+        tokio::spawn(async move {
+            if let Err(err) = usage.record(&counter, 1).await {
+                tracing::warn!(error = ?err, "failed to record usage");
+            }
+        });
+    }
+
+    let mut response = next.run(req).await;
+    apply_rate_limit_headers(response.headers_mut(), &result);
+    response
+}
+
+/// The overall quota's result, or the route limit's when the route has
+/// one that is exhausted or closer to it, so the headers show whichever
+/// limit the client will hit first.
+async fn check(
+    state: &AppState,
+    identity: &RateLimitIdentity,
+    request: &RequestAttributes<'_>,
+) -> anyhow::Result<RateLimitResult> {
+    let overall = state.rate_limits.check(identity).await?;
+    if !overall.allowed {
+        return Ok(overall);
+    }
+
+    Ok(match state.rate_limits.check_route(request).await? {
+        Some(own) if !own.allowed || own.remaining < overall.remaining => own,
+        _ => overall,
+    })
+}
+
+fn resolve_identity(claims: Option<&AccessTokenClaims>, client: IpAddr) -> RateLimitIdentity {
+    match claims {
+        Some(claims) => match &claims.tenant_id {
+            Some(tenant_id) => RateLimitIdentity::Tenant(tenant_id.clone()),
+            None => RateLimitIdentity::User(claims.sub.to_string()),
+        },
+        None => RateLimitIdentity::Ip(client.to_string()),
+    }
+}
+
+/// The client address, trusting `X-Forwarded-For` only from configured
+/// proxies. Without connection info (only in tests or odd embeddings)
+/// every request is counted as one unspecified client.
+fn client_ip(state: &AppState, req: &Request) -> IpAddr {
+    let peer = req
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED), |ConnectInfo(addr)| {
+            addr.ip()
+        });
+    state.client_ip.resolve(peer, req.headers())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::Arc, time::Duration};
+
+    use axum::{http::StatusCode, middleware, routing::get, Router};
+    use reqwest::header::RETRY_AFTER;
+
+    use super::*;
+    use crate::rate_limit::{StaticQuotas, TenantRateLimits, X_RATELIMIT_REMAINING};
+
+    async fn serve(state: AppState) -> String {
+        let app = Router::new()
+            .route("/", get(|| async { "ok" }))
+            .layer(middleware::from_fn_with_state(state.clone(), rate_limit))
+            .with_state(state);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        url
+    }
+
+    #[tokio::test]
+    async fn over_the_limit_is_a_429_with_retry_after() {
+        let state = AppState::for_tests().await;
+        let state = AppState {
+            rate_limits: TenantRateLimits::new(
+                state.cache.rate_limits(),
+                Duration::from_secs(60),
+                Arc::new(StaticQuotas::new(HashMap::new())),
+                2,
+                2,
+            ),
+            ..state
+        };
+        let url = serve(state).await;
+        let client = reqwest::Client::new();
+
+        for remaining in ["1", "0"] {
+            let response = client.get(&url).send().await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(response.headers()[X_RATELIMIT_REMAINING], remaining);
+        }
+
+        let response = client.get(&url).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        let retry_after: u64 = response.headers()[RETRY_AFTER]
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!(
+            (1..=60).contains(&retry_after),
+            "Retry-After: {retry_after}"
+        );
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(body["code"], "RATE_LIMITED");
+    }
+}
@@ -0,0 +1,142 @@
+//! Baseline hardening headers, and keeping traffic on HTTPS.
+//!
+//! Every response gets the configured security headers unless the
+//! handler already set its own. The defaults suit an API that never
+//! serves pages: no framing, no sniffing, no referrers, and a CSP that
+//! allows nothing to load.
+//!
+//! TLS ends at the proxy, so whether a request arrived over plain HTTP is
+//! only known from its `X-Forwarded-Proto`, which is believed from trusted
+//! proxies alone. What happens to such requests is up to the
+//! [`PlaintextHttp`] policy.
+
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::{
+        header::{HOST, LOCATION},
+        HeaderName, HeaderValue, Method, StatusCode,
+    },
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use std::{net::SocketAddr, sync::Arc};
+
+use crate::{
+    client_ip::{in_any, IpRange},
+    error::AppError,
+};
+
+const X_FORWARDED_PROTO: HeaderName = HeaderName::from_static("x-forwarded-proto");
+
+/// What to do with a request a trusted proxy received over plain HTTP.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PlaintextHttp {
+    #[default]
+    Allow,
+    /// Sends `GET` and `HEAD` to the same URL over HTTPS with a 308 and
+    /// rejects anything else: a client that already sent a body or
+    /// credentials in the clear shouldn't be told to just send them again.
+    Redirect,
+    /// Refuses with a 403.
+    Reject,
+}
+
+impl PlaintextHttp {
+    pub fn parse(raw: &str) -> Option<Self> {
+        match raw.to_ascii_lowercase().as_str() {
+            "allow" => Some(Self::Allow),
+            "redirect" => Some(Self::Redirect),
+            "reject" => Some(Self::Reject),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SecurityHeaders {
+    headers: Arc<Vec<(HeaderName, HeaderValue)>>,
+    plaintext: PlaintextHttp,
+    trusted_proxies: Arc<[IpRange]>,
+}
+
+impl SecurityHeaders {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds `name: value` to responses that don't set `name` themselves.
+    pub fn with_header(mut self, name: HeaderName, value: HeaderValue) -> Self {
+        Arc::make_mut(&mut self.headers).push((name, value));
+        self
+    }
+
+    /// Applies `policy` to requests that `trusted_proxies` report as
+    /// plain HTTP.
+    pub fn with_plaintext_http(
+        mut self,
+        policy: PlaintextHttp,
+        trusted_proxies: Vec<IpRange>,
+    ) -> Self {
+        self.plaintext = policy;
+        self.trusted_proxies = trusted_proxies.into();
+        self
+    }
+
+    /// Whether a trusted proxy says `req` came in over plain HTTP. The
+    /// first value counts, as the one set by the proxy facing the client.
+    fn is_plaintext(&self, req: &Request) -> bool {
+        let from_proxy = req
+            .extensions()
+            .get::<ConnectInfo<SocketAddr>>()
+            .is_some_and(|ConnectInfo(peer)| in_any(&self.trusted_proxies, peer.ip()));
+        from_proxy
+            && req
+                .headers()
+                .get(X_FORWARDED_PROTO)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.split(',').next())
+                .is_some_and(|proto| proto.trim().eq_ignore_ascii_case("http"))
+    }
+}
+
+/// Adds the security headers, after first turning away plain-HTTP
+/// requests when the policy says to. Refusals get the headers too.
+pub async fn security_headers(
+    State(security): State<SecurityHeaders>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let refused = security.plaintext != PlaintextHttp::Allow && security.is_plaintext(&req);
+    let mut response = if refused {
+        refuse_plaintext(security.plaintext, &req)
+    } else {
+        next.run(req).await
+    };
+
+    let headers = response.headers_mut();
+    for (name, value) in security.headers.iter() {
+        if !headers.contains_key(name) {
+            headers.insert(name.clone(), value.clone());
+        }
+    }
+    response
+}
+
+fn refuse_plaintext(policy: PlaintextHttp, req: &Request) -> Response {
+    let redirectable =
+        policy == PlaintextHttp::Redirect && matches!(*req.method(), Method::GET | Method::HEAD);
+    let location = req
+        .headers()
+        .get(HOST)
+        .and_then(|host| host.to_str().ok())
+        .filter(|_| redirectable)
+        .and_then(|host| {
+            let path = req.uri().path_and_query().map_or("/", |path| path.as_str());
+            HeaderValue::try_from(format!("https://{host}{path}")).ok()
+        });
+
+    match location {
+        Some(location) => (StatusCode::PERMANENT_REDIRECT, [(LOCATION, location)]).into_response(),
+        None => AppError::Forbidden("HTTPS is required").into_response(),
+    }
+}
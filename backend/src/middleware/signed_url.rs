@@ -0,0 +1,44 @@
+use axum::{
+    extract::{Path, Query, Request, State},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+use crate::{
+    auth::{extractor::OptionalAuthUser, signed_url::SignedUrlQuery},
+    error::AppError,
+    state::AppState,
+};
+
+/// Guards a document download route, whose only path parameter is the
+/// document id. The link must verify for that document; the resulting
+/// [`DocumentGrant`](crate::auth::signed_url::DocumentGrant) is added to
+/// the request's extensions.
+///
+/// A caller who also presents credentials must be the user the link was
+/// issued to, so a link forwarded to a colleague doesn't work from their
+/// session. Anonymous requests, e.g. a plain browser download, are
+/// allowed on the strength of the signature alone.
+pub async fn require_signed_url(
+    State(state): State<AppState>,
+    document_id: Option<Path<String>>,
+    query: Option<Query<SignedUrlQuery>>,
+    OptionalAuthUser(caller): OptionalAuthUser,
+    mut req: Request,
+    next: Next,
+) -> Response {
+    let (Some(Path(document_id)), Some(Query(query))) = (document_id, query) else {
+        return AppError::Unauthorized("invalid download link").into_response();
+    };
+
+    let grant = match state.signed_urls.verify(&document_id, &query).await {
+        Ok(grant) => grant,
+        Err(err) => return err.into_response(),
+    };
+    if caller.is_some_and(|caller| caller.sub != grant.user_id) {
+        return AppError::Forbidden("download link was issued to another user").into_response();
+    }
+
+    req.extensions_mut().insert(grant);
+    next.run(req).await
+}
@@ -0,0 +1,197 @@
+//! HTTPS with optional client-certificate authentication, behind the
+//! `mtls` feature.
+//!
+//! Started with `TLS_CERT_FILE` and `TLS_KEY_FILE` set, the server speaks
+//! TLS instead of plain HTTP:
+//!
+//! - `MTLS_CLIENT_CA_FILE`, PEM bundle of CAs that client certificates
+//!   must chain to. Without it no client certificates are asked for.
+//! - `MTLS_REQUIRE_CLIENT_CERT` (default `false`) refuses the handshake
+//!   without one. Left off, certificates are optional at the TLS layer
+//!   and routes that need one take [`ServiceClient`], so people can keep
+//!   using bearer tokens on the same listener.
+//!
+//! A verified certificate's names are attached to every request on that
+//! connection as a [`ClientIdentity`].
+//!
+//! [`ServiceClient`]: crate::auth::extractor::ServiceClient
+
+use anyhow::{Context, Result};
+use axum::{extract::ConnectInfo, Router};
+use hyper::{body::Incoming, Request};
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::conn::auto::Builder,
+};
+use std::{env, fs::File, io::BufReader, sync::Arc};
+use tokio::{net::TcpListener, task::JoinSet};
+use tokio_rustls::{
+    rustls::{
+        crypto::ring::default_provider,
+        pki_types::{CertificateDer, PrivateKeyDer},
+        server::WebPkiClientVerifier,
+        RootCertStore, ServerConfig,
+    },
+    TlsAcceptor,
+};
+use tower_service::Service;
+use x509_parser::{extensions::GeneralName, parse_x509_certificate};
+
+use crate::{auth::client_cert::ClientIdentity, shutdown::ShutdownSignal};
+
+/// The TLS settings from the environment, or `None` to serve plain HTTP.
+pub fn server_config_from_env() -> Result<Option<Arc<ServerConfig>>> {
+    let (Ok(cert_file), Ok(key_file)) = (env::var("TLS_CERT_FILE"), env::var("TLS_KEY_FILE"))
+    else {
+        return Ok(None);
+    };
+    let require_client_cert = match env::var("MTLS_REQUIRE_CLIENT_CERT") {
+        Ok(raw) => raw
+            .parse()
+            .context("MTLS_REQUIRE_CLIENT_CERT must be true or false")?,
+        Err(_) => false,
+    };
+
+    let provider = Arc::new(default_provider());
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()?;
+
+    let builder = match env::var("MTLS_CLIENT_CA_FILE") {
+        Ok(ca_file) => {
+            let mut roots = RootCertStore::empty();
+            for ca in read_certs(&ca_file)? {
+                roots.add(ca).with_context(|| format!("bad CA certificate in {ca_file}"))?;
+            }
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider);
+            let verifier = if require_client_cert {
+                verifier.build()?
+            } else {
+                verifier.allow_unauthenticated().build()?
+            };
+            builder.with_client_cert_verifier(verifier)
+        }
+        Err(_) => builder.with_no_client_auth(),
+    };
+
+    let mut config = builder
+        .with_single_cert(read_certs(&cert_file)?, read_key(&key_file)?)
+        .context("TLS certificate and key don't match")?;
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(Some(Arc::new(config)))
+}
+
+/// Accepts TLS connections on `listener` and serves `app` on each.
+/// Failed handshakes only drop that connection. Once `shutdown` fires it
+/// stops accepting, lets each connection finish its in-flight requests,
+/// and returns when they have all closed.
+pub async fn serve(
+    listener: TcpListener,
+    app: Router,
+    tls: Arc<ServerConfig>,
+    mut shutdown: ShutdownSignal,
+) -> Result<()> {
+    let acceptor = TlsAcceptor::from(tls);
+    let mut connections = JoinSet::new();
+
+    loop {
+        while connections.try_join_next().is_some() {}
+
+        let (stream, remote) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    tracing::warn!(error = ?err, "failed to accept connection");
+                    continue;
+                }
+            },
+            () = shutdown.cancelled() => break,
+        };
+        let acceptor = acceptor.clone();
+        let app = app.clone();
+        let mut shutdown = shutdown.clone();
+
+        connections.spawn(async move {
+            let stream = match acceptor.accept(stream).await {
+                Ok(stream) => stream,
+                Err(err) => {
+                    tracing::debug!(%remote, error = ?err, "TLS handshake failed");
+                    return;
+                }
+            };
+            let identity = stream
+                .get_ref()
+                .1
+                .peer_certificates()
+                .and_then(|chain| chain.first())
+                .and_then(client_identity);
+
+            let service = hyper::service::service_fn(move |mut req: Request<Incoming>| {
+                req.extensions_mut().insert(ConnectInfo(remote));
+                if let Some(identity) = &identity {
+                    req.extensions_mut().insert(identity.clone());
+                }
+                app.clone().call(req)
+            });
+
+            let builder = Builder::new(TokioExecutor::new());
+            let conn = builder.serve_connection_with_upgrades(TokioIo::new(stream), service);
+            tokio::pin!(conn);
+            let result = tokio::select! {
+                result = conn.as_mut() => result,
+                () = shutdown.cancelled() => {
+                    conn.as_mut().graceful_shutdown();
+                    conn.await
+                }
+            };
+            if let Err(err) = result {
+                tracing::debug!(%remote, error = ?err, "connection closed with an error");
+            }
+        });
+    }
+
+    while connections.join_next().await.is_some() {}
+    Ok(())
+}
+
+/// The CN and DNS/URI SANs of an already-verified certificate.
+fn client_identity(der: &CertificateDer<'_>) -> Option<ClientIdentity> {
+    let (_, cert) = parse_x509_certificate(der).ok()?;
+
+    let common_name = cert
+        .subject()
+        .iter_common_name()
+        .next()
+        .and_then(|cn| cn.as_str().ok())
+        .map(str::to_string);
+    let subject_alt_names = match cert.subject_alternative_name() {
+        Ok(Some(san)) => san
+            .value
+            .general_names
+            .iter()
+            .filter_map(|name| match name {
+                GeneralName::DNSName(name) | GeneralName::URI(name) => Some(name.to_string()),
+                _ => None,
+            })
+            .collect(),
+        _ => Vec::new(),
+    };
+
+    Some(ClientIdentity {
+        common_name,
+        subject_alt_names,
+    })
+}
+
+fn read_certs(path: &str) -> Result<Vec<CertificateDer<'static>>> {
+    let file = File::open(path).with_context(|| format!("cannot open {path}"))?;
+    rustls_pemfile::certs(&mut BufReader::new(file))
+        .collect::<Result<_, _>>()
+        .with_context(|| format!("cannot read certificates from {path}"))
+}
+
+fn read_key(path: &str) -> Result<PrivateKeyDer<'static>> {
+    let file = File::open(path).with_context(|| format!("cannot open {path}"))?;
+    rustls_pemfile::private_key(&mut BufReader::new(file))
+        .with_context(|| format!("cannot read private key from {path}"))?
+        .with_context(|| format!("no private key in {path}"))
+}
@@ -0,0 +1,167 @@
+//! Per-endpoint circuit breaker for webhook deliveries.
+//!
+//! A receiver that is down fails every delivery, and retrying each of
+//! them keeps the delivery workers busy with requests that can't succeed.
+//! After `threshold` consecutive failures an endpoint's breaker opens and
+//! deliveries to it are held back without a request. Once `cooldown` has
+//! passed it is half-open: one delivery is let through as a probe, and
+//! success closes the breaker while failure opens it for another
+//! `cooldown`.
+//!
+//! State lives in Redis, so every worker sees the same breaker. The probe
+//! is claimed with a TTL, so a worker that dies mid-probe doesn't leave
+//! the endpoint half-open for good. Endpoints are keyed by a hash of
+//! their URL, which may carry a token of the receiver's.
+
+use anyhow::Result;
+use serde::Serialize;
+use std::time::Duration;
+
+use crate::{
+    cache::{
+        cache_service::{CacheService, KeyTtl, Persistence},
+        keys,
+    },
+    metrics,
+};
+
+/// How long consecutive failures are remembered without a further one.
+const FAILURE_MEMORY: Duration = Duration::from_secs(24 * 3600);
+
+/// An endpoint's breaker, as [`WebhookBreaker::state`] reports it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum BreakerState {
+    Closed { consecutive_failures: u32 },
+    Open { retry_after_secs: u64 },
+    HalfOpen,
+}
+
+/// Whether a delivery may go out now.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Admission {
+    Allowed,
+    /// The half-open probe; report its outcome like any other delivery.
+    Probe,
+    /// Held back. Leave the job for a later attempt, no sooner than
+    /// `retry_after`.
+    Refused {
+        retry_after: Duration,
+    },
+}
+
+#[derive(Clone)]
+pub struct WebhookBreaker {
+    cache: CacheService,
+    threshold: u32,
+    cooldown: Duration,
+}
+
+impl WebhookBreaker {
+    /// Opens after `threshold` consecutive failures, and probes `cooldown`
+    /// after opening.
+    pub fn new(cache: CacheService, threshold: u32, cooldown: Duration) -> Self {
+        Self {
+            cache,
+            threshold: threshold.max(1),
+            cooldown,
+        }
+    }
+
+    /// Asks before delivering to `url`. Every [`Allowed`](Admission::Allowed)
+    /// or [`Probe`](Admission::Probe) must be followed by
+    /// [`record_success`](Self::record_success) or
+    /// [`record_failure`](Self::record_failure).
+    pub async fn admit(&self, url: &str) -> Result<Admission> {
+        if let KeyTtl::Expires(remaining) = self.cache.ttl(&keys::webhook_open(url)).await? {
+            metrics::webhook_short_circuited();
+            return Ok(Admission::Refused {
+                retry_after: remaining,
+            });
+        }
+        if self.failures(url).await? < self.threshold {
+            return Ok(Admission::Allowed);
+        }
+
+        let probing = self
+            .cache
+            .set_if_not_exists(&keys::webhook_probe(url), "1", self.cooldown)
+            .await?;
+        if probing {
+            Ok(Admission::Probe)
+        } else {
+            metrics::webhook_short_circuited();
+            Ok(Admission::Refused {
+                retry_after: self.cooldown,
+            })
+        }
+    }
+
+    /// Closes the breaker, if it wasn't already.
+    pub async fn record_success(&self, url: &str) -> Result<()> {
+        let failures = self.failures(url).await?;
+        if failures == 0 {
+            return Ok(());
+        }
+        self.cache.delete(&keys::webhook_failures(url)).await?;
+        self.cache.delete(&keys::webhook_probe(url)).await?;
+        if failures >= self.threshold {
+            tracing::info!("webhook endpoint recovered, breaker closed");
+            metrics::webhook_breaker_transition("closed");
+        }
+        Ok(())
+    }
+
+    /// Counts a failed delivery, opening the breaker at `threshold` and
+    /// reopening it when a probe fails.
+    pub async fn record_failure(&self, url: &str) -> Result<()> {
+        let failures = self
+            .cache
+            .increment(
+                &keys::webhook_failures(url),
+                1,
+                Persistence::Ttl(FAILURE_MEMORY),
+            )
+            .await?;
+        if failures < i64::from(self.threshold) {
+            return Ok(());
+        }
+
+        self.cache
+            .set(
+                &keys::webhook_open(url),
+                &failures,
+                Persistence::Ttl(self.cooldown),
+            )
+            .await?;
+        self.cache.delete(&keys::webhook_probe(url)).await?;
+        tracing::warn!(
+            consecutive_failures = failures,
+            cooldown_secs = self.cooldown.as_secs(),
+            "webhook endpoint failing, breaker open"
+        );
+        metrics::webhook_breaker_transition("open");
+        Ok(())
+    }
+
+    pub async fn state(&self, url: &str) -> Result<BreakerState> {
+        if let KeyTtl::Expires(remaining) = self.cache.ttl(&keys::webhook_open(url)).await? {
+            return Ok(BreakerState::Open {
+                retry_after_secs: remaining.as_secs().max(1),
+            });
+        }
+        let failures = self.failures(url).await?;
+        Ok(if failures < self.threshold {
+            BreakerState::Closed {
+                consecutive_failures: failures,
+            }
+        } else {
+            BreakerState::HalfOpen
+        })
+    }
+
+    async fn failures(&self, url: &str) -> Result<u32> {
+        let failures: Option<i64> = self.cache.get(&keys::webhook_failures(url)).await?;
+        Ok(failures.map_or(0, |n| u32::try_from(n).unwrap_or(u32::MAX)))
+    }
+}
@@ -0,0 +1,100 @@
+//! Fans an event out to the channels a user has enabled.
+//!
+//! In-app notifications are stored and pushed to open websockets right
+//! away, as both are a Redis write. Email and webhook deliveries go onto
+//! the [`DELIVERY_STREAM`] job queue as [`DeliveryJob`]s, so a slow mail
+//! server or a dead webhook endpoint is retried by a worker instead of
+//! holding up whoever raised the event.
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    auth::ids::UserId,
+    notifications::{
+        inbox::Inbox,
+        preferences::{Channel, PreferenceStore},
+        Notification, Notifier,
+    },
+    queue::JobQueue,
+};
+
+pub const DELIVERY_STREAM: &str = "notifications";
+
+/// One notification for one user on one out-of-process channel.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeliveryJob {
+    pub channel: Channel,
+    pub user_id: UserId,
+    pub notification: Notification,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub webhook_url: Option<String>,
+}
+
+#[derive(Clone)]
+pub struct Dispatcher {
+    preferences: PreferenceStore,
+    inbox: Inbox,
+    notifier: Notifier,
+    queue: JobQueue,
+}
+
+impl Dispatcher {
+    pub fn new(
+        preferences: PreferenceStore,
+        inbox: Inbox,
+        notifier: Notifier,
+        queue: JobQueue,
+    ) -> Self {
+        Self {
+            preferences,
+            inbox,
+            notifier,
+            queue,
+        }
+    }
+
+    pub fn preferences(&self) -> &PreferenceStore {
+        &self.preferences
+    }
+
+    pub fn inbox(&self) -> &Inbox {
+        &self.inbox
+    }
+
+    /// Delivers `notification` on every channel `user_id` has enabled and
+    /// returns those channels.
+    pub async fn dispatch(
+        &self,
+        user_id: UserId,
+        notification: Notification,
+    ) -> Result<Vec<Channel>> {
+        let preferences = self.preferences.get(user_id).await?;
+
+        for &channel in &preferences.channels {
+            match channel {
+                Channel::InApp => {
+                    self.inbox.push(user_id, notification.clone()).await?;
+                    // Live delivery is a courtesy; the inbox already has it.
+                    if let Err(err) = self.notifier.notify_user(user_id, &notification).await {
+                        tracing::warn!(%user_id, error = ?err, "failed to publish notification");
+                    }
+                }
+                Channel::Email | Channel::Webhook => {
+                    let job = DeliveryJob {
+                        channel,
+                        user_id,
+                        notification: notification.clone(),
+                        webhook_url: match channel {
+                            Channel::Webhook => preferences.webhook_url.clone(),
+                            _ => None,
+                        },
+                    };
+                    self.queue.enqueue(DELIVERY_STREAM, &job).await?;
+                }
+            }
+        }
+
+        Ok(preferences.channels.into_iter().collect())
+    }
+}
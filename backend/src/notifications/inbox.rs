@@ -0,0 +1,182 @@
+//! Stored in-app notifications.
+//!
+//! Each user's inbox is one JSON document holding the newest
+//! [`MAX_ITEMS`] notifications and the unread count, so the count is
+//! always consistent with the items and reading it is a single `GET`.
+//! Writers update the document with compare-and-set rather than a lock,
+//! retrying when another write got there first. The document expires
+//! [`RETENTION`] after it was last written.
+
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use uuid::Uuid;
+
+use crate::{
+    auth::ids::UserId,
+    cache::{
+        cache_service::{CacheService, Persistence},
+        keys,
+    },
+    notifications::Notification,
+};
+
+pub const MAX_ITEMS: usize = 100;
+pub const RETENTION: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// A concurrent writer winning this many times in a row means something
+/// is hammering one inbox; better to fail than spin.
+const MAX_UPDATE_ATTEMPTS: usize = 5;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InboxItem {
+    pub id: Uuid,
+    #[serde(flatten)]
+    pub notification: Notification,
+    pub read: bool,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct InboxState {
+    /// Newest first.
+    items: Vec<InboxItem>,
+    unread: usize,
+}
+
+impl InboxState {
+    fn recount(&mut self) {
+        self.unread = self.items.iter().filter(|item| !item.read).count();
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct InboxPage {
+    pub items: Vec<InboxItem>,
+    pub unread: usize,
+}
+
+#[derive(Clone)]
+pub struct Inbox {
+    cache: CacheService,
+}
+
+impl Inbox {
+    pub fn new(cache: CacheService) -> Self {
+        Self { cache }
+    }
+
+    /// Stores `notification` as unread, dropping the oldest items past
+    /// [`MAX_ITEMS`].
+    pub async fn push(&self, user_id: UserId, notification: Notification) -> Result<InboxItem> {
+        let item = InboxItem {
+            id: Uuid::new_v4(),
+            notification,
+            read: false,
+        };
+
+        self.update(user_id, |state| {
+            state.items.insert(0, item.clone());
+            state.items.truncate(MAX_ITEMS);
+            state.recount();
+        })
+        .await?;
+        Ok(item)
+    }
+
+    /// Up to `limit` notifications, newest first, plus the unread count.
+    pub async fn page(
+        &self,
+        user_id: UserId,
+        limit: usize,
+        unread_only: bool,
+    ) -> Result<InboxPage> {
+        let state = self.load(user_id).await?;
+
+        Ok(InboxPage {
+            items: state
+                .items
+                .into_iter()
+                .filter(|item| !unread_only || !item.read)
+                .take(limit)
+                .collect(),
+            unread: state.unread,
+        })
+    }
+
+    pub async fn unread_count(&self, user_id: UserId) -> Result<usize> {
+        Ok(self.load(user_id).await?.unread)
+    }
+
+    /// Marks one notification read and returns the new unread count, or
+    /// `None` if there is no such notification.
+    pub async fn mark_read(&self, user_id: UserId, id: Uuid) -> Result<Option<usize>> {
+        if !self
+            .load(user_id)
+            .await?
+            .items
+            .iter()
+            .any(|item| item.id == id)
+        {
+            return Ok(None);
+        }
+
+        let state = self
+            .update(user_id, |state| {
+                if let Some(item) = state.items.iter_mut().find(|item| item.id == id) {
+                    item.read = true;
+                }
+                state.recount();
+            })
+            .await?;
+        Ok(Some(state.unread))
+    }
+
+    pub async fn mark_all_read(&self, user_id: UserId) -> Result<()> {
+        self.update(user_id, |state| {
+            state.items.iter_mut().for_each(|item| item.read = true);
+            state.recount();
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn load(&self, user_id: UserId) -> Result<InboxState> {
+        Ok(self
+            .cache
+            .get(&keys::notification_inbox(user_id))
+            .await?
+            .unwrap_or_default())
+    }
+
+    async fn update(
+        &self,
+        user_id: UserId,
+        change: impl Fn(&mut InboxState),
+    ) -> Result<InboxState> {
+        let key = keys::notification_inbox(user_id);
+
+        for _ in 0..MAX_UPDATE_ATTEMPTS {
+            let current: Option<InboxState> = self.cache.get(&key).await?;
+            let mut next = current.clone().unwrap_or_default();
+            change(&mut next);
+
+            let written = match &current {
+                Some(current) => {
+                    self.cache
+                        .compare_and_set(&key, current, &next, Persistence::Ttl(RETENTION))
+                        .await?
+                }
+                None => {
+                    self.cache
+                        .set_if_absent(&key, &next, Persistence::Ttl(RETENTION))
+                        .await?
+                }
+            };
+            if written {
+                return Ok(next);
+            }
+        }
+
+        bail!("inbox of {user_id} kept changing; gave up after {MAX_UPDATE_ATTEMPTS} attempts")
+    }
+}
@@ -0,0 +1,155 @@
+//! Real-time notifications over Redis pub/sub.
+//!
+//! Events are published to a channel per user and per tenant, and every
+//! websocket connection holds its own subscription to the channels of
+//! whoever opened it. Delivery is best-effort: nobody subscribed means
+//! nobody sees the event, so anything that must not be missed has to be
+//! stored elsewhere as well.
+//!
+//! Browsers can't set headers on a websocket handshake, and a JWT in the
+//! query string ends up in access logs, so connections authenticate with
+//! a short-lived single-use ticket obtained over normal authenticated
+//! HTTP instead.
+//!
+//! Events users should see later too go through the [`dispatch`]
+//! module, which stores them in the [`inbox`] as well.
+
+pub mod breaker;
+pub mod dispatch;
+pub mod inbox;
+pub mod preferences;
+pub mod webhook;
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use futures_util::{Stream, StreamExt};
+use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::time::Duration;
+
+use crate::{
+    auth::{
+        ids::UserId,
+        token_service::{generate_secret, AccessTokenClaims},
+    },
+    cache::{
+        cache_service::{CacheService, Persistence},
+        keys,
+        redis_client::RedisClient,
+    },
+};
+
+const TICKET_BYTES: usize = 32;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Notification {
+    pub kind: String,
+    pub payload: Value,
+    #[serde(with = "crate::timestamp::millis")]
+    pub at: DateTime<Utc>,
+}
+
+impl Notification {
+    pub fn new(kind: impl Into<String>, payload: Value) -> Self {
+        Self {
+            kind: kind.into(),
+            payload,
+            at: Utc::now(),
+        }
+    }
+}
+
+/// Who a redeemed ticket was issued to.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Ticket {
+    pub user_id: UserId,
+    pub tenant_id: Option<String>,
+}
+
+#[derive(Clone)]
+pub struct Notifier {
+    redis: RedisClient,
+    cache: CacheService,
+    prefix: String,
+    ticket_ttl: Duration,
+}
+
+impl Notifier {
+    pub fn new(redis: RedisClient, cache: CacheService, prefix: &str) -> Self {
+        Self {
+            redis,
+            cache,
+            prefix: prefix.to_string(),
+            ticket_ttl: Duration::from_secs(30),
+        }
+    }
+
+    pub fn ticket_ttl(&self) -> Duration {
+        self.ticket_ttl
+    }
+
+    fn user_channel(&self, user_id: UserId) -> String {
+        format!("{}:notify:user:{user_id}", self.prefix)
+    }
+
+    fn tenant_channel(&self, tenant_id: &str) -> String {
+        format!("{}:notify:tenant:{tenant_id}", self.prefix)
+    }
+
+    /// Returns how many connections received the event.
+    pub async fn notify_user(&self, user_id: UserId, notification: &Notification) -> Result<u64> {
+        self.publish(&self.user_channel(user_id), notification)
+            .await
+    }
+
+    pub async fn notify_tenant(&self, tenant_id: &str, notification: &Notification) -> Result<u64> {
+        self.publish(&self.tenant_channel(tenant_id), notification)
+            .await
+    }
+
+    async fn publish(&self, channel: &str, notification: &Notification) -> Result<u64> {
+        let mut conn = self.redis.connection();
+        let payload = serde_json::to_string(notification)?;
+        Ok(conn.publish(channel, payload).await?)
+    }
+
+    /// A ticket that lets `claims`' owner open one websocket within
+    /// [`ticket_ttl`](Self::ticket_ttl).
+    pub async fn issue_ticket(&self, claims: &AccessTokenClaims) -> Result<String> {
+        let ticket = generate_secret(TICKET_BYTES).to_string();
+        let owner = Ticket {
+            user_id: claims.sub,
+            tenant_id: claims.tenant_id.clone(),
+        };
+
+        self.cache
+            .set(
+                &keys::ws_ticket(&ticket),
+                &owner,
+                Persistence::Ttl(self.ticket_ttl),
+            )
+            .await?;
+        Ok(ticket)
+    }
+
+    /// Spends `ticket`. Unknown, expired and already used tickets all come
+    /// back as `None`.
+    pub async fn redeem_ticket(&self, ticket: &str) -> Result<Option<Ticket>> {
+        self.cache.take(&keys::ws_ticket(ticket)).await
+    }
+
+    /// Raw JSON events for the ticket owner's user and tenant channels.
+    /// The stream ends if the Redis connection drops.
+    pub async fn subscribe(&self, owner: &Ticket) -> Result<impl Stream<Item = String> + Send> {
+        let mut pubsub = self.redis.pubsub().await?;
+        pubsub.subscribe(self.user_channel(owner.user_id)).await?;
+        if let Some(tenant_id) = &owner.tenant_id {
+            pubsub.subscribe(self.tenant_channel(tenant_id)).await?;
+        }
+
+        Ok(pubsub
+            .into_on_message()
+            .filter_map(|msg| async move { msg.get_payload::<String>().ok() }))
+    }
+}
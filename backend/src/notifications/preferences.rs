@@ -0,0 +1,90 @@
+//! Which channels each user wants notifications on.
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+
+use crate::{
+    auth::ids::UserId,
+    cache::{
+        cache_service::{CacheService, Persistence},
+        keys,
+    },
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Channel {
+    Email,
+    InApp,
+    Webhook,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Preferences {
+    #[serde(default = "default_channels")]
+    pub channels: BTreeSet<Channel>,
+    /// Where [`Channel::Webhook`] deliveries are POSTed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub webhook_url: Option<String>,
+}
+
+/// Users who never chose get in-app notifications only.
+impl Default for Preferences {
+    fn default() -> Self {
+        Self {
+            channels: default_channels(),
+            webhook_url: None,
+        }
+    }
+}
+
+fn default_channels() -> BTreeSet<Channel> {
+    BTreeSet::from([Channel::InApp])
+}
+
+impl Preferences {
+    /// Why these preferences can't be saved, if they can't.
+    pub fn problem(&self) -> Option<&'static str> {
+        if !self.channels.contains(&Channel::Webhook) {
+            return None;
+        }
+
+        match &self.webhook_url {
+            Some(url) if url.starts_with("https://") => None,
+            Some(_) => Some("webhook_url must be an https url"),
+            None => Some("the webhook channel needs a webhook_url"),
+        }
+    }
+}
+
+/// Preferences are kept without expiry; they are user settings, not a
+/// cache of something stored elsewhere.
+#[derive(Clone)]
+pub struct PreferenceStore {
+    cache: CacheService,
+}
+
+impl PreferenceStore {
+    pub fn new(cache: CacheService) -> Self {
+        Self { cache }
+    }
+
+    pub async fn get(&self, user_id: UserId) -> Result<Preferences> {
+        Ok(self
+            .cache
+            .get(&keys::notification_preferences(user_id))
+            .await?
+            .unwrap_or_default())
+    }
+
+    pub async fn set(&self, user_id: UserId, preferences: &Preferences) -> Result<()> {
+        self.cache
+            .set(
+                &keys::notification_preferences(user_id),
+                preferences,
+                Persistence::Persist,
+            )
+            .await
+    }
+}
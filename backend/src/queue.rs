@@ -0,0 +1,299 @@
+//! Background-job queue over Redis Streams.
+//!
+//! Jobs are appended with `XADD` and consumed through a consumer group, so
+//! every job is delivered to one worker at a time and stays in the
+//! group's pending list until it is acknowledged. A worker that crashes
+//! (or whose handler fails) leaves its jobs pending; once they have been
+//! idle for `claim_idle`, another worker takes them over with
+//! `XAUTOCLAIM`. A job delivered more than `max_retries + 1` times is
+//! moved to `<stream>:dead` instead of being retried again.
+
+use anyhow::{anyhow, Context, Result};
+use redis::{
+    streams::{
+        StreamId, StreamPendingCountReply, StreamRangeReply, StreamReadOptions, StreamReadReply,
+    },
+    AsyncCommands, FromRedisValue, Value,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{future::Future, marker::PhantomData, time::Duration};
+
+use crate::{cache::redis_client::RedisClient, shutdown::ShutdownSignal};
+
+const PAYLOAD_FIELD: &str = "payload";
+
+#[derive(Clone)]
+pub struct JobQueue {
+    redis: RedisClient,
+    prefix: String,
+    max_retries: u32,
+    claim_idle: Duration,
+}
+
+/// A job handed to a worker. `id` is the stream entry id used to ack it.
+#[derive(Debug)]
+pub struct Delivery<T> {
+    pub id: String,
+    pub job: T,
+}
+
+pub struct Consumer<T> {
+    queue: JobQueue,
+    stream: String,
+    group: String,
+    name: String,
+    _job: PhantomData<fn() -> T>,
+}
+
+impl JobQueue {
+    pub fn new(redis: RedisClient, prefix: impl Into<String>) -> Self {
+        Self {
+            redis,
+            prefix: prefix.into(),
+            max_retries: 5,
+            claim_idle: Duration::from_secs(60),
+        }
+    }
+
+    pub fn with_max_retries(mut self, max_retries: u32) -> Self {
+        self.max_retries = max_retries;
+        self
+    }
+
+    /// How long a job may sit unacknowledged before another worker may
+    /// reclaim it. Should comfortably exceed the slowest handler.
+    pub fn with_claim_idle(mut self, claim_idle: Duration) -> Self {
+        self.claim_idle = claim_idle;
+        self
+    }
+
+    fn stream_key(&self, stream: &str) -> String {
+        format!("{}:queue:{}", self.prefix, stream)
+    }
+
+    fn dead_letter_key(&self, stream: &str) -> String {
+        format!("{}:dead", self.stream_key(stream))
+    }
+
+    /// Appends a job and returns its stream entry id.
+    pub async fn enqueue<T: Serialize>(&self, stream: &str, job: &T) -> Result<String> {
+        let mut conn = self.redis.connection();
+        let payload = serde_json::to_string(job)?;
+
+        Ok(conn
+            .xadd(self.stream_key(stream), "*", &[(PAYLOAD_FIELD, payload)])
+            .await?)
+    }
+
+    /// Joins (creating if needed) consumer group `group` on `stream` as the
+    /// worker `name`. Names should be stable per worker process so its own
+    /// pending jobs are recognisable after a restart.
+    pub async fn consumer<T: DeserializeOwned>(
+        &self,
+        stream: &str,
+        group: &str,
+        name: &str,
+    ) -> Result<Consumer<T>> {
+        let mut conn = self.redis.connection();
+        let created: redis::RedisResult<()> = conn
+            .xgroup_create_mkstream(self.stream_key(stream), group, "0")
+            .await;
+
+        match created {
+            Ok(()) => {}
+            Err(err) if err.code() == Some("BUSYGROUP") => {}
+            Err(err) => return Err(err.into()),
+        }
+
+        Ok(Consumer {
+            queue: self.clone(),
+            stream: stream.to_string(),
+            group: group.to_string(),
+            name: name.to_string(),
+            _job: PhantomData,
+        })
+    }
+}
+
+impl<T: DeserializeOwned> Consumer<T> {
+    /// Returns up to `count` jobs: stale jobs reclaimed from other workers
+    /// first, otherwise new ones, blocking up to `block` for them to arrive.
+    pub async fn fetch(&self, count: usize, block: Duration) -> Result<Vec<Delivery<T>>> {
+        let claimed = self.claim_stale(count).await?;
+        if !claimed.is_empty() {
+            return Ok(claimed);
+        }
+
+        let mut conn = self.queue.redis.connection();
+        let options = StreamReadOptions::default()
+            .group(&self.group, &self.name)
+            .count(count)
+            .block(block.as_millis() as usize);
+
+        let reply: Option<StreamReadReply> = conn
+            .xread_options(&[self.queue.stream_key(&self.stream)], &[">"], &options)
+            .await?;
+
+        let entries = reply
+            .into_iter()
+            .flat_map(|reply| reply.keys)
+            .flat_map(|key| key.ids);
+
+        let mut deliveries = Vec::new();
+        for entry in entries {
+            if let Some(delivery) = self.decode(entry).await? {
+                deliveries.push(delivery);
+            }
+        }
+
+        Ok(deliveries)
+    }
+
+    /// Acknowledges and removes a processed job.
+    pub async fn ack(&self, delivery: &Delivery<T>) -> Result<()> {
+        self.ack_id(&delivery.id).await
+    }
+
+    /// Processes jobs until `shutdown` fires. Jobs whose handler succeeds
+    /// are acked; jobs whose handler fails stay pending and are retried
+    /// after `claim_idle`. On shutdown no further batch is fetched, but the
+    /// one in hand is finished first. Jobs a cut-short fetch had already
+    /// been handed stay pending and are claimed like any other.
+    pub async fn run<F, Fut>(
+        &self,
+        batch: usize,
+        block: Duration,
+        mut shutdown: ShutdownSignal,
+        handler: F,
+    ) -> Result<()>
+    where
+        F: Fn(T) -> Fut,
+        Fut: Future<Output = Result<()>>,
+    {
+        while !shutdown.is_shutting_down() {
+            let deliveries = tokio::select! {
+                deliveries = self.fetch(batch, block) => deliveries?,
+                () = shutdown.cancelled() => break,
+            };
+
+            for delivery in deliveries {
+                let id = delivery.id.clone();
+                let Delivery { job, .. } = delivery;
+
+                match handler(job).await {
+                    Ok(()) => self.ack_id(&id).await?,
+                    Err(err) => tracing::warn!(
+                        stream = %self.stream,
+                        id = %id,
+                        error = ?err,
+                        "job failed, leaving pending for retry"
+                    ),
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn ack_id(&self, id: &str) -> Result<()> {
+        let mut conn = self.queue.redis.connection();
+        let key = self.queue.stream_key(&self.stream);
+
+        redis::pipe()
+            .xack(&key, &self.group, &[id])
+            .ignore()
+            .xdel(&key, &[id])
+            .ignore()
+            .query_async::<_, ()>(&mut conn)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn claim_stale(&self, count: usize) -> Result<Vec<Delivery<T>>> {
+        let mut conn = self.queue.redis.connection();
+        let reply: Value = redis::cmd("XAUTOCLAIM")
+            .arg(self.queue.stream_key(&self.stream))
+            .arg(&self.group)
+            .arg(&self.name)
+            .arg(self.queue.claim_idle.as_millis() as u64)
+            .arg("0-0")
+            .arg("COUNT")
+            .arg(count)
+            .query_async(&mut conn)
+            .await?;
+
+        let Value::Bulk(parts) = reply else {
+            return Err(anyhow!("unexpected XAUTOCLAIM reply"));
+        };
+        let claimed = parts
+            .get(1)
+            .map(StreamRangeReply::from_redis_value)
+            .transpose()?
+            .unwrap_or_default();
+
+        let mut deliveries = Vec::new();
+        for entry in claimed.ids {
+            if self.delivery_count(&entry.id).await? > self.queue.max_retries as usize + 1 {
+                self.dead_letter(&entry).await?;
+                continue;
+            }
+
+            if let Some(delivery) = self.decode(entry).await? {
+                deliveries.push(delivery);
+            }
+        }
+
+        Ok(deliveries)
+    }
+
+    async fn delivery_count(&self, id: &str) -> Result<usize> {
+        let mut conn = self.queue.redis.connection();
+        let pending: StreamPendingCountReply = conn
+            .xpending_count(self.queue.stream_key(&self.stream), &self.group, id, id, 1)
+            .await?;
+
+        Ok(pending
+            .ids
+            .first()
+            .map(|pending| pending.times_delivered)
+            .unwrap_or(0))
+    }
+
+    async fn dead_letter(&self, entry: &StreamId) -> Result<()> {
+        let payload: String = entry.get(PAYLOAD_FIELD).unwrap_or_default();
+        let mut conn = self.queue.redis.connection();
+
+        conn.xadd::<_, _, _, _, ()>(
+            self.queue.dead_letter_key(&self.stream),
+            "*",
+            &[
+                (PAYLOAD_FIELD, payload.as_str()),
+                ("original_id", entry.id.as_str()),
+            ],
+        )
+        .await?;
+
+        tracing::warn!(stream = %self.stream, id = %entry.id, "job moved to dead-letter stream");
+        self.ack_id(&entry.id).await
+    }
+
+    /// Jobs that can't be decoded will never succeed, so they go straight
+    /// to the dead-letter stream.
+    async fn decode(&self, entry: StreamId) -> Result<Option<Delivery<T>>> {
+        let payload: Option<String> = entry.get(PAYLOAD_FIELD);
+        let job = payload
+            .as_deref()
+            .context("job is missing its payload")
+            .and_then(|raw| serde_json::from_str(raw).context("job payload is not valid"));
+
+        match job {
+            Ok(job) => Ok(Some(Delivery { id: entry.id, job })),
+            Err(err) => {
+                tracing::warn!(stream = %self.stream, id = %entry.id, error = ?err, "undecodable job");
+                self.dead_letter(&entry).await?;
+                Ok(None)
+            }
+        }
+    }
+}
@@ -0,0 +1,382 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use axum::{
+    http::{header::RETRY_AFTER, HeaderMap, HeaderName, HeaderValue, Method, StatusCode},
+    response::Response,
+};
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    cache::cache_service::{CacheService, Persistence},
+    client_ip::{in_any, IpRange},
+    error::{error_response, ErrorCode},
+};
+
+pub const X_RATELIMIT_LIMIT: HeaderName = HeaderName::from_static("x-ratelimit-limit");
+pub const X_RATELIMIT_REMAINING: HeaderName = HeaderName::from_static("x-ratelimit-remaining");
+pub const X_RATELIMIT_RESET: HeaderName = HeaderName::from_static("x-ratelimit-reset");
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimitResult {
+    pub allowed: bool,
+    pub limit: u64,
+    pub remaining: u64,
+    /// Time until the current window rolls over.
+    pub reset_after: Duration,
+}
+
+/// The inputs behind a [`RateLimitResult`]; see
+/// [`RateLimiter::check_limit_explained`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimitDiagnostics {
+    pub result: RateLimitResult,
+    pub current_window_count: i64,
+    pub previous_window_count: i64,
+    /// Sliding-window estimate compared against the limit.
+    pub estimated: u64,
+    /// Wall-clock time the current window rolls over.
+    pub resets_at: SystemTime,
+}
+
+/// Sliding-window limiter: the count for the current fixed window is
+/// blended with the previous window's count, weighted by how much of the
+/// previous window still overlaps the sliding interval.
+#[derive(Clone)]
+pub struct RateLimiter {
+    cache: CacheService,
+    limit: u64,
+    window: Duration,
+}
+
+impl RateLimiter {
+    pub fn new(cache: CacheService, limit: u64, window: Duration) -> Self {
+        Self {
+            cache,
+            limit,
+            window,
+        }
+    }
+
+    pub async fn check(&self, key: &str) -> Result<RateLimitResult> {
+        self.check_limit(key, self.limit).await
+    }
+
+    /// Like [`check`](Self::check) but with a per-call limit, for callers
+    /// whose quota depends on who is asking.
+    pub async fn check_limit(&self, key: &str, limit: u64) -> Result<RateLimitResult> {
+        Ok(self.check_limit_explained(key, limit).await?.result)
+    }
+
+    /// [`check_limit`](Self::check_limit) plus the counts that decided it
+    /// and the absolute reset time. Counts the request the same way.
+    pub async fn check_limit_explained(
+        &self,
+        key: &str,
+        limit: u64,
+    ) -> Result<RateLimitDiagnostics> {
+        let window_ms = self.window.as_millis().max(1) as u64;
+        let now_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("time went backwards")
+            .as_millis() as u64;
+
+        let index = now_ms / window_ms;
+        let elapsed = now_ms % window_ms;
+
+        // Both windows share a hash tag so they stay in one cluster slot.
+        let tag = format!("rl:{key}");
+        let current = self
+            .cache
+            .increment(
+                &CacheService::tagged(&tag, &index.to_string()),
+                1,
+                Persistence::Ttl(self.window * 2),
+            )
+            .await?;
+        let previous: i64 = self
+            .cache
+            .get(&CacheService::tagged(
+                &tag,
+                &index.saturating_sub(1).to_string(),
+            ))
+            .await?
+            .unwrap_or(0);
+
+        let overlap = (window_ms - elapsed) as f64 / window_ms as f64;
+        let estimated = (previous as f64 * overlap + current as f64).ceil() as u64;
+
+        let reset_after = Duration::from_millis(window_ms - elapsed);
+
+        Ok(RateLimitDiagnostics {
+            result: RateLimitResult {
+                allowed: estimated <= limit,
+                limit,
+                remaining: limit.saturating_sub(estimated),
+                reset_after,
+            },
+            current_window_count: current,
+            previous_window_count: previous,
+            estimated,
+            resets_at: UNIX_EPOCH + Duration::from_millis(now_ms) + reset_after,
+        })
+    }
+}
+
+/// Where per-tenant quotas come from: static config, or a tenant settings
+/// store. `None` means the tenant has no override.
+#[async_trait]
+pub trait QuotaSource: Send + Sync {
+    async fn tenant_quota(&self, tenant_id: &str) -> Result<Option<u64>>;
+}
+
+/// Quotas fixed at startup, e.g. parsed from `acme=5000,globex=20000`.
+pub struct StaticQuotas {
+    quotas: HashMap<String, u64>,
+}
+
+impl StaticQuotas {
+    pub fn new(quotas: HashMap<String, u64>) -> Self {
+        Self { quotas }
+    }
+}
+
+#[async_trait]
+impl QuotaSource for StaticQuotas {
+    async fn tenant_quota(&self, tenant_id: &str) -> Result<Option<u64>> {
+        Ok(self.quotas.get(tenant_id).copied())
+    }
+}
+
+/// Who a request is counted against.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RateLimitIdentity {
+    Tenant(String),
+    /// Authenticated, but the token carries no tenant.
+    User(String),
+    /// Unauthenticated or pre-auth traffic.
+    Ip(String),
+}
+
+/// A request attribute a rate-limit key can be built from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyPart {
+    Ip,
+    User,
+    Tenant,
+    /// The route template, e.g. `/users/:id`.
+    Route,
+    Method,
+}
+
+impl KeyPart {
+    pub fn parse(raw: &str) -> Option<Self> {
+        match raw {
+            "ip" => Some(Self::Ip),
+            "user" => Some(Self::User),
+            "tenant" => Some(Self::Tenant),
+            "route" => Some(Self::Route),
+            "method" => Some(Self::Method),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Ip => "ip",
+            Self::User => "user",
+            Self::Tenant => "tenant",
+            Self::Route => "route",
+            Self::Method => "method",
+        }
+    }
+}
+
+/// What a request contributes to the keys it is counted under.
+#[derive(Debug, Clone)]
+pub struct RequestAttributes<'a> {
+    pub ip: IpAddr,
+    /// `None` unless the request carries a valid token.
+    pub user: Option<String>,
+    pub tenant: Option<String>,
+    pub route: &'a str,
+    pub method: &'a Method,
+}
+
+/// The parts a key combines, in order, e.g. user and route for "each
+/// user's calls to this route".
+///
+/// A missing user or tenant is replaced by the client address, so
+/// anonymous callers are still counted one by one rather than as a single
+/// caller.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KeyStrategy {
+    parts: Vec<KeyPart>,
+}
+
+impl KeyStrategy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn by(mut self, part: KeyPart) -> Self {
+        if !self.parts.contains(&part) {
+            self.parts.push(part);
+        }
+        self
+    }
+
+    pub fn parts(&self) -> &[KeyPart] {
+        &self.parts
+    }
+
+    pub fn key(&self, request: &RequestAttributes<'_>) -> String {
+        let value = |part: KeyPart| match part {
+            KeyPart::Ip => request.ip.to_string(),
+            KeyPart::User => request
+                .user
+                .clone()
+                .unwrap_or_else(|| format!("ip:{}", request.ip)),
+            KeyPart::Tenant => request
+                .tenant
+                .clone()
+                .unwrap_or_else(|| format!("ip:{}", request.ip)),
+            KeyPart::Route => request.route.to_string(),
+            KeyPart::Method => request.method.to_string(),
+        };
+        self.parts
+            .iter()
+            .map(|part| format!("{}={}", part.name(), value(*part)))
+            .collect::<Vec<_>>()
+            .join("|")
+    }
+}
+
+/// A route's own limit, applied on top of the caller's overall quota.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RouteRateLimit {
+    pub limit: u64,
+    pub key: KeyStrategy,
+}
+
+impl RouteRateLimit {
+    pub fn new(limit: u64, key: KeyStrategy) -> Self {
+        Self { limit, key }
+    }
+}
+
+/// Applies each tenant's own quota, falling back to `default_limit` for
+/// tenants without one and to `anonymous_limit` for per-IP traffic.
+#[derive(Clone)]
+pub struct TenantRateLimits {
+    limiter: RateLimiter,
+    quotas: Arc<dyn QuotaSource>,
+    default_limit: u64,
+    anonymous_limit: u64,
+    /// Client addresses that are never limited, e.g. our own monitors.
+    bypass: Arc<[IpRange]>,
+    /// Keyed by route template.
+    routes: Arc<HashMap<String, RouteRateLimit>>,
+}
+
+impl TenantRateLimits {
+    pub fn new(
+        cache: CacheService,
+        window: Duration,
+        quotas: Arc<dyn QuotaSource>,
+        default_limit: u64,
+        anonymous_limit: u64,
+    ) -> Self {
+        Self {
+            limiter: RateLimiter::new(cache, default_limit, window),
+            quotas,
+            default_limit,
+            anonymous_limit,
+            bypass: Arc::new([]),
+            routes: Arc::default(),
+        }
+    }
+
+    pub fn with_bypass(mut self, ranges: Vec<IpRange>) -> Self {
+        self.bypass = ranges.into();
+        self
+    }
+
+    /// Gives routes, named by their template as registered (e.g.
+    /// `/auth/login`), limits of their own.
+    pub fn with_route_limits(mut self, routes: HashMap<String, RouteRateLimit>) -> Self {
+        self.routes = Arc::new(routes);
+        self
+    }
+
+    /// Whether requests from `client` skip limiting altogether.
+    pub fn bypasses(&self, client: IpAddr) -> bool {
+        in_any(&self.bypass, client)
+    }
+
+    pub async fn check(&self, identity: &RateLimitIdentity) -> Result<RateLimitResult> {
+        let (key, limit) = match identity {
+            RateLimitIdentity::Tenant(tenant_id) => {
+                let quota = self.quotas.tenant_quota(tenant_id).await?;
+                (
+                    format!("tenant:{tenant_id}"),
+                    quota.unwrap_or(self.default_limit),
+                )
+            }
+            RateLimitIdentity::User(user_id) => (format!("user:{user_id}"), self.default_limit),
+            RateLimitIdentity::Ip(ip) => (format!("ip:{ip}"), self.anonymous_limit),
+        };
+
+        self.limiter.check_limit(&key, limit).await
+    }
+
+    /// Counts `request` against its route's own limit. `None` when the
+    /// route has none.
+    ///
+    /// The counter is named by the key alone, so routes whose keys leave
+    /// out the route share one: with `user` alone, a user's calls to any
+    /// such route count against all of them.
+    pub async fn check_route(
+        &self,
+        request: &RequestAttributes<'_>,
+    ) -> Result<Option<RateLimitResult>> {
+        let Some(rule) = self.routes.get(request.route) else {
+            return Ok(None);
+        };
+        let key = format!("rule:{}", rule.key.key(request));
+        Ok(Some(self.limiter.check_limit(&key, rule.limit).await?))
+    }
+}
+
+/// Writes the `X-RateLimit-*` headers. `X-RateLimit-Reset` is the number
+/// of seconds until the window resets, not an absolute timestamp.
+pub fn apply_rate_limit_headers(headers: &mut HeaderMap, result: &RateLimitResult) {
+    headers.insert(X_RATELIMIT_LIMIT, HeaderValue::from(result.limit));
+    headers.insert(X_RATELIMIT_REMAINING, HeaderValue::from(result.remaining));
+    headers.insert(X_RATELIMIT_RESET, HeaderValue::from(reset_secs(result)));
+}
+
+/// The one place a throttled request is turned into a 429, so every
+/// limiter call site sends the same headers.
+pub fn too_many_requests(result: &RateLimitResult) -> Response {
+    let mut response = error_response(
+        StatusCode::TOO_MANY_REQUESTS,
+        ErrorCode::RateLimited,
+        "too many requests",
+    );
+
+    let headers = response.headers_mut();
+    apply_rate_limit_headers(headers, result);
+    headers.insert(RETRY_AFTER, HeaderValue::from(reset_secs(result)));
+
+    response
+}
+
+fn reset_secs(result: &RateLimitResult) -> u64 {
+    // Round up so clients never retry a moment too early.
+    result.reset_after.as_millis().div_ceil(1000) as u64
+}
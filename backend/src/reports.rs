@@ -0,0 +1,102 @@
+//! Limits on report generation.
+//!
+//! Reports are heavy on CPU and storage, and a month-end rush of them
+//! shouldn't slow down everyone else. [`ReportSlots`] caps how many run at
+//! once across all instances with a semaphore in Redis. A report arriving
+//! while every slot is taken waits up to a configured time for one to
+//! free up, then is refused with a 429.
+
+use std::{future::Future, time::Duration};
+
+use crate::{
+    cache::{
+        cache_service::{CacheService, SemaphorePermit},
+        keys,
+    },
+    error::AppError,
+    metrics,
+    rate_limit::RateLimitResult,
+};
+
+/// How long a slot is held without renewal. A running report renews it
+/// every third of this; an instance that dies gives its slots back once
+/// their lease lapses.
+const SLOT_LEASE: Duration = Duration::from_secs(60);
+const SLOT_POLL: Duration = Duration::from_millis(100);
+/// Suggested wait before a refused report is retried.
+const RETRY_AFTER: Duration = Duration::from_secs(5);
+
+#[derive(Clone)]
+pub struct ReportSlots {
+    cache: CacheService,
+    capacity: u64,
+    max_wait: Duration,
+}
+
+impl ReportSlots {
+    /// At most `capacity` reports run at once; 0 means unlimited. A report
+    /// waits up to `max_wait` for a slot.
+    pub fn new(cache: CacheService, capacity: u64, max_wait: Duration) -> Self {
+        Self {
+            cache,
+            capacity,
+            max_wait,
+        }
+    }
+
+    /// Runs `generate` in a report slot, holding it until `generate`
+    /// finishes.
+    pub async fn run<T, F>(&self, generate: F) -> Result<T, AppError>
+    where
+        F: Future<Output = Result<T, AppError>>,
+    {
+        if self.capacity == 0 {
+            return generate.await;
+        }
+
+        let key = keys::report_slots();
+        let Some(permit) = self
+            .cache
+            .acquire_permit_wait(&key, self.capacity, SLOT_LEASE, self.max_wait, SLOT_POLL)
+            .await?
+        else {
+            metrics::report_rejected();
+            return Err(AppError::TooManyRequests(RateLimitResult {
+                allowed: false,
+                limit: self.capacity,
+                remaining: 0,
+                reset_after: RETRY_AFTER,
+            }));
+        };
+
+        let result = self.hold(&permit, generate).await;
+        if let Err(err) = self.cache.release_permit(&key, &permit).await {
+            // The lease runs out on its own; the report itself is done.
+            tracing::warn!(error = ?err, "failed to release report slot");
+        }
+        result
+    }
+
+    async fn hold<T, F>(&self, permit: &SemaphorePermit, generate: F) -> Result<T, AppError>
+    where
+        F: Future<Output = Result<T, AppError>>,
+    {
+        let key = keys::report_slots();
+        let mut renew = tokio::time::interval(SLOT_LEASE / 3);
+        renew.tick().await;
+        tokio::pin!(generate);
+
+        loop {
+            tokio::select! {
+                result = &mut generate => return result,
+                _ = renew.tick() => {
+                    match self.cache.extend_permit(&key, permit, self.capacity, SLOT_LEASE).await {
+                        Ok(true) => {}
+                        Ok(false) => tracing::warn!("report slot lapsed and was taken"),
+                        Err(err) => tracing::warn!(error = ?err, "failed to renew report slot"),
+                    }
+                }
+            }
+        }
+    }
+}
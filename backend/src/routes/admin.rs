@@ -0,0 +1,798 @@
+use axum::{
+    extract::{Path, Query, State},
+    routing::{get, post, put},
+    Json, Router,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+use crate::{
+    audit::{self, AuditEvent, AuditOutcome, AuditPage, AuditQuery},
+    auth::{
+        deactivation::{Deactivations, ScheduledDeactivation},
+        elevation::{Elevation, Elevations, MAX_ELEVATION},
+        extractor::AdminUser,
+        impersonation::{Impersonation, IMPERSONATE_SCOPE, MAX_IMPERSONATION},
+        ids::UserId,
+        role::Role,
+    },
+    cache::cache_service::KeyTtl,
+    error::AppError,
+    maintenance::MaintenanceState,
+    notifications::breaker::BreakerState,
+    state::AppState,
+    validation::{FieldErrors, QueryParams, RequestBody, Valid, ValidatedQuery},
+};
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/users/:id/logout-all", post(logout_all))
+        .route("/users/:id/role", put(change_role))
+        .route(
+            "/users/:id/elevation",
+            post(grant_elevation).delete(revoke_elevation),
+        )
+        .route(
+            "/users/:id/deactivation",
+            put(schedule_deactivation).delete(cancel_deactivation),
+        )
+        .route("/users/:id/reactivate", post(reactivate))
+        .route("/users/:id/impersonation", post(start_impersonation))
+        .route(
+            "/impersonations/:id",
+            get(impersonation_status).delete(end_impersonation),
+        )
+        .route("/cache", get(inspect_cache))
+        .route("/audit", get(query_audit))
+        .route("/tenants/:tenant/purge", post(purge_tenant))
+        .route("/maintenance", get(maintenance_status).put(set_maintenance))
+        .route("/webhooks/breaker", get(webhook_breaker))
+}
+
+/// Keys in the auth namespace whose values are credentials or revocation
+/// state. Their existence and TTL may be inspected, but never their
+/// contents.
+const REDACTED_PREFIXES: &[&str] = &["jwt:", "session:", "user:sessions:"];
+
+/// Number of affected keys or ids a dry run lists.
+const DRY_RUN_SAMPLE: usize = 20;
+
+/// Body of a destructive operation. With `dry_run` set nothing is changed;
+/// the response lists what would be, with a `confirmation` that the real
+/// run must send back. The real run is refused if what it would affect
+/// has changed since, so an operator only ever confirms the scope they
+/// looked at.
+#[derive(Deserialize)]
+struct DestructiveRequest {
+    #[serde(default)]
+    dry_run: bool,
+    confirmation: Option<String>,
+}
+
+#[derive(Serialize)]
+struct DryRunResponse {
+    dry_run: bool,
+    affected: u64,
+    sample: Vec<String>,
+    confirmation: String,
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum Destructive<T> {
+    DryRun(DryRunResponse),
+    Done(T),
+}
+
+impl DestructiveRequest {
+    /// The dry-run response when this is one. Otherwise checks that the
+    /// request carries the confirmation a dry run of the same scope
+    /// would give.
+    fn dry_run<T>(
+        &self,
+        operation: &str,
+        target: &str,
+        affected: u64,
+        sample: Vec<String>,
+    ) -> Result<Option<Destructive<T>>, AppError> {
+        let expected = confirmation(operation, target, affected);
+        if self.dry_run {
+            return Ok(Some(Destructive::DryRun(DryRunResponse {
+                dry_run: true,
+                affected,
+                sample,
+                confirmation: expected,
+            })));
+        }
+        match &self.confirmation {
+            None => Err(AppError::BadRequest(
+                "run with dry_run first and send back its confirmation",
+            )),
+            Some(given) if *given != expected => Err(AppError::Conflict(
+                "what this would affect has changed since the dry run",
+            )),
+            Some(_) => Ok(None),
+        }
+    }
+}
+
+/// Summarizes a dry run's scope. Not a secret: it only proves the caller
+/// saw this exact scope, the admin check having already authorized them.
+fn confirmation(operation: &str, target: &str, affected: u64) -> String {
+    let summary = format!("{operation}\0{target}\0{affected}");
+    blake3::hash(summary.as_bytes()).to_hex()[..16].to_string()
+}
+
+#[derive(Serialize)]
+struct LogoutAllResponse {
+    sessions_revoked: u64,
+}
+
+/// Kills every refresh session of a user and rejects all access tokens
+/// issued to them so far.
+async fn logout_all(
+    AdminUser(admin): AdminUser,
+    State(state): State<AppState>,
+    Path(user_id): Path<UserId>,
+    Json(request): Json<DestructiveRequest>,
+) -> Result<Json<Destructive<LogoutAllResponse>>, AppError> {
+    let sessions = state.auth.live_sessions(user_id).await?;
+    let sample = sessions
+        .iter()
+        .take(DRY_RUN_SAMPLE)
+        .map(ToString::to_string)
+        .collect();
+    if let Some(dry_run) = request.dry_run(
+        "logout_all",
+        &user_id.to_string(),
+        sessions.len() as u64,
+        sample,
+    )? {
+        return Ok(Json(dry_run));
+    }
+
+    let sessions_revoked = state.auth.revoke_all_sessions(user_id).await?;
+    state.auth.invalidate_user_tokens(user_id).await?;
+
+    state
+        .audit
+        .record(
+            AuditEvent::new(admin.sub.to_string(), "admin.logout_all", AuditOutcome::Success)
+                .target(user_id.to_string())
+                .detail(serde_json::json!({ "sessions_revoked": sessions_revoked })),
+        )
+        .await;
+
+    Ok(Json(Destructive::Done(LogoutAllResponse {
+        sessions_revoked,
+    })))
+}
+
+#[derive(Deserialize)]
+struct RoleChangeRequest {
+    role: Role,
+    /// Reject the user's current access tokens rather than letting them
+    /// run out with the old role.
+    #[serde(default)]
+    revoke_access_tokens: bool,
+}
+
+#[derive(Serialize)]
+struct RoleChangeResponse {
+    role: Role,
+    /// When every access token still carrying the old role will have
+    /// expired; now if they were revoked.
+    #[serde(with = "crate::timestamp::millis")]
+    effective_by: DateTime<Utc>,
+}
+
+/// Changes a user's role. Their sessions pick it up on their next
+/// refresh; until then, unless `revoke_access_tokens` is set, access
+/// tokens already issued keep the old role for up to the access token
+/// TTL. Admins can't change their own role or assign one above theirs.
+async fn change_role(
+    AdminUser(admin): AdminUser,
+    State(state): State<AppState>,
+    Path(user_id): Path<UserId>,
+    Json(request): Json<RoleChangeRequest>,
+) -> Result<Json<RoleChangeResponse>, AppError> {
+    let refusal = if user_id == admin.sub {
+        Some(("another user".to_string(), "cannot change your own role"))
+    } else if !admin.role.implies(request.role) {
+        Some((
+            format!("role:{}", request.role),
+            "cannot assign a role above your own",
+        ))
+    } else {
+        None
+    };
+    if let Some((required, reason)) = refusal {
+        let resource = format!("role:{user_id}");
+        return Err(state
+            .denials
+            .deny(&admin, &resource, &required, reason)
+            .await);
+    }
+
+    state
+        .auth
+        .change_role(user_id, request.role, request.revoke_access_tokens)
+        .await?;
+
+    let now = Utc::now();
+    let effective_by = if request.revoke_access_tokens {
+        now
+    } else {
+        now + state.auth.tokens().access_token_ttl()
+    };
+
+    state
+        .audit
+        .record(
+            AuditEvent::new(admin.sub.to_string(), "admin.role_changed", AuditOutcome::Success)
+                .target(user_id.to_string())
+                .detail(serde_json::json!({
+                    "role": request.role,
+                    "revoke_access_tokens": request.revoke_access_tokens,
+                })),
+        )
+        .await;
+
+    Ok(Json(RoleChangeResponse {
+        role: request.role,
+        effective_by,
+    }))
+}
+
+#[derive(Deserialize)]
+struct ElevationRequest {
+    role: Role,
+    duration_secs: u64,
+    reason: Option<String>,
+}
+
+struct ValidatedElevation {
+    role: Role,
+    duration: Duration,
+    reason: Option<String>,
+}
+
+impl TryFrom<ElevationRequest> for ValidatedElevation {
+    type Error = AppError;
+
+    fn try_from(raw: ElevationRequest) -> Result<Self, Self::Error> {
+        let duration = Duration::from_secs(raw.duration_secs);
+        if duration.is_zero() || duration > MAX_ELEVATION {
+            return Err(AppError::BadRequest(
+                "duration_secs must be between 1 and 28800",
+            ));
+        }
+        Ok(Self {
+            role: raw.role,
+            duration,
+            reason: raw.reason.filter(|reason| !reason.trim().is_empty()),
+        })
+    }
+}
+
+impl RequestBody for ValidatedElevation {
+    type Raw = ElevationRequest;
+}
+
+fn elevations(state: &AppState) -> Result<&Elevations, AppError> {
+    state
+        .auth
+        .elevations()
+        .ok_or(AppError::NotFound("role elevation is not enabled"))
+}
+
+/// Grants a user a higher role for a limited time. Admins can't grant
+/// above their own role, can't elevate themselves, and can't grant while
+/// their own role is only elevated.
+async fn grant_elevation(
+    AdminUser(admin): AdminUser,
+    State(state): State<AppState>,
+    Path(user_id): Path<UserId>,
+    Valid(request): Valid<ValidatedElevation>,
+) -> Result<Json<Elevation>, AppError> {
+    let elevations = elevations(&state)?;
+
+    let refusal = if user_id == admin.sub {
+        Some(("another user".to_string(), "cannot elevate yourself"))
+    } else if !admin.role.implies(request.role) {
+        Some((
+            format!("role:{}", request.role),
+            "cannot grant a role above your own",
+        ))
+    } else if elevations.active(admin.sub).await?.is_some() {
+        Some((
+            "unelevated role".to_string(),
+            "elevated admins cannot grant elevations",
+        ))
+    } else {
+        None
+    };
+    if let Some((required, reason)) = refusal {
+        let resource = format!("elevation:{user_id}");
+        return Err(state
+            .denials
+            .deny(&admin, &resource, &required, reason)
+            .await);
+    }
+
+    let elevation = elevations
+        .grant(
+            user_id,
+            request.role,
+            admin.sub,
+            request.duration,
+            request.reason,
+        )
+        .await?;
+    Ok(Json(elevation))
+}
+
+#[derive(Serialize)]
+struct RevokeElevationResponse {
+    revoked: bool,
+}
+
+/// Ends a user's elevation before it expires.
+async fn revoke_elevation(
+    AdminUser(admin): AdminUser,
+    State(state): State<AppState>,
+    Path(user_id): Path<UserId>,
+) -> Result<Json<RevokeElevationResponse>, AppError> {
+    let revoked = elevations(&state)?.revoke(user_id, admin.sub).await?;
+    Ok(Json(RevokeElevationResponse { revoked }))
+}
+
+#[derive(Deserialize)]
+struct ImpersonationRequest {
+    role: Role,
+    duration_secs: u64,
+    reason: String,
+}
+
+struct ValidatedImpersonation {
+    role: Role,
+    duration: Duration,
+    reason: String,
+}
+
+impl TryFrom<ImpersonationRequest> for ValidatedImpersonation {
+    type Error = AppError;
+
+    fn try_from(raw: ImpersonationRequest) -> Result<Self, Self::Error> {
+        let duration = Duration::from_secs(raw.duration_secs);
+        if duration.is_zero() || duration > MAX_IMPERSONATION {
+            return Err(AppError::BadRequest(
+                "duration_secs must be between 1 and 3600",
+            ));
+        }
+        let reason = raw.reason.trim();
+        if reason.is_empty() {
+            return Err(AppError::BadRequest("reason is required"));
+        }
+        Ok(Self {
+            role: raw.role,
+            duration,
+            reason: reason.to_string(),
+        })
+    }
+}
+
+impl RequestBody for ValidatedImpersonation {
+    type Raw = ImpersonationRequest;
+}
+
+#[derive(Serialize)]
+struct ImpersonationResponse {
+    access_token: String,
+    #[serde(flatten)]
+    impersonation: Impersonation,
+}
+
+/// Mints a token for acting as a user, for support. Needs the
+/// impersonation scope, and the role asked for can't be above the
+/// admin's own; there is no user store to read the user's role from, so
+/// support picks the one the user has.
+async fn start_impersonation(
+    AdminUser(admin): AdminUser,
+    State(state): State<AppState>,
+    Path(user_id): Path<UserId>,
+    Valid(request): Valid<ValidatedImpersonation>,
+) -> Result<Json<ImpersonationResponse>, AppError> {
+    let refusal = if !admin.scopes.iter().any(|scope| scope == IMPERSONATE_SCOPE) {
+        Some((
+            format!("scope:{IMPERSONATE_SCOPE}"),
+            "impersonation scope required",
+        ))
+    } else if user_id == admin.sub {
+        Some(("another user".to_string(), "cannot impersonate yourself"))
+    } else if !admin.role.implies(request.role) {
+        Some((
+            format!("role:{}", request.role),
+            "cannot impersonate with a role above your own",
+        ))
+    } else {
+        None
+    };
+    if let Some((required, reason)) = refusal {
+        let resource = format!("impersonation:{user_id}");
+        return Err(state
+            .denials
+            .deny(&admin, &resource, &required, reason)
+            .await);
+    }
+
+    let (access_token, impersonation) = state
+        .impersonations
+        .start(
+            &admin,
+            user_id,
+            request.role,
+            request.duration,
+            request.reason,
+        )
+        .await?;
+    Ok(Json(ImpersonationResponse {
+        access_token,
+        impersonation,
+    }))
+}
+
+async fn impersonation_status(
+    AdminUser(_): AdminUser,
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Json<Impersonation>, AppError> {
+    state
+        .impersonations
+        .get(&id)
+        .await?
+        .map(Json)
+        .ok_or(AppError::NotFound("no such impersonation"))
+}
+
+/// Ends an impersonation before it expires, revoking its token.
+async fn end_impersonation(
+    AdminUser(admin): AdminUser,
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Json<Impersonation>, AppError> {
+    state
+        .impersonations
+        .end(&id, admin.sub)
+        .await?
+        .map(Json)
+        .ok_or(AppError::NotFound("no such impersonation"))
+}
+
+#[derive(Deserialize)]
+struct DeactivationRequest {
+    #[serde(with = "crate::timestamp::millis")]
+    at: DateTime<Utc>,
+    reason: Option<String>,
+}
+
+struct ValidatedDeactivation {
+    at: DateTime<Utc>,
+    reason: Option<String>,
+}
+
+impl TryFrom<DeactivationRequest> for ValidatedDeactivation {
+    type Error = AppError;
+
+    fn try_from(raw: DeactivationRequest) -> Result<Self, Self::Error> {
+        if raw.at <= Utc::now() {
+            return Err(AppError::BadRequest("at must be in the future"));
+        }
+        Ok(Self {
+            at: raw.at,
+            reason: raw.reason.filter(|reason| !reason.trim().is_empty()),
+        })
+    }
+}
+
+impl RequestBody for ValidatedDeactivation {
+    type Raw = DeactivationRequest;
+}
+
+fn deactivations(state: &AppState) -> Result<&Deactivations, AppError> {
+    state
+        .auth
+        .deactivations()
+        .ok_or(AppError::NotFound("account deactivation is not enabled"))
+}
+
+/// Schedules a user's account to be deactivated at `at`, or moves an
+/// existing schedule. Admins can't schedule their own.
+async fn schedule_deactivation(
+    AdminUser(admin): AdminUser,
+    State(state): State<AppState>,
+    Path(user_id): Path<UserId>,
+    Valid(request): Valid<ValidatedDeactivation>,
+) -> Result<Json<ScheduledDeactivation>, AppError> {
+    let deactivations = deactivations(&state)?;
+    if user_id == admin.sub {
+        let resource = format!("deactivation:{user_id}");
+        return Err(state
+            .denials
+            .deny(
+                &admin,
+                &resource,
+                "another user",
+                "cannot deactivate yourself",
+            )
+            .await);
+    }
+
+    let schedule = deactivations
+        .schedule(user_id, request.at, admin.sub, request.reason)
+        .await?;
+    Ok(Json(schedule))
+}
+
+#[derive(Serialize)]
+struct CancelDeactivationResponse {
+    cancelled: bool,
+}
+
+/// Cancels a pending deactivation. Once it has been carried out, use
+/// reactivation instead.
+async fn cancel_deactivation(
+    AdminUser(admin): AdminUser,
+    State(state): State<AppState>,
+    Path(user_id): Path<UserId>,
+) -> Result<Json<CancelDeactivationResponse>, AppError> {
+    let cancelled = deactivations(&state)?.cancel(user_id, admin.sub).await?;
+    Ok(Json(CancelDeactivationResponse { cancelled }))
+}
+
+#[derive(Serialize)]
+struct ReactivateResponse {
+    reactivated: bool,
+}
+
+/// Lets a deactivated account sign in again.
+async fn reactivate(
+    AdminUser(admin): AdminUser,
+    State(state): State<AppState>,
+    Path(user_id): Path<UserId>,
+) -> Result<Json<ReactivateResponse>, AppError> {
+    let reactivated = deactivations(&state)?
+        .reactivate(user_id, admin.sub)
+        .await?;
+    Ok(Json(ReactivateResponse { reactivated }))
+}
+
+#[derive(Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum InspectNamespace {
+    #[default]
+    Values,
+    Locks,
+    RateLimits,
+    Auth,
+}
+
+#[derive(Deserialize)]
+struct InspectQuery {
+    key: String,
+    #[serde(default)]
+    namespace: InspectNamespace,
+    #[serde(default)]
+    include_value: bool,
+}
+
+#[derive(Serialize)]
+struct InspectResponse {
+    key: String,
+    exists: bool,
+    /// Remaining lifetime in milliseconds; absent for missing or
+    /// non-expiring keys.
+    ttl_ms: Option<u64>,
+    persistent: bool,
+    value: Option<String>,
+    redacted: bool,
+}
+
+/// Read-only view of a single key for support, in the values namespace
+/// unless `namespace` says otherwise.
+async fn inspect_cache(
+    AdminUser(admin): AdminUser,
+    State(state): State<AppState>,
+    Query(query): Query<InspectQuery>,
+) -> Result<Json<InspectResponse>, AppError> {
+    let cache = match query.namespace {
+        InspectNamespace::Values => state.cache.clone(),
+        InspectNamespace::Locks => state.cache.locks(),
+        InspectNamespace::RateLimits => state.cache.rate_limits(),
+        InspectNamespace::Auth => state.cache.auth(),
+    };
+
+    let ttl = cache.ttl(&query.key).await?;
+    let redacted = query.namespace == InspectNamespace::Auth
+        && REDACTED_PREFIXES
+            .iter()
+            .any(|prefix| query.key.starts_with(prefix));
+
+    let value = if query.include_value && !redacted {
+        cache.get_raw(&query.key).await?
+    } else {
+        None
+    };
+
+    state
+        .audit
+        .record(
+            AuditEvent::new(admin.sub.to_string(), "admin.cache_inspect", AuditOutcome::Success)
+                .target(&query.key),
+        )
+        .await;
+
+    Ok(Json(InspectResponse {
+        key: query.key,
+        exists: ttl != KeyTtl::Missing,
+        ttl_ms: match ttl {
+            KeyTtl::Expires(remaining) => Some(remaining.as_millis() as u64),
+            KeyTtl::Missing | KeyTtl::Persistent => None,
+        },
+        persistent: ttl == KeyTtl::Persistent,
+        value,
+        redacted: redacted && query.include_value,
+    }))
+}
+
+/// An [`AuditQuery`] whose cursor and time range make sense.
+struct ValidatedAuditQuery(AuditQuery);
+
+impl TryFrom<AuditQuery> for ValidatedAuditQuery {
+    type Error = AppError;
+
+    fn try_from(query: AuditQuery) -> Result<Self, Self::Error> {
+        let mut errors = FieldErrors::default();
+        errors.check(
+            query.cursor.as_deref().is_none_or(audit::is_valid_cursor),
+            "cursor",
+            "must be a next_cursor from a previous page",
+        );
+        if let (Some(since), Some(until)) = (query.since, query.until) {
+            errors.check(since <= until, "since", "must not be after until");
+        }
+        errors.finish()?;
+        Ok(Self(query))
+    }
+}
+
+impl QueryParams for ValidatedAuditQuery {
+    type Raw = AuditQuery;
+}
+
+/// Pages through the audit trail, newest first.
+async fn query_audit(
+    AdminUser(_): AdminUser,
+    State(state): State<AppState>,
+    ValidatedQuery(ValidatedAuditQuery(query)): ValidatedQuery<ValidatedAuditQuery>,
+) -> Result<Json<AuditPage>, AppError> {
+    Ok(Json(state.audit.query(&query).await?))
+}
+
+#[derive(Serialize)]
+struct PurgeTenantResponse {
+    keys_removed: u64,
+}
+
+/// Deletes all cached data in a tenant's namespace, for offboarding. The
+/// confirmation binds the tenant id too, so a mistyped or replayed URL
+/// alone can't wipe a tenant.
+async fn purge_tenant(
+    AdminUser(admin): AdminUser,
+    State(state): State<AppState>,
+    Path(tenant): Path<String>,
+    Json(request): Json<DestructiveRequest>,
+) -> Result<Json<Destructive<PurgeTenantResponse>>, AppError> {
+    let preview = state
+        .cache
+        .preview_tenant_purge(&tenant, DRY_RUN_SAMPLE)
+        .await?;
+    if let Some(dry_run) =
+        request.dry_run("purge_tenant", &tenant, preview.count, preview.sample)?
+    {
+        return Ok(Json(dry_run));
+    }
+
+    let keys_removed = state.cache.purge_tenant(&tenant).await?;
+
+    state
+        .audit
+        .record(
+            AuditEvent::new(admin.sub.to_string(), "admin.purge_tenant", AuditOutcome::Success)
+                .target(&tenant)
+                .detail(serde_json::json!({ "keys_removed": keys_removed })),
+        )
+        .await;
+
+    Ok(Json(Destructive::Done(PurgeTenantResponse {
+        keys_removed,
+    })))
+}
+
+#[derive(Serialize)]
+struct MaintenanceResponse {
+    enabled: bool,
+    #[serde(flatten)]
+    state: Option<MaintenanceState>,
+}
+
+async fn maintenance_status(
+    AdminUser(_): AdminUser,
+    State(state): State<AppState>,
+) -> Result<Json<MaintenanceResponse>, AppError> {
+    let current = state.maintenance.current().await?;
+
+    Ok(Json(MaintenanceResponse {
+        enabled: current.is_some(),
+        state: current,
+    }))
+}
+
+#[derive(Deserialize)]
+struct SetMaintenanceRequest {
+    enabled: bool,
+    reason: Option<String>,
+    #[serde(default = "default_retry_after_secs")]
+    retry_after_secs: u64,
+}
+
+fn default_retry_after_secs() -> u64 {
+    300
+}
+
+/// Switches read-only mode on or off for every instance.
+async fn set_maintenance(
+    AdminUser(admin): AdminUser,
+    State(state): State<AppState>,
+    Json(payload): Json<SetMaintenanceRequest>,
+) -> Result<Json<MaintenanceResponse>, AppError> {
+    let current = if payload.enabled {
+        Some(
+            state
+                .maintenance
+                .enable(payload.reason, payload.retry_after_secs)
+                .await?,
+        )
+    } else {
+        state.maintenance.disable().await?;
+        None
+    };
+
+    state
+        .audit
+        .record(
+            AuditEvent::new(admin.sub.to_string(), "admin.maintenance", AuditOutcome::Success)
+                .detail(serde_json::json!({ "enabled": payload.enabled })),
+        )
+        .await;
+
+    Ok(Json(MaintenanceResponse {
+        enabled: current.is_some(),
+        state: current,
+    }))
+}
+
+#[derive(Deserialize)]
+struct BreakerQuery {
+    url: String,
+}
+
+/// Where the breaker of the webhook endpoint at `url` stands.
+async fn webhook_breaker(
+    AdminUser(_): AdminUser,
+    State(state): State<AppState>,
+    Query(query): Query<BreakerQuery>,
+) -> Result<Json<BreakerState>, AppError> {
+    Ok(Json(state.webhook_breaker.state(&query.url).await?))
+}
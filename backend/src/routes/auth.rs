@@ -0,0 +1,106 @@
+use axum::{
+    extract::State,
+    http::{header::SET_COOKIE, HeaderMap, HeaderValue},
+    routing::{get, post},
+    Json, Router,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    auth::{extractor::AuthUser, ids::UserId, role::Role},
+    error::AppError,
+    state::AppState,
+};
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/me", get(me))
+        .route("/refresh", post(refresh))
+}
+
+#[derive(Deserialize)]
+struct RefreshRequest {
+    refresh_token: String,
+}
+
+#[derive(Serialize)]
+struct AccessTokenResponse {
+    access_token: String,
+    token_type: &'static str,
+    #[serde(with = "crate::timestamp::millis")]
+    expires_at: DateTime<Utc>,
+    refresh_token: String,
+    #[serde(with = "crate::timestamp::millis")]
+    refresh_expires_at: DateTime<Utc>,
+}
+
+/// Issues a new access token and rotates the refresh token; clients must
+/// keep the returned one, as the presented one is spent. With cookie
+/// delivery enabled the access token is also set as the access-token
+/// cookie, so SSR clients don't have to handle it, alongside a CSRF token
+/// cookie that cookie-authenticated writes must echo in `X-CSRF-Token`.
+/// Both use the `SameSite` mode configured for the caller's origin.
+async fn refresh(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(payload): Json<RefreshRequest>,
+) -> Result<(HeaderMap, Json<AccessTokenResponse>), AppError> {
+    let refreshed = state.auth.refresh(&payload.refresh_token).await?;
+
+    let mut response_headers = HeaderMap::new();
+    if let Some(cookie) = &state.token_cookie {
+        let same_site = cookie.same_site_for(&headers);
+        let max_age = (refreshed.access_expires_at - Utc::now())
+            .to_std()
+            .unwrap_or_default();
+        let csrf_token = state.csrf.issue(&refreshed.session_id.to_string());
+
+        for value in [
+            cookie.set_cookie(&refreshed.access_token, max_age, same_site),
+            cookie.csrf_cookie(&state.csrf, &csrf_token, same_site),
+        ] {
+            response_headers.append(
+                SET_COOKIE,
+                HeaderValue::from_str(&value).map_err(anyhow::Error::from)?,
+            );
+        }
+    }
+
+    Ok((
+        response_headers,
+        Json(AccessTokenResponse {
+            access_token: refreshed.access_token,
+            token_type: "Bearer",
+            expires_at: refreshed.access_expires_at,
+            refresh_token: refreshed.refresh_token,
+            refresh_expires_at: refreshed.refresh_expires_at,
+        }),
+    ))
+}
+
+#[derive(Serialize)]
+struct WhoAmIResponse {
+    sub: UserId,
+    role: Role,
+    scopes: Vec<String>,
+    tenant_id: Option<String>,
+    /// Set while an admin is impersonating `sub`, so the UI can say so.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    impersonator: Option<UserId>,
+    #[serde(with = "crate::timestamp::millis")]
+    expires_at: DateTime<Utc>,
+}
+
+/// Identity behind the presented token. [`AuthUser`] has already checked
+/// revocation, so a revoked token never gets this far.
+async fn me(AuthUser(claims): AuthUser) -> Json<WhoAmIResponse> {
+    Json(WhoAmIResponse {
+        expires_at: claims.expires_at(),
+        impersonator: claims.impersonator(),
+        sub: claims.sub,
+        role: claims.role,
+        scopes: claims.scopes,
+        tenant_id: claims.tenant_id,
+    })
+}
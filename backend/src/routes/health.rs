@@ -0,0 +1,201 @@
+//! Health endpoints, tiered by audience.
+//!
+//! `/health`, `/health/live` and `/health/ready` are public and say only
+//! whether the service and Redis are up; probes and load balancers need
+//! nothing more.
+//! Dependency details, latencies and the build version are only shown to
+//! admins, at `/health/detailed`.
+
+use axum::{extract::State, http::StatusCode, routing::get, Json, Router};
+use futures_util::future::join_all;
+use serde::Serialize;
+use std::time::Instant;
+
+use crate::{
+    auth::extractor::AdminUser,
+    cache::redis_client::{RedisClient, RedisClients},
+    state::AppState,
+};
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/", get(ready))
+        .route("/live", get(live))
+        .route("/ready", get(ready))
+        .route("/detailed", get(detailed))
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+struct HealthResponse {
+    status: &'static str,
+    /// `"up"` or `"down"`; left out where Redis isn't consulted.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    redis: Option<&'static str>,
+}
+
+/// The process is running and serving requests; dependencies aren't
+/// consulted, so a Redis outage doesn't get the pod restarted.
+async fn live() -> Json<HealthResponse> {
+    Json(HealthResponse {
+        status: "ok",
+        redis: None,
+    })
+}
+
+/// Every Redis instance answers, so traffic can be routed here.
+async fn ready(State(state): State<AppState>) -> (StatusCode, Json<HealthResponse>) {
+    readiness(&state.redis).await
+}
+
+async fn readiness(redis: &RedisClients) -> (StatusCode, Json<HealthResponse>) {
+    let (code, status, redis) = match redis.ping().await {
+        Ok(()) => (StatusCode::OK, "ok", "up"),
+        Err(_) => (StatusCode::SERVICE_UNAVAILABLE, "degraded", "down"),
+    };
+
+    (
+        code,
+        Json(HealthResponse {
+            status,
+            redis: Some(redis),
+        }),
+    )
+}
+
+#[derive(Serialize)]
+struct DetailedHealthResponse {
+    status: &'static str,
+    version: &'static str,
+    dependencies: Vec<DependencyHealth>,
+}
+
+#[derive(Serialize)]
+struct DependencyHealth {
+    name: &'static str,
+    status: &'static str,
+    latency_ms: u128,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// Pings every Redis instance separately and checks that access tokens
+/// still round-trip, and reports how each did.
+async fn detailed(
+    AdminUser(_): AdminUser,
+    State(state): State<AppState>,
+) -> (StatusCode, Json<DetailedHealthResponse>) {
+    let redis = &state.redis;
+    let dependencies = join_all([
+        check("redis.primary", &redis.primary),
+        check("redis.cache", &redis.cache),
+        check("redis.rate_limits", &redis.rate_limits),
+        check("redis.sessions", &redis.sessions),
+    ])
+    .await
+    .into_iter()
+    .chain([check_signing(&state)])
+    .collect::<Vec<_>>();
+
+    let healthy = dependencies
+        .iter()
+        .all(|dependency| dependency.error.is_none());
+    let (code, status) = if healthy {
+        (StatusCode::OK, "ok")
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, "unavailable")
+    };
+
+    (
+        code,
+        Json(DetailedHealthResponse {
+            status,
+            version: env!("CARGO_PKG_VERSION"),
+            dependencies,
+        }),
+    )
+}
+
+/// Issues and verifies a throwaway access token.
+fn check_signing(state: &AppState) -> DependencyHealth {
+    let started = Instant::now();
+    let result = state.auth.tokens().self_check();
+
+    DependencyHealth {
+        name: "jwt.signing",
+        status: if result.is_ok() { "ok" } else { "unavailable" },
+        latency_ms: started.elapsed().as_millis(),
+        error: result.err().map(|err| err.to_string()),
+    }
+}
+
+async fn check(name: &'static str, client: &RedisClient) -> DependencyHealth {
+    let started = Instant::now();
+    let result = client.ping().await;
+
+    DependencyHealth {
+        name,
+        status: if result.is_ok() { "ok" } else { "unavailable" },
+        latency_ms: started.elapsed().as_millis(),
+        error: result.err().map(|err| err.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    /// A `redis://` URL with something other than Redis behind it: it
+    /// refuses every command it is sent.
+    async fn bogus_redis_url() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let mut buf = [0; 4096];
+                    while let Ok(len @ 1..) = socket.read(&mut buf).await {
+                        // One error reply per command; each starts a line
+                        // with the `*` of its argument array.
+                        let commands = (0..len)
+                            .filter(|&i| buf[i] == b'*' && (i == 0 || buf[i - 1] == b'\n'))
+                            .count();
+                        let replies = b"-ERR not redis\r\n".repeat(commands);
+                        if socket.write_all(&replies).await.is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+
+        format!("redis://{addr}")
+    }
+
+    #[tokio::test]
+    async fn unreachable_redis_degrades_readiness() {
+        let client = RedisClient::new(bogus_redis_url().await).await.unwrap();
+
+        let (code, Json(body)) = readiness(&RedisClients::shared(client)).await;
+
+        assert_eq!(code, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(
+            serde_json::to_value(&body).unwrap(),
+            serde_json::json!({ "status": "degraded", "redis": "down" })
+        );
+    }
+
+    #[tokio::test]
+    async fn liveness_ignores_redis() {
+        let Json(body) = live().await;
+
+        assert_eq!(
+            serde_json::to_value(&body).unwrap(),
+            serde_json::json!({ "status": "ok" })
+        );
+    }
+}
@@ -0,0 +1,6 @@
+pub mod admin;
+pub mod auth;
+pub mod health;
+pub mod notifications;
+pub mod users;
+pub mod ws;
@@ -0,0 +1,146 @@
+use axum::{
+    extract::{Path, State},
+    routing::{get, post},
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    auth::extractor::AuthUser,
+    error::AppError,
+    notifications::{inbox::InboxPage, preferences::Preferences},
+    state::AppState,
+    validation::{page_size, QueryParams, RequestBody, Valid, ValidatedQuery},
+};
+
+const DEFAULT_PAGE: usize = 20;
+const MAX_PAGE: usize = 100;
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/", get(list))
+        .route("/unread-count", get(unread_count))
+        .route("/:id/read", post(mark_read))
+        .route("/read-all", post(mark_all_read))
+        .route("/preferences", get(preferences).put(set_preferences))
+}
+
+#[derive(Deserialize)]
+struct RawListQuery {
+    limit: Option<usize>,
+    #[serde(default)]
+    unread_only: bool,
+}
+
+struct ListQuery {
+    limit: usize,
+    unread_only: bool,
+}
+
+impl TryFrom<RawListQuery> for ListQuery {
+    type Error = AppError;
+
+    fn try_from(raw: RawListQuery) -> Result<Self, Self::Error> {
+        Ok(Self {
+            limit: page_size(raw.limit, DEFAULT_PAGE, MAX_PAGE),
+            unread_only: raw.unread_only,
+        })
+    }
+}
+
+impl QueryParams for ListQuery {
+    type Raw = RawListQuery;
+}
+
+#[derive(Serialize)]
+struct UnreadResponse {
+    unread: usize,
+}
+
+/// The caller's stored in-app notifications, newest first.
+async fn list(
+    AuthUser(claims): AuthUser,
+    State(state): State<AppState>,
+    ValidatedQuery(query): ValidatedQuery<ListQuery>,
+) -> Result<Json<InboxPage>, AppError> {
+    let page = state
+        .notifications
+        .inbox()
+        .page(claims.sub, query.limit, query.unread_only)
+        .await?;
+    Ok(Json(page))
+}
+
+async fn unread_count(
+    AuthUser(claims): AuthUser,
+    State(state): State<AppState>,
+) -> Result<Json<UnreadResponse>, AppError> {
+    let unread = state.notifications.inbox().unread_count(claims.sub).await?;
+    Ok(Json(UnreadResponse { unread }))
+}
+
+async fn mark_read(
+    AuthUser(claims): AuthUser,
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>,
+) -> Result<Json<UnreadResponse>, AppError> {
+    let unread = state
+        .notifications
+        .inbox()
+        .mark_read(claims.sub, id)
+        .await?
+        .ok_or(AppError::NotFound("no such notification"))?;
+    Ok(Json(UnreadResponse { unread }))
+}
+
+async fn mark_all_read(
+    AuthUser(claims): AuthUser,
+    State(state): State<AppState>,
+) -> Result<Json<UnreadResponse>, AppError> {
+    state
+        .notifications
+        .inbox()
+        .mark_all_read(claims.sub)
+        .await?;
+    Ok(Json(UnreadResponse { unread: 0 }))
+}
+
+async fn preferences(
+    AuthUser(claims): AuthUser,
+    State(state): State<AppState>,
+) -> Result<Json<Preferences>, AppError> {
+    let preferences = state.notifications.preferences().get(claims.sub).await?;
+    Ok(Json(preferences))
+}
+
+/// Preferences that are fit to be saved.
+struct ValidPreferences(Preferences);
+
+impl TryFrom<Preferences> for ValidPreferences {
+    type Error = AppError;
+
+    fn try_from(preferences: Preferences) -> Result<Self, Self::Error> {
+        match preferences.problem() {
+            Some(problem) => Err(AppError::BadRequest(problem)),
+            None => Ok(Self(preferences)),
+        }
+    }
+}
+
+impl RequestBody for ValidPreferences {
+    type Raw = Preferences;
+}
+
+async fn set_preferences(
+    AuthUser(claims): AuthUser,
+    State(state): State<AppState>,
+    Valid(ValidPreferences(preferences)): Valid<ValidPreferences>,
+) -> Result<Json<Preferences>, AppError> {
+    state
+        .notifications
+        .preferences()
+        .set(claims.sub, &preferences)
+        .await?;
+    Ok(Json(preferences))
+}
@@ -0,0 +1,67 @@
+use axum::{extract::State, routing::post, Json, Router};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    auth::ids::UserId,
+    cache::keys,
+    error::AppError,
+    state::AppState,
+    users::email::EmailAddress,
+    validation::{RequestBody, Valid},
+};
+
+pub fn router() -> Router<AppState> {
+    Router::new().route("/", post(create_user))
+}
+
+async fn create_user(
+    State(state): State<AppState>,
+    Valid(user): Valid<ValidatedCreateUser>,
+) -> Result<Json<UserResponse>, AppError> {
+    let email = user.email.normalize(state.canonicalize_gmail);
+
+    let claimed = state
+        .claims
+        .claim(&keys::user_email(&email.normalized), &email.original)
+        .await?;
+
+    if !claimed {
+        return Err(AppError::Conflict("email already registered"));
+    }
+
+    Ok(Json(UserResponse {
+        id: UserId::new(),
+        email: email.normalized,
+        display_email: email.original,
+    }))
+}
+
+#[derive(Deserialize)]
+struct CreateUserRequest {
+    email: String,
+}
+
+struct ValidatedCreateUser {
+    email: EmailAddress,
+}
+
+impl TryFrom<CreateUserRequest> for ValidatedCreateUser {
+    type Error = AppError;
+
+    fn try_from(raw: CreateUserRequest) -> Result<Self, Self::Error> {
+        Ok(Self {
+            email: EmailAddress::parse(&raw.email).ok_or(AppError::BadRequest("invalid email"))?,
+        })
+    }
+}
+
+impl RequestBody for ValidatedCreateUser {
+    type Raw = CreateUserRequest;
+}
+
+#[derive(Serialize)]
+struct UserResponse {
+    id: UserId,
+    email: String,
+    display_email: String,
+}
@@ -0,0 +1,108 @@
+use axum::{
+    extract::{
+        ws::{close_code, CloseFrame, Message, WebSocket, WebSocketUpgrade},
+        Query, State,
+    },
+    response::Response,
+    routing::{get, post},
+    Json, Router,
+};
+use futures_util::{Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::{pin::pin, time::Duration};
+
+use crate::{auth::extractor::AuthUser, error::AppError, state::AppState};
+
+/// How long one event may take to reach a client before the connection
+/// is dropped as too slow.
+const SEND_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/", get(connect))
+        .route("/ticket", post(issue_ticket))
+}
+
+#[derive(Serialize)]
+struct TicketResponse {
+    ticket: String,
+    expires_in: u64,
+}
+
+/// Single-use ticket for opening `/ws`.
+async fn issue_ticket(
+    AuthUser(claims): AuthUser,
+    State(state): State<AppState>,
+) -> Result<Json<TicketResponse>, AppError> {
+    let ticket = state.notifier.issue_ticket(&claims).await?;
+
+    Ok(Json(TicketResponse {
+        ticket,
+        expires_in: state.notifier.ticket_ttl().as_secs(),
+    }))
+}
+
+#[derive(Deserialize)]
+struct ConnectQuery {
+    ticket: String,
+}
+
+/// Redeems the ticket and subscribes before upgrading, so a bad ticket or
+/// an unreachable Redis is an ordinary HTTP error.
+async fn connect(
+    ws: WebSocketUpgrade,
+    State(state): State<AppState>,
+    Query(query): Query<ConnectQuery>,
+) -> Result<Response, AppError> {
+    let owner = state
+        .notifier
+        .redeem_ticket(&query.ticket)
+        .await?
+        .ok_or(AppError::Unauthorized("invalid websocket ticket"))?;
+
+    let events = state.notifier.subscribe(&owner).await?;
+
+    Ok(ws.on_upgrade(move |socket| forward(socket, events)))
+}
+
+/// Pushes events to the client until either side goes away. Events are
+/// read from Redis only as fast as the client takes them, and a client
+/// that stalls is disconnected rather than buffered for.
+async fn forward(mut socket: WebSocket, events: impl Stream<Item = String> + Send) {
+    let mut events = pin!(events);
+
+    loop {
+        tokio::select! {
+            event = events.next() => {
+                let Some(event) = event else {
+                    close(socket, close_code::AGAIN, "notification feed lost").await;
+                    return;
+                };
+
+                match tokio::time::timeout(SEND_TIMEOUT, socket.send(Message::Text(event))).await {
+                    Ok(Ok(())) => {}
+                    Ok(Err(_)) => return,
+                    Err(_) => {
+                        tracing::warn!("dropping websocket client that stopped reading");
+                        close(socket, close_code::POLICY, "too slow").await;
+                        return;
+                    }
+                }
+            }
+            incoming = socket.recv() => match incoming {
+                // Pings are answered by axum; anything else the client
+                // sends is ignored.
+                Some(Ok(Message::Close(_)) | Err(_)) | None => return,
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+}
+
+async fn close(mut socket: WebSocket, code: u16, reason: &'static str) {
+    let frame = CloseFrame {
+        code,
+        reason: reason.into(),
+    };
+    let _ = tokio::time::timeout(SEND_TIMEOUT, socket.send(Message::Close(Some(frame)))).await;
+}
@@ -0,0 +1,185 @@
+//! Coordinated shutdown for the server and background tasks.
+//!
+//! The HTTP server runs under [`serve_until_terminated`]: once the process
+//! is asked to stop, it stops accepting connections and gets a drain
+//! timeout to finish the requests already in flight.
+//!
+//! Long-running workers are started through [`Shutdown::spawn`], which
+//! hands each one a [`ShutdownSignal`]. [`Shutdown::drain`] then fires the
+//! signal so workers stop taking new work, and waits up to a grace period
+//! for them to finish what they already hold. Anything still running
+//! after that is aborted and logged.
+
+use std::{
+    future::Future,
+    sync::{Arc, Mutex, PoisonError},
+    time::Duration,
+};
+use tokio::{sync::watch, task::JoinSet};
+
+/// Owns the background tasks and the signal they observe. Cheap to clone;
+/// clones share the same tasks.
+#[derive(Clone)]
+pub struct Shutdown {
+    trigger: Arc<watch::Sender<bool>>,
+    tasks: Arc<Mutex<JoinSet<()>>>,
+}
+
+/// A worker's view of the shutdown. Poll [`is_shutting_down`] between
+/// items, or await [`cancelled`] alongside whatever the worker blocks on.
+///
+/// [`is_shutting_down`]: Self::is_shutting_down
+/// [`cancelled`]: Self::cancelled
+#[derive(Clone)]
+pub struct ShutdownSignal {
+    rx: watch::Receiver<bool>,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        let (trigger, _) = watch::channel(false);
+        Self {
+            trigger: Arc::new(trigger),
+            tasks: Arc::new(Mutex::new(JoinSet::new())),
+        }
+    }
+
+    pub fn signal(&self) -> ShutdownSignal {
+        ShutdownSignal {
+            rx: self.trigger.subscribe(),
+        }
+    }
+
+    /// Runs `task` in the background until it returns. It is expected to
+    /// return soon after its signal fires; see [`drain`](Self::drain).
+    pub fn spawn<F, Fut>(&self, name: &'static str, task: F)
+    where
+        F: FnOnce(ShutdownSignal) -> Fut,
+        Fut: Future<Output = anyhow::Result<()>> + Send + 'static,
+    {
+        let work = task(self.signal());
+        self.lock_tasks().spawn(async move {
+            match work.await {
+                Ok(()) => tracing::debug!(task = name, "background task stopped"),
+                Err(err) => tracing::error!(task = name, error = ?err, "background task failed"),
+            }
+        });
+    }
+
+    /// Signals every task to stop and waits up to `grace` for them to
+    /// return. Returns `false` if some had to be aborted.
+    pub async fn drain(&self, grace: Duration) -> bool {
+        self.trigger.send_replace(true);
+
+        let mut tasks = std::mem::take(&mut *self.lock_tasks());
+        let running = tasks.len();
+        if running == 0 {
+            return true;
+        }
+        tracing::info!(
+            running,
+            grace_secs = grace.as_secs(),
+            "draining background tasks"
+        );
+
+        let drained =
+            tokio::time::timeout(grace, async { while tasks.join_next().await.is_some() {} })
+                .await
+                .is_ok();
+
+        if !drained {
+            tracing::warn!(
+                aborted = tasks.len(),
+                "background tasks did not stop within the grace period"
+            );
+            tasks.shutdown().await;
+        }
+        drained
+    }
+
+    fn lock_tasks(&self) -> std::sync::MutexGuard<'_, JoinSet<()>> {
+        self.tasks.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl ShutdownSignal {
+    pub fn is_shutting_down(&self) -> bool {
+        *self.rx.borrow()
+    }
+
+    /// Resolves once shutdown has begun. Also resolves if the
+    /// [`Shutdown`] itself is dropped, as nothing could drain the task
+    /// then anyway.
+    pub async fn cancelled(&mut self) {
+        let _ = self.rx.wait_for(|stopping| *stopping).await;
+    }
+}
+
+/// Runs the server `serve` starts until it stops by itself, or until
+/// [`termination`]. Then its signal fires, and it has up to `drain` to
+/// stop accepting connections and finish the requests it holds; `serve`
+/// should return once they are done. Past `drain` they are left to be
+/// cut off when the process exits.
+pub async fn serve_until_terminated<F, Fut>(drain: Duration, serve: F) -> anyhow::Result<()>
+where
+    F: FnOnce(ShutdownSignal) -> Fut,
+    Fut: Future<Output = anyhow::Result<()>>,
+{
+    let requests = Shutdown::new();
+    let server = serve(requests.signal());
+    tokio::pin!(server);
+
+    tokio::select! {
+        result = &mut server => return result,
+        () = termination() => {}
+    }
+
+    tracing::info!(
+        drain_secs = drain.as_secs(),
+        "shutdown requested, draining in-flight requests"
+    );
+    requests.trigger.send_replace(true);
+    match tokio::time::timeout(drain, server).await {
+        Ok(result) => result,
+        Err(_) => {
+            tracing::warn!("in-flight requests did not finish within the drain timeout");
+            Ok(())
+        }
+    }
+}
+
+/// Resolves on Ctrl-C, or on `SIGTERM` where there is one.
+pub async fn termination() {
+    let ctrl_c = async {
+        if let Err(err) = tokio::signal::ctrl_c().await {
+            tracing::error!(error = ?err, "cannot listen for Ctrl-C");
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let sigterm = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                sigterm.recv().await;
+            }
+            Err(err) => {
+                tracing::error!(error = ?err, "cannot listen for SIGTERM");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let sigterm = std::future::pending::<()>();
+
+    tokio::select! {
+        () = ctrl_c => {}
+        () = sigterm => {}
+    }
+}
@@ -0,0 +1,109 @@
+use crate::{
+    audit::AuditLog,
+    auth::{
+        auth_service::AuthService, cookie::TokenCookie, csrf::CsrfProtection,
+        impersonation::Impersonations, policy::DenialAudit, signed_url::SignedUrls,
+        step_up::StepUp,
+    },
+    cache::{cache_service::CacheService, redis_client::RedisClients},
+    client_ip::ClientIpResolver,
+    maintenance::Maintenance,
+    notifications::{breaker::WebhookBreaker, dispatch::Dispatcher, Notifier},
+    rate_limit::TenantRateLimits,
+    reports::ReportSlots,
+    tenant::TenantResolver,
+    usage::UsageCounters,
+};
+
+#[derive(Clone)]
+pub struct AppState {
+    pub redis: RedisClients,
+    pub cache: CacheService,
+    /// Uniqueness claims such as registered emails: the primary's auth
+    /// namespace, never best-effort, so no eviction or bulk invalidation
+    /// of cached values can release one.
+    pub claims: CacheService,
+    pub auth: AuthService,
+    pub audit: AuditLog,
+    pub denials: DenialAudit,
+    pub rate_limits: TenantRateLimits,
+    pub client_ip: ClientIpResolver,
+    pub tenants: TenantResolver,
+    pub notifier: Notifier,
+    pub notifications: Dispatcher,
+    pub webhook_breaker: WebhookBreaker,
+    pub maintenance: Maintenance,
+    pub reports: ReportSlots,
+    pub canonicalize_gmail: bool,
+    /// Set when access tokens are also delivered and accepted as cookies.
+    pub token_cookie: Option<TokenCookie>,
+    /// Checked on cookie-authenticated writes; unused without cookies.
+    pub csrf: CsrfProtection,
+    /// Verifies document download links.
+    pub signed_urls: SignedUrls,
+    pub step_up: StepUp,
+    pub impersonations: Impersonations,
+    /// Per-tenant API usage; `None` when metering is off.
+    pub usage: Option<UsageCounters>,
+}
+
+#[cfg(test)]
+impl AppState {
+    /// A state on an in-memory cache and a fake Redis, with every feature
+    /// at its default and generous rate limits. Tests replace the parts
+    /// they exercise.
+    pub(crate) async fn for_tests() -> Self {
+        use std::{sync::Arc, time::Duration};
+
+        use crate::{
+            auth::token_service::TokenService,
+            cache::{fake_redis, memory::MemoryBackend},
+            notifications::{inbox::Inbox, preferences::PreferenceStore},
+            queue::JobQueue,
+            rate_limit::StaticQuotas,
+        };
+
+        const SECRET: &[u8] = b"test-secret-that-is-long-enough";
+
+        let redis = fake_redis::client().await;
+        let cache = CacheService::from_backend(Arc::new(MemoryBackend::new()), "test");
+        let audit = AuditLog::new(redis.clone(), "test", 100);
+        let tokens = TokenService::new("test-secret-that-is-long-enough", Duration::from_secs(900));
+        let notifier = Notifier::new(redis.clone(), cache.auth(), "test");
+
+        Self {
+            redis: RedisClients::shared(redis.clone()),
+            claims: cache.auth(),
+            auth: AuthService::new(tokens.clone(), cache.auth(), audit.clone()),
+            denials: DenialAudit::new(audit.clone(), cache.clone(), Duration::from_secs(300)),
+            rate_limits: TenantRateLimits::new(
+                cache.clone(),
+                Duration::from_secs(60),
+                Arc::new(StaticQuotas::new(Default::default())),
+                1_000,
+                1_000,
+            ),
+            client_ip: ClientIpResolver::new(Vec::new()),
+            tenants: TenantResolver::new(None),
+            notifications: Dispatcher::new(
+                PreferenceStore::new(cache.clone()),
+                Inbox::new(cache.clone()),
+                notifier.clone(),
+                JobQueue::new(redis, "test"),
+            ),
+            notifier,
+            webhook_breaker: WebhookBreaker::new(cache.clone(), 5, Duration::from_secs(30)),
+            maintenance: Maintenance::new(cache.clone()),
+            reports: ReportSlots::new(cache.clone(), 4, Duration::from_secs(1)),
+            canonicalize_gmail: false,
+            token_cookie: None,
+            csrf: CsrfProtection::new(SECRET, Duration::from_secs(3600)),
+            signed_urls: SignedUrls::new(SECRET, Duration::from_secs(300), cache.auth()),
+            step_up: StepUp::new(cache.auth(), Duration::from_secs(300)),
+            impersonations: Impersonations::new(tokens, cache.auth(), audit.clone()),
+            usage: None,
+            audit,
+            cache,
+        }
+    }
+}
@@ -0,0 +1,140 @@
+//! Tracing subscriber setup.
+//!
+//! Logs always go to stdout. Built with the `otel` feature and started
+//! with `OTEL_EXPORTER_OTLP_ENDPOINT` set, spans are also exported over
+//! OTLP/gRPC:
+//!
+//! - `OTEL_SERVICE_NAME` (default `hrapp-backend`)
+//! - `OTEL_SERVICE_VERSION` (default: the crate version)
+//! - `OTEL_TRACES_SAMPLER_ARG`, the fraction of new traces to sample
+//!   (default `1.0`). Requests that arrive with a sampled W3C
+//!   `traceparent` are always kept.
+
+use axum::http::HeaderMap;
+use tracing::Span;
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
+
+/// Flushes pending spans when dropped. Keep it alive for the lifetime of
+/// the process.
+pub struct TelemetryGuard {
+    #[cfg(feature = "otel")]
+    provider: Option<opentelemetry_sdk::trace::TracerProvider>,
+}
+
+impl Drop for TelemetryGuard {
+    fn drop(&mut self) {
+        #[cfg(feature = "otel")]
+        if let Some(provider) = self.provider.take() {
+            if let Err(err) = provider.shutdown() {
+                eprintln!("failed to flush traces: {err}");
+            }
+        }
+    }
+}
+
+pub fn init() -> anyhow::Result<TelemetryGuard> {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let registry = tracing_subscriber::registry()
+        .with(filter)
+        .with(tracing_subscriber::fmt::layer());
+
+    #[cfg(feature = "otel")]
+    {
+        use opentelemetry::trace::TracerProvider as _;
+
+        let provider = otel::provider_from_env()?;
+        let layer = provider
+            .as_ref()
+            .map(|provider| tracing_opentelemetry::layer().with_tracer(provider.tracer("backend")));
+
+        registry.with(layer).init();
+        Ok(TelemetryGuard { provider })
+    }
+
+    #[cfg(not(feature = "otel"))]
+    {
+        registry.init();
+        Ok(TelemetryGuard {})
+    }
+}
+
+/// Makes `span` a child of the caller's trace when the request carries a
+/// W3C `traceparent` header. Does nothing without the `otel` feature.
+pub fn link_remote_parent(span: &Span, headers: &HeaderMap) {
+    #[cfg(feature = "otel")]
+    {
+        use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+        let parent = opentelemetry::global::get_text_map_propagator(|propagator| {
+            propagator.extract(&otel::HeaderExtractor(headers))
+        });
+        span.set_parent(parent);
+    }
+
+    #[cfg(not(feature = "otel"))]
+    let _ = (span, headers);
+}
+
+#[cfg(feature = "otel")]
+mod otel {
+    use anyhow::Context;
+    use axum::http::HeaderMap;
+    use opentelemetry::{propagation::Extractor, KeyValue};
+    use opentelemetry_otlp::WithExportConfig;
+    use opentelemetry_sdk::{
+        propagation::TraceContextPropagator,
+        runtime,
+        trace::{Sampler, TracerProvider},
+        Resource,
+    };
+
+    pub(super) fn provider_from_env() -> anyhow::Result<Option<TracerProvider>> {
+        let Ok(endpoint) = std::env::var("OTEL_EXPORTER_OTLP_ENDPOINT") else {
+            return Ok(None);
+        };
+
+        let service_name =
+            std::env::var("OTEL_SERVICE_NAME").unwrap_or_else(|_| "hrapp-backend".to_string());
+        let service_version = std::env::var("OTEL_SERVICE_VERSION")
+            .unwrap_or_else(|_| env!("CARGO_PKG_VERSION").to_string());
+        let ratio = match std::env::var("OTEL_TRACES_SAMPLER_ARG") {
+            Ok(value) => value
+                .parse::<f64>()
+                .context("OTEL_TRACES_SAMPLER_ARG must be a number between 0 and 1")?,
+            Err(_) => 1.0,
+        };
+
+        let exporter = opentelemetry_otlp::SpanExporter::builder()
+            .with_tonic()
+            .with_endpoint(endpoint)
+            .build()?;
+
+        let provider = TracerProvider::builder()
+            .with_batch_exporter(exporter, runtime::Tokio)
+            .with_sampler(Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(
+                ratio.clamp(0.0, 1.0),
+            ))))
+            .with_resource(Resource::new([
+                KeyValue::new("service.name", service_name),
+                KeyValue::new("service.version", service_version),
+            ]))
+            .build();
+
+        opentelemetry::global::set_text_map_propagator(TraceContextPropagator::new());
+        opentelemetry::global::set_tracer_provider(provider.clone());
+
+        Ok(Some(provider))
+    }
+
+    pub(super) struct HeaderExtractor<'a>(pub &'a HeaderMap);
+
+    impl Extractor for HeaderExtractor<'_> {
+        fn get(&self, key: &str) -> Option<&str> {
+            self.0.get(key).and_then(|value| value.to_str().ok())
+        }
+
+        fn keys(&self) -> Vec<&str> {
+            self.0.keys().map(|key| key.as_str()).collect()
+        }
+    }
+}
@@ -0,0 +1,183 @@
+//! Working out which tenant a request is for.
+//!
+//! Browsers reach a tenant through its subdomain (`acme.app.com` under a
+//! base domain of `app.com`); API clients, which often share one host,
+//! name it in `X-Tenant-Id`. A request may use both as long as they
+//! agree. When the caller is signed in, the tenant must also be the one
+//! their token was issued for, so a token for `acme` can't be used
+//! against `globex.app.com`; a token's tenant alone is enough when the
+//! request names none.
+//!
+//! Handlers take [`Tenant`] and key anything tenant-specific through
+//! [`Tenant::cache`], so it stays isolated and
+//! [`purge_tenant`](CacheService::purge_tenant) can find it.
+
+use axum::{
+    async_trait,
+    extract::FromRequestParts,
+    http::{header::HOST, request::Parts, HeaderMap, HeaderName},
+};
+
+use crate::{
+    auth::{
+        extractor::{request_token, route},
+        token_service::AccessTokenClaims,
+    },
+    cache::cache_service::CacheService,
+    error::AppError,
+    state::AppState,
+};
+
+pub const TENANT_HEADER: HeaderName = HeaderName::from_static("x-tenant-id");
+
+/// Longest tenant id accepted; the most a DNS label can hold.
+const MAX_TENANT_LEN: usize = 63;
+
+/// Reads the tenant a request names.
+#[derive(Debug, Clone, Default)]
+pub struct TenantResolver {
+    /// Hosts one label below this name the tenant; `None` ignores the host.
+    base_domain: Option<String>,
+}
+
+impl TenantResolver {
+    pub fn new(base_domain: Option<String>) -> Self {
+        Self {
+            base_domain: base_domain
+                .map(|domain| domain.trim_matches('.').to_ascii_lowercase())
+                .filter(|domain| !domain.is_empty()),
+        }
+    }
+
+    /// The tenant named by the host and `X-Tenant-Id`, `None` if neither
+    /// names one. A malformed header or one disagreeing with the host is
+    /// refused.
+    pub fn requested(&self, parts: &Parts) -> Result<Option<String>, AppError> {
+        let from_host = self.host_tenant(parts);
+        let from_header = match parts.headers.get(TENANT_HEADER) {
+            Some(value) => Some(
+                value
+                    .to_str()
+                    .ok()
+                    .map(str::trim)
+                    .filter(|id| is_valid_tenant(id))
+                    .ok_or(AppError::BadRequest("invalid X-Tenant-Id"))?
+                    .to_string(),
+            ),
+            None => None,
+        };
+
+        match (from_host, from_header) {
+            (Some(host), Some(header)) if host != header => Err(AppError::Forbidden(
+                "X-Tenant-Id does not match the tenant's host",
+            )),
+            (host, header) => Ok(host.or(header)),
+        }
+    }
+
+    fn host_tenant(&self, parts: &Parts) -> Option<String> {
+        let base = self.base_domain.as_deref()?;
+        let host = host(&parts.headers).or(parts.uri.host())?;
+        let host = host
+            .rsplit_once(':')
+            .filter(|(_, port)| port.bytes().all(|b| b.is_ascii_digit()))
+            .map_or(host, |(name, _)| name)
+            .to_ascii_lowercase();
+
+        let label = host.strip_suffix(base)?.strip_suffix('.')?;
+        is_valid_tenant(label).then(|| label.to_string())
+    }
+}
+
+fn host(headers: &HeaderMap) -> Option<&str> {
+    headers.get(HOST)?.to_str().ok()
+}
+
+/// One DNS label's worth of letters, digits, `-` and `_`, so the same id
+/// works as a subdomain, a header and a cache key tag.
+fn is_valid_tenant(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_TENANT_LEN
+        && id
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_'))
+}
+
+/// The tenant a request is for, reconciled with the caller's token.
+///
+/// Refused with a 400 when no tenant can be determined, and with a 403
+/// when the host, header and token disagree or a signed-in caller's token
+/// carries no tenant at all. Public routes get the requested tenant
+/// unchecked; anything the caller must be allowed to see still needs
+/// [`AuthUser`](crate::auth::extractor::AuthUser).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tenant(pub String);
+
+impl Tenant {
+    pub fn id(&self) -> &str {
+        &self.0
+    }
+
+    /// `cache`, with every key scoped to this tenant.
+    pub fn cache(&self, cache: &CacheService) -> CacheService {
+        cache.for_tenant(&self.0)
+    }
+}
+
+#[async_trait]
+impl FromRequestParts<AppState> for Tenant {
+    type Rejection = AppError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        if let Some(tenant) = parts.extensions.get::<Tenant>() {
+            return Ok(tenant.clone());
+        }
+
+        let requested = state.tenants.requested(parts)?;
+        let claims = match request_token(&parts.headers, state) {
+            Some(token) => match state.auth.authenticate(token).await {
+                Ok(claims) => Some(claims),
+                // Whether the token is any good is for AuthUser to say.
+                Err(
+                    AppError::Unauthorized(_) | AppError::TokenExpired | AppError::BadRequest(_),
+                ) => None,
+                Err(err) => return Err(err),
+            },
+            None => None,
+        };
+
+        let tenant = match claims {
+            Some(claims) => reconcile(requested, &claims, parts, state).await?,
+            None => requested.ok_or(AppError::BadRequest("tenant is required"))?,
+        };
+        let tenant = Tenant(tenant);
+        parts.extensions.insert(tenant.clone());
+        Ok(tenant)
+    }
+}
+
+async fn reconcile(
+    requested: Option<String>,
+    claims: &AccessTokenClaims,
+    parts: &Parts,
+    state: &AppState,
+) -> Result<String, AppError> {
+    let resource = route(parts);
+    match (requested, &claims.tenant_id) {
+        (Some(requested), Some(own)) if requested == *own => Ok(requested),
+        (None, Some(own)) => Ok(own.clone()),
+        (Some(requested), _) => Err(state
+            .denials
+            .deny(
+                claims,
+                &resource,
+                &format!("tenant:{requested}"),
+                "token is not valid for this tenant",
+            )
+            .await),
+        (None, None) => Err(AppError::BadRequest("tenant is required")),
+    }
+}
@@ -0,0 +1,107 @@
+//! How timestamps are written down.
+//!
+//! API responses and stored records carry RFC 3339 timestamps in UTC with
+//! millisecond precision, e.g. `2024-05-01T09:30:00.125Z`, so events within
+//! the same second still sort in the order they happened. Fields opt in
+//! with `#[serde(with = "crate::timestamp::millis")]` (or
+//! [`millis_option`]). Reading also accepts the bare unix seconds records
+//! were written with before, so existing data keeps loading.
+//!
+//! JWT claims (`iat`, `exp`) and similar compact wire formats stay in unix
+//! seconds, as the spec requires. Convert through [`to_unix_seconds`] and
+//! [`from_unix_seconds`] rather than by hand, so the two representations
+//! can't drift apart.
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::{Deserialize, Deserializer};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// `at` as RFC 3339 with milliseconds, the form every response uses. For
+/// timestamps built into JSON by hand, e.g. audit details.
+pub fn format(at: DateTime<Utc>) -> String {
+    at.to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+/// Out-of-range values clamp to the epoch rather than failing; they can
+/// only come from a corrupted or hand-crafted timestamp.
+pub fn from_unix_seconds(secs: i64) -> DateTime<Utc> {
+    DateTime::from_timestamp(secs, 0).unwrap_or(DateTime::UNIX_EPOCH)
+}
+
+/// Like [`from_unix_seconds`], for unix milliseconds.
+pub fn from_unix_millis(millis: i64) -> DateTime<Utc> {
+    DateTime::from_timestamp_millis(millis).unwrap_or(DateTime::UNIX_EPOCH)
+}
+
+/// Whole unix seconds, truncating any fraction. Times before the epoch
+/// clamp to 0.
+pub fn to_unix_seconds(at: DateTime<Utc>) -> usize {
+    usize::try_from(at.timestamp()).unwrap_or(0)
+}
+
+/// Unix seconds now, for wire formats that carry bare integers (JWT
+/// claims, CSRF tokens). Everything else should use [`Utc::now`].
+pub fn current_unix_seconds() -> usize {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("time went backwards")
+        .as_secs() as usize
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Stored {
+    Text(String),
+    Seconds(i64),
+}
+
+impl Stored {
+    fn into_datetime<E: serde::de::Error>(self) -> Result<DateTime<Utc>, E> {
+        match self {
+            Stored::Text(text) => DateTime::parse_from_rfc3339(&text)
+                .map(|at| at.with_timezone(&Utc))
+                .map_err(|err| E::custom(format!("invalid timestamp {text:?}: {err}"))),
+            Stored::Seconds(secs) => Ok(from_unix_seconds(secs)),
+        }
+    }
+}
+
+/// Serde adapter for `DateTime<Utc>` fields.
+pub mod millis {
+    use super::*;
+    use serde::Serializer;
+
+    pub fn serialize<S: Serializer>(at: &DateTime<Utc>, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&format(*at))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<DateTime<Utc>, D::Error> {
+        Stored::deserialize(deserializer)?.into_datetime()
+    }
+}
+
+/// Serde adapter for `Option<DateTime<Utc>>` fields; `None` is `null`.
+pub mod millis_option {
+    use super::*;
+    use serde::Serializer;
+
+    pub fn serialize<S: Serializer>(
+        at: &Option<DateTime<Utc>>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match at {
+            Some(at) => serializer.serialize_some(&format(*at)),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<DateTime<Utc>>, D::Error> {
+        Option::<Stored>::deserialize(deserializer)?
+            .map(Stored::into_datetime)
+            .transpose()
+    }
+}
@@ -0,0 +1,137 @@
+//! Usage metering with buffered counters.
+//!
+//! Recording usage is an `INCRBY` on a Redis counter plus noting the
+//! counter as pending. Nothing is written to durable storage per event.
+//! A background flusher periodically takes each pending count with
+//! `GETDEL`, which reads and resets it in one step, and hands the totals
+//! to a [`UsageSink`]. Because every count is taken atomically, several
+//! instances can flush at once without double counting. A count the sink
+//! fails to persist is added back, to be retried on the next flush.
+
+use anyhow::Result;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use std::{sync::Arc, time::Duration};
+
+use crate::{
+    cache::{
+        cache_service::{CacheService, Persistence},
+        keys,
+    },
+    shutdown::ShutdownSignal,
+};
+
+/// A counter's total since the previous flush.
+#[derive(Debug, Clone)]
+pub struct UsageCount {
+    pub counter: String,
+    pub count: i64,
+    pub flushed_at: DateTime<Utc>,
+}
+
+/// Where flushed totals are persisted.
+#[async_trait]
+pub trait UsageSink: Send + Sync {
+    async fn persist(&self, counts: &[UsageCount]) -> Result<()>;
+}
+
+/// Logs each flushed total. Stands in until usage is persisted to a
+/// database.
+pub struct LogSink;
+
+#[async_trait]
+impl UsageSink for LogSink {
+    async fn persist(&self, counts: &[UsageCount]) -> Result<()> {
+        for usage in counts {
+            tracing::info!(counter = %usage.counter, count = usage.count, "usage flushed");
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+pub struct UsageCounters {
+    cache: CacheService,
+}
+
+impl UsageCounters {
+    pub fn new(cache: CacheService) -> Self {
+        Self { cache }
+    }
+
+    /// Adds `by` to `counter`. Counter names become Redis keys and sink
+    /// rows, so keep them to a bounded set, e.g. `api:tenant:<id>`.
+    pub async fn record(&self, counter: &str, by: i64) -> Result<()> {
+        self.cache
+            .increment(&keys::usage_count(counter), by, Persistence::Persist)
+            .await?;
+        self.cache
+            .sorted_add(
+                &keys::usage_pending(),
+                counter,
+                Utc::now().timestamp(),
+                Persistence::Persist,
+            )
+            .await
+    }
+
+    /// Takes every pending count and persists them to `sink`. Returns how
+    /// many counters were flushed.
+    ///
+    /// A counter leaves the pending set before its count is taken, so one
+    /// bumped in between is only re-added, never lost.
+    pub async fn flush(&self, sink: &dyn UsageSink) -> Result<usize> {
+        let pending = self.cache.sorted_members(&keys::usage_pending()).await?;
+        if pending.is_empty() {
+            return Ok(0);
+        }
+
+        let flushed_at = Utc::now();
+        let mut counts = Vec::with_capacity(pending.len());
+        for counter in pending {
+            self.cache
+                .sorted_remove(&keys::usage_pending(), &[&counter])
+                .await?;
+            if let Some(count) = self.cache.take::<i64>(&keys::usage_count(&counter)).await? {
+                counts.push(UsageCount {
+                    counter,
+                    count,
+                    flushed_at,
+                });
+            }
+        }
+
+        if let Err(err) = sink.persist(&counts).await {
+            for usage in &counts {
+                self.record(&usage.counter, usage.count).await?;
+            }
+            return Err(err.context("failed to persist usage; counts were put back"));
+        }
+        Ok(counts.len())
+    }
+
+    /// Flushes to `sink` every `interval` until shutdown, then once more so
+    /// counts recorded since the last tick aren't left behind.
+    pub async fn run_flusher(
+        self,
+        sink: Arc<dyn UsageSink>,
+        interval: Duration,
+        mut shutdown: ShutdownSignal,
+    ) -> Result<()> {
+        let mut ticker = tokio::time::interval(interval);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+        loop {
+            tokio::select! {
+                _ = ticker.tick() => {}
+                () = shutdown.cancelled() => break,
+            }
+            if let Err(err) = self.flush(sink.as_ref()).await {
+                tracing::warn!(error = ?err, "usage flush failed");
+            }
+        }
+
+        self.flush(sink.as_ref()).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,54 @@
+/// An email address in the form used for storage and uniqueness checks,
+/// alongside the address as the user typed it (trimmed) for display.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NormalizedEmail {
+    pub normalized: String,
+    pub original: String,
+}
+
+/// A trimmed address that passed the plausibility check, as the user
+/// typed it. Normalizing it can't fail, so code holding one never has to
+/// handle an invalid address.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EmailAddress(String);
+
+impl EmailAddress {
+    /// Returns `None` when `raw` isn't a plausible address.
+    pub fn parse(raw: &str) -> Option<Self> {
+        let original = raw.trim();
+        let (local, domain) = original.rsplit_once('@')?;
+
+        if local.is_empty() || domain.is_empty() || !domain.contains('.') || local.contains('@') {
+            return None;
+        }
+        Some(Self(original.to_string()))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Lowercases the address. With `canonicalize_gmail`, Gmail addresses
+    /// additionally lose dots and `+tag` suffixes in the local part, since
+    /// Gmail delivers all of those variants to the same inbox.
+    pub fn normalize(&self, canonicalize_gmail: bool) -> NormalizedEmail {
+        let lowered = self.0.to_lowercase();
+        let normalized = match lowered.rsplit_once('@') {
+            Some((local, "gmail.com" | "googlemail.com")) if canonicalize_gmail => {
+                let local = local.split('+').next().unwrap_or(local).replace('.', "");
+                format!("{local}@gmail.com")
+            }
+            _ => lowered,
+        };
+
+        NormalizedEmail {
+            normalized,
+            original: self.0.clone(),
+        }
+    }
+}
+
+/// [`EmailAddress::parse`] followed by [`EmailAddress::normalize`].
+pub fn normalize_email(raw: &str, canonicalize_gmail: bool) -> Option<NormalizedEmail> {
+    EmailAddress::parse(raw).map(|email| email.normalize(canonicalize_gmail))
+}
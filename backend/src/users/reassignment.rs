@@ -0,0 +1,72 @@
+//! Moving a manager's direct reports to someone else, e.g. when the
+//! manager leaves.
+//!
+//! Reports go to the manager named, or by default to the departing
+//! manager's own manager. The move is all or nothing: the directory
+//! applies it in one transaction, and afterwards every cached management
+//! chain is dropped, since the chains of everyone below the reports
+//! changed too.
+
+use anyhow::Result;
+use async_trait::async_trait;
+
+use crate::{
+    auth::{ids::UserId, policy::OrgHierarchy},
+    cache::{cache_service::CacheService, keys},
+    error::AppError,
+};
+
+/// An [`OrgHierarchy`] whose reporting lines can be changed.
+#[async_trait]
+pub trait OrgDirectory: OrgHierarchy {
+    /// Employees reporting directly to `manager_id`.
+    async fn direct_reports(&self, manager_id: UserId) -> Result<Vec<UserId>>;
+
+    /// Makes every direct report of `from` report to `to` instead, in a
+    /// single transaction, and returns who moved.
+    async fn reassign_reports(&self, from: UserId, to: UserId) -> Result<Vec<UserId>>;
+}
+
+/// Moves `from`'s direct reports to `to`, or to `from`'s manager when
+/// `to` is `None`, and returns the employees moved.
+///
+/// Refused with a 400 when `to` is `from` or `from` has no manager to
+/// fall back to, and with a 409 when `to` reports to `from`, since the
+/// reports would then end up managing themselves. `cache` is the one the
+/// [`CachedOrgHierarchy`](crate::auth::policy::CachedOrgHierarchy) in
+/// front of `directory` uses; it is passed the undecorated directory so
+/// the cycle check reads current reporting lines.
+pub async fn reassign_direct_reports(
+    directory: &dyn OrgDirectory,
+    cache: &CacheService,
+    from: UserId,
+    to: Option<UserId>,
+) -> Result<Vec<UserId>, AppError> {
+    let to = match to {
+        Some(to) => to,
+        None => directory
+            .management_chain(from)
+            .await?
+            .first()
+            .copied()
+            .ok_or(AppError::BadRequest(
+                "manager has no manager to take their reports",
+            ))?,
+    };
+    if to == from {
+        return Err(AppError::BadRequest(
+            "reports can't be reassigned to their own manager",
+        ));
+    }
+    if directory.management_chain(to).await?.contains(&from) {
+        return Err(AppError::Conflict(
+            "new manager reports to the departing manager",
+        ));
+    }
+
+    let moved = directory.reassign_reports(from, to).await?;
+    if !moved.is_empty() {
+        cache.delete_by_pattern(keys::ORG_CHAIN_PATTERN).await?;
+    }
+    Ok(moved)
+}
@@ -0,0 +1,127 @@
+//! Request bodies and query strings that are checked before a handler
+//! sees them.
+//!
+//! A raw DTO is deserialized as-is and then converted with `TryFrom` into
+//! a type that upholds its invariants. Handlers take [`Valid<T>`] or
+//! [`ValidatedQuery<T>`] and only ever work with the converted type.
+//! Invalid input never gets past the extractor, and every rule for one
+//! body or query lives in one `TryFrom` impl.
+
+use axum::{
+    async_trait,
+    extract::{FromRequest, FromRequestParts, Query, Request},
+    http::request::Parts,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::de::DeserializeOwned;
+use std::{fmt::Display, ops::RangeInclusive};
+
+use crate::error::{AppError, FieldError};
+
+/// A validated request body and the raw DTO it is converted from.
+pub trait RequestBody: TryFrom<Self::Raw, Error = AppError> {
+    type Raw: DeserializeOwned;
+}
+
+/// Extracts a JSON `T::Raw` and converts it into `T`. Malformed JSON is
+/// rejected as [`Json`] would reject it; a failed conversion is returned
+/// as its [`AppError`].
+pub struct Valid<T>(pub T);
+
+#[async_trait]
+impl<S, T> FromRequest<S> for Valid<T>
+where
+    S: Send + Sync,
+    T: RequestBody,
+    T::Raw: Send,
+{
+    type Rejection = Response;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let Json(raw) = Json::<T::Raw>::from_request(req, state)
+            .await
+            .map_err(IntoResponse::into_response)?;
+        T::try_from(raw).map(Valid).map_err(IntoResponse::into_response)
+    }
+}
+
+/// Validated query parameters and the raw DTO they are converted from.
+/// Defaults belong on the raw DTO, as `#[serde(default)]`s.
+pub trait QueryParams: TryFrom<Self::Raw, Error = AppError> {
+    type Raw: DeserializeOwned;
+}
+
+/// Extracts the query string as `T::Raw` and converts it into `T`. Both
+/// a query string that doesn't deserialize and a failed conversion are a
+/// 422 listing the offending fields.
+pub struct ValidatedQuery<T>(pub T);
+
+#[async_trait]
+impl<S, T> FromRequestParts<S> for ValidatedQuery<T>
+where
+    S: Send + Sync,
+    T: QueryParams,
+{
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let Query(raw) = Query::<T::Raw>::try_from_uri(&parts.uri).map_err(|rejection| {
+            AppError::Invalid(vec![FieldError {
+                field: "query",
+                message: rejection.body_text(),
+            }])
+        })?;
+        T::try_from(raw).map(ValidatedQuery)
+    }
+}
+
+/// Collects every problem with an input, so a client learns about all of
+/// them from one response.
+#[derive(Debug, Default)]
+pub struct FieldErrors(Vec<FieldError>);
+
+impl FieldErrors {
+    pub fn add(&mut self, field: &'static str, message: impl Into<String>) {
+        self.0.push(FieldError {
+            field,
+            message: message.into(),
+        });
+    }
+
+    pub fn check(&mut self, ok: bool, field: &'static str, message: impl Into<String>) {
+        if !ok {
+            self.add(field, message);
+        }
+    }
+
+    pub fn in_range<T: PartialOrd + Display>(
+        &mut self,
+        field: &'static str,
+        value: &T,
+        range: RangeInclusive<T>,
+    ) {
+        if !range.contains(value) {
+            self.add(
+                field,
+                format!("must be between {} and {}", range.start(), range.end()),
+            );
+        }
+    }
+
+    /// `Err(AppError::Invalid)` if anything was added.
+    pub fn finish(self) -> Result<(), AppError> {
+        if self.0.is_empty() {
+            Ok(())
+        } else {
+            Err(AppError::Invalid(self.0))
+        }
+    }
+}
+
+/// The requested page size, or `default` without one, clamped to
+/// `1..=max`. Oversized pages are trimmed rather than refused, so clients
+/// can ask for "as many as allowed".
+pub fn page_size(requested: Option<usize>, default: usize, max: usize) -> usize {
+    requested.unwrap_or(default).clamp(1, max)
+}
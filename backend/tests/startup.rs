@@ -0,0 +1,42 @@
+//! Startup failures end the process with an error report and status 1,
+//! not a panic.
+
+use std::process::{Command, Output};
+
+const JWT_SECRET: &str = "0123456789abcdef0123456789abcdef";
+
+/// Runs the server binary with only `vars` set, so nothing from the
+/// caller's environment leaks in.
+fn start(vars: &[(&str, &str)]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_backend"))
+        .env_clear()
+        .envs(vars.iter().copied())
+        .output()
+        .expect("failed to run the server binary")
+}
+
+fn assert_failed_with(output: &Output, message: &str) {
+    let stderr = String::from_utf8_lossy(&output.stderr);
+
+    assert_eq!(output.status.code(), Some(1), "stderr: {stderr}");
+    assert!(stderr.contains(message), "stderr: {stderr}");
+    assert!(!stderr.contains("panicked"), "stderr: {stderr}");
+}
+
+#[test]
+fn unreachable_redis_is_reported() {
+    let output = start(&[
+        ("JWT_SECRET", JWT_SECRET),
+        ("REDIS_URL", "redis://127.0.0.1:1"),
+        ("REDIS_STARTUP_ATTEMPTS", "1"),
+    ]);
+
+    assert_failed_with(&output, "Error: failed to connect to Redis");
+}
+
+#[test]
+fn invalid_config_is_reported() {
+    let output = start(&[("REDIS_URL", "redis://127.0.0.1:1")]);
+
+    assert_failed_with(&output, "JWT_SECRET or JWT_SECRET_FILE must be set");
+}